@@ -0,0 +1 @@
+ALTER TABLE indexes ADD COLUMN archived_at DATETIME;
@@ -0,0 +1,5 @@
+CREATE TABLE compactions (
+    index_id VARCHAR PRIMARY KEY NOT NULL,
+    last_compaction_at DATETIME,
+    writes_since_compaction INTEGER NOT NULL DEFAULT(0)
+);
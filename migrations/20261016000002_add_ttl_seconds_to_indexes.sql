@@ -0,0 +1 @@
+ALTER TABLE indexes ADD COLUMN ttl_seconds INTEGER;
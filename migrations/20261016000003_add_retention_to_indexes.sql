@@ -0,0 +1,2 @@
+ALTER TABLE indexes ADD COLUMN last_activity_at DATETIME;
+ALTER TABLE indexes ADD COLUMN stale_at DATETIME;
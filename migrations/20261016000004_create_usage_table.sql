@@ -0,0 +1,10 @@
+CREATE TABLE usage (
+    index_id VARCHAR NOT NULL,
+    day DATE NOT NULL,
+    endpoint VARCHAR NOT NULL,
+    requests INTEGER NOT NULL DEFAULT(0),
+    errors INTEGER NOT NULL DEFAULT(0),
+    bytes_received INTEGER NOT NULL DEFAULT(0),
+    bytes_sent INTEGER NOT NULL DEFAULT(0),
+    PRIMARY KEY (index_id, day, endpoint)
+);
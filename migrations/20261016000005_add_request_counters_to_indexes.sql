@@ -0,0 +1,4 @@
+ALTER TABLE indexes ADD COLUMN fetches INTEGER NOT NULL DEFAULT(0);
+ALTER TABLE indexes ADD COLUMN upserts INTEGER NOT NULL DEFAULT(0);
+ALTER TABLE indexes ADD COLUMN chain_inserts INTEGER NOT NULL DEFAULT(0);
+ALTER TABLE indexes ADD COLUMN rejected_signatures INTEGER NOT NULL DEFAULT(0);
@@ -0,0 +1,2 @@
+ALTER TABLE compactions ADD COLUMN lock_token VARCHAR;
+ALTER TABLE compactions ADD COLUMN lock_expires_at DATETIME;
@@ -0,0 +1,4 @@
+CREATE TABLE index_settings (
+    index_id VARCHAR NOT NULL PRIMARY KEY,
+    settings TEXT NOT NULL
+);
@@ -0,0 +1 @@
+ALTER TABLE compactions ADD COLUMN progress TEXT;
@@ -0,0 +1 @@
+ALTER TABLE index_settings ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,189 @@
+/// Short-lived fetch-only access tokens, to let analysts search an index without sharing the
+/// long-term callback seeds.
+///
+/// `POST /indexes/{id}/access_tokens` with `{"ttl_seconds": 3600}` (1 hour by default, at most
+/// `ACCESS_TOKEN_MAX_TTL_SECONDS`, 1 day by default) returns `{"token": "…", "expires_at": …}`.
+/// The index owner proves it holds the seeds by signing the JSON body like a `fetch_entries`
+/// callback (signature and expiration timestamp before the data, signed with the
+/// `fetch_entries` seed). The administrators can also mint tokens with the admin API key as
+/// bearer token and a plain JSON body.
+///
+/// `fetch_entries` and `fetch_chains` accept the token as a bearer token
+/// (`Authorization: Bearer <token>`) with an unsigned body (only the serialized UIDs) until it
+/// expires. Upserts and chain inserts still require a signature.
+///
+/// Tokens are not stored: a token is the expiration timestamp with its KMAC under a key derived
+/// from the `fetch_entries` seed of the index, and cannot be used for another index. They cannot
+/// be revoked individually, keep the TTL short (deleting the index invalidates them).
+use std::{
+    env,
+    future::{ready, Ready},
+};
+
+use actix_web::{
+    dev::Payload,
+    http::header::Header,
+    post,
+    web::{Bytes, Data, Json},
+    FromRequest, HttpRequest,
+};
+use actix_web_httpauth::headers::authorization::{Authorization, Bearer};
+use base64::{engine::general_purpose, Engine};
+use chrono::{NaiveDateTime, Utc};
+use cloudproof_findex::cloud::{CALLBACK_SIGNATURE_LENGTH, SIGNATURE_SEED_LENGTH};
+use cosmian_crypto_core::bytes_ser_de::Serializable;
+use cosmian_findex::{kmac, parameters::KmacKey, KeyingMaterial};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::{
+    admin::Admin,
+    core::{check_body_signature, Index},
+    errors::{Error, Response},
+};
+
+const DEFAULT_ACCESS_TOKEN_TTL_SECONDS: u64 = 60 * 60;
+const DEFAULT_ACCESS_TOKEN_MAX_TTL_SECONDS: u64 = 24 * 60 * 60;
+
+pub(crate) struct AccessTokens {
+    max_ttl_seconds: u64,
+}
+
+impl AccessTokens {
+    pub(crate) fn from_env() -> Self {
+        AccessTokens {
+            max_ttl_seconds: env::var("ACCESS_TOKEN_MAX_TTL_SECONDS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(DEFAULT_ACCESS_TOKEN_MAX_TTL_SECONDS),
+        }
+    }
+}
+
+/// KMAC of the expiration timestamp, the key is specific to the access tokens of this index.
+fn token_mac(index: &Index, expires_at: u64) -> Result<[u8; CALLBACK_SIGNATURE_LENGTH], Error> {
+    let key: KmacKey =
+        KeyingMaterial::<SIGNATURE_SEED_LENGTH>::deserialize(index.fetch_entries_key.as_slice())?
+            .derive_kmac_key::<CALLBACK_SIGNATURE_LENGTH>(
+            format!("{}/access_token", index.id).as_bytes(),
+        );
+
+    Ok(kmac!(
+        CALLBACK_SIGNATURE_LENGTH,
+        &key,
+        &expires_at.to_be_bytes()
+    ))
+}
+
+fn check_token(token: &str, index: &Index) -> Result<(), Error> {
+    let bytes = general_purpose::URL_SAFE_NO_PAD
+        .decode(token)
+        .map_err(|_| Error::Unauthorized)?;
+
+    if bytes.len() != 8 + CALLBACK_SIGNATURE_LENGTH {
+        return Err(Error::Unauthorized);
+    }
+    let (expires_at, mac) = bytes.split_at(8);
+    let expires_at = u64::from_be_bytes(expires_at.try_into().map_err(|_| Error::Unauthorized)?);
+
+    // Compare the whole MACs to not leak the position of the first wrong byte
+    let difference = token_mac(index, expires_at)?
+        .iter()
+        .zip(mac)
+        .fold(0, |difference, (a, b)| difference | (a ^ b));
+    if difference != 0 {
+        return Err(Error::Unauthorized);
+    }
+
+    if Utc::now().timestamp() as u64 > expires_at {
+        return Err(Error::Unauthorized);
+    }
+
+    Ok(())
+}
+
+/// Extractor of the bearer token of the fetch callbacks, `None` without `Authorization` header.
+pub(crate) struct AccessToken(Option<String>);
+
+impl FromRequest for AccessToken {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(AccessToken(
+            Authorization::<Bearer>::parse(req)
+                .ok()
+                .map(|authorization| authorization.as_ref().token().to_string()),
+        )))
+    }
+}
+
+impl AccessToken {
+    /// Data of a fetch callback: the whole body with a valid access token, otherwise the
+    /// signed data (see `check_body_signature()`).
+    pub(crate) fn check_fetch_body(
+        &self,
+        bytes: Bytes,
+        index: &Index,
+        seed: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, Error> {
+        match &self.0 {
+            Some(token) => {
+                check_token(token, index)?;
+                Ok(Zeroizing::new(bytes.to_vec()))
+            }
+            None => check_body_signature(bytes, &index.id, seed),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct NewAccessTokenRequest {
+    ttl_seconds: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct NewAccessToken {
+    token: String,
+    expires_at: NaiveDateTime,
+}
+
+#[post("/indexes/{id}/access_tokens")]
+pub(crate) async fn post_access_token(
+    index: Index,
+    admin: Option<Admin>,
+    bytes: Bytes,
+    access_tokens: Data<AccessTokens>,
+) -> Response<NewAccessToken> {
+    let data = match admin {
+        Some(Admin) => Zeroizing::new(bytes.to_vec()),
+        None => check_body_signature(bytes, &index.id, &index.fetch_entries_key)?,
+    };
+
+    let request: NewAccessTokenRequest = if data.is_empty() {
+        Default::default()
+    } else {
+        serde_json::from_slice(&data)?
+    };
+
+    let ttl_seconds = request
+        .ttl_seconds
+        .unwrap_or(DEFAULT_ACCESS_TOKEN_TTL_SECONDS);
+    if !(1..=access_tokens.max_ttl_seconds).contains(&ttl_seconds) {
+        return Err(Error::BadRequest(format!(
+            "`ttl_seconds` must be between 1 and {}",
+            access_tokens.max_ttl_seconds
+        )));
+    }
+
+    let expires_at = Utc::now().timestamp() as u64 + ttl_seconds;
+
+    let mut token = expires_at.to_be_bytes().to_vec();
+    token.extend(token_mac(&index, expires_at)?);
+
+    Ok(Json(NewAccessToken {
+        token: general_purpose::URL_SAFE_NO_PAD.encode(token),
+        expires_at: NaiveDateTime::from_timestamp_opt(expires_at as i64, 0)
+            .unwrap_or(NaiveDateTime::MAX),
+    }))
+}
@@ -0,0 +1,71 @@
+/// Authentication of the administration endpoints (`/admin/*`).
+///
+/// The administration endpoints are only available if an `ADMIN_API_KEY` env
+/// variable is set. Requests must send this key as a bearer token
+/// (`Authorization: Bearer <ADMIN_API_KEY>`). The key can also be read from the
+/// file at `ADMIN_API_KEY_FILE`. With passkeys (see `webauthn.rs`), the session cookie is
+/// accepted instead.
+use std::future::{ready, Ready};
+
+use actix_web::{dev::Payload, http::header::Header, web::Data, FromRequest, HttpRequest};
+use actix_web_httpauth::headers::authorization::{Authorization, Bearer};
+
+use crate::{config, errors::Error};
+
+pub(crate) struct AdminApiKey(String);
+
+impl AdminApiKey {
+    pub(crate) fn from_env() -> Option<Data<AdminApiKey>> {
+        config::secret_from_env("ADMIN_API_KEY")
+            .filter(|key| !key.is_empty())
+            .map(|key| Data::new(AdminApiKey(key)))
+    }
+}
+
+/// Extractor checking the admin API key. Add it as a parameter to the
+/// administration handlers.
+pub(crate) struct Admin;
+
+impl FromRequest for Admin {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        #[cfg(feature = "webauthn")]
+        if req
+            .app_data::<Data<crate::webauthn::WebAuthn>>()
+            .map_or(false, |webauthn| webauthn.has_session(req))
+        {
+            return ready(Ok(Admin));
+        }
+
+        let Some(admin_api_key) = req.app_data::<Data<AdminApiKey>>() else {
+            return ready(Err(Error::Unauthorized));
+        };
+
+        let result = Authorization::<Bearer>::parse(req)
+            .map_err(|_| Error::Unauthorized)
+            .and_then(|authorization| {
+                check_bearer_token(authorization.as_ref().token(), &admin_api_key.0)
+            })
+            .map(|_| Admin);
+
+        ready(result)
+    }
+}
+
+/// Compare the whole strings to not leak the position of the first wrong byte.
+pub(crate) fn check_bearer_token(token: &str, key: &str) -> Result<(), Error> {
+    let token = token.as_bytes();
+    let key = key.as_bytes();
+    let difference = token
+        .iter()
+        .zip(key)
+        .fold(0, |difference, (a, b)| difference | (a ^ b));
+
+    if token.len() != key.len() || difference != 0 {
+        return Err(Error::Unauthorized);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,346 @@
+/// Alerts when an index grows beyond a storage threshold, to find the runaway indexes
+/// before the disk is full, and when the signature checks of an index fail at an anomalous
+/// rate.
+///
+/// The global thresholds are `STORAGE_ALERT_SIZE_BYTES` (size of the index) and
+/// `STORAGE_ALERT_ENTRIES_COUNT` (number of entries). `STORAGE_ALERT_INDEX_THRESHOLDS` overrides
+/// them per index with a JSON object, for example
+/// `{"abcde": {"size_bytes": 1000000000, "entries_count": null}}` (`null` disables a global
+/// threshold for this index, a missing field keeps it).
+///
+/// The sizes are checked after the writes (see `Compactions::record_writes_in_background`).
+/// When a threshold is crossed, a warning is logged and, with the "webhooks" feature,
+/// `STORAGE_ALERT_WEBHOOK_URL` receives a `POST` with the index ID, the metric, its value and
+/// the threshold (or the `storage_alert_webhook_url` of the settings of the index, see
+/// `settings.rs`). The alert is sent once, and again after the value went back below the
+/// threshold (or after a restart of the server). The indexes databases without sizes
+/// (DynamoDB) never trigger alerts.
+///
+/// A spike of rejected signatures (wrong or expired signature or access token, see
+/// `counters.rs`) usually means an attack or a client with a broken key.
+/// `SIGNATURE_ALERT_THRESHOLD` is the number of rejected requests of an index during a window
+/// of `SIGNATURE_ALERT_WINDOW_SECONDS` seconds (60 by default) raising an alert: a warning is
+/// logged, `findex_cloud_signature_alerts_total` is incremented (see `metrics.rs`) and, with
+/// the "webhooks" feature, `SIGNATURE_ALERT_WEBHOOK_URL` receives a `POST` with the index ID,
+/// the number of rejected requests, the window and the threshold. The alert is sent once per
+/// spike, and again after a window below the threshold. The rejections are counted per
+/// instance.
+///
+/// With the "smtp" feature, both alerts are also sent by email (see `smtp.rs`).
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::web::Data;
+use serde::{Deserialize, Deserializer};
+
+#[cfg(feature = "webhooks")]
+use crate::settings::IndexSettings;
+use crate::{
+    core::{Index, MetadataDatabase},
+    metrics::Metrics,
+};
+
+#[derive(Clone, Copy, Debug)]
+struct Thresholds {
+    size_bytes: Option<i64>,
+    entries_count: Option<i64>,
+}
+
+/// Per index override, `Some(None)` disables the global threshold.
+#[derive(Deserialize, Clone, Copy, Debug)]
+struct IndexThresholds {
+    #[serde(default, deserialize_with = "explicit_null")]
+    size_bytes: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    entries_count: Option<Option<i64>>,
+}
+
+/// Distinguish a missing field (`None`) from an explicit `null` (`Some(None)`),
+/// with `#[serde(default, deserialize_with = "explicit_null")]`.
+pub(crate) fn explicit_null<'de, D: Deserializer<'de>, T: Deserialize<'de>>(
+    deserializer: D,
+) -> Result<Option<Option<T>>, D::Error> {
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+pub(crate) struct StorageAlerts {
+    global: Thresholds,
+    per_index: HashMap<String, IndexThresholds>,
+    /// Index IDs and metrics above their threshold, already alerted
+    alerted: Mutex<HashSet<(String, &'static str)>>,
+    #[cfg(feature = "webhooks")]
+    webhook_url: Option<String>,
+}
+
+impl StorageAlerts {
+    /// `None` if no threshold is configured
+    pub(crate) fn from_env() -> Option<Self> {
+        let threshold = |name: &str| {
+            env::var(name).ok().map(|value| {
+                value
+                    .parse()
+                    .unwrap_or_else(|_| panic!("`{name}` must be a number (found `{value}`)"))
+            })
+        };
+
+        let global = Thresholds {
+            size_bytes: threshold("STORAGE_ALERT_SIZE_BYTES"),
+            entries_count: threshold("STORAGE_ALERT_ENTRIES_COUNT"),
+        };
+
+        let per_index: HashMap<String, IndexThresholds> =
+            match env::var("STORAGE_ALERT_INDEX_THRESHOLDS") {
+                Ok(json) => serde_json::from_str(&json).unwrap_or_else(|err| {
+                    panic!("Cannot parse `STORAGE_ALERT_INDEX_THRESHOLDS` ({err})")
+                }),
+                Err(_) => HashMap::new(),
+            };
+
+        #[cfg(not(feature = "webhooks"))]
+        if env::var("STORAGE_ALERT_WEBHOOK_URL").is_ok() {
+            panic!("Cannot load `STORAGE_ALERT_WEBHOOK_URL` because `findex_cloud` wasn't compiled with \"webhooks\" feature.");
+        }
+
+        if global.size_bytes.is_none() && global.entries_count.is_none() && per_index.is_empty() {
+            return None;
+        }
+
+        Some(StorageAlerts {
+            global,
+            per_index,
+            alerted: Mutex::new(HashSet::new()),
+            #[cfg(feature = "webhooks")]
+            webhook_url: env::var("STORAGE_ALERT_WEBHOOK_URL").ok(),
+        })
+    }
+
+    fn thresholds(&self, index_id: &str) -> Thresholds {
+        let Some(overrides) = self.per_index.get(index_id) else {
+            return self.global;
+        };
+
+        Thresholds {
+            size_bytes: overrides.size_bytes.unwrap_or(self.global.size_bytes),
+            entries_count: overrides.entries_count.unwrap_or(self.global.entries_count),
+        }
+    }
+
+    /// `index` must have its sizes set (see `IndexesDatabase::set_size`).
+    #[cfg_attr(not(feature = "webhooks"), allow(unused_variables))]
+    pub(crate) async fn check(&self, index: &Index, metadata_db: &dyn MetadataDatabase) {
+        let thresholds = self.thresholds(&index.id);
+
+        for (metric, value, threshold) in [
+            ("size_bytes", index.size, thresholds.size_bytes),
+            (
+                "entries_count",
+                index.entries_count,
+                thresholds.entries_count,
+            ),
+        ] {
+            let (Some(value), Some(threshold)) = (value, threshold) else {
+                continue;
+            };
+
+            let newly_crossed = match self.alerted.lock() {
+                Ok(mut alerted) if value >= threshold => alerted.insert((index.id.clone(), metric)),
+                Ok(mut alerted) => {
+                    alerted.remove(&(index.id.clone(), metric));
+                    false
+                }
+                Err(_) => false,
+            };
+
+            if newly_crossed {
+                log::warn!(
+                    "Index {} crossed its storage alert threshold: {metric} is {value} (threshold {threshold})",
+                    index.id
+                );
+
+                #[cfg(feature = "smtp")]
+                crate::smtp::notify(crate::smtp::Alert::StorageThreshold {
+                    index_id: index.id.clone(),
+                    metric,
+                    value,
+                    threshold,
+                });
+
+                #[cfg(feature = "webhooks")]
+                self.notify(metadata_db, &index.id, metric, value, threshold)
+                    .await;
+            }
+        }
+    }
+
+    #[cfg(feature = "webhooks")]
+    async fn notify(
+        &self,
+        metadata_db: &dyn MetadataDatabase,
+        index_id: &str,
+        metric: &str,
+        value: i64,
+        threshold: i64,
+    ) {
+        let settings = match metadata_db.get_settings(index_id).await {
+            Ok(settings) => settings.unwrap_or_default(),
+            Err(err) => {
+                log::error!("Cannot read the settings of index {index_id} for the storage alert webhook ({err:?})");
+                IndexSettings::default()
+            }
+        };
+        let Some(webhook_url) = settings
+            .storage_alert_webhook_url
+            .as_ref()
+            .or(self.webhook_url.as_ref())
+        else {
+            return;
+        };
+
+        let result = reqwest::Client::new()
+            .post(webhook_url)
+            .json(&serde_json::json!({
+                "index_id": index_id,
+                "metric": metric,
+                "value": value,
+                "threshold": threshold,
+            }))
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+
+        if let Err(err) = result {
+            log::error!("Cannot notify the storage alert webhook for index {index_id} ({err})");
+        }
+    }
+}
+
+const DEFAULT_SIGNATURE_ALERT_WINDOW_SECONDS: u64 = 60;
+
+/// Rejected signatures of an index during the current window
+struct RejectionsWindow {
+    started_at: Instant,
+    rejections: u64,
+    /// Alerted since the spike started
+    alerted: bool,
+}
+
+pub(crate) struct SignatureAlerts {
+    threshold: u64,
+    window: Duration,
+    /// By index ID
+    windows: Mutex<HashMap<String, RejectionsWindow>>,
+    metrics: Data<Metrics>,
+    #[cfg(feature = "webhooks")]
+    webhook_url: Option<String>,
+}
+
+impl SignatureAlerts {
+    /// `None` if `SIGNATURE_ALERT_THRESHOLD` is not set
+    pub(crate) fn from_env(metrics: Data<Metrics>) -> Option<Self> {
+        let parse = |name: &str| {
+            env::var(name).ok().map(|value| match value.parse::<u64>() {
+                Ok(number) if number > 0 => number,
+                _ => panic!("`{name}` must be a positive number (found `{value}`)"),
+            })
+        };
+
+        #[cfg(not(feature = "webhooks"))]
+        if env::var("SIGNATURE_ALERT_WEBHOOK_URL").is_ok() {
+            panic!("Cannot load `SIGNATURE_ALERT_WEBHOOK_URL` because `findex_cloud` wasn't compiled with \"webhooks\" feature.");
+        }
+
+        let threshold = parse("SIGNATURE_ALERT_THRESHOLD")?;
+        let window = Duration::from_secs(
+            parse("SIGNATURE_ALERT_WINDOW_SECONDS")
+                .unwrap_or(DEFAULT_SIGNATURE_ALERT_WINDOW_SECONDS),
+        );
+
+        Some(SignatureAlerts {
+            threshold,
+            window,
+            windows: Mutex::new(HashMap::new()),
+            metrics,
+            #[cfg(feature = "webhooks")]
+            webhook_url: env::var("SIGNATURE_ALERT_WEBHOOK_URL").ok(),
+        })
+    }
+
+    /// Count a request of `index_id` refused by the signature check, and alert if it starts
+    /// a spike.
+    pub(crate) fn record_rejection(&self, index_id: &str) {
+        let rejections = match self.windows.lock() {
+            Ok(mut windows) => {
+                let now = Instant::now();
+                let window =
+                    windows
+                        .entry(index_id.to_string())
+                        .or_insert_with(|| RejectionsWindow {
+                            started_at: now,
+                            rejections: 0,
+                            alerted: false,
+                        });
+
+                let elapsed = now.duration_since(window.started_at);
+                if elapsed >= self.window {
+                    // The spike ends after a window below the threshold (or without requests)
+                    if window.rejections < self.threshold || elapsed >= 2 * self.window {
+                        window.alerted = false;
+                    }
+                    window.started_at = now;
+                    window.rejections = 0;
+                }
+
+                window.rejections += 1;
+                if window.rejections < self.threshold || window.alerted {
+                    return;
+                }
+                window.alerted = true;
+                window.rejections
+            }
+            Err(_) => return,
+        };
+
+        log::warn!(
+            "Index {index_id} had {rejections} requests refused by the signature check in less than {} seconds (attack or client with a broken key?)",
+            self.window.as_secs()
+        );
+        self.metrics.record_signature_alert(index_id);
+
+        #[cfg(feature = "smtp")]
+        crate::smtp::notify(crate::smtp::Alert::SignatureSpike {
+            index_id: index_id.to_string(),
+            rejected_requests: rejections,
+            window_seconds: self.window.as_secs(),
+        });
+
+        #[cfg(feature = "webhooks")]
+        if let Some(webhook_url) = self.webhook_url.clone() {
+            let body = serde_json::json!({
+                "index_id": index_id,
+                "rejected_requests": rejections,
+                "window_seconds": self.window.as_secs(),
+                "threshold": self.threshold,
+            });
+            let index_id = index_id.to_string();
+
+            actix_web::rt::spawn(async move {
+                let result = reqwest::Client::new()
+                    .post(webhook_url)
+                    .json(&body)
+                    .send()
+                    .await
+                    .and_then(reqwest::Response::error_for_status);
+
+                if let Err(err) = result {
+                    log::error!(
+                        "Cannot notify the signature alert webhook for index {index_id} ({err})"
+                    );
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,98 @@
+/// Global allocator wiping every heap block before freeing it ("zeroize_on_free" feature).
+///
+/// The seeds and the decoded payloads owned by Findex Cloud are wiped after use (`Zeroizing`,
+/// `Drop for Index`, `wipe_table()`), but some buffers are out of reach: the request bodies and
+/// the responses inside actix-web, the rows read by the database drivers, the copies made by
+/// `serde`… With this allocator nothing freed lingers in the heap, at the cost of a write of
+/// each block on deallocation.
+///
+/// Only the `findex_cloud` binary sets it: the library must not impose its global allocator
+/// on the programs registering custom backends (see `plugin`).
+use std::alloc::{GlobalAlloc, Layout, System};
+
+use zeroize::Zeroize;
+
+/// Wipes the blocks before freeing them with `A`
+struct ZeroizingAllocator<A>(A);
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for ZeroizingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.0.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        std::slice::from_raw_parts_mut(ptr, layout.size()).zeroize();
+        self.0.dealloc(ptr, layout)
+    }
+
+    // The default `realloc()` allocates a new block, copies the data and deallocates the old
+    // block (wiped): `System.realloc()` could leave the data in the old place.
+}
+
+#[global_allocator]
+static ALLOCATOR: ZeroizingAllocator<System> = ZeroizingAllocator(System);
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    const BODY: &[u8] = b"signature, expiration timestamp and callback payload";
+
+    /// Copies the blocks before freeing them
+    struct Recorder(Mutex<Vec<Vec<u8>>>);
+
+    unsafe impl GlobalAlloc for Recorder {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            let freed = std::slice::from_raw_parts(ptr, layout.size()).to_vec();
+            self.0.lock().unwrap().push(freed);
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    fn recording_allocator() -> ZeroizingAllocator<Recorder> {
+        ZeroizingAllocator(Recorder(Mutex::new(vec![])))
+    }
+
+    /// Allocate a block with `allocator` and copy the body inside
+    unsafe fn body_block(allocator: &ZeroizingAllocator<Recorder>) -> (*mut u8, Layout) {
+        let layout = Layout::array::<u8>(BODY.len()).unwrap();
+        let ptr = allocator.alloc(layout);
+        assert!(!ptr.is_null());
+        ptr.copy_from_nonoverlapping(BODY.as_ptr(), BODY.len());
+        (ptr, layout)
+    }
+
+    #[test]
+    fn freed_body_is_wiped() {
+        let allocator = recording_allocator();
+        unsafe {
+            let (ptr, layout) = body_block(&allocator);
+            allocator.dealloc(ptr, layout);
+        }
+
+        let freed = allocator.0 .0.into_inner().unwrap();
+        assert_eq!(freed, vec![vec![0; BODY.len()]]);
+    }
+
+    #[test]
+    fn reallocated_body_is_wiped() {
+        let allocator = recording_allocator();
+        unsafe {
+            let (ptr, layout) = body_block(&allocator);
+            let new_size = BODY.len() * 2;
+            let ptr = allocator.realloc(ptr, layout, new_size);
+            assert!(!ptr.is_null());
+            assert_eq!(std::slice::from_raw_parts(ptr, BODY.len()), BODY);
+            allocator.dealloc(ptr, Layout::array::<u8>(new_size).unwrap());
+        }
+
+        let freed = allocator.0 .0.into_inner().unwrap();
+        assert_eq!(freed, vec![vec![0; BODY.len()], vec![0; BODY.len() * 2]]);
+    }
+}
@@ -0,0 +1,581 @@
+/// Archive of dormant indexes to an object store to cut hot-storage costs.
+///
+/// `POST /indexes/{id}/archive` moves the entries and chains of the index to the archive
+/// store (same JSON format as the admin export, see `export.rs`) and removes them from the
+/// indexes database. Until `POST /indexes/{id}/unarchive` restores them, the Findex callbacks
+/// on this index are refused with a `410 Gone`.
+///
+/// The store is selected with `ARCHIVE_STORE_TYPE`:
+/// - `filesystem`: one file per index inside `ARCHIVE_DIR` (`<DATA_DIR>/archives` by default),
+/// for example a mounted network volume
+/// - `s3`: one object per index inside `ARCHIVE_S3_BUCKET` (needs the "s3" feature)
+/// - `azure`: one blob per index inside `ARCHIVE_AZURE_CONTAINER` (needs the "azure" feature)
+///
+/// Without `ARCHIVE_STORE_TYPE` the archive endpoints return a `501 Not Implemented`.
+use std::{env, fs, path::PathBuf, sync::Arc};
+
+use actix_web::{
+    post,
+    web::{self, Data, Json, Path},
+};
+use async_trait::async_trait;
+use chrono::Utc;
+
+#[cfg(feature = "replication")]
+use crate::replication::{Shipper, Standby};
+use crate::{
+    config,
+    core::{Index, IndexesDatabase, MetadataCache, MetadataDatabase, Table},
+    errors::{Error, Response},
+    export::{decode, encode, Export},
+    maintenance::Maintenance,
+};
+
+#[async_trait]
+pub(crate) trait ArchiveStore: Sync + Send {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), Error>;
+    async fn get(&self, key: &str) -> Result<Vec<u8>, Error>;
+    async fn delete(&self, key: &str) -> Result<(), Error>;
+}
+
+pub(crate) fn archive_store_from_env() -> Option<Data<dyn ArchiveStore>> {
+    let store: Arc<dyn ArchiveStore> = match env::var("ARCHIVE_STORE_TYPE").ok()?.as_str() {
+        "filesystem" => Arc::new(Filesystem::from_env()),
+
+        #[cfg(feature = "s3")]
+        "s3" => Arc::new(s3::S3::from_env()),
+        #[cfg(not(feature = "s3"))]
+        "s3" => panic!("Cannot load `ARCHIVE_STORE_TYPE=s3` because `findex_cloud` wasn't compiled with \"s3\" feature."),
+
+        #[cfg(feature = "azure")]
+        "azure" => Arc::new(azure::AzureBlob::from_env()),
+        #[cfg(not(feature = "azure"))]
+        "azure" => panic!("Cannot load `ARCHIVE_STORE_TYPE=azure` because `findex_cloud` wasn't compiled with \"azure\" feature."),
+
+        archive_store_type => panic!("Unknown `ARCHIVE_STORE_TYPE` env variable `{archive_store_type}` (please use `filesystem`, `s3` or `azure`)"),
+    };
+
+    Some(Data::from(store))
+}
+
+fn archive_key(index_id: &str) -> String {
+    format!("{index_id}.json")
+}
+
+struct Filesystem {
+    directory: PathBuf,
+}
+
+impl Filesystem {
+    fn from_env() -> Self {
+        let directory = env::var("ARCHIVE_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| config::data_dir().join("archives"));
+        config::prepare_directory(&directory);
+
+        Filesystem { directory }
+    }
+}
+
+#[async_trait]
+impl ArchiveStore for Filesystem {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), Error> {
+        let path = self.directory.join(key);
+        let temporary_path = self.directory.join(format!("{key}.tmp"));
+
+        // Write in a temporary file and rename to never leave a partial archive
+        web::block(move || {
+            fs::write(&temporary_path, bytes).and_then(|_| fs::rename(&temporary_path, &path))
+        })
+        .await
+        .map_err(|err| Error::Internal(err.to_string()))?
+        .map_err(|err| Error::Internal(format!("Cannot write archive {key} ({err})")))
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, Error> {
+        let path = self.directory.join(key);
+
+        web::block(move || fs::read(path))
+            .await
+            .map_err(|err| Error::Internal(err.to_string()))?
+            .map_err(|err| Error::Internal(format!("Cannot read archive {key} ({err})")))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        let path = self.directory.join(key);
+
+        web::block(move || fs::remove_file(path))
+            .await
+            .map_err(|err| Error::Internal(err.to_string()))?
+            .map_err(|err| Error::Internal(format!("Cannot delete archive {key} ({err})")))
+    }
+}
+
+#[cfg(feature = "s3")]
+mod s3 {
+    use std::{env, time::SystemTime};
+
+    use async_trait::async_trait;
+    use aws_sigv4::http_request::{
+        sign, PayloadChecksumKind, SignableRequest, SigningParams, SigningSettings,
+        UriPathNormalizationMode,
+    };
+    use http::Method;
+
+    use super::ArchiveStore;
+    use crate::{config, errors::Error};
+
+    /// Minimal S3 client (path-style requests signed with SigV4). Credentials are
+    /// read from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`
+    /// like for DynamoDB.
+    pub(super) struct S3 {
+        client: reqwest::Client,
+        endpoint_url: String,
+        bucket: String,
+        region: String,
+    }
+
+    impl S3 {
+        pub(super) fn from_env() -> Self {
+            let bucket = env::var("ARCHIVE_S3_BUCKET").unwrap_or_else(|_| {
+                panic!("`ARCHIVE_S3_BUCKET` env variable is required with `ARCHIVE_STORE_TYPE=s3`")
+            });
+            let region = env::var("ARCHIVE_S3_REGION")
+                .or_else(|_| env::var("AWS_REGION"))
+                .unwrap_or_else(|_| "us-east-1".to_string());
+            let endpoint_url = env::var("ARCHIVE_S3_ENDPOINT_URL")
+                .unwrap_or_else(|_| format!("https://s3.{region}.amazonaws.com"));
+
+            S3 {
+                client: reqwest::Client::new(),
+                endpoint_url: endpoint_url.trim_end_matches('/').to_string(),
+                bucket,
+                region,
+            }
+        }
+
+        async fn send(
+            &self,
+            method: Method,
+            key: &str,
+            body: Vec<u8>,
+        ) -> Result<reqwest::Response, Error> {
+            // Read on each request: the files of the Kubernetes secrets are updated in place.
+            let secret = |name: &str| config::try_secret_from_env(name).map_err(Error::Internal);
+            let access_key = secret("AWS_ACCESS_KEY_ID")?
+                .ok_or_else(|| Error::Internal("Missing `AWS_ACCESS_KEY_ID`".to_string()))?;
+            let secret_key = secret("AWS_SECRET_ACCESS_KEY")?
+                .ok_or_else(|| Error::Internal("Missing `AWS_SECRET_ACCESS_KEY`".to_string()))?;
+            let security_token = secret("AWS_SESSION_TOKEN")?;
+
+            let url = format!("{}/{}/{key}", self.endpoint_url, self.bucket);
+            let mut request = http::Request::builder()
+                .method(method)
+                .uri(&url)
+                .body(body)
+                .map_err(|err| Error::Internal(format!("Invalid S3 request {url} ({err})")))?;
+
+            let mut settings = SigningSettings::default();
+            settings.payload_checksum_kind = PayloadChecksumKind::XAmzSha256;
+            settings.uri_path_normalization_mode = UriPathNormalizationMode::Disabled;
+
+            let mut params = SigningParams::builder()
+                .access_key(&access_key)
+                .secret_key(&secret_key)
+                .region(&self.region)
+                .service_name("s3")
+                .time(SystemTime::now())
+                .settings(settings);
+            params.set_security_token(security_token.as_deref());
+            let params = params
+                .build()
+                .map_err(|err| Error::Internal(format!("Cannot sign S3 request ({err})")))?;
+
+            let (instructions, _signature) = sign(SignableRequest::from(&request), &params)
+                .map_err(|err| Error::Internal(format!("Cannot sign S3 request ({err})")))?
+                .into_parts();
+            instructions.apply_to_request(&mut request);
+
+            let (parts, body) = request.into_parts();
+            let response = self
+                .client
+                .request(parts.method, url.as_str())
+                .headers(parts.headers)
+                .body(body)
+                .send()
+                .await
+                .map_err(|err| Error::Internal(format!("S3 request {url} failed ({err})")))?;
+
+            if !response.status().is_success() {
+                return Err(Error::Internal(format!(
+                    "S3 request {url} failed with status {}",
+                    response.status()
+                )));
+            }
+
+            Ok(response)
+        }
+    }
+
+    #[async_trait]
+    impl ArchiveStore for S3 {
+        async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), Error> {
+            self.send(Method::PUT, key, bytes).await?;
+
+            Ok(())
+        }
+
+        async fn get(&self, key: &str) -> Result<Vec<u8>, Error> {
+            let response = self.send(Method::GET, key, vec![]).await?;
+
+            Ok(response
+                .bytes()
+                .await
+                .map_err(|err| Error::Internal(format!("Cannot read S3 object {key} ({err})")))?
+                .to_vec())
+        }
+
+        async fn delete(&self, key: &str) -> Result<(), Error> {
+            self.send(Method::DELETE, key, vec![]).await?;
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "azure")]
+mod azure {
+    use std::{
+        env,
+        sync::Mutex,
+        time::{Duration, SystemTime},
+    };
+
+    use async_trait::async_trait;
+    use reqwest::Method;
+    use serde::Deserialize;
+
+    use super::ArchiveStore;
+    use crate::{config, errors::Error};
+
+    const API_VERSION: &str = "2021-08-06";
+    const IMDS_TOKEN_URL: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+    const STORAGE_RESOURCE: &str = "https://storage.azure.com/";
+    /// Tokens are renewed a bit before their expiration
+    const TOKEN_RENEWAL_MARGIN: Duration = Duration::from_secs(300);
+
+    enum Authentication {
+        /// Shared access signature appended to the URL of each request
+        SasToken(String),
+        /// Token of the managed identity of the VM / AKS node (from the instance
+        /// metadata service), `client_id` selects a user-assigned identity.
+        ManagedIdentity {
+            client_id: Option<String>,
+            token: Mutex<Option<(String, SystemTime)>>,
+        },
+    }
+
+    #[derive(Deserialize)]
+    struct TokenResponse {
+        access_token: String,
+        /// Unix timestamp (in seconds), as a string
+        expires_on: String,
+    }
+
+    /// Minimal Azure Blob Storage client (block blobs), authenticated with
+    /// `ARCHIVE_AZURE_SAS_TOKEN` or with the managed identity if no SAS token is set.
+    pub(super) struct AzureBlob {
+        client: reqwest::Client,
+        endpoint_url: String,
+        container: String,
+        authentication: Authentication,
+    }
+
+    impl AzureBlob {
+        pub(super) fn from_env() -> Self {
+            let container = env::var("ARCHIVE_AZURE_CONTAINER").unwrap_or_else(|_| {
+                panic!("`ARCHIVE_AZURE_CONTAINER` env variable is required with `ARCHIVE_STORE_TYPE=azure`")
+            });
+            let endpoint_url = env::var("ARCHIVE_AZURE_ENDPOINT_URL").unwrap_or_else(|_| {
+                let account = env::var("ARCHIVE_AZURE_ACCOUNT").unwrap_or_else(|_| {
+                    panic!("`ARCHIVE_AZURE_ACCOUNT` or `ARCHIVE_AZURE_ENDPOINT_URL` env variable is required with `ARCHIVE_STORE_TYPE=azure`")
+                });
+                format!("https://{account}.blob.core.windows.net")
+            });
+
+            let authentication = match config::secret_from_env("ARCHIVE_AZURE_SAS_TOKEN") {
+                Some(sas_token) => {
+                    Authentication::SasToken(sas_token.trim_start_matches('?').to_string())
+                }
+                None => Authentication::ManagedIdentity {
+                    client_id: env::var("ARCHIVE_AZURE_CLIENT_ID").ok(),
+                    token: Mutex::new(None),
+                },
+            };
+
+            AzureBlob {
+                client: reqwest::Client::new(),
+                endpoint_url: endpoint_url.trim_end_matches('/').to_string(),
+                container,
+                authentication,
+            }
+        }
+
+        async fn managed_identity_token(
+            &self,
+            client_id: &Option<String>,
+            token: &Mutex<Option<(String, SystemTime)>>,
+        ) -> Result<String, Error> {
+            let lock_error = || Error::Internal("Azure token lock is poisoned".to_string());
+
+            if let Some((access_token, expires_at)) = &*token.lock().map_err(|_| lock_error())? {
+                if SystemTime::now() + TOKEN_RENEWAL_MARGIN < *expires_at {
+                    return Ok(access_token.clone());
+                }
+            }
+
+            let mut query = vec![
+                ("api-version", "2018-02-01"),
+                ("resource", STORAGE_RESOURCE),
+            ];
+            if let Some(client_id) = client_id {
+                query.push(("client_id", client_id.as_str()));
+            }
+
+            let response: TokenResponse = self
+                .client
+                .get(IMDS_TOKEN_URL)
+                .query(&query)
+                .header("Metadata", "true")
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|err| {
+                    Error::Internal(format!(
+                        "Cannot get the Azure managed identity token ({err})"
+                    ))
+                })?
+                .json()
+                .await
+                .map_err(|err| {
+                    Error::Internal(format!("Invalid Azure managed identity token ({err})"))
+                })?;
+
+            let expires_at = response
+                .expires_on
+                .parse()
+                .map(|seconds| SystemTime::UNIX_EPOCH + Duration::from_secs(seconds))
+                .map_err(|_| {
+                    Error::Internal(format!(
+                        "Invalid expiration of the Azure managed identity token {:?}",
+                        response.expires_on
+                    ))
+                })?;
+
+            *token.lock().map_err(|_| lock_error())? =
+                Some((response.access_token.clone(), expires_at));
+
+            Ok(response.access_token)
+        }
+
+        async fn send(
+            &self,
+            method: Method,
+            key: &str,
+            body: Vec<u8>,
+        ) -> Result<reqwest::Response, Error> {
+            // `url` is without the SAS token to be logged
+            let url = format!("{}/{}/{key}", self.endpoint_url, self.container);
+
+            let request = match &self.authentication {
+                Authentication::SasToken(sas_token) => {
+                    self.client.request(method, format!("{url}?{sas_token}"))
+                }
+                Authentication::ManagedIdentity { client_id, token } => self
+                    .client
+                    .request(method, url.as_str())
+                    .bearer_auth(self.managed_identity_token(client_id, token).await?),
+            };
+
+            let response = request
+                .header("x-ms-version", API_VERSION)
+                .header("x-ms-blob-type", "BlockBlob")
+                .body(body)
+                .send()
+                .await
+                .map_err(|err| {
+                    Error::Internal(format!(
+                        "Azure request {url} failed ({})",
+                        err.without_url()
+                    ))
+                })?;
+
+            if !response.status().is_success() {
+                return Err(Error::Internal(format!(
+                    "Azure request {url} failed with status {}",
+                    response.status()
+                )));
+            }
+
+            Ok(response)
+        }
+    }
+
+    #[async_trait]
+    impl ArchiveStore for AzureBlob {
+        async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), Error> {
+            self.send(Method::PUT, key, bytes).await?;
+
+            Ok(())
+        }
+
+        async fn get(&self, key: &str) -> Result<Vec<u8>, Error> {
+            let response = self.send(Method::GET, key, vec![]).await?;
+
+            Ok(response
+                .bytes()
+                .await
+                .map_err(|err| Error::Internal(format!("Cannot read Azure blob {key} ({err})")))?
+                .to_vec())
+        }
+
+        async fn delete(&self, key: &str) -> Result<(), Error> {
+            self.send(Method::DELETE, key, vec![]).await?;
+
+            Ok(())
+        }
+    }
+}
+
+/// Read the index without the cache to get the current archive state.
+async fn get_index(metadata_db: &Data<dyn MetadataDatabase>, id: &str) -> Result<Index, Error> {
+    metadata_db
+        .get_index(id)
+        .await?
+        .ok_or_else(|| Error::BadRequest(format!("Unknown index for ID {id}")))
+}
+
+#[post("/indexes/{id}/archive")]
+pub(crate) async fn archive_index(
+    id: Path<String>,
+    metadata_cache: Data<MetadataCache>,
+    metadata_db: Data<dyn MetadataDatabase>,
+    indexes_db: Data<dyn IndexesDatabase>,
+    archive_store: Option<Data<dyn ArchiveStore>>,
+    maintenance: Data<Maintenance>,
+    #[cfg(feature = "replication")] shipper: Option<Data<Shipper>>,
+    #[cfg(feature = "replication")] standby: Option<Data<Standby>>,
+) -> Response<Index> {
+    maintenance.check_index(&id)?;
+    #[cfg(feature = "replication")]
+    Standby::check_writable(&standby)?;
+    #[cfg(feature = "replication")]
+    Shipper::check_writable(&shipper)?;
+    let archive_store = archive_store
+        .ok_or_else(|| Error::Unsupported("No archive store configured".to_string()))?;
+
+    let mut index = get_index(&metadata_db, &id).await?;
+    if index.archived_at.is_some() {
+        return Err(Error::IndexArchived(index.id.clone()));
+    }
+
+    // Mark the index as archived first to refuse the upserts and inserts
+    // during the copy.
+    let archived_at = Utc::now().naive_utc();
+    metadata_db.set_archived_at(&id, Some(archived_at)).await?;
+    metadata_cache.remove(&id);
+
+    let result = move_to_archive(&index, &indexes_db, &archive_store).await;
+    if let Err(err) = result {
+        metadata_db.set_archived_at(&id, None).await?;
+        return Err(err);
+    }
+
+    log::info!("Index {id} archived");
+
+    index.archived_at = Some(archived_at);
+    index.size = None;
+
+    Ok(Json(index))
+}
+
+async fn move_to_archive(
+    index: &Index,
+    indexes_db: &Data<dyn IndexesDatabase>,
+    archive_store: &Data<dyn ArchiveStore>,
+) -> Result<(), Error> {
+    let export = Export {
+        entries: encode(indexes_db.fetch_all(index, Table::Entries).await?),
+        chains: encode(indexes_db.fetch_all(index, Table::Chains).await?),
+    };
+
+    let key = archive_key(&index.id);
+    archive_store
+        .put(&key, serde_json::to_vec(&export)?)
+        .await?;
+
+    if let Err(err) = indexes_db.delete_index_data(&index.id).await {
+        if let Err(err) = archive_store.delete(&key).await {
+            log::error!("Cannot delete archive of index {} ({err:?})", index.id);
+        }
+
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+#[post("/indexes/{id}/unarchive")]
+pub(crate) async fn unarchive_index(
+    id: Path<String>,
+    metadata_cache: Data<MetadataCache>,
+    metadata_db: Data<dyn MetadataDatabase>,
+    indexes_db: Data<dyn IndexesDatabase>,
+    archive_store: Option<Data<dyn ArchiveStore>>,
+    maintenance: Data<Maintenance>,
+    #[cfg(feature = "replication")] shipper: Option<Data<Shipper>>,
+    #[cfg(feature = "replication")] standby: Option<Data<Standby>>,
+) -> Response<Index> {
+    maintenance.check_index(&id)?;
+    #[cfg(feature = "replication")]
+    Standby::check_writable(&standby)?;
+    #[cfg(feature = "replication")]
+    Shipper::check_writable(&shipper)?;
+    let archive_store = archive_store
+        .ok_or_else(|| Error::Unsupported("No archive store configured".to_string()))?;
+
+    let mut index = get_index(&metadata_db, &id).await?;
+    if index.archived_at.is_none() {
+        return Err(Error::BadRequest(format!("Index {id} is not archived")));
+    }
+
+    let key = archive_key(&index.id);
+    let export: Export = serde_json::from_slice(&archive_store.get(&key).await?)?;
+
+    indexes_db
+        .put_values(&index, Table::Entries, decode(export.entries)?)
+        .await?;
+    indexes_db
+        .put_values(&index, Table::Chains, decode(export.chains)?)
+        .await?;
+
+    match indexes_db.recompute_size(&index).await {
+        Ok(()) | Err(Error::Unsupported(_)) => {}
+        Err(err) => return Err(err),
+    }
+
+    metadata_db.set_archived_at(&id, None).await?;
+    metadata_cache.remove(&id);
+
+    // The data is restored, a leftover archive is only a waste of space.
+    if let Err(err) = archive_store.delete(&key).await {
+        log::error!("Cannot delete archive of index {id} ({err:?})");
+    }
+
+    log::info!("Index {id} unarchived");
+
+    index.archived_at = None;
+    indexes_db.set_size(&mut index).await?;
+
+    Ok(Json(index))
+}
@@ -0,0 +1,114 @@
+/// Backoff hints returned when the indexes or metadata database is overloaded.
+///
+/// Throttling (DynamoDB `ProvisionedThroughputExceededException`, `ThrottlingException`,
+/// `RequestLimitExceeded`) is answered with a `429 Too Many Requests` and write contention
+/// (DynamoDB `TransactionConflictException`, RocksDB lock timeouts and busy errors) with a
+/// `503 Service Unavailable`, instead of a generic `500`. Both have a `Retry-After` header
+/// and a JSON body describing how to back off:
+///
+/// ```json
+/// {"error": "Throttled", "reason": "…", "retry_after_ms": 400,
+///  "backoff": {"initial_ms": 100, "max_ms": 10000, "multiplier": 2, "jitter": "full"}}
+/// ```
+///
+/// The hint adapts to the load of the instance: `retry_after_ms` doubles with every
+/// overloaded call in the last `WINDOW` (starting at `initial_ms`, at most `max_ms`), so the
+/// clients slow down more when the backend stays overloaded.
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::errors::Error;
+
+const INITIAL_BACKOFF_MS: u64 = 100;
+const MAX_BACKOFF_MS: u64 = 10_000;
+const MULTIPLIER: u64 = 2;
+const WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overload {
+    /// The backend refused the call because of its capacity (429)
+    Throttled,
+    /// Concurrent writes on the same values (503)
+    Contention,
+}
+
+/// Overloaded calls since `window_start`
+struct RecentOverloads {
+    window_start: Option<Instant>,
+    count: u32,
+}
+
+static RECENT_OVERLOADS: Mutex<RecentOverloads> = Mutex::new(RecentOverloads {
+    window_start: None,
+    count: 0,
+});
+
+#[derive(Serialize)]
+struct BackoffPolicy {
+    initial_ms: u64,
+    max_ms: u64,
+    multiplier: u64,
+    /// Wait a random duration between 0 and the computed delay
+    jitter: &'static str,
+}
+
+#[derive(Serialize)]
+pub(crate) struct BackoffHint<'a> {
+    error: Overload,
+    reason: &'a str,
+    retry_after_ms: u64,
+    backoff: BackoffPolicy,
+}
+
+impl<'a> BackoffHint<'a> {
+    pub(crate) fn new(error: Overload, reason: &'a str, retry_after_ms: u64) -> Self {
+        BackoffHint {
+            error,
+            reason,
+            retry_after_ms,
+            backoff: BackoffPolicy {
+                initial_ms: INITIAL_BACKOFF_MS,
+                max_ms: MAX_BACKOFF_MS,
+                multiplier: MULTIPLIER,
+                jitter: "full",
+            },
+        }
+    }
+}
+
+/// Record an overloaded call and build its error with the current backoff hint.
+pub(crate) fn overloaded(overload: Overload, reason: String) -> Error {
+    let count = match RECENT_OVERLOADS.lock() {
+        Ok(mut recent) => {
+            let now = Instant::now();
+            match recent.window_start {
+                Some(window_start) if now.duration_since(window_start) < WINDOW => {
+                    recent.count = recent.count.saturating_add(1);
+                }
+                _ => {
+                    recent.window_start = Some(now);
+                    recent.count = 1;
+                }
+            }
+            recent.count
+        }
+        Err(_) => 1,
+    };
+
+    let retry_after_ms = MULTIPLIER
+        .checked_pow(count - 1)
+        .and_then(|factor| INITIAL_BACKOFF_MS.checked_mul(factor))
+        .map_or(MAX_BACKOFF_MS, |delay| delay.min(MAX_BACKOFF_MS));
+
+    log::warn!("{overload:?} by the database ({reason}), retry hinted after {retry_after_ms}ms");
+
+    Error::Overloaded {
+        overload,
+        reason,
+        retry_after_ms,
+    }
+}
@@ -0,0 +1,48 @@
+/// Online backups of the indexes database (only RocksDB, see `rocksdb.rs`).
+///
+/// `POST /admin/backups` creates a backup while the server continues to serve the
+/// requests and `GET /admin/backups` lists the available backups. Only one backup
+/// runs at a time, other requests get a `429 Too Many Requests`.
+///
+/// With the server stopped, `findex_cloud backup` creates a backup and
+/// `findex_cloud restore [BACKUP_ID]` rebuilds the indexes database from a backup
+/// (the latest by default).
+use actix_web::{
+    get, post,
+    web::{Data, Json},
+};
+use serde::Serialize;
+
+use crate::{admin::Admin, core::IndexesDatabase, errors::Response};
+
+#[derive(Serialize, Debug)]
+pub struct BackupInfo {
+    pub id: u32,
+    /// Unix timestamp (in seconds)
+    pub timestamp: i64,
+    /// In bytes, files shared with other backups are counted
+    pub size: u64,
+    pub files: u32,
+}
+
+#[post("/admin/backups")]
+pub(crate) async fn post_backup(
+    _admin: Admin,
+    indexes_db: Data<dyn IndexesDatabase>,
+) -> Response<BackupInfo> {
+    log::info!("Creating a backup of the indexes database");
+
+    let backup = indexes_db.backup().await?;
+
+    log::info!("Backup {} created ({} bytes)", backup.id, backup.size);
+
+    Ok(Json(backup))
+}
+
+#[get("/admin/backups")]
+pub(crate) async fn get_backups(
+    _admin: Admin,
+    indexes_db: Data<dyn IndexesDatabase>,
+) -> Response<Vec<BackupInfo>> {
+    Ok(Json(indexes_db.backups().await?))
+}
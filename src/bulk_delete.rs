@@ -0,0 +1,163 @@
+/// Deletion of all the indexes matching a filter, to clean up after the load tests creating
+/// thousands of throwaway indexes.
+///
+/// `POST /admin/indexes/delete` (with the admin API key) takes the filter: `name_prefix`,
+/// `created_before` (UTC date) and/or `older_than_days`, at least one of them, an index must
+/// match all of them. With `"dry_run": true` the matching indexes are only listed.
+///
+/// The indexes are deleted like with `DELETE /indexes/{id}`: the metadata first, then their
+/// data in the background (one index after the other). The indexes in maintenance are skipped.
+use actix_web::{
+    post,
+    web::{Data, Json},
+};
+use chrono::{Duration, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "replication")]
+use crate::replication::{self, Record, Shipper, Standby};
+use crate::{
+    admin::Admin,
+    core::{IndexesDatabase, MetadataCache, MetadataDatabase},
+    errors::{Error, Response},
+    maintenance::Maintenance,
+};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BulkDeleteFilter {
+    name_prefix: Option<String>,
+    created_before: Option<NaiveDateTime>,
+    older_than_days: Option<i64>,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Serialize)]
+struct MatchedIndex {
+    id: String,
+    name: String,
+    created_at: NaiveDateTime,
+}
+
+#[derive(Serialize)]
+struct BulkDeleteResult {
+    dry_run: bool,
+    /// Deleted, or to delete with `dry_run`
+    deleted: Vec<MatchedIndex>,
+    /// In maintenance
+    skipped: Vec<MatchedIndex>,
+}
+
+#[post("/admin/indexes/delete")]
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn bulk_delete_indexes(
+    _admin: Admin,
+    filter: Json<BulkDeleteFilter>,
+    metadata_cache: Data<MetadataCache>,
+    metadata_db: Data<dyn MetadataDatabase>,
+    indexes_db: Data<dyn IndexesDatabase>,
+    maintenance: Data<Maintenance>,
+    #[cfg(feature = "replication")] shipper: Option<Data<Shipper>>,
+    #[cfg(feature = "replication")] standby: Option<Data<Standby>>,
+) -> Response<BulkDeleteResult> {
+    if filter.name_prefix.is_none()
+        && filter.created_before.is_none()
+        && filter.older_than_days.is_none()
+    {
+        return Err(Error::BadRequest(
+            "The filter needs a `name_prefix`, a `created_before` or an `older_than_days`"
+                .to_string(),
+        ));
+    }
+    if filter.older_than_days.map_or(false, |days| days < 0) {
+        return Err(Error::BadRequest(
+            "`older_than_days` must be positive".to_string(),
+        ));
+    }
+
+    if !filter.dry_run {
+        maintenance.check_server()?;
+        #[cfg(feature = "replication")]
+        Standby::check_writable(&standby)?;
+        #[cfg(feature = "replication")]
+        Shipper::check_writable(&shipper)?;
+    }
+
+    let created_before = [
+        filter.created_before,
+        filter
+            .older_than_days
+            .map(|days| Utc::now().naive_utc() - Duration::days(days)),
+    ]
+    .into_iter()
+    .flatten()
+    .min();
+
+    let (skipped, deleted): (Vec<_>, Vec<_>) = metadata_db
+        .get_indexes()
+        .await?
+        .into_iter()
+        .filter(|index| {
+            filter
+                .name_prefix
+                .as_ref()
+                .map_or(true, |prefix| index.name.starts_with(prefix.as_str()))
+                && created_before.map_or(true, |created_before| index.created_at < created_before)
+        })
+        .map(|index| MatchedIndex {
+            id: index.id,
+            name: index.name,
+            created_at: index.created_at,
+        })
+        .partition(|index| maintenance.check_index(&index.id).is_err());
+
+    if filter.dry_run {
+        return Ok(Json(BulkDeleteResult {
+            dry_run: true,
+            deleted,
+            skipped,
+        }));
+    }
+
+    let mut ids_to_purge = Vec::with_capacity(deleted.len());
+    let mut result = Ok(());
+    for index in &deleted {
+        if let Err(err) = metadata_db.delete_index(&index.id).await {
+            result = Err(err);
+            break;
+        }
+        metadata_cache.remove(&index.id);
+
+        #[cfg(feature = "replication")]
+        replication::ship(&shipper, || Record::DeleteIndex {
+            id: index.id.clone(),
+        });
+
+        ids_to_purge.push(index.id.clone());
+    }
+
+    // The indexes are already unreachable, if it fails the data is orphaned (see `check.rs`).
+    // Also purged when the deletion stopped on an error.
+    actix_web::rt::spawn(async move {
+        for id in ids_to_purge {
+            match indexes_db.delete_index_data(&id).await {
+                Ok(()) | Err(Error::Unsupported(_)) => {}
+                Err(err) => log::error!("Cannot delete the data of index {id} ({err:?})"),
+            }
+        }
+    });
+    result?;
+
+    log::warn!(
+        "{} index(es) deleted by filter ({} skipped in maintenance)",
+        deleted.len(),
+        skipped.len()
+    );
+
+    Ok(Json(BulkDeleteResult {
+        dry_run: false,
+        deleted,
+        skipped,
+    }))
+}
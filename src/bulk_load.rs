@@ -0,0 +1,274 @@
+/// Bulk seeding of an empty index with precomputed entries and chains.
+///
+/// `POST /indexes/{id}/bulk_load` (with the admin API key) streams the rows of the request
+/// body into the indexes database by batches of `BULK_LOAD_BATCH_SIZE` rows (1 000 by
+/// default) with `put_values` (a RocksDB `WriteBatch`, DynamoDB `BatchWriteItem`…), without
+/// the compare-and-swap of the upserts. The body is never held entirely in memory:
+/// - `Content-Type: application/x-ndjson`: one `{"table": "entries" | "chains", "uid": "…",
+///   "value": "…"}` object per line, the UID and the value base64 encoded,
+/// - otherwise (`application/octet-stream`): the rows one after the other, each row is the
+///   table (`0` for the entries, `1` for the chains), the UID, the length of the value
+///   (4 bytes big-endian) and the value.
+///
+/// The index must be empty (no size, no write counted since its creation), so the rows
+/// cannot overwrite values written concurrently by the clients. The rows written before an
+/// invalid row stay in the index: delete and recreate the index before retrying.
+use std::{env, mem};
+
+use actix_web::{
+    http::header::CONTENT_TYPE,
+    post,
+    web::{Data, Json, Payload},
+    HttpRequest,
+};
+use base64::{engine::general_purpose, Engine};
+use cosmian_findex::{parameters::UID_LENGTH, EncryptedTable, Uid};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "replication")]
+use crate::replication::{self, Record, Shipper, Standby};
+use crate::{
+    admin::Admin,
+    core::{Index, IndexesDatabase, MetadataDatabase, Table},
+    errors::{Error, Response},
+    maintenance::Maintenance,
+};
+
+const DEFAULT_BULK_LOAD_BATCH_SIZE: usize = 1_000;
+/// Larger values are refused to bound the buffered bytes of the binary format
+const MAX_VALUE_LENGTH: usize = 1024 * 1024;
+const BINARY_ROW_HEADER_LENGTH: usize = 1 + UID_LENGTH + 4;
+
+#[derive(Clone, Copy)]
+enum Format {
+    Ndjson,
+    Binary,
+}
+
+#[derive(Deserialize)]
+struct NdjsonRow {
+    table: Table,
+    uid: String,
+    value: String,
+}
+
+/// Rows written, a UID repeated inside a batch is counted once
+#[derive(Serialize, Default)]
+struct BulkLoadReport {
+    entries: u64,
+    chains: u64,
+}
+
+impl Format {
+    fn from_request(req: &HttpRequest) -> Self {
+        match req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+        {
+            Some(content_type) if content_type.starts_with("application/x-ndjson") => {
+                Format::Ndjson
+            }
+            _ => Format::Binary,
+        }
+    }
+
+    /// Parse the complete rows at the start of `bytes` and return the number of bytes
+    /// read. At the `end` of the body, the remaining bytes must be a complete row.
+    fn parse(
+        self,
+        bytes: &[u8],
+        end: bool,
+        rows: &mut Vec<(Table, Uid<UID_LENGTH>, Vec<u8>)>,
+    ) -> Result<usize, Error> {
+        let mut position = 0;
+
+        match self {
+            Format::Ndjson => loop {
+                let line = match bytes[position..].iter().position(|byte| *byte == b'\n') {
+                    Some(length) => &bytes[position..position + length + 1],
+                    None if end && position < bytes.len() => &bytes[position..],
+                    None => break,
+                };
+                position += line.len();
+
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+
+                let row: NdjsonRow = serde_json::from_slice(line)
+                    .map_err(|err| Error::BadRequest(format!("Invalid bulk load row ({err})")))?;
+                let uid: [u8; UID_LENGTH] = general_purpose::STANDARD
+                    .decode(row.uid)
+                    .ok()
+                    .and_then(|uid| uid.try_into().ok())
+                    .ok_or(Error::WrongEncoding)?;
+                let value = general_purpose::STANDARD
+                    .decode(row.value)
+                    .map_err(|_| Error::WrongEncoding)?;
+
+                rows.push((row.table, Uid::from(uid), value));
+            },
+            Format::Binary => loop {
+                let Some(header) = bytes.get(position..position + BINARY_ROW_HEADER_LENGTH) else {
+                    break;
+                };
+
+                let table = match header[0] {
+                    0 => Table::Entries,
+                    1 => Table::Chains,
+                    table => {
+                        return Err(Error::BadRequest(format!(
+                            "Unknown table {table} in bulk load row (0 for the entries, 1 for the chains)"
+                        )))
+                    }
+                };
+                let uid: [u8; UID_LENGTH] = header[1..1 + UID_LENGTH]
+                    .try_into()
+                    .map_err(|_| Error::WrongEncoding)?;
+                let length = u32::from_be_bytes(
+                    header[1 + UID_LENGTH..]
+                        .try_into()
+                        .map_err(|_| Error::WrongEncoding)?,
+                ) as usize;
+                if length > MAX_VALUE_LENGTH {
+                    return Err(Error::BadRequest(format!(
+                        "Bulk load value of {length} bytes, the maximum is {MAX_VALUE_LENGTH} bytes"
+                    )));
+                }
+
+                let start = position + BINARY_ROW_HEADER_LENGTH;
+                let Some(value) = bytes.get(start..start + length) else {
+                    break;
+                };
+                position = start + length;
+
+                rows.push((table, Uid::from(uid), value.to_vec()));
+            },
+        }
+
+        if end && position < bytes.len() {
+            return Err(Error::BadRequest(format!(
+                "Incomplete bulk load row at the end of the body ({} bytes)",
+                bytes.len() - position
+            )));
+        }
+
+        Ok(position)
+    }
+}
+
+/// Refuse the indexes with values or writes, checked with the size when the indexes
+/// database knows it and with the writes counted for the compactions.
+async fn check_empty(
+    index: &Index,
+    metadata_db: &Data<dyn MetadataDatabase>,
+    indexes_db: &Data<dyn IndexesDatabase>,
+) -> Result<(), Error> {
+    let stats = metadata_db.get_compaction_stats(&index.id).await?;
+
+    let mut index = index.clone();
+    indexes_db.set_size(&mut index).await?;
+
+    if stats.writes_since_compaction > 0
+        || stats.last_compaction_at.is_some()
+        || index.size.unwrap_or(0) > 0
+    {
+        return Err(Error::IndexNotEmpty(index.id));
+    }
+
+    Ok(())
+}
+
+#[post("/indexes/{id}/bulk_load")]
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn bulk_load(
+    _admin: Admin,
+    index: Index,
+    req: HttpRequest,
+    mut payload: Payload,
+    metadata_db: Data<dyn MetadataDatabase>,
+    indexes_db: Data<dyn IndexesDatabase>,
+    maintenance: Data<Maintenance>,
+    #[cfg(feature = "replication")] shipper: Option<Data<Shipper>>,
+    #[cfg(feature = "replication")] standby: Option<Data<Standby>>,
+) -> Response<BulkLoadReport> {
+    maintenance.check_index(&index.id)?;
+    #[cfg(feature = "replication")]
+    Standby::check_writable(&standby)?;
+    #[cfg(feature = "replication")]
+    Shipper::check_writable(&shipper)?;
+
+    check_empty(&index, &metadata_db, &indexes_db).await?;
+
+    let batch_size = env::var("BULK_LOAD_BATCH_SIZE")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_BULK_LOAD_BATCH_SIZE);
+    let format = Format::from_request(&req);
+
+    log::info!("Bulk loading index {}", index.id);
+
+    let mut report = BulkLoadReport::default();
+    let mut entries = EncryptedTable::<UID_LENGTH>::with_capacity(batch_size);
+    let mut chains = EncryptedTable::<UID_LENGTH>::with_capacity(batch_size);
+    let mut buffer = Vec::new();
+    let mut rows = Vec::new();
+
+    loop {
+        let chunk = payload.next().await;
+        let end = chunk.is_none();
+        if let Some(chunk) = chunk {
+            let chunk = chunk.map_err(|err| Error::BadRequest(err.to_string()))?;
+            buffer.extend_from_slice(&chunk);
+        }
+
+        let parsed = format.parse(&buffer, end, &mut rows)?;
+        buffer.drain(..parsed);
+
+        for (table, uid, value) in rows.drain(..) {
+            let (batch, count) = match table {
+                Table::Entries => (&mut entries, &mut report.entries),
+                Table::Chains => (&mut chains, &mut report.chains),
+            };
+            // A UID repeated inside the batch is written once
+            if batch.insert(uid, value).is_none() {
+                *count += 1;
+            }
+
+            if batch.len() >= batch_size {
+                let batch = mem::replace(batch, EncryptedTable::with_capacity(batch_size));
+                #[cfg(feature = "replication")]
+                replication::ship(&shipper, || Record::put_values(&index, table, batch.iter()));
+                indexes_db.put_values(&index, table, batch).await?;
+            }
+        }
+
+        if end {
+            break;
+        }
+    }
+
+    for (table, batch) in [(Table::Entries, entries), (Table::Chains, chains)] {
+        if !batch.is_empty() {
+            #[cfg(feature = "replication")]
+            replication::ship(&shipper, || Record::put_values(&index, table, batch.iter()));
+            indexes_db.put_values(&index, table, batch).await?;
+        }
+    }
+
+    match indexes_db.recompute_size(&index).await {
+        Ok(()) | Err(Error::Unsupported(_)) => {}
+        Err(err) => return Err(err),
+    }
+
+    log::info!(
+        "Index {} bulk loaded ({} entries, {} chains)",
+        index.id,
+        report.entries,
+        report.chains
+    );
+
+    Ok(Json(report))
+}
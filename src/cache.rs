@@ -0,0 +1,113 @@
+/// Administration of the metadata cache (see `MetadataCache` in `core.rs`).
+///
+/// Indexes are cached forever after their first read. After a manual change
+/// inside the metadata database (keys rotation, restore of a backup…) flush the
+/// cache to read the new values without restarting the server.
+use std::{mem::size_of, sync::atomic::Ordering};
+
+use actix_web::{
+    get, post,
+    web::{Data, Json, Query},
+};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin::Admin,
+    core::{Index, MetadataCache},
+    errors::{Error, Response},
+};
+
+#[derive(Serialize)]
+struct CachedIndex {
+    id: String,
+    name: String,
+    created_at: NaiveDateTime,
+}
+
+#[derive(Serialize)]
+struct CacheStatus {
+    /// Keys are not returned
+    entries: Vec<CachedIndex>,
+    hits: u64,
+    misses: u64,
+    /// Estimation of the memory used by the entries, in bytes
+    memory: usize,
+}
+
+fn memory(index: &Index) -> usize {
+    size_of::<Index>()
+        + index.id.capacity() * 2 // Key of the `HashMap` and `Index::id`
+        + index.name.capacity()
+        + index.fetch_entries_key.capacity()
+        + index.fetch_chains_key.capacity()
+        + index.upsert_entries_key.capacity()
+        + index.insert_chains_key.capacity()
+}
+
+#[get("/admin/cache")]
+pub(crate) async fn get_cache(
+    _admin: Admin,
+    metadata_cache: Data<MetadataCache>,
+) -> Response<CacheStatus> {
+    let entries = metadata_cache
+        .entries
+        .read()
+        .map_err(|_| Error::Internal("Metadata cache lock is poisoned".to_string()))?;
+
+    let mut cached_indexes: Vec<_> = entries
+        .values()
+        .map(|index| CachedIndex {
+            id: index.id.clone(),
+            name: index.name.clone(),
+            created_at: index.created_at,
+        })
+        .collect();
+    cached_indexes.sort_by(|a, b| a.id.cmp(&b.id));
+
+    Ok(Json(CacheStatus {
+        entries: cached_indexes,
+        hits: metadata_cache.hits.load(Ordering::Relaxed),
+        misses: metadata_cache.misses.load(Ordering::Relaxed),
+        memory: entries.values().map(memory).sum(),
+    }))
+}
+
+#[derive(Deserialize)]
+struct FlushQuery {
+    /// Flush only this index, the whole cache if `None`
+    index_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FlushResult {
+    flushed: usize,
+}
+
+#[post("/admin/cache/flush")]
+pub(crate) async fn flush_cache(
+    _admin: Admin,
+    query: Query<FlushQuery>,
+    metadata_cache: Data<MetadataCache>,
+) -> Response<FlushResult> {
+    let mut entries = metadata_cache
+        .entries
+        .write()
+        .map_err(|_| Error::Internal("Metadata cache lock is poisoned".to_string()))?;
+
+    let flushed = match &query.index_id {
+        Some(index_id) => usize::from(entries.remove(index_id).is_some()),
+        None => {
+            let flushed = entries.len();
+            entries.clear();
+            flushed
+        }
+    };
+
+    match &query.index_id {
+        Some(index_id) => log::warn!("Metadata cache flushed for index {index_id}"),
+        None => log::warn!("Metadata cache flushed ({flushed} index(es))"),
+    }
+
+    Ok(Json(FlushResult { flushed }))
+}
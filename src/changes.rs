@@ -0,0 +1,152 @@
+/// Persisted log of the mutations of each index (change data capture) for the
+/// environments without an event bus. An external replica can call
+/// `GET /indexes/{id}/changes?since=<cursor>` in a loop and re-fetch the values
+/// of the returned UIDs to stay in sync.
+///
+/// Each change has a cursor, increasing inside an index (starting at 1). The
+/// response contains a `next_cursor` to send as `since` in the next request.
+///
+/// As for the event bus, only the UIDs are stored, not the values. The log is
+/// written after the mutation, so the replica may see a change for a value
+/// already updated a second time. It's not a problem since the replica always
+/// fetches the latest value. The mutation is committed before, so a failure to
+/// append its changes is only logged: an error would make the client retry a
+/// mutation already applied, and the replica misses these changes.
+///
+/// The log is enabled with `CHANGES_LOG_ENABLED=true`. Only RocksDB and LMDB
+/// support it.
+use std::env;
+
+use actix_web::{
+    get,
+    web::{Data, Json, Query},
+};
+use cosmian_findex::{parameters::UID_LENGTH, Uid};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    core::{Index, IndexesDatabase},
+    errors::{Error, Response},
+    events::{serialize_uid, Mutation, Operation},
+};
+
+const DEFAULT_LIMIT: usize = 1_000;
+const MAX_LIMIT: usize = 10_000;
+
+/// Present in the app data only if the changes log is enabled.
+pub(crate) struct ChangesLog;
+
+impl ChangesLog {
+    pub(crate) fn from_env() -> Option<Data<ChangesLog>> {
+        match env::var("CHANGES_LOG_ENABLED").as_deref() {
+            Ok("true") | Ok("1") => Some(Data::new(ChangesLog)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Change {
+    pub cursor: u64,
+    #[serde(serialize_with = "serialize_uid")]
+    pub uid: Uid<UID_LENGTH>,
+    pub operation: Operation,
+}
+
+impl Change {
+    /// Value stored inside the database for this change (the cursor is inside the key).
+    pub(crate) fn serialize_value(mutation: &Mutation) -> Vec<u8> {
+        let operation = match mutation.operation {
+            Operation::UpsertEntry => 0,
+            Operation::InsertChain => 1,
+        };
+
+        [&[operation][..], mutation.uid.as_ref()].concat()
+    }
+
+    pub(crate) fn deserialize_value(cursor: u64, value: &[u8]) -> Result<Self, Error> {
+        let (operation, uid) = value
+            .split_first()
+            .ok_or_else(|| Error::Internal("Empty change inside the changes log".to_string()))?;
+
+        let operation = match operation {
+            0 => Operation::UpsertEntry,
+            1 => Operation::InsertChain,
+            _ => {
+                return Err(Error::Internal(format!(
+                    "Unknown operation {operation} inside the changes log"
+                )))
+            }
+        };
+
+        let uid: [u8; UID_LENGTH] = uid.try_into().map_err(|_| {
+            Error::Internal(format!(
+                "Wrong UID length inside the changes log for cursor {cursor}"
+            ))
+        })?;
+
+        Ok(Change {
+            cursor,
+            uid: Uid::from(uid),
+            operation,
+        })
+    }
+}
+
+/// Append the mutations to the log of the index if the log is enabled, the errors are
+/// logged (see above).
+pub(crate) async fn append(
+    changes_log: Option<Data<ChangesLog>>,
+    indexes: &Data<dyn IndexesDatabase>,
+    index: &Index,
+    mutations: &[Mutation],
+) {
+    if changes_log.is_none() || mutations.is_empty() {
+        return;
+    }
+
+    if let Err(err) = indexes.append_changes(index, mutations).await {
+        log::error!(
+            "Cannot append {} change(s) to the log of index {}, the mutations are applied ({err:?})",
+            mutations.len(),
+            index.id
+        );
+    }
+}
+
+#[derive(Deserialize)]
+struct ChangesQuery {
+    #[serde(default)]
+    since: u64,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct ChangesResponse {
+    changes: Vec<Change>,
+    next_cursor: u64,
+}
+
+#[get("/indexes/{id}/changes")]
+pub(crate) async fn get_changes(
+    index: Index,
+    query: Query<ChangesQuery>,
+    indexes: Data<dyn IndexesDatabase>,
+    changes_log: Option<Data<ChangesLog>>,
+) -> Response<ChangesResponse> {
+    if changes_log.is_none() {
+        return Err(Error::BadRequest(
+            "The changes log is disabled (set `CHANGES_LOG_ENABLED=true` to enable it)".to_string(),
+        ));
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let changes = indexes.fetch_changes(&index, query.since, limit).await?;
+    let next_cursor = changes.last().map_or(query.since, |change| change.cursor);
+
+    Ok(Json(ChangesResponse {
+        changes,
+        next_cursor,
+    }))
+}
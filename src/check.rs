@@ -0,0 +1,123 @@
+/// Integrity check between the metadata database and the indexes database.
+///
+/// - every index inside the metadata database should be readable from the indexes database,
+/// - data inside the indexes database without an index inside the metadata database
+///   are orphaned (the index was deleted but not its data).
+///
+/// The check runs on boot (report only, disable it with `STARTUP_CHECK=false`)
+/// and with `findex_cloud check [--repair]`. The repair deletes the orphaned data
+/// and recomputes the size of every index. With RocksDB and LMDB, the server
+/// must be stopped to run the CLI (the database is locked by the server).
+use std::{collections::HashSet, fmt::Display};
+
+use cosmian_findex::{parameters::UID_LENGTH, Uid};
+
+use crate::{
+    core::{IndexesDatabase, MetadataDatabase, Table},
+    errors::Error,
+};
+
+#[derive(Default, Debug)]
+pub(crate) struct Report {
+    pub(crate) checked_indexes: usize,
+    /// Indexes referenced by the metadata whose data cannot be read
+    pub(crate) unreachable_indexes: Vec<(String, String)>,
+    /// IDs found inside the indexes database without metadata
+    pub(crate) orphaned_indexes: Vec<String>,
+    /// `false` if the indexes database cannot list its indexes
+    pub(crate) orphans_checked: bool,
+    pub(crate) deleted_orphaned_indexes: Vec<String>,
+    pub(crate) recomputed_sizes: usize,
+}
+
+impl Report {
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.unreachable_indexes.is_empty()
+            && self.orphaned_indexes.len() == self.deleted_orphaned_indexes.len()
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} index(es) checked.", self.checked_indexes)?;
+
+        for (id, error) in &self.unreachable_indexes {
+            writeln!(f, "Index {id} is unreachable ({error}).")?;
+        }
+
+        if self.orphans_checked {
+            for id in &self.orphaned_indexes {
+                if self.deleted_orphaned_indexes.contains(id) {
+                    writeln!(f, "Orphaned data for deleted index {id} removed.")?;
+                } else {
+                    writeln!(f, "Orphaned data found for deleted index {id}.")?;
+                }
+            }
+        } else {
+            writeln!(
+                f,
+                "Orphaned data not checked (not supported by the indexes database)."
+            )?;
+        }
+
+        if self.recomputed_sizes > 0 {
+            writeln!(f, "{} size(s) recomputed.", self.recomputed_sizes)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) async fn check(
+    metadata_database: &dyn MetadataDatabase,
+    indexes_database: &dyn IndexesDatabase,
+    repair: bool,
+) -> Result<Report, Error> {
+    let mut report = Report::default();
+
+    let indexes = metadata_database.get_indexes().await?;
+    report.checked_indexes = indexes.len();
+
+    for index in &indexes {
+        // Fetch a random UID to check that the storage of this index is reachable.
+        let uids = HashSet::from([Uid::<UID_LENGTH>::from([0; UID_LENGTH])]);
+        if let Err(err) = indexes_database.fetch(index, Table::Entries, uids).await {
+            report
+                .unreachable_indexes
+                .push((index.id.clone(), err.to_string()));
+            continue;
+        }
+
+        if repair {
+            match indexes_database.recompute_size(index).await {
+                Ok(()) => report.recomputed_sizes += 1,
+                Err(Error::Unsupported(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    match indexes_database.indexes_ids_with_data().await {
+        Ok(ids) => {
+            report.orphans_checked = true;
+
+            let known_ids: HashSet<_> = indexes.iter().map(|index| index.id.as_str()).collect();
+            report.orphaned_indexes = ids
+                .into_iter()
+                .filter(|id| !known_ids.contains(id.as_str()))
+                .collect();
+            report.orphaned_indexes.sort();
+        }
+        Err(Error::Unsupported(_)) => {}
+        Err(err) => return Err(err),
+    }
+
+    if repair {
+        for id in &report.orphaned_indexes {
+            indexes_database.delete_index_data(id).await?;
+            report.deleted_orphaned_indexes.push(id.clone());
+        }
+    }
+
+    Ok(report)
+}
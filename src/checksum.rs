@@ -0,0 +1,126 @@
+/// Format of the values stored inside the on-disk indexes databases (RocksDB and LMDB), with
+/// optional integrity checksums.
+///
+/// Each stored value starts with a format byte (since the schema version 2, see `schema.rs`):
+/// - `1`: the value sent by the client,
+/// - `2`: the value followed by its big-endian CRC32 (`VALUES_CHECKSUMS=crc32`). The checksum
+///   is verified on every read and a mismatch is returned as a `CorruptedValue` error instead
+///   of sending the corrupted ciphertext to the clients.
+///
+/// The values are written in the format of the configuration and read in every known format,
+/// so the configuration can change at any time: a fetched value in another format is
+/// rewritten in the configured format (see `is_current`), without a migration of the whole
+/// database. A new format (compression…) gets the next format byte.
+use std::env;
+
+use cosmian_findex::{parameters::UID_LENGTH, Uid};
+
+use crate::errors::Error;
+
+const CHECKSUM_LENGTH: usize = 4;
+const FORMAT_PLAIN: u8 = 1;
+const FORMAT_CRC32: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Checksums {
+    Disabled,
+    Crc32,
+}
+
+impl Checksums {
+    pub(crate) fn from_env() -> Self {
+        match env::var("VALUES_CHECKSUMS").as_deref() {
+            Err(_) | Ok("") | Ok("none") => Checksums::Disabled,
+            Ok("crc32") => Checksums::Crc32,
+            Ok(value) => panic!(
+                "Unknown `VALUES_CHECKSUMS` {value:?}, the supported values are \"none\" and \"crc32\""
+            ),
+        }
+    }
+
+    fn format(self) -> u8 {
+        match self {
+            Checksums::Disabled => FORMAT_PLAIN,
+            Checksums::Crc32 => FORMAT_CRC32,
+        }
+    }
+
+    /// The value to store inside the database
+    pub(crate) fn wrap(self, value: Vec<u8>) -> Vec<u8> {
+        let mut stored_value = Vec::with_capacity(1 + value.len() + CHECKSUM_LENGTH);
+        stored_value.push(self.format());
+        stored_value.extend_from_slice(&value);
+        if self == Checksums::Crc32 {
+            stored_value.extend_from_slice(&crc32fast::hash(&value).to_be_bytes());
+        }
+
+        stored_value
+    }
+
+    /// The value sent to the clients from the value stored inside the database, in any
+    /// format.
+    pub(crate) fn verify<'a>(
+        self,
+        uid: &Uid<UID_LENGTH>,
+        stored_value: &'a [u8],
+    ) -> Result<&'a [u8], Error> {
+        match stored_value.split_first() {
+            Some((&FORMAT_PLAIN, value)) => Ok(value),
+            Some((&FORMAT_CRC32, value_and_checksum)) => {
+                let corrupted = || {
+                    Error::CorruptedValue(format!(
+                        "Wrong checksum of the value of UID {uid:?} inside the indexes database"
+                    ))
+                };
+
+                let value_length = value_and_checksum
+                    .len()
+                    .checked_sub(CHECKSUM_LENGTH)
+                    .ok_or_else(corrupted)?;
+                let (value, checksum) = value_and_checksum.split_at(value_length);
+                if crc32fast::hash(value).to_be_bytes() != checksum {
+                    return Err(corrupted());
+                }
+
+                Ok(value)
+            }
+            _ => Err(Error::CorruptedValue(format!(
+                "Unknown format of the value of UID {uid:?} inside the indexes database"
+            ))),
+        }
+    }
+
+    /// `false` if the stored value must be rewritten in the configured format
+    pub(crate) fn is_current(self, stored_value: &[u8]) -> bool {
+        stored_value.first() == Some(&self.format())
+    }
+
+    /// A value written before the format byte (schema version 1), in the plain format:
+    /// the binaries of the schema 1 never stored a checksum, whatever `VALUES_CHECKSUMS`
+    /// says now. The fetched values are then rewritten in the configured format.
+    pub(crate) fn add_format_byte(mut stored_value: Vec<u8>) -> Vec<u8> {
+        stored_value.insert(0, FORMAT_PLAIN);
+        stored_value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schema_1_value_upgraded_with_crc32_configured() {
+        let uid = Uid::from([1; UID_LENGTH]);
+        let value = b"ciphertext written by a schema 1 binary".to_vec();
+
+        let stored_value = Checksums::add_format_byte(value.clone());
+
+        let checksums = Checksums::Crc32;
+        assert_eq!(checksums.verify(&uid, &stored_value).unwrap(), &value[..]);
+        assert!(!checksums.is_current(&stored_value));
+
+        let rewritten = checksums.wrap(value.clone());
+        assert!(checksums.is_current(&rewritten));
+        assert_eq!(checksums.verify(&uid, &rewritten).unwrap(), &value[..]);
+    }
+}
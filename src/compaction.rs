@@ -0,0 +1,478 @@
+/// Bookkeeping of the compactions (the compaction itself is done by the clients).
+///
+/// Every entry upserted and every chain inserted since the last compaction is counted
+/// in the metadata database. Once `COMPACTION_RECOMMENDED_AFTER_WRITES` writes (1 000 000
+/// by default) are reached, `GET /indexes/{id}/stats` returns `compaction_recommended: true`
+/// and, with the "webhooks" feature, `COMPACTION_WEBHOOK_URL` receives a `POST` with the
+/// index ID and the number of writes (only once, when the threshold is crossed). The
+/// `compaction_webhook_url` of the settings of the index replaces `COMPACTION_WEBHOOK_URL`.
+///
+/// Clients report a finished compaction with `POST /indexes/{id}/compactions` to reset
+/// the counter and save the compaction date.
+///
+/// Two clients compacting the same index concurrently corrupt each other's work, so a
+/// compaction should be wrapped by the compaction lock, saved in the metadata database:
+/// `POST /indexes/{id}/compaction_lock` returns a token (or `409 Conflict` if the lock is
+/// held) and `DELETE /indexes/{id}/compaction_lock` releases it. The lock expires after
+/// `ttl_seconds` (15 minutes by default) in case the client crashes, a long compaction
+/// renews it by acquiring it again with its token.
+///
+/// The holder of the lock reports its progress with `POST /indexes/{id}/compaction/progress`
+/// (its token, a free `phase`, the `processed` items and the estimated `total`) and
+/// `GET /indexes/{id}/compaction/progress` shows it to the operators. A compaction without
+/// report since `COMPACTION_STALLED_AFTER_SECONDS` (5 minutes by default, counted from the
+/// acquisition without report) is `stalled`: its client probably crashed.
+/// `DELETE /admin/indexes/{id}/compaction_lock` (with the admin API key) releases the lock of a
+/// stalled or expired compaction without its token, `?force=true` releases it anyway.
+///
+/// After the writes, the sizes of the index are also checked against the storage alert
+/// thresholds (see `alerts.rs`).
+use std::env;
+
+use actix_web::{
+    delete, get, post,
+    web::{Data, Json, Query},
+};
+use chrono::{Duration, NaiveDateTime, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "webhooks")]
+use crate::settings::IndexSettings;
+use crate::{
+    admin::Admin,
+    alerts::StorageAlerts,
+    core::{Index, IndexesDatabase, MetadataDatabase},
+    errors::{Error, Response},
+};
+
+const DEFAULT_COMPACTION_RECOMMENDED_AFTER_WRITES: u64 = 1_000_000;
+const DEFAULT_COMPACTION_LOCK_TTL_SECONDS: u32 = 15 * 60;
+const MAX_COMPACTION_LOCK_TTL_SECONDS: u32 = 24 * 60 * 60;
+const COMPACTION_LOCK_TOKEN_LENGTH: usize = 32;
+const DEFAULT_COMPACTION_STALLED_AFTER_SECONDS: i64 = 5 * 60;
+
+#[derive(Serialize, Debug, Default)]
+pub struct CompactionStats {
+    pub last_compaction_at: Option<NaiveDateTime>,
+    /// Entries upserted and chains inserted since the last compaction
+    /// (or since the creation of the index)
+    pub writes_since_compaction: u64,
+}
+
+/// Reported by the client holding the compaction lock
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CompactionProgress {
+    /// Free text (`"entries"`, `"chains"`…)
+    pub phase: Option<String>,
+    pub processed: u64,
+    /// Estimated by the client
+    pub total: Option<u64>,
+    pub reported_at: NaiveDateTime,
+}
+
+/// Compaction lock held (maybe expired)
+#[derive(Clone, Debug)]
+pub struct CompactionLockState {
+    pub token: String,
+    pub expires_at: NaiveDateTime,
+    /// Last progress reported with this token
+    pub progress: Option<CompactionProgress>,
+}
+
+pub(crate) struct Compactions {
+    metadata_db: Data<dyn MetadataDatabase>,
+    indexes_db: Data<dyn IndexesDatabase>,
+    storage_alerts: Option<StorageAlerts>,
+    recommended_after_writes: u64,
+    stalled_after: Duration,
+    #[cfg(feature = "webhooks")]
+    webhook_url: Option<String>,
+}
+
+impl Compactions {
+    pub(crate) fn from_env(
+        metadata_db: Data<dyn MetadataDatabase>,
+        indexes_db: Data<dyn IndexesDatabase>,
+    ) -> Self {
+        let recommended_after_writes = env::var("COMPACTION_RECOMMENDED_AFTER_WRITES")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_COMPACTION_RECOMMENDED_AFTER_WRITES);
+
+        let stalled_after_seconds = match env::var("COMPACTION_STALLED_AFTER_SECONDS") {
+            Ok(value) => match value.parse() {
+                Ok(seconds) if seconds > 0 => seconds,
+                _ => panic!("`COMPACTION_STALLED_AFTER_SECONDS` must be a positive number (found `{value}`)"),
+            },
+            Err(_) => DEFAULT_COMPACTION_STALLED_AFTER_SECONDS,
+        };
+
+        #[cfg(not(feature = "webhooks"))]
+        if env::var("COMPACTION_WEBHOOK_URL").is_ok() {
+            panic!("Cannot load `COMPACTION_WEBHOOK_URL` because `findex_cloud` wasn't compiled with \"webhooks\" feature.");
+        }
+
+        Compactions {
+            metadata_db,
+            indexes_db,
+            storage_alerts: StorageAlerts::from_env(),
+            recommended_after_writes,
+            stalled_after: Duration::seconds(stalled_after_seconds),
+            #[cfg(feature = "webhooks")]
+            webhook_url: env::var("COMPACTION_WEBHOOK_URL").ok(),
+        }
+    }
+
+    /// No progress reported since `stalled_after`. Without any report, the lock was acquired
+    /// at most `MAX_COMPACTION_LOCK_TTL_SECONDS` before its expiration, so only an expired
+    /// lock is known to be stalled.
+    fn is_stalled(&self, lock: &CompactionLockState, now: NaiveDateTime) -> bool {
+        match &lock.progress {
+            Some(progress) => now - progress.reported_at > self.stalled_after,
+            None => lock.expires_at < now,
+        }
+    }
+
+    fn is_recommended(&self, writes_since_compaction: u64) -> bool {
+        writes_since_compaction >= self.recommended_after_writes
+    }
+
+    /// Count the writes (and check the storage alerts) without blocking the response to
+    /// the client. An error is only logged because the writes are already applied.
+    pub(crate) fn record_writes_in_background(
+        compactions: &Data<Self>,
+        index: &Index,
+        writes: u64,
+    ) {
+        if writes == 0 {
+            return;
+        }
+
+        let compactions = compactions.clone();
+        let index_id = index.id.clone();
+        let index_to_check = compactions.storage_alerts.as_ref().map(|_| index.clone());
+
+        actix_web::rt::spawn(async move {
+            if let (Some(storage_alerts), Some(mut index)) =
+                (&compactions.storage_alerts, index_to_check)
+            {
+                match compactions.indexes_db.set_size(&mut index).await {
+                    Ok(()) => {
+                        storage_alerts
+                            .check(&index, compactions.metadata_db.get_ref())
+                            .await
+                    }
+                    Err(err) => {
+                        log::error!("Cannot read the sizes of index {index_id} to check the storage alerts ({err:?})")
+                    }
+                }
+            }
+
+            let total = match compactions
+                .metadata_db
+                .add_writes_since_compaction(&index_id, writes)
+                .await
+            {
+                Ok(total) => total,
+                Err(err) => {
+                    log::error!("Cannot count the writes since the last compaction of index {index_id} ({err:?})");
+                    return;
+                }
+            };
+
+            if compactions.is_recommended(total) && !compactions.is_recommended(total - writes) {
+                log::info!("Compaction recommended for index {index_id} ({total} writes since the last compaction)");
+
+                #[cfg(feature = "webhooks")]
+                compactions.notify(&index_id, total).await;
+            }
+        });
+    }
+
+    #[cfg(feature = "webhooks")]
+    async fn notify(&self, index_id: &str, writes_since_compaction: u64) {
+        let settings = match self.metadata_db.get_settings(index_id).await {
+            Ok(settings) => settings.unwrap_or_default(),
+            Err(err) => {
+                log::error!("Cannot read the settings of index {index_id} for the compaction webhook ({err:?})");
+                IndexSettings::default()
+            }
+        };
+        let Some(webhook_url) = settings
+            .compaction_webhook_url
+            .as_ref()
+            .or(self.webhook_url.as_ref())
+        else {
+            return;
+        };
+
+        let result = reqwest::Client::new()
+            .post(webhook_url)
+            .json(&serde_json::json!({
+                "index_id": index_id,
+                "writes_since_compaction": writes_since_compaction,
+            }))
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+
+        if let Err(err) = result {
+            log::error!("Cannot notify the compaction webhook for index {index_id} ({err})");
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct IndexStats {
+    /// In bytes, see `Index::size`
+    size: Option<i64>,
+    entries_size: Option<i64>,
+    chains_size: Option<i64>,
+    entries_count: Option<i64>,
+    chains_count: Option<i64>,
+    #[serde(flatten)]
+    compaction: CompactionStats,
+    compaction_recommended: bool,
+}
+
+async fn index_stats(
+    mut index: Index,
+    indexes_db: &Data<dyn IndexesDatabase>,
+    compactions: &Compactions,
+) -> Result<IndexStats, Error> {
+    indexes_db.set_size(&mut index).await?;
+    let compaction = compactions
+        .metadata_db
+        .get_compaction_stats(&index.id)
+        .await?;
+
+    Ok(IndexStats {
+        size: index.size,
+        entries_size: index.entries_size,
+        chains_size: index.chains_size,
+        entries_count: index.entries_count,
+        chains_count: index.chains_count,
+        compaction_recommended: compactions.is_recommended(compaction.writes_since_compaction),
+        compaction,
+    })
+}
+
+#[get("/indexes/{id}/stats")]
+pub(crate) async fn get_stats(
+    index: Index,
+    indexes_db: Data<dyn IndexesDatabase>,
+    compactions: Data<Compactions>,
+) -> Response<IndexStats> {
+    Ok(Json(index_stats(index, &indexes_db, &compactions).await?))
+}
+
+#[post("/indexes/{id}/compactions")]
+pub(crate) async fn post_compaction(
+    index: Index,
+    indexes_db: Data<dyn IndexesDatabase>,
+    compactions: Data<Compactions>,
+) -> Response<IndexStats> {
+    compactions
+        .metadata_db
+        .set_compacted(&index.id, Utc::now().naive_utc())
+        .await?;
+
+    Ok(Json(index_stats(index, &indexes_db, &compactions).await?))
+}
+
+#[derive(Deserialize, Default)]
+struct AcquireCompactionLock {
+    /// Token of a lock already held, to renew it
+    token: Option<String>,
+    ttl_seconds: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct CompactionLock {
+    token: String,
+    expires_at: NaiveDateTime,
+}
+
+#[post("/indexes/{id}/compaction_lock")]
+pub(crate) async fn acquire_compaction_lock(
+    index: Index,
+    body: Option<Json<AcquireCompactionLock>>,
+    compactions: Data<Compactions>,
+) -> Response<CompactionLock> {
+    let body = body.map(Json::into_inner).unwrap_or_default();
+
+    let ttl_seconds = body
+        .ttl_seconds
+        .unwrap_or(DEFAULT_COMPACTION_LOCK_TTL_SECONDS);
+    if ttl_seconds == 0 || ttl_seconds > MAX_COMPACTION_LOCK_TTL_SECONDS {
+        return Err(Error::BadRequest(format!(
+            "`ttl_seconds` must be between 1 and {MAX_COMPACTION_LOCK_TTL_SECONDS}"
+        )));
+    }
+
+    let token = body.token.unwrap_or_else(|| {
+        rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(COMPACTION_LOCK_TOKEN_LENGTH)
+            .map(char::from)
+            .collect()
+    });
+    let now = Utc::now().naive_utc();
+    let expires_at = now + Duration::seconds(ttl_seconds.into());
+
+    let renewal = body.token.is_some();
+    let acquired = compactions
+        .metadata_db
+        .acquire_compaction_lock(&index.id, &token, expires_at, now)
+        .await?;
+    if !acquired {
+        return Err(Error::CompactionLocked(index.id.clone()));
+    }
+
+    // A new compaction doesn't show the progress of a crashed one.
+    if !renewal {
+        compactions
+            .metadata_db
+            .set_compaction_progress(&index.id, &token, None)
+            .await?;
+    }
+
+    Ok(Json(CompactionLock { token, expires_at }))
+}
+
+#[derive(Deserialize)]
+struct ReleaseCompactionLock {
+    token: String,
+}
+
+#[delete("/indexes/{id}/compaction_lock")]
+pub(crate) async fn release_compaction_lock(
+    index: Index,
+    body: Json<ReleaseCompactionLock>,
+    compactions: Data<Compactions>,
+) -> Response<()> {
+    let released = compactions
+        .metadata_db
+        .release_compaction_lock(&index.id, &body.token)
+        .await?;
+    if !released {
+        return Err(Error::CompactionLocked(index.id.clone()));
+    }
+
+    Ok(Json(()))
+}
+
+#[derive(Deserialize)]
+struct ReportCompactionProgress {
+    token: String,
+    phase: Option<String>,
+    processed: u64,
+    total: Option<u64>,
+}
+
+#[post("/indexes/{id}/compaction/progress")]
+pub(crate) async fn post_compaction_progress(
+    index: Index,
+    body: Json<ReportCompactionProgress>,
+    compactions: Data<Compactions>,
+) -> Response<()> {
+    let body = body.into_inner();
+    let progress = CompactionProgress {
+        phase: body.phase,
+        processed: body.processed,
+        total: body.total,
+        reported_at: Utc::now().naive_utc(),
+    };
+
+    let saved = compactions
+        .metadata_db
+        .set_compaction_progress(&index.id, &body.token, Some(&progress))
+        .await?;
+    if !saved {
+        return Err(Error::CompactionLocked(index.id.clone()));
+    }
+
+    Ok(Json(()))
+}
+
+#[derive(Serialize)]
+struct CompactionStatus {
+    locked: bool,
+    expires_at: Option<NaiveDateTime>,
+    expired: bool,
+    stalled: bool,
+    progress: Option<CompactionProgress>,
+}
+
+#[get("/indexes/{id}/compaction/progress")]
+pub(crate) async fn get_compaction_progress(
+    index: Index,
+    compactions: Data<Compactions>,
+) -> Response<CompactionStatus> {
+    let lock = compactions
+        .metadata_db
+        .get_compaction_lock(&index.id)
+        .await?;
+    let now = Utc::now().naive_utc();
+
+    Ok(Json(match lock {
+        Some(lock) => CompactionStatus {
+            locked: true,
+            expires_at: Some(lock.expires_at),
+            expired: lock.expires_at < now,
+            stalled: compactions.is_stalled(&lock, now),
+            progress: lock.progress,
+        },
+        None => CompactionStatus {
+            locked: false,
+            expires_at: None,
+            expired: false,
+            stalled: false,
+            progress: None,
+        },
+    }))
+}
+
+#[derive(Deserialize)]
+struct ForceRelease {
+    #[serde(default)]
+    force: bool,
+}
+
+#[delete("/admin/indexes/{id}/compaction_lock")]
+pub(crate) async fn admin_release_compaction_lock(
+    _admin: Admin,
+    index: Index,
+    query: Query<ForceRelease>,
+    compactions: Data<Compactions>,
+) -> Response<()> {
+    let Some(lock) = compactions
+        .metadata_db
+        .get_compaction_lock(&index.id)
+        .await?
+    else {
+        return Ok(Json(()));
+    };
+
+    if !query.force && !compactions.is_stalled(&lock, Utc::now().naive_utc()) {
+        return Err(Error::CompactionLocked(index.id.clone()));
+    }
+
+    // Fails if the lock was renewed or released in the meantime.
+    let released = compactions
+        .metadata_db
+        .release_compaction_lock(&index.id, &lock.token)
+        .await?;
+    if !released {
+        return Err(Error::CompactionLocked(index.id.clone()));
+    }
+
+    log::warn!(
+        "Compaction lock of index {} released by an administrator (progress: {:?})",
+        index.id,
+        lock.progress
+    );
+
+    Ok(Json(()))
+}
@@ -0,0 +1,50 @@
+/// Conditional `GET` of the indexes, polled every few seconds by the dashboard.
+///
+/// The responses of `GET /indexes` and `GET /indexes/{id}` have a weak `ETag` (a hash of
+/// the body, so it changes with the sizes and the request counters of the indexes). A
+/// request with the same tag inside `If-None-Match` gets a `304 Not Modified` without body.
+///
+/// The metadata is still read to compute the tag: it saves the bandwidth and the rendering
+/// of the dashboard, not the database calls.
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use actix_web::{
+    http::header::{ETAG, IF_NONE_MATCH},
+    HttpRequest, HttpResponse, HttpResponseBuilder,
+};
+
+/// `response` with `body`, or a `304 Not Modified` if the client already has it.
+pub(crate) fn respond(
+    req: &HttpRequest,
+    mut response: HttpResponseBuilder,
+    body: Vec<u8>,
+) -> HttpResponse {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    let etag = format!("W/\"{:016x}\"", hasher.finish());
+
+    if if_none_match(req, &etag) {
+        return HttpResponse::NotModified()
+            .insert_header((ETAG, etag))
+            .finish();
+    }
+
+    response.insert_header((ETAG, etag)).body(body)
+}
+
+/// The tags are compared with the weak comparison (RFC 9110), `*` matches any tag.
+fn if_none_match(req: &HttpRequest, etag: &str) -> bool {
+    let weak = |tag: &str| {
+        let tag = tag.trim();
+        tag.strip_prefix("W/").unwrap_or(tag).to_string()
+    };
+
+    req.headers()
+        .get_all(IF_NONE_MATCH)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || weak(tag) == weak(etag))
+}
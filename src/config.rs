@@ -0,0 +1,157 @@
+/// Paths of the local files (databases, logs and web UI).
+///
+/// Everything is stored inside `DATA_DIR` (`data` by default) but each file can be
+/// moved with its own env variable (`ROCKSDB_PATH`, `ROCKSDB_BACKUP_DIR`, `LMDB_PATH`,
+/// `SQLITE_PATH`, `REQUESTS_LOG_PATH` and `WEBAUTHN_CREDENTIALS_PATH`) to point to
+/// different mounted volumes.
+///
+/// Before opening a local database, `prepare_directory` creates the directory,
+/// checks that it's writable (to fail at startup with a clear message instead of
+/// failing on the first write) and warns if the free disk space is below
+/// `MIN_FREE_DISK_SPACE_MB` (100MB by default).
+///
+/// Sensitive values are read with `secret_from_env` to also accept `*_FILE` variables.
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+const DEFAULT_MIN_FREE_DISK_SPACE_MB: u64 = 100;
+
+pub(crate) fn data_dir() -> PathBuf {
+    env::var("DATA_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("data"))
+}
+
+fn path_from_env(name: &str, default_file_name: &str) -> PathBuf {
+    env::var(name)
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| data_dir().join(default_file_name))
+}
+
+#[cfg(feature = "rocksdb")]
+pub(crate) fn rocksdb_path() -> PathBuf {
+    path_from_env("ROCKSDB_PATH", "indexes_rocksdb")
+}
+
+#[cfg(feature = "rocksdb")]
+pub(crate) fn rocksdb_backup_dir() -> PathBuf {
+    path_from_env("ROCKSDB_BACKUP_DIR", "backups_rocksdb")
+}
+
+#[cfg(feature = "lmmd")]
+pub(crate) fn lmdb_path() -> PathBuf {
+    path_from_env("LMDB_PATH", "indexes.lmdb")
+}
+
+#[cfg(feature = "sqlite")]
+pub(crate) fn sqlite_path() -> PathBuf {
+    path_from_env("SQLITE_PATH", "database.sqlite")
+}
+
+#[cfg(feature = "webauthn")]
+pub(crate) fn webauthn_credentials_path() -> PathBuf {
+    path_from_env("WEBAUTHN_CREDENTIALS_PATH", "webauthn_credentials.json")
+}
+
+#[cfg(feature = "log_requests")]
+pub(crate) fn requests_log_path() -> PathBuf {
+    path_from_env("REQUESTS_LOG_PATH", "requests.log")
+}
+
+/// Value of a sensitive env variable (keys, credentials). `{name}_FILE` can point to a
+/// file containing the value instead (Docker and Kubernetes secrets), the trailing newline
+/// is removed. `{name}` has priority if both are set.
+pub(crate) fn try_secret_from_env(name: &str) -> Result<Option<String>, String> {
+    if let Ok(value) = env::var(name) {
+        return Ok(Some(value));
+    }
+
+    let file_variable = format!("{name}_FILE");
+    let Ok(path) = env::var(&file_variable) else {
+        return Ok(None);
+    };
+
+    fs::read_to_string(&path)
+        .map(|value| Some(value.trim_end_matches(['\r', '\n']).to_string()))
+        .map_err(|err| format!("Cannot read `{file_variable}` ({path}: {err})"))
+}
+
+/// See `try_secret_from_env`, for the secrets read at startup: panic if the file cannot be read.
+pub(crate) fn secret_from_env(name: &str) -> Option<String> {
+    try_secret_from_env(name).unwrap_or_else(|err| panic!("{err}"))
+}
+
+/// Directory of the web UI, `None` if the UI is disabled with `SERVE_STATIC_UI=false`
+/// (to run headless or serve the UI from a CDN).
+pub(crate) fn static_ui_dir() -> Option<PathBuf> {
+    if env::var("SERVE_STATIC_UI").as_deref() == Ok("false") {
+        return None;
+    }
+
+    Some(
+        env::var("STATIC_UI_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("./static")),
+    )
+}
+
+/// Create the directory if needed, panic if it's not writable and warn
+/// if the disk is almost full.
+pub(crate) fn prepare_directory(directory: &Path) {
+    fs::create_dir_all(directory)
+        .unwrap_or_else(|e| panic!("Cannot create directory {} ({e})", directory.display()));
+
+    let probe = directory.join(".findex_cloud_write_check");
+    fs::write(&probe, b"")
+        .and_then(|_| fs::remove_file(&probe))
+        .unwrap_or_else(|e| panic!("Directory {} is not writable ({e})", directory.display()));
+
+    let min_free_disk_space_mb = env::var("MIN_FREE_DISK_SPACE_MB")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_MIN_FREE_DISK_SPACE_MB);
+
+    if let Some(free_disk_space) = free_disk_space(directory) {
+        let free_disk_space_mb = free_disk_space / 1024 / 1024;
+        if free_disk_space_mb < min_free_disk_space_mb {
+            log::warn!(
+                "Only {free_disk_space_mb}MB of free disk space for {} (minimum recommended is {min_free_disk_space_mb}MB)",
+                directory.display()
+            );
+        }
+    }
+}
+
+/// See `prepare_directory`, for files (SQLite database, logs)
+pub(crate) fn prepare_parent_directory(file: &Path) {
+    if let Some(directory) = file
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        prepare_directory(directory);
+    }
+}
+
+/// In bytes, `None` if unknown
+#[cfg(unix)]
+fn free_disk_space(directory: &Path) -> Option<u64> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(directory.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    // SAFETY: `path` is a valid C string and `stat` is a valid `statvfs` struct.
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+
+    #[allow(clippy::unnecessary_cast)] // Types are different between platforms
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_disk_space(_directory: &Path) -> Option<u64> {
+    None
+}
@@ -2,7 +2,10 @@ use std::{
     collections::{HashMap, HashSet},
     future::Future,
     pin::Pin,
-    sync::RwLock,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
     time::SystemTime,
 };
 
@@ -12,49 +15,243 @@ use actix_web::{
     FromRequest,
 };
 use async_trait::async_trait;
-use cloudproof_findex::cloud::{CALLBACK_SIGNATURE_LENGTH, SIGNATURE_SEED_LENGTH};
+use cloudproof_findex::cloud::{CALLBACK_SIGNATURE_LENGTH, INDEX_ID_LENGTH, SIGNATURE_SEED_LENGTH};
 
-use chrono::NaiveDateTime;
+use chrono::{NaiveDate, NaiveDateTime};
 use cosmian_crypto_core::bytes_ser_de::Serializable;
 use cosmian_findex::{
     kmac,
     parameters::{KmacKey, UID_LENGTH},
     EncryptedTable, KeyingMaterial, Uid, UpsertData,
 };
-use serde::Serialize;
-
-use crate::errors::Error;
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{
+    backup::BackupInfo,
+    changes::Change,
+    compaction::{CompactionLockState, CompactionProgress, CompactionStats},
+    counters::IndexCounters,
+    errors::Error,
+    events::Mutation,
+    metrics::StorageGauge,
+    scrub::ScrubBatch,
+    settings::{self, Consistency, IndexSettings},
+    usage::{DailyUsage, UsageCounters},
+};
 
 #[derive(Serialize, Debug, Clone)]
-pub(crate) struct Index {
-    pub(crate) id: String,
-    pub(crate) name: String,
-    pub(crate) fetch_entries_key: Vec<u8>,
-    pub(crate) fetch_chains_key: Vec<u8>,
-    pub(crate) upsert_entries_key: Vec<u8>,
-    pub(crate) insert_chains_key: Vec<u8>,
+pub struct Index {
+    pub id: String,
+    pub name: String,
+    pub fetch_entries_key: Vec<u8>,
+    pub fetch_chains_key: Vec<u8>,
+    pub upsert_entries_key: Vec<u8>,
+    pub insert_chains_key: Vec<u8>,
     /// In bytes, if `None` the size is not available (because it was too costly to
     /// compute or because the driver doesn't support getting the size of the index).
-    pub(crate) size: Option<i64>,
-    pub(crate) created_at: NaiveDateTime,
+    pub size: Option<i64>,
+    /// Part of `size` used by the entries and by the chains. A high chains/entries
+    /// ratio means the index needs a compaction. `None` if not available.
+    pub entries_size: Option<i64>,
+    pub chains_size: Option<i64>,
+    /// Number of rows inside the entries and chains tables. `None` if not available.
+    pub entries_count: Option<i64>,
+    pub chains_count: Option<i64>,
+    pub created_at: NaiveDateTime,
+    /// The data of an archived index is in the archive store (see `archive.rs`),
+    /// Findex callbacks are refused until the index is unarchived.
+    pub archived_at: Option<NaiveDateTime>,
+    /// Entries and chains expire `ttl_seconds` after their last write
+    /// (only with an indexes database supporting it, see `IndexesDatabase::supports_ttl`).
+    pub ttl_seconds: Option<i64>,
+    /// Last Findex callback, saved periodically (see `retention.rs`).
+    /// `None` if the index was never used since the retention policy is enabled.
+    pub last_activity_at: Option<NaiveDateTime>,
+    /// Flagged as stale by the retention policy, purged after the grace period.
+    pub stale_at: Option<NaiveDateTime>,
+    /// Requests to the Findex callbacks, saved periodically (see `counters.rs`)
+    pub fetches: i64,
+    pub upserts: i64,
+    pub chain_inserts: i64,
+    pub rejected_signatures: i64,
+}
+
+#[cfg(test)]
+impl Index {
+    /// Index without keys nor counters, `id` must be `INDEX_ID_LENGTH` characters.
+    pub(crate) fn for_tests(id: &str) -> Self {
+        Index {
+            id: id.to_string(),
+            name: id.to_string(),
+            fetch_entries_key: vec![],
+            fetch_chains_key: vec![],
+            upsert_entries_key: vec![],
+            insert_chains_key: vec![],
+            size: None,
+            entries_size: None,
+            chains_size: None,
+            entries_count: None,
+            chains_count: None,
+            created_at: NaiveDateTime::default(),
+            archived_at: None,
+            ttl_seconds: None,
+            last_activity_at: None,
+            stale_at: None,
+            fetches: 0,
+            upserts: 0,
+            chain_inserts: 0,
+            rejected_signatures: 0,
+        }
+    }
 }
 
 #[derive(Debug)]
-pub(crate) struct NewIndex {
-    pub(crate) id: String,
-    pub(crate) name: String,
-    pub(crate) fetch_entries_key: Vec<u8>,
-    pub(crate) fetch_chains_key: Vec<u8>,
-    pub(crate) upsert_entries_key: Vec<u8>,
-    pub(crate) insert_chains_key: Vec<u8>,
+pub struct NewIndex {
+    pub id: String,
+    pub name: String,
+    pub fetch_entries_key: Vec<u8>,
+    pub fetch_chains_key: Vec<u8>,
+    pub upsert_entries_key: Vec<u8>,
+    pub insert_chains_key: Vec<u8>,
+    pub ttl_seconds: Option<i64>,
+}
+
+/// In characters, after normalization
+pub(crate) const MAX_INDEX_NAME_LENGTH: usize = 255;
+
+impl NewIndex {
+    /// Normalize the name (trim and collapse whitespaces) and check the name and
+    /// the keys. Every `MetadataDatabase::create_index` implementation must call it
+    /// before storing the index.
+    pub fn validate(mut self) -> Result<Self, Error> {
+        // The ID is at the beginning of the client tokens (and of the keys inside
+        // the indexes databases) without separator, its length is fixed.
+        if self.id.len() != INDEX_ID_LENGTH || !self.id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(Error::InvalidIndexId(format!(
+                "the ID must be {INDEX_ID_LENGTH} ASCII letters or digits"
+            )));
+        }
+
+        if self.name.chars().any(char::is_control) {
+            return Err(Error::InvalidIndexName(
+                "the name cannot contain control characters".to_string(),
+            ));
+        }
+
+        self.name = self.name.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        if self.name.is_empty() {
+            return Err(Error::InvalidIndexName(
+                "the name cannot be empty".to_string(),
+            ));
+        }
+
+        let name_length = self.name.chars().count();
+        if name_length > MAX_INDEX_NAME_LENGTH {
+            return Err(Error::InvalidIndexName(format!(
+                "the name is {name_length} characters long, the maximum is {MAX_INDEX_NAME_LENGTH}"
+            )));
+        }
+
+        for (key_name, key) in [
+            ("fetch_entries_key", &self.fetch_entries_key),
+            ("fetch_chains_key", &self.fetch_chains_key),
+            ("upsert_entries_key", &self.upsert_entries_key),
+            ("insert_chains_key", &self.insert_chains_key),
+        ] {
+            if key.len() != SIGNATURE_SEED_LENGTH {
+                return Err(Error::InvalidKeyLength {
+                    key: key_name,
+                    length: key.len(),
+                    expected: SIGNATURE_SEED_LENGTH,
+                });
+            }
+        }
+
+        if matches!(self.ttl_seconds, Some(ttl_seconds) if ttl_seconds <= 0) {
+            return Err(Error::BadRequest(
+                "the TTL must be a positive number of seconds".to_string(),
+            ));
+        }
+
+        Ok(self)
+    }
 }
 
+/// Wipe the callback seeds of the indexes (cached, cloned or read from the metadata database)
+/// when they are dropped.
+impl Drop for Index {
+    fn drop(&mut self) {
+        self.fetch_entries_key.zeroize();
+        self.fetch_chains_key.zeroize();
+        self.upsert_entries_key.zeroize();
+        self.insert_chains_key.zeroize();
+    }
+}
+
+impl Drop for NewIndex {
+    fn drop(&mut self) {
+        self.fetch_entries_key.zeroize();
+        self.fetch_chains_key.zeroize();
+        self.upsert_entries_key.zeroize();
+        self.insert_chains_key.zeroize();
+    }
+}
+
+/// Wipe the values of a table once serialized or sent to the database.
+pub(crate) fn wipe_table(table: &mut EncryptedTable<UID_LENGTH>) {
+    for value in table.values_mut() {
+        value.zeroize();
+    }
+}
+
+/// Serialize borrowed UIDs and values like `EncryptedTable::serialize` (number of values,
+/// then each UID and its length-prefixed value, the lengths in LEB128) into a single buffer
+/// allocated with the final size.
+pub(crate) fn serialize_values(values: &[(&Uid<UID_LENGTH>, &[u8])]) -> Zeroizing<Vec<u8>> {
+    fn leb128_length(mut n: u64) -> usize {
+        let mut length = 1;
+        while n >= 0x80 {
+            n >>= 7;
+            length += 1;
+        }
+        length
+    }
+
+    fn write_leb128(bytes: &mut Vec<u8>, mut n: u64) {
+        while n >= 0x80 {
+            bytes.push((n as u8 & 0x7f) | 0x80);
+            n >>= 7;
+        }
+        bytes.push(n as u8);
+    }
+
+    let length = leb128_length(values.len() as u64)
+        + values
+            .iter()
+            .map(|(_, value)| UID_LENGTH + leb128_length(value.len() as u64) + value.len())
+            .sum::<usize>();
+
+    let mut bytes = Zeroizing::new(Vec::with_capacity(length));
+    write_leb128(&mut bytes, values.len() as u64);
+    for (uid, value) in values {
+        bytes.extend_from_slice(uid.as_ref());
+        write_leb128(&mut bytes, value.len() as u64);
+        bytes.extend_from_slice(value);
+    }
+
+    bytes
+}
+
+/// The data is returned inside a `Zeroizing` to be wiped after the deserialization.
 #[allow(clippy::result_large_err)]
 pub(crate) fn check_body_signature(
     body: Bytes,
     index_id: &str,
     seed: &[u8],
-) -> Result<Vec<u8>, Error> {
+) -> Result<Zeroizing<Vec<u8>>, Error> {
     let original_length = body.len();
     let mut bytes = body.into_iter();
 
@@ -70,18 +267,9 @@ pub(crate) fn check_body_signature(
         .next_chunk()
         .map_err(|_| Error::BadRequest(format!("Body of request is too small ({original_length} bytes), not enought bytes to read expiration timestamp.")))?;
 
-    let data: Vec<_> = bytes.collect();
+    let data = Zeroizing::new(bytes.collect::<Vec<_>>());
 
-    let key: KmacKey =
-        KeyingMaterial::<SIGNATURE_SEED_LENGTH>::deserialize(seed.to_vec().as_slice())?
-            .derive_kmac_key::<CALLBACK_SIGNATURE_LENGTH>(index_id.as_bytes());
-
-    let signature_computed = kmac!(
-        CALLBACK_SIGNATURE_LENGTH,
-        &key,
-        &expiration_timestamp_bytes,
-        &data
-    );
+    let signature_computed = body_signature(index_id, seed, &expiration_timestamp_bytes, &data)?;
 
     if signature_received != signature_computed {
         return Err(Error::InvalidSignature);
@@ -100,14 +288,37 @@ pub(crate) fn check_body_signature(
     Ok(data)
 }
 
-#[derive(Copy, Clone, Debug)]
-pub(crate) enum Table {
+/// KMAC of the expiration timestamp and the data, with a key derived from the seed and the
+/// index ID.
+pub(crate) fn body_signature(
+    index_id: &str,
+    seed: &[u8],
+    expiration_timestamp_bytes: &[u8],
+    data: &[u8],
+) -> Result<[u8; CALLBACK_SIGNATURE_LENGTH], Error> {
+    let key: KmacKey = KeyingMaterial::<SIGNATURE_SEED_LENGTH>::deserialize(seed)?
+        .derive_kmac_key::<CALLBACK_SIGNATURE_LENGTH>(index_id.as_bytes());
+
+    Ok(kmac!(
+        CALLBACK_SIGNATURE_LENGTH,
+        &key,
+        expiration_timestamp_bytes,
+        data
+    ))
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Table {
     Entries,
     Chains,
 }
 
+/// Sizes read at the same time by the default `IndexesDatabase::set_sizes`
+const SET_SIZES_CONCURRENCY: usize = 16;
+
 #[async_trait]
-pub(crate) trait IndexesDatabase: Sync + Send {
+pub trait IndexesDatabase: Sync + Send {
     /// Set the size of the index inside the `Index` struct. Size is set in bytes.
     /// The index struct is fetched from the `MetadataDatabase` but the
     /// size is often known by the `IndexesDatabase`, this is why this function
@@ -121,14 +332,19 @@ pub(crate) trait IndexesDatabase: Sync + Send {
     async fn set_size(&self, indexes: &mut Index) -> Result<(), Error>;
 
     /// See `set_size` function.
-    /// Some drivers could define a more optimized version to fetch multiple sizes at once
-    /// than fetching individual sizes one by one.
+    /// The sizes are fetched concurrently (at most `SET_SIZES_CONCURRENCY` at a time),
+    /// drivers which can fetch multiple sizes at once should define a more optimized version.
     async fn set_sizes(&self, indexes: &mut Vec<Index>) -> Result<(), Error> {
-        for index in indexes {
-            self.set_size(index).await?;
-        }
-
-        Ok(())
+        // The futures don't run until polled by `buffer_unordered`.
+        let futures: Vec<_> = indexes
+            .iter_mut()
+            .map(|index| self.set_size(index))
+            .collect();
+
+        futures::stream::iter(futures)
+            .buffer_unordered(SET_SIZES_CONCURRENCY)
+            .try_collect()
+            .await
     }
 
     async fn fetch(
@@ -150,16 +366,223 @@ pub(crate) trait IndexesDatabase: Sync + Send {
         data: EncryptedTable<UID_LENGTH>,
     ) -> Result<(), Error>;
 
+    /// Write the values without any check (overwrite the existing values).
+    /// Used to apply the values already checked by another instance.
+    async fn put_values(
+        &self,
+        index: &Index,
+        table: Table,
+        data: EncryptedTable<UID_LENGTH>,
+    ) -> Result<(), Error>;
+
+    /// Read all the values of a table for an index. Used by the admin export
+    /// (see `export.rs`), can be slow on big indexes.
+    async fn fetch_all(
+        &self,
+        index: &Index,
+        table: Table,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error>;
+
+    /// `fetch` serialized for the response of a callback (see `fetch_chains`). The default
+    /// implementation serializes the result of `fetch`, the databases reading from a memory
+    /// map override it to serialize their values without copying them first (see
+    /// `serialize_values`).
+    async fn fetch_serialized(
+        &self,
+        index: &Index,
+        table: Table,
+        uids: HashSet<Uid<UID_LENGTH>>,
+    ) -> Result<Zeroizing<Vec<u8>>, Error> {
+        let mut uids_and_values = self.fetch(index, table, uids).await?;
+        let bytes = uids_and_values.serialize()?;
+        wipe_table(&mut uids_and_values);
+        Ok(bytes)
+    }
+
+    /// Fetch with the consistency chosen in the settings of the index (see `settings.rs`).
+    /// The default implementation ignores it, for the databases whose reads are always
+    /// consistent.
+    async fn fetch_with_consistency(
+        &self,
+        index: &Index,
+        table: Table,
+        uids: HashSet<Uid<UID_LENGTH>>,
+        _consistency: Consistency,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        self.fetch(index, table, uids).await
+    }
+
+    /// IDs of all the indexes having data inside this database. Used to find
+    /// the data of deleted indexes (see `check.rs`).
+    async fn indexes_ids_with_data(&self) -> Result<HashSet<String>, Error> {
+        Err(Error::Unsupported(
+            "This indexes database cannot list the indexes with data".to_string(),
+        ))
+    }
+
+    /// Remove all the data (entries, chains, size…) of an index.
+    async fn delete_index_data(&self, _index_id: &str) -> Result<(), Error> {
+        Err(Error::Unsupported(
+            "This indexes database cannot delete the data of an index".to_string(),
+        ))
+    }
+
+    /// Create a backup of the whole indexes database while serving the requests
+    /// (see `backup.rs`).
+    async fn backup(&self) -> Result<BackupInfo, Error> {
+        Err(Error::Unsupported(
+            "This indexes database doesn't support backups".to_string(),
+        ))
+    }
+
+    async fn backups(&self) -> Result<Vec<BackupInfo>, Error> {
+        Err(Error::Unsupported(
+            "This indexes database doesn't support backups".to_string(),
+        ))
+    }
+
+    /// If `true`, the values of the indexes with a `ttl_seconds` expire inside the
+    /// database. Indexes with a TTL cannot be created if it's `false`.
+    fn supports_ttl(&self) -> bool {
+        false
+    }
+
+    /// Recompute the size of an index from the stored values and save it
+    /// (see `set_size`).
+    async fn recompute_size(&self, _index: &Index) -> Result<(), Error> {
+        Err(Error::Unsupported(
+            "This indexes database cannot recompute the size of an index".to_string(),
+        ))
+    }
+
+    /// Validate the keys and the values of at most `limit` items of the table, starting
+    /// after the key `from` (without the index and table prefix), see `scrub.rs`.
+    async fn scrub(
+        &self,
+        _index: &Index,
+        _table: Table,
+        _from: Option<&[u8]>,
+        _limit: usize,
+    ) -> Result<ScrubBatch, Error> {
+        Err(Error::Unsupported(
+            "This indexes database doesn't support scrubbing".to_string(),
+        ))
+    }
+
+    /// Append the mutations at the end of the changes log of the index.
+    /// See `changes.rs`.
+    async fn append_changes(&self, _index: &Index, _mutations: &[Mutation]) -> Result<(), Error> {
+        Err(Error::Unsupported(
+            "This indexes database doesn't support the changes log".to_string(),
+        ))
+    }
+
+    /// Fetch at most `limit` changes with a cursor strictly greater than `since`
+    /// in cursor order.
+    async fn fetch_changes(
+        &self,
+        _index: &Index,
+        _since: u64,
+        _limit: usize,
+    ) -> Result<Vec<Change>, Error> {
+        Err(Error::Unsupported(
+            "This indexes database doesn't support the changes log".to_string(),
+        ))
+    }
+
+    /// Gauges of the storage layer (pending compactions, client retries…) for
+    /// `GET /metrics`, see `metrics.rs`.
+    async fn storage_gauges(&self) -> Result<Vec<StorageGauge>, Error> {
+        Ok(Vec::new())
+    }
+
     #[cfg(feature = "log_requests")]
     async fn fetch_all_as_json(&self, _index: &Index, _table: Table) -> Result<String, Error> {
         unimplemented!();
     }
 }
 
-pub(crate) type MetadataCache = RwLock<HashMap<String, Index>>;
+/// Indexes read from the `MetadataDatabase`, by ID. See `cache.rs` for the
+/// administration endpoints.
+#[derive(Default)]
+pub struct MetadataCache {
+    pub(crate) entries: RwLock<HashMap<String, Index>>,
+    /// See `settings.rs`
+    pub(crate) settings: RwLock<HashMap<String, Arc<IndexSettings>>>,
+    pub(crate) hits: AtomicU64,
+    pub(crate) misses: AtomicU64,
+}
+
+impl MetadataCache {
+    pub(crate) fn get(&self, id: &str) -> Option<Index> {
+        let index = self
+            .entries
+            .read()
+            .ok()
+            .and_then(|entries| entries.get(id).cloned());
+
+        if index.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        index
+    }
+
+    pub(crate) fn insert(&self, index: Index) {
+        if let Ok(mut entries) = self.entries.write() {
+            entries.insert(index.id.clone(), index);
+        }
+    }
+
+    /// Without counting a hit or a miss
+    pub(crate) fn contains(&self, id: &str) -> bool {
+        self.entries
+            .read()
+            .map_or(false, |entries| entries.contains_key(id))
+    }
+
+    pub(crate) fn remove(&self, id: &str) {
+        if let Ok(mut entries) = self.entries.write() {
+            entries.remove(id);
+        }
+        if let Ok(mut settings) = self.settings.write() {
+            settings.remove(id);
+        }
+    }
+
+    /// Returns the number of removed indexes.
+    pub(crate) fn clear(&self) -> usize {
+        if let Ok(mut settings) = self.settings.write() {
+            settings.clear();
+        }
+
+        self.entries.write().map_or(0, |mut entries| {
+            let count = entries.len();
+            entries.clear();
+            count
+        })
+    }
+
+    pub(crate) fn get_settings(&self, id: &str) -> Option<Arc<IndexSettings>> {
+        self.settings
+            .read()
+            .ok()
+            .and_then(|settings| settings.get(id).cloned())
+    }
+
+    pub(crate) fn insert_settings(&self, id: &str, index_settings: Arc<IndexSettings>) {
+        if let Ok(mut settings) = self.settings.write() {
+            settings.insert(id.to_string(), index_settings);
+        }
+    }
+}
 
+/// Only the indexes CRUD is required, the optional features (archives, compaction, retention,
+/// usage, counters and settings) return `Error::Unsupported` by default.
 #[async_trait]
-pub(crate) trait MetadataDatabase: Sync + Send {
+pub trait MetadataDatabase: Sync + Send {
     async fn get_indexes(&self) -> Result<Vec<Index>, Error>;
 
     async fn get_index(&self, id: &str) -> Result<Option<Index>, Error>;
@@ -168,18 +591,14 @@ pub(crate) trait MetadataDatabase: Sync + Send {
         cache: &MetadataCache,
         id: &str,
     ) -> Result<Option<Index>, Error> {
-        if let Ok(cache) = cache.read() {
-            if let Some(index) = cache.get(id) {
-                return Ok(Some(index.clone()));
-            }
+        if let Some(index) = cache.get(id) {
+            return Ok(Some(index));
         }
 
         let index = self.get_index(id).await?;
 
         if let Some(index) = index {
-            if let Ok(mut cache) = cache.write() {
-                cache.insert(id.to_string(), index.clone());
-            }
+            cache.insert(index.clone());
 
             return Ok(Some(index));
         }
@@ -189,6 +608,167 @@ pub(crate) trait MetadataDatabase: Sync + Send {
 
     async fn delete_index(&self, id: &str) -> Result<(), Error>;
     async fn create_index(&self, new_index: NewIndex) -> Result<Index, Error>;
+    /// Create all the indexes or none of them. If an ID is already used, returns
+    /// `Error::IndexAlreadyExists` with the first conflicting ID.
+    async fn create_indexes(&self, _new_indexes: Vec<NewIndex>) -> Result<Vec<Index>, Error> {
+        Err(Error::Unsupported(
+            "This metadata database cannot create several indexes at once".to_string(),
+        ))
+    }
+    /// Mark the index as archived (or unarchived with `None`).
+    async fn set_archived_at(
+        &self,
+        _id: &str,
+        _archived_at: Option<NaiveDateTime>,
+    ) -> Result<(), Error> {
+        Err(Error::Unsupported(
+            "This metadata database doesn't support archives".to_string(),
+        ))
+    }
+
+    /// See `compaction.rs`
+    async fn get_compaction_stats(&self, _id: &str) -> Result<CompactionStats, Error> {
+        Err(Error::Unsupported(
+            "This metadata database doesn't support compactions".to_string(),
+        ))
+    }
+    /// Returns the new number of writes since the last compaction.
+    async fn add_writes_since_compaction(&self, _id: &str, _writes: u64) -> Result<u64, Error> {
+        Err(Error::Unsupported(
+            "This metadata database doesn't support compactions".to_string(),
+        ))
+    }
+    async fn set_compacted(&self, _id: &str, _compacted_at: NaiveDateTime) -> Result<(), Error> {
+        Err(Error::Unsupported(
+            "This metadata database doesn't support compactions".to_string(),
+        ))
+    }
+    /// Take the compaction lock if it's free, expired (before `now`) or already held with
+    /// `token` (renewal). Returns `false` if it's held by someone else.
+    async fn acquire_compaction_lock(
+        &self,
+        _id: &str,
+        _token: &str,
+        _expires_at: NaiveDateTime,
+        _now: NaiveDateTime,
+    ) -> Result<bool, Error> {
+        Err(Error::Unsupported(
+            "This metadata database doesn't support compactions".to_string(),
+        ))
+    }
+    /// Returns `false` if the lock is not held with `token` (expired and taken by someone else).
+    /// Also removes the progress of the compaction.
+    async fn release_compaction_lock(&self, _id: &str, _token: &str) -> Result<bool, Error> {
+        Err(Error::Unsupported(
+            "This metadata database doesn't support compactions".to_string(),
+        ))
+    }
+    /// The compaction lock if it's held, even expired, with the last progress reported.
+    async fn get_compaction_lock(&self, _id: &str) -> Result<Option<CompactionLockState>, Error> {
+        Err(Error::Unsupported(
+            "This metadata database doesn't support compactions".to_string(),
+        ))
+    }
+    /// Save the progress of the compaction (or remove it with `None`) if the lock is held with
+    /// `token`. Returns `false` otherwise.
+    async fn set_compaction_progress(
+        &self,
+        _id: &str,
+        _token: &str,
+        _progress: Option<&CompactionProgress>,
+    ) -> Result<bool, Error> {
+        Err(Error::Unsupported(
+            "This metadata database doesn't support compactions".to_string(),
+        ))
+    }
+
+    /// See `retention.rs`, also removes the stale flag. Does nothing for a deleted index.
+    async fn set_last_activity_at(
+        &self,
+        _id: &str,
+        _last_activity_at: NaiveDateTime,
+    ) -> Result<(), Error> {
+        Err(Error::Unsupported(
+            "This metadata database doesn't support the retention".to_string(),
+        ))
+    }
+    async fn set_stale_at(&self, _id: &str, _stale_at: NaiveDateTime) -> Result<(), Error> {
+        Err(Error::Unsupported(
+            "This metadata database doesn't support the retention".to_string(),
+        ))
+    }
+
+    /// See `usage.rs`, adds the counters to the saved ones.
+    async fn add_usage(
+        &self,
+        _id: &str,
+        _day: NaiveDate,
+        _endpoint: &str,
+        _counters: &UsageCounters,
+    ) -> Result<(), Error> {
+        Err(Error::Unsupported(
+            "This metadata database doesn't support the usage".to_string(),
+        ))
+    }
+    async fn get_usage(&self, _id: &str, _since: NaiveDate) -> Result<Vec<DailyUsage>, Error> {
+        Err(Error::Unsupported(
+            "This metadata database doesn't support the usage".to_string(),
+        ))
+    }
+    async fn delete_usage_before(&self, _day: NaiveDate) -> Result<(), Error> {
+        Err(Error::Unsupported(
+            "This metadata database doesn't support the usage".to_string(),
+        ))
+    }
+
+    /// See `counters.rs`. Does nothing for a deleted index.
+    async fn add_request_counters(
+        &self,
+        _id: &str,
+        _increments: &IndexCounters,
+    ) -> Result<(), Error> {
+        Err(Error::Unsupported(
+            "This metadata database doesn't support the request counters".to_string(),
+        ))
+    }
+
+    /// See `settings.rs`, `None` if the settings of the index were never saved. The default
+    /// implementation never saves them: the indexes keep the default settings.
+    async fn get_settings(&self, _id: &str) -> Result<Option<IndexSettings>, Error> {
+        Ok(None)
+    }
+    /// Also increments the version of the settings.
+    async fn set_settings(&self, _id: &str, _settings: &IndexSettings) -> Result<(), Error> {
+        Err(Error::Unsupported(
+            "This metadata database doesn't support the settings".to_string(),
+        ))
+    }
+    /// The settings with their version (incremented by each update), `None` if they were
+    /// never saved.
+    async fn get_versioned_settings(
+        &self,
+        _id: &str,
+    ) -> Result<Option<(IndexSettings, u64)>, Error> {
+        Ok(None)
+    }
+    /// Save the settings only if their version is still `version` (0 if they were never
+    /// saved). Returns the new version, `None` if the settings were modified in the meantime.
+    async fn replace_settings(
+        &self,
+        _id: &str,
+        _settings: &IndexSettings,
+        _version: u64,
+    ) -> Result<Option<u64>, Error> {
+        Err(Error::Unsupported(
+            "This metadata database doesn't support the settings".to_string(),
+        ))
+    }
+
+    /// Gauges of the storage layer (connection pool, client retries…) for `GET /metrics`,
+    /// see `metrics.rs`.
+    async fn storage_gauges(&self) -> Result<Vec<StorageGauge>, Error> {
+        Ok(Vec::new())
+    }
 }
 
 impl FromRequest for Index {
@@ -211,10 +791,82 @@ impl FromRequest for Index {
                 .await?;
 
             if let Some(index) = index {
-                Ok(index)
+                if index.archived_at.is_some() {
+                    return Err(Error::IndexArchived(index.id.clone()));
+                }
+
+                let settings =
+                    settings::settings_with_cache(metadata_database.get_ref(), metadata_cache, &id)
+                        .await?;
+
+                Ok(settings.apply(index))
             } else {
                 Err(Error::BadRequest(format!("Unknown index for ID {id}")))
             }
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
+    use test::Bencher;
+
+    use super::*;
+
+    const BENCH_VALUES: u32 = 1_000;
+    const BENCH_VALUE_LENGTH: usize = 128;
+
+    fn borrowed(table: &EncryptedTable<UID_LENGTH>) -> Vec<(&Uid<UID_LENGTH>, &[u8])> {
+        table
+            .iter()
+            .map(|(uid, value)| (uid, value.as_slice()))
+            .collect()
+    }
+
+    #[test]
+    fn serialize_values_like_encrypted_table() {
+        // The lengths take 1, 2 and 3 bytes in LEB128
+        for lengths in [&[][..], &[0, 1, 127, 128, 300, 16_383, 16_384]] {
+            let mut table = EncryptedTable::<UID_LENGTH>::with_capacity(lengths.len());
+            for (i, length) in lengths.iter().enumerate() {
+                table.insert(Uid::from([i as u8; UID_LENGTH]), vec![i as u8; *length]);
+            }
+
+            let bytes = serialize_values(&borrowed(&table));
+            assert_eq!(bytes.len(), bytes.capacity());
+            assert_eq!(&bytes[..], &table.serialize().unwrap()[..]);
+
+            let deserialized = EncryptedTable::<UID_LENGTH>::deserialize(&bytes).unwrap();
+            assert_eq!(deserialized.len(), table.len());
+            for (uid, value) in table.iter() {
+                assert_eq!(deserialized.get(uid), Some(value));
+            }
+        }
+    }
+
+    fn bench_table() -> EncryptedTable<UID_LENGTH> {
+        let mut table = EncryptedTable::<UID_LENGTH>::with_capacity(BENCH_VALUES as usize);
+        for i in 0..BENCH_VALUES {
+            let mut uid = [0; UID_LENGTH];
+            uid[..4].copy_from_slice(&i.to_be_bytes());
+            table.insert(Uid::from(uid), vec![1; BENCH_VALUE_LENGTH]);
+        }
+        table
+    }
+
+    /// `fetch_serialized` of LMDB: the values borrowed from the memory map
+    #[bench]
+    fn bench_serialize_borrowed_values(b: &mut Bencher) {
+        let table = bench_table();
+        b.iter(|| serialize_values(&borrowed(&table)));
+    }
+
+    /// Default `fetch_serialized`: the values copied by `fetch` then serialized
+    #[bench]
+    fn bench_serialize_fetched_table(b: &mut Bencher) {
+        let table = bench_table();
+        b.iter(|| table.clone().serialize().unwrap());
+    }
+}
@@ -0,0 +1,165 @@
+/// Durable request counters of each index, returned with the index (`GET /indexes/{id}`
+/// and `GET /indexes`).
+///
+/// - `fetches`: `fetch_entries` and `fetch_chains` requests,
+/// - `upserts`: `upsert_entries` requests,
+/// - `chain_inserts`: `insert_chains` requests,
+/// - `rejected_signatures`: requests refused by the signature check (wrong or expired
+///   signature or access token, body too small). They are not counted in the other counters.
+///
+/// To keep the database out of the requests, the counters are incremented in memory and
+/// added to the metadata database every `REQUEST_COUNTERS_FLUSH_SECONDS` seconds (10 by
+/// default, `0` disables the counters). A crash loses at most this interval.
+///
+/// The rejected signatures are also checked for spikes (see `SignatureAlerts`), even when the
+/// counters are disabled.
+use std::{collections::HashMap, env, mem, sync::Mutex, time::Duration};
+
+use actix_web::web::{Bytes, Data};
+use serde::Serialize;
+use zeroize::Zeroizing;
+
+use crate::{
+    alerts::SignatureAlerts,
+    core::{check_body_signature, Index, MetadataDatabase},
+    errors::Error,
+};
+
+const DEFAULT_REQUEST_COUNTERS_FLUSH_SECONDS: u64 = 10;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Counter {
+    Fetches,
+    Upserts,
+    ChainInserts,
+}
+
+#[derive(Serialize, Debug, Default, Clone, Copy)]
+pub struct IndexCounters {
+    pub fetches: u64,
+    pub upserts: u64,
+    pub chain_inserts: u64,
+    pub rejected_signatures: u64,
+}
+
+impl IndexCounters {
+    fn add(&mut self, other: &IndexCounters) {
+        self.fetches += other.fetches;
+        self.upserts += other.upserts;
+        self.chain_inserts += other.chain_inserts;
+        self.rejected_signatures += other.rejected_signatures;
+    }
+}
+
+/// Present in the app data only if the counters or the signature alerts are enabled.
+pub(crate) struct RequestCounters {
+    /// `None` if the counters are disabled
+    flush_interval: Option<Duration>,
+    /// Counters not saved yet, by index ID
+    pending: Mutex<HashMap<String, IndexCounters>>,
+    signature_alerts: Option<SignatureAlerts>,
+}
+
+impl RequestCounters {
+    pub(crate) fn from_env(
+        signature_alerts: Option<SignatureAlerts>,
+    ) -> Option<Data<RequestCounters>> {
+        let flush_seconds = env::var("REQUEST_COUNTERS_FLUSH_SECONDS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_REQUEST_COUNTERS_FLUSH_SECONDS);
+        let flush_interval = (flush_seconds > 0).then(|| Duration::from_secs(flush_seconds));
+
+        (flush_interval.is_some() || signature_alerts.is_some()).then(|| {
+            Data::new(RequestCounters {
+                flush_interval,
+                pending: Mutex::new(HashMap::new()),
+                signature_alerts,
+            })
+        })
+    }
+
+    fn increment(&self, index_id: &str, increment: impl FnOnce(&mut IndexCounters)) {
+        if self.flush_interval.is_none() {
+            return;
+        }
+
+        if let Ok(mut pending) = self.pending.lock() {
+            increment(pending.entry(index_id.to_string()).or_default());
+        }
+    }
+
+    pub(crate) fn start(counters: Data<Self>, metadata_db: Data<dyn MetadataDatabase>) {
+        let Some(flush_interval) = counters.flush_interval else {
+            return;
+        };
+
+        actix_web::rt::spawn(async move {
+            loop {
+                actix_web::rt::time::sleep(flush_interval).await;
+
+                let pending = match counters.pending.lock() {
+                    Ok(mut pending) => mem::take(&mut *pending),
+                    Err(_) => continue,
+                };
+
+                for (index_id, increments) in pending {
+                    if let Err(err) = metadata_db
+                        .add_request_counters(&index_id, &increments)
+                        .await
+                    {
+                        log::error!(
+                            "Cannot save the request counters of index {index_id} ({err:?})"
+                        );
+
+                        // Retried at the next flush
+                        if let Ok(mut pending) = counters.pending.lock() {
+                            pending.entry(index_id).or_default().add(&increments);
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// `check_body_signature()` counting the request inside `counter`, or inside the rejected
+/// signatures if the check fails.
+pub(crate) fn check_signature_and_count(
+    counters: &Option<Data<RequestCounters>>,
+    bytes: Bytes,
+    index: &Index,
+    seed: &[u8],
+    counter: Counter,
+) -> Result<Zeroizing<Vec<u8>>, Error> {
+    count(
+        counters,
+        &index.id,
+        counter,
+        check_body_signature(bytes, &index.id, seed),
+    )
+}
+
+/// Count the result of a signature (or access token) check inside `counter`, or inside the
+/// rejected signatures if the check failed.
+pub(crate) fn count<T>(
+    counters: &Option<Data<RequestCounters>>,
+    index_id: &str,
+    counter: Counter,
+    result: Result<T, Error>,
+) -> Result<T, Error> {
+    if let Some(counters) = counters {
+        if let (Err(_), Some(signature_alerts)) = (&result, &counters.signature_alerts) {
+            signature_alerts.record_rejection(index_id);
+        }
+
+        counters.increment(index_id, |counters| match (&result, counter) {
+            (Err(_), _) => counters.rejected_signatures += 1,
+            (Ok(_), Counter::Fetches) => counters.fetches += 1,
+            (Ok(_), Counter::Upserts) => counters.upserts += 1,
+            (Ok(_), Counter::ChainInserts) => counters.chain_inserts += 1,
+        });
+    }
+
+    result
+}
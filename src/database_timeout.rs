@@ -0,0 +1,477 @@
+/// Timeout on the calls to the indexes and metadata databases, so a hung database returns
+/// a `504 Gateway Timeout` instead of holding the connection of the client forever.
+///
+/// The timeout is `DATABASE_TIMEOUT_SECONDS` (30 by default, `0` to disable it). The long
+/// operations (full reads for the exports, deletion of the data of an index, backups and
+/// size recomputations) are not limited.
+///
+/// The RocksDB calls run on the blocking threads of actix-web and the LMDB writes on the
+/// writer thread: on timeout the request fails but the call continues in the background (a
+/// RocksDB transaction already fails after waiting 10ms for a lock). The LMDB reads are
+/// synchronous, the timeout is only checked when they return. It really interrupts the
+/// calls to the network databases (DynamoDB, SQLite pool).
+use std::{collections::HashSet, env, future::Future, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{NaiveDate, NaiveDateTime};
+use cosmian_findex::{parameters::UID_LENGTH, EncryptedTable, Uid, UpsertData};
+use zeroize::Zeroizing;
+
+use crate::{
+    backup::BackupInfo,
+    changes::Change,
+    compaction::{CompactionLockState, CompactionProgress, CompactionStats},
+    core::{Index, IndexesDatabase, MetadataDatabase, NewIndex, Table},
+    counters::IndexCounters,
+    errors::Error,
+    events::Mutation,
+    metrics::StorageGauge,
+    scrub::ScrubBatch,
+    settings::{Consistency, IndexSettings},
+    usage::{DailyUsage, UsageCounters},
+};
+
+const DEFAULT_DATABASE_TIMEOUT_SECONDS: u64 = 30;
+
+/// `None` if the timeout is disabled
+pub(crate) fn timeout_from_env() -> Option<Duration> {
+    let seconds = env::var("DATABASE_TIMEOUT_SECONDS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_DATABASE_TIMEOUT_SECONDS);
+
+    (seconds > 0).then(|| Duration::from_secs(seconds))
+}
+
+async fn with_timeout<T>(
+    timeout: Duration,
+    operation: &str,
+    future: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    actix_web::rt::time::timeout(timeout, future)
+        .await
+        .map_err(|_| {
+            Error::DatabaseTimeout(format!(
+                "`{operation}` took more than {} seconds",
+                timeout.as_secs()
+            ))
+        })?
+}
+
+pub(crate) struct IndexesDatabaseWithTimeout {
+    inner: Arc<dyn IndexesDatabase>,
+    timeout: Duration,
+}
+
+impl IndexesDatabaseWithTimeout {
+    pub(crate) fn new(inner: Arc<dyn IndexesDatabase>, timeout: Duration) -> Self {
+        IndexesDatabaseWithTimeout { inner, timeout }
+    }
+}
+
+#[async_trait]
+impl IndexesDatabase for IndexesDatabaseWithTimeout {
+    async fn set_size(&self, index: &mut Index) -> Result<(), Error> {
+        with_timeout(self.timeout, "set_size", self.inner.set_size(index)).await
+    }
+
+    async fn set_sizes(&self, indexes: &mut Vec<Index>) -> Result<(), Error> {
+        with_timeout(self.timeout, "set_sizes", self.inner.set_sizes(indexes)).await
+    }
+
+    async fn fetch(
+        &self,
+        index: &Index,
+        table: Table,
+        uids: HashSet<Uid<UID_LENGTH>>,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        with_timeout(self.timeout, "fetch", self.inner.fetch(index, table, uids)).await
+    }
+
+    async fn fetch_serialized(
+        &self,
+        index: &Index,
+        table: Table,
+        uids: HashSet<Uid<UID_LENGTH>>,
+    ) -> Result<Zeroizing<Vec<u8>>, Error> {
+        with_timeout(
+            self.timeout,
+            "fetch",
+            self.inner.fetch_serialized(index, table, uids),
+        )
+        .await
+    }
+
+    async fn upsert_entries(
+        &self,
+        index: &Index,
+        data: UpsertData<UID_LENGTH>,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        with_timeout(
+            self.timeout,
+            "upsert_entries",
+            self.inner.upsert_entries(index, data),
+        )
+        .await
+    }
+
+    async fn insert_chains(
+        &self,
+        index: &Index,
+        data: EncryptedTable<UID_LENGTH>,
+    ) -> Result<(), Error> {
+        with_timeout(
+            self.timeout,
+            "insert_chains",
+            self.inner.insert_chains(index, data),
+        )
+        .await
+    }
+
+    async fn put_values(
+        &self,
+        index: &Index,
+        table: Table,
+        data: EncryptedTable<UID_LENGTH>,
+    ) -> Result<(), Error> {
+        with_timeout(
+            self.timeout,
+            "put_values",
+            self.inner.put_values(index, table, data),
+        )
+        .await
+    }
+
+    async fn fetch_all(
+        &self,
+        index: &Index,
+        table: Table,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        self.inner.fetch_all(index, table).await
+    }
+
+    async fn fetch_with_consistency(
+        &self,
+        index: &Index,
+        table: Table,
+        uids: HashSet<Uid<UID_LENGTH>>,
+        consistency: Consistency,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        with_timeout(
+            self.timeout,
+            "fetch_with_consistency",
+            self.inner
+                .fetch_with_consistency(index, table, uids, consistency),
+        )
+        .await
+    }
+
+    async fn indexes_ids_with_data(&self) -> Result<HashSet<String>, Error> {
+        self.inner.indexes_ids_with_data().await
+    }
+
+    async fn delete_index_data(&self, index_id: &str) -> Result<(), Error> {
+        self.inner.delete_index_data(index_id).await
+    }
+
+    async fn backup(&self) -> Result<BackupInfo, Error> {
+        self.inner.backup().await
+    }
+
+    async fn backups(&self) -> Result<Vec<BackupInfo>, Error> {
+        with_timeout(self.timeout, "backups", self.inner.backups()).await
+    }
+
+    fn supports_ttl(&self) -> bool {
+        self.inner.supports_ttl()
+    }
+
+    async fn recompute_size(&self, index: &Index) -> Result<(), Error> {
+        self.inner.recompute_size(index).await
+    }
+
+    async fn scrub(
+        &self,
+        index: &Index,
+        table: Table,
+        from: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<ScrubBatch, Error> {
+        with_timeout(
+            self.timeout,
+            "scrub",
+            self.inner.scrub(index, table, from, limit),
+        )
+        .await
+    }
+
+    async fn append_changes(&self, index: &Index, mutations: &[Mutation]) -> Result<(), Error> {
+        with_timeout(
+            self.timeout,
+            "append_changes",
+            self.inner.append_changes(index, mutations),
+        )
+        .await
+    }
+
+    async fn fetch_changes(
+        &self,
+        index: &Index,
+        since: u64,
+        limit: usize,
+    ) -> Result<Vec<Change>, Error> {
+        with_timeout(
+            self.timeout,
+            "fetch_changes",
+            self.inner.fetch_changes(index, since, limit),
+        )
+        .await
+    }
+
+    async fn storage_gauges(&self) -> Result<Vec<StorageGauge>, Error> {
+        with_timeout(self.timeout, "storage_gauges", self.inner.storage_gauges()).await
+    }
+
+    #[cfg(feature = "log_requests")]
+    async fn fetch_all_as_json(&self, index: &Index, table: Table) -> Result<String, Error> {
+        self.inner.fetch_all_as_json(index, table).await
+    }
+}
+
+pub(crate) struct MetadataDatabaseWithTimeout {
+    inner: Arc<dyn MetadataDatabase>,
+    timeout: Duration,
+}
+
+impl MetadataDatabaseWithTimeout {
+    pub(crate) fn new(inner: Arc<dyn MetadataDatabase>, timeout: Duration) -> Self {
+        MetadataDatabaseWithTimeout { inner, timeout }
+    }
+}
+
+#[async_trait]
+impl MetadataDatabase for MetadataDatabaseWithTimeout {
+    async fn get_indexes(&self) -> Result<Vec<Index>, Error> {
+        with_timeout(self.timeout, "get_indexes", self.inner.get_indexes()).await
+    }
+
+    async fn get_index(&self, id: &str) -> Result<Option<Index>, Error> {
+        with_timeout(self.timeout, "get_index", self.inner.get_index(id)).await
+    }
+
+    async fn delete_index(&self, id: &str) -> Result<(), Error> {
+        with_timeout(self.timeout, "delete_index", self.inner.delete_index(id)).await
+    }
+
+    async fn create_index(&self, new_index: NewIndex) -> Result<Index, Error> {
+        with_timeout(
+            self.timeout,
+            "create_index",
+            self.inner.create_index(new_index),
+        )
+        .await
+    }
+
+    async fn create_indexes(&self, new_indexes: Vec<NewIndex>) -> Result<Vec<Index>, Error> {
+        with_timeout(
+            self.timeout,
+            "create_indexes",
+            self.inner.create_indexes(new_indexes),
+        )
+        .await
+    }
+
+    async fn set_archived_at(
+        &self,
+        id: &str,
+        archived_at: Option<NaiveDateTime>,
+    ) -> Result<(), Error> {
+        with_timeout(
+            self.timeout,
+            "set_archived_at",
+            self.inner.set_archived_at(id, archived_at),
+        )
+        .await
+    }
+
+    async fn get_compaction_stats(&self, id: &str) -> Result<CompactionStats, Error> {
+        with_timeout(
+            self.timeout,
+            "get_compaction_stats",
+            self.inner.get_compaction_stats(id),
+        )
+        .await
+    }
+
+    async fn add_writes_since_compaction(&self, id: &str, writes: u64) -> Result<u64, Error> {
+        with_timeout(
+            self.timeout,
+            "add_writes_since_compaction",
+            self.inner.add_writes_since_compaction(id, writes),
+        )
+        .await
+    }
+
+    async fn set_compacted(&self, id: &str, compacted_at: NaiveDateTime) -> Result<(), Error> {
+        with_timeout(
+            self.timeout,
+            "set_compacted",
+            self.inner.set_compacted(id, compacted_at),
+        )
+        .await
+    }
+
+    async fn acquire_compaction_lock(
+        &self,
+        id: &str,
+        token: &str,
+        expires_at: NaiveDateTime,
+        now: NaiveDateTime,
+    ) -> Result<bool, Error> {
+        with_timeout(
+            self.timeout,
+            "acquire_compaction_lock",
+            self.inner
+                .acquire_compaction_lock(id, token, expires_at, now),
+        )
+        .await
+    }
+
+    async fn release_compaction_lock(&self, id: &str, token: &str) -> Result<bool, Error> {
+        with_timeout(
+            self.timeout,
+            "release_compaction_lock",
+            self.inner.release_compaction_lock(id, token),
+        )
+        .await
+    }
+
+    async fn get_compaction_lock(&self, id: &str) -> Result<Option<CompactionLockState>, Error> {
+        with_timeout(
+            self.timeout,
+            "get_compaction_lock",
+            self.inner.get_compaction_lock(id),
+        )
+        .await
+    }
+
+    async fn set_compaction_progress(
+        &self,
+        id: &str,
+        token: &str,
+        progress: Option<&CompactionProgress>,
+    ) -> Result<bool, Error> {
+        with_timeout(
+            self.timeout,
+            "set_compaction_progress",
+            self.inner.set_compaction_progress(id, token, progress),
+        )
+        .await
+    }
+
+    async fn set_last_activity_at(
+        &self,
+        id: &str,
+        last_activity_at: NaiveDateTime,
+    ) -> Result<(), Error> {
+        with_timeout(
+            self.timeout,
+            "set_last_activity_at",
+            self.inner.set_last_activity_at(id, last_activity_at),
+        )
+        .await
+    }
+
+    async fn set_stale_at(&self, id: &str, stale_at: NaiveDateTime) -> Result<(), Error> {
+        with_timeout(
+            self.timeout,
+            "set_stale_at",
+            self.inner.set_stale_at(id, stale_at),
+        )
+        .await
+    }
+
+    async fn add_usage(
+        &self,
+        id: &str,
+        day: NaiveDate,
+        endpoint: &str,
+        counters: &UsageCounters,
+    ) -> Result<(), Error> {
+        with_timeout(
+            self.timeout,
+            "add_usage",
+            self.inner.add_usage(id, day, endpoint, counters),
+        )
+        .await
+    }
+
+    async fn get_usage(&self, id: &str, since: NaiveDate) -> Result<Vec<DailyUsage>, Error> {
+        with_timeout(self.timeout, "get_usage", self.inner.get_usage(id, since)).await
+    }
+
+    async fn delete_usage_before(&self, day: NaiveDate) -> Result<(), Error> {
+        with_timeout(
+            self.timeout,
+            "delete_usage_before",
+            self.inner.delete_usage_before(day),
+        )
+        .await
+    }
+
+    async fn add_request_counters(
+        &self,
+        id: &str,
+        increments: &IndexCounters,
+    ) -> Result<(), Error> {
+        with_timeout(
+            self.timeout,
+            "add_request_counters",
+            self.inner.add_request_counters(id, increments),
+        )
+        .await
+    }
+
+    async fn get_settings(&self, id: &str) -> Result<Option<IndexSettings>, Error> {
+        with_timeout(self.timeout, "get_settings", self.inner.get_settings(id)).await
+    }
+
+    async fn set_settings(&self, id: &str, settings: &IndexSettings) -> Result<(), Error> {
+        with_timeout(
+            self.timeout,
+            "set_settings",
+            self.inner.set_settings(id, settings),
+        )
+        .await
+    }
+
+    async fn get_versioned_settings(
+        &self,
+        id: &str,
+    ) -> Result<Option<(IndexSettings, u64)>, Error> {
+        with_timeout(
+            self.timeout,
+            "get_versioned_settings",
+            self.inner.get_versioned_settings(id),
+        )
+        .await
+    }
+
+    async fn replace_settings(
+        &self,
+        id: &str,
+        settings: &IndexSettings,
+        version: u64,
+    ) -> Result<Option<u64>, Error> {
+        with_timeout(
+            self.timeout,
+            "replace_settings",
+            self.inner.replace_settings(id, settings, version),
+        )
+        .await
+    }
+
+    async fn storage_gauges(&self) -> Result<Vec<StorageGauge>, Error> {
+        with_timeout(self.timeout, "storage_gauges", self.inner.storage_gauges()).await
+    }
+}
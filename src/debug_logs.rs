@@ -1,4 +1,5 @@
-/// This module is only used to log all requests and export the database.
+/// This module is only used to log all requests (fetches, upserts and inserts)
+/// and export the database.
 /// Its feature SHOULD never be activate on production.
 /// We currently use this feature to generate data to run attack scripts on it
 /// and verify the security of Findex.
@@ -7,38 +8,35 @@
 /// the logged request to let the client determine the starting time for each request
 /// while keeping the correct difference between the fetch_entries and fetch_chains calls.
 ///
-/// Requests logs are JSON encoded lines to easy append a new line to the file. `get_requests_log`
-/// will convert these JSON lines to a correct JSON array (adding the `[]` around the file and
-/// the `,` between each lines)
+/// Requests logs are JSON encoded lines written to a sink (see `requests_log.rs`). `get_requests_log`
+/// will convert these JSON lines to a correct JSON array (adding the `[]` around the lines and
+/// the `,` between each lines). For long captures, use `query_requests_log` to filter
+/// and paginate the lines instead of loading everything in memory.
 use std::collections::HashMap;
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::{sync::RwLock, time::SystemTime};
+use std::time::SystemTime;
 
 use actix_web::{
     get, post,
-    web::{Data, Json, Path},
+    web::{Data, Json, Path, Query},
 };
 use base64::{engine::general_purpose, Engine as _};
-use cosmian_findex::{parameters::UID_LENGTH, Uid};
+use cosmian_findex::{parameters::UID_LENGTH, Uid, UpsertData};
+use serde::{Deserialize, Serialize};
 
 use crate::core::IndexesDatabase;
+use crate::requests_log::{LogFilter, LogLine, RequestsLog};
 use crate::{
     core::{Index, Table},
     errors::{Error, Response},
 };
 
-const LOGS_PATH: &str = "data/requests.log";
-
-pub(crate) type DataTimeDiffInMillisecondsMutex = Data<RwLock<TimeDiffInMilliseconds>>;
-
 #[derive(Default)]
 pub(crate) struct TimeDiffInMilliseconds(pub(crate) i128);
 
 #[post("/set_time_diff/{fake_time}")]
 pub(crate) async fn set_time_diff(
     fake_time: Path<String>,
-    time_diff_mutex: DataTimeDiffInMillisecondsMutex,
+    requests_log: Data<RequestsLog>,
 ) -> Response<()> {
     let fake_time_in_milliseconds: u128 = fake_time
         .parse()
@@ -49,7 +47,10 @@ pub(crate) async fn set_time_diff(
         .map_err(|_| Error::BadRequest("SystemTime is before UNIX_EPOCH".to_owned()))?;
 
     {
-        let mut time_diff = time_diff_mutex.write().unwrap();
+        let mut time_diff = requests_log
+            .time_diff
+            .write()
+            .map_err(|_| Error::Internal("Time diff lock is poisoned".to_string()))?;
         time_diff.0 = current_time.as_millis() as i128 - fake_time_in_milliseconds as i128;
     }
 
@@ -57,12 +58,64 @@ pub(crate) async fn set_time_diff(
 }
 
 #[get("/requests_log")]
-pub(crate) async fn get_requests_log() -> String {
-    let contents = std::fs::read_to_string(LOGS_PATH).unwrap_or("".to_owned());
+pub(crate) async fn get_requests_log(requests_log: Data<RequestsLog>) -> Result<String, Error> {
+    let contents_with_commas = requests_log.read_all().await?.join(",\n");
+
+    Ok(format!("[{contents_with_commas}]"))
+}
+
+const DEFAULT_QUERY_LIMIT: usize = 1_000;
+const MAX_QUERY_LIMIT: usize = 10_000;
+
+#[derive(Deserialize)]
+pub(crate) struct RequestsLogQuery {
+    /// Inclusive, in milliseconds
+    from: Option<i64>,
+    /// Inclusive, in milliseconds
+    to: Option<i64>,
+    index_id: Option<String>,
+    #[serde(rename = "type")]
+    log_type: Option<String>,
+    #[serde(default)]
+    since: u64,
+    limit: Option<usize>,
+}
 
-    let contents_with_commas = contents.lines().collect::<Vec<_>>().join(",\n");
+#[derive(Serialize)]
+pub(crate) struct RequestsLogPage {
+    lines: Vec<serde_json::Value>,
+    /// To send as `since` to get the next page
+    next_cursor: u64,
+}
 
-    format!("[{contents_with_commas}]")
+/// `GET /requests_log/query?from=&to=&index_id=&type=&since=&limit=`
+#[get("/requests_log/query")]
+pub(crate) async fn query_requests_log(
+    query: Query<RequestsLogQuery>,
+    requests_log: Data<RequestsLog>,
+) -> Response<RequestsLogPage> {
+    let query = query.into_inner();
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_QUERY_LIMIT)
+        .clamp(1, MAX_QUERY_LIMIT);
+    let filter = LogFilter {
+        from: query.from,
+        to: query.to,
+        index_id: query.index_id,
+        log_type: query.log_type,
+    };
+
+    let lines = requests_log.query(&filter, query.since, limit).await?;
+    let next_cursor = lines.last().map_or(query.since, |line| line.cursor);
+
+    Ok(Json(RequestsLogPage {
+        lines: lines
+            .iter()
+            .map(|line| serde_json::from_str(&line.line))
+            .collect::<Result<_, _>>()?,
+        next_cursor,
+    }))
 }
 
 #[get("/export_entries_for_index/{id}")]
@@ -82,28 +135,20 @@ pub(crate) async fn export_chains_for_index(
 }
 
 #[post("/reset_requests_log")]
-async fn post_reset_requests_log() -> String {
-    let _ = std::fs::remove_file(LOGS_PATH); // Don't want to crash if the file doesn't exists
-    "OK".to_owned()
+async fn post_reset_requests_log(requests_log: Data<RequestsLog>) -> Result<String, Error> {
+    requests_log.reset().await?;
+
+    Ok("OK".to_owned())
 }
 
-pub(crate) fn save_log(
+/// Log a `fetch_entries` or `fetch_chains` with the requested UIDs and the found values.
+pub(crate) fn save_fetch_log(
     log_type: &str,
-    time_diff_mutex: Data<std::sync::RwLock<TimeDiffInMilliseconds>>,
+    index_id: &str,
+    requests_log: &RequestsLog,
     uids: std::collections::HashSet<Uid<UID_LENGTH>>,
     uids_and_values: &cosmian_findex::EncryptedTable<UID_LENGTH>,
 ) -> Result<(), Error> {
-    let mut file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .append(true)
-        .open(LOGS_PATH)
-        .map_err(|_| Error::BadRequest(format!("Cannot open {}", LOGS_PATH)))?;
-
-    let current_time = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .map_err(|_| Error::BadRequest("SystemTime is before UNIX_EPOCH".to_owned()))?;
-
     let data: HashMap<String, Option<String>> = uids
         .iter()
         .map(|uid| {
@@ -116,22 +161,95 @@ pub(crate) fn save_log(
         })
         .collect();
 
-    // Lock for writing to prevent writing two lines at once inside file
-    // This is sub-optimal since it put a sync point between requests that
-    // could change timing patterns.
-    let time_diff = time_diff_mutex.write().unwrap();
-    let timestamp = current_time.as_millis() as i128 + time_diff.0;
+    save_log(log_type, index_id, requests_log, data)
+}
 
-    let json = serde_json::json!({
-        "date": timestamp,
-        "type": log_type,
-        "data": data,
-    });
+#[derive(Serialize)]
+pub(crate) struct UpsertLog {
+    old_value: bool,
+    new_value: bool,
+    rejected: bool,
+}
 
-    let json_string = serde_json::to_string(&json)
-        .map_err(|_| Error::BadRequest(format!("Cannot convert to JSON {json:?}")))?;
-    writeln!(file, "{json_string}",)
-        .map_err(|_| Error::BadRequest(format!("Cannot write JSON '{json_string}' to file")))?;
+/// Presence of the old and new values for each UID, to call before the upsert
+/// consumes the data.
+pub(crate) fn upsert_log_data(
+    data: &UpsertData<UID_LENGTH>,
+) -> HashMap<Uid<UID_LENGTH>, UpsertLog> {
+    data.iter()
+        .map(|(uid, (old_value, new_value))| {
+            (
+                *uid,
+                UpsertLog {
+                    old_value: old_value.is_some(),
+                    new_value: !new_value.is_empty(),
+                    rejected: false,
+                },
+            )
+        })
+        .collect()
+}
+
+/// Log an `upsert_entries` with, for each UID, the presence of the old and new values
+/// and if the upsert was rejected (the old value didn't match).
+pub(crate) fn save_upsert_log(
+    index_id: &str,
+    requests_log: &RequestsLog,
+    upsert_log_data: HashMap<Uid<UID_LENGTH>, UpsertLog>,
+    rejected: &cosmian_findex::EncryptedTable<UID_LENGTH>,
+) -> Result<(), Error> {
+    let data: HashMap<String, UpsertLog> = upsert_log_data
+        .into_iter()
+        .map(|(uid, mut upsert_log)| {
+            upsert_log.rejected = rejected.contains_key(&uid);
+            (general_purpose::STANDARD_NO_PAD.encode(uid), upsert_log)
+        })
+        .collect();
+
+    save_log("upsert_entries", index_id, requests_log, data)
+}
+
+/// Log an `insert_chains` with the inserted UIDs and values.
+pub(crate) fn save_insert_log(
+    index_id: &str,
+    requests_log: &RequestsLog,
+    uids_and_values: &cosmian_findex::EncryptedTable<UID_LENGTH>,
+) -> Result<(), Error> {
+    let data: HashMap<String, String> = uids_and_values
+        .iter()
+        .map(|(uid, value)| {
+            (
+                general_purpose::STANDARD_NO_PAD.encode(uid),
+                general_purpose::STANDARD_NO_PAD.encode(value),
+            )
+        })
+        .collect();
+
+    save_log("insert_chains", index_id, requests_log, data)
+}
+
+fn save_log(
+    log_type: &str,
+    index_id: &str,
+    requests_log: &RequestsLog,
+    data: impl Serialize,
+) -> Result<(), Error> {
+    let current_time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|_| Error::BadRequest("SystemTime is before UNIX_EPOCH".to_owned()))?;
+
+    let time_diff = requests_log
+        .time_diff
+        .read()
+        .map_err(|_| Error::Internal("Time diff lock is poisoned".to_string()))?
+        .0;
+
+    requests_log.log(LogLine {
+        date: current_time.as_millis() as i128 + time_diff,
+        log_type: log_type.to_string(),
+        index_id: index_id.to_string(),
+        data: serde_json::to_value(data)?,
+    });
 
     Ok(())
 }
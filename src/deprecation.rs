@@ -0,0 +1,142 @@
+/// Deprecated routes and protocol versions, to tell the clients before removing them and
+/// to measure when it's safe to do so.
+///
+/// `DEPRECATIONS` (JSON) lists the deprecated routes (method and actix-web pattern) and
+/// protocol versions (see `protocol.rs`), each with an optional sunset date and a link to
+/// the migration documentation:
+///
+/// ```json
+/// {
+///   "routes": {"POST /indexes/{id}/fetch_entries": {"sunset": "2027-06-30T00:00:00Z", "link": "https://…"}},
+///   "protocol_versions": {"1": {"sunset": "2027-01-01T00:00:00Z"}}
+/// }
+/// ```
+///
+/// The responses of a deprecated route or protocol version have a `Deprecation: true` header,
+/// a `Sunset` header with the HTTP date of the sunset and a `Link` header with
+/// `rel="deprecation"`. The old protocol versions are always deprecated, even when they are
+/// not listed. Each deprecated request is counted in the metrics
+/// (`findex_cloud_deprecated_requests_total` with a `route` or a `protocol_version` label).
+use std::{collections::HashMap, env};
+
+use actix_web::{
+    dev::ServiceResponse,
+    http::header::{HeaderName, HeaderValue},
+    web::Data,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::{metrics::Metrics, protocol::PROTOCOL_VERSION_HEADER};
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Deprecations {
+    /// By `"{method} {pattern}"`
+    #[serde(default)]
+    routes: HashMap<String, Deprecation>,
+    #[serde(default)]
+    protocol_versions: HashMap<u8, Deprecation>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Deprecation {
+    sunset: Option<DateTime<Utc>>,
+    link: Option<String>,
+}
+
+impl Deprecations {
+    pub(crate) fn from_env() -> Self {
+        let deprecations: Deprecations = match env::var("DEPRECATIONS") {
+            Ok(json) => serde_json::from_str(&json)
+                .unwrap_or_else(|err| panic!("Cannot parse `DEPRECATIONS` ({err})")),
+            Err(_) => Default::default(),
+        };
+
+        for (name, deprecation) in deprecations
+            .routes
+            .iter()
+            .map(|(route, deprecation)| (format!("route `{route}`"), deprecation))
+            .chain(
+                deprecations
+                    .protocol_versions
+                    .iter()
+                    .map(|(version, deprecation)| {
+                        (format!("protocol version {version}"), deprecation)
+                    }),
+            )
+        {
+            if let Some(link) = &deprecation.link {
+                if HeaderValue::from_str(&format!("<{link}>")).is_err() {
+                    panic!("Invalid link of the deprecated {name} inside `DEPRECATIONS`");
+                }
+            }
+            log::info!(
+                "Deprecated {name}{}",
+                deprecation
+                    .sunset
+                    .map(|sunset| format!(" (sunset on {sunset})"))
+                    .unwrap_or_default()
+            );
+        }
+
+        deprecations
+    }
+
+    /// Add the deprecation headers to the response of a deprecated route or protocol version
+    /// and count it.
+    pub(crate) fn mark<B>(&self, metrics: &Data<Metrics>, response: &mut ServiceResponse<B>) {
+        let route = response
+            .request()
+            .match_pattern()
+            .map(|pattern| format!("{} {pattern}", response.request().method()));
+        let protocol_version = response
+            .headers()
+            .get(PROTOCOL_VERSION_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u8>().ok());
+
+        let (label, deprecation) = match route
+            .as_ref()
+            .and_then(|route| Some((route, self.routes.get(route)?)))
+        {
+            Some((route, deprecation)) => (("route", route.clone()), Some(deprecation)),
+            None => match protocol_version {
+                Some(version)
+                    if response.headers().contains_key("deprecation")
+                        || self.protocol_versions.contains_key(&version) =>
+                {
+                    (
+                        ("protocol_version", version.to_string()),
+                        self.protocol_versions.get(&version),
+                    )
+                }
+                _ => return,
+            },
+        };
+
+        metrics.record_deprecated_request(label.0, &label.1);
+
+        let headers = response.headers_mut();
+        headers.insert(
+            HeaderName::from_static("deprecation"),
+            HeaderValue::from_static("true"),
+        );
+        let Some(deprecation) = deprecation else {
+            return;
+        };
+        if let Some(sunset) = deprecation.sunset {
+            if let Ok(value) =
+                HeaderValue::from_str(&sunset.format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+            {
+                headers.insert(HeaderName::from_static("sunset"), value);
+            }
+        }
+        if let Some(link) = &deprecation.link {
+            if let Ok(value) = HeaderValue::from_str(&format!("<{link}>; rel=\"deprecation\"")) {
+                headers.append(HeaderName::from_static("link"), value);
+            }
+        }
+    }
+}
@@ -1,31 +1,48 @@
 use std::{
     collections::{HashMap, HashSet},
-    env,
+    env, mem,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
 };
 
 use async_trait::async_trait;
-use aws_config::{environment::EnvironmentVariableCredentialsProvider, retry::RetryConfigBuilder};
+use aws_config::{
+    environment::EnvironmentVariableCredentialsProvider,
+    retry::{RetryConfig, RetryConfigBuilder},
+    timeout::TimeoutConfig,
+};
 use aws_sdk_dynamodb::{
+    config::{Credentials, Region},
     operation::{
-        create_table::{CreateTableError, CreateTableOutput},
+        create_table::{builders::CreateTableFluentBuilder, CreateTableError, CreateTableOutput},
         put_item::PutItemError,
+        transact_write_items::TransactWriteItemsError,
         update_item::UpdateItemError,
     },
     primitives::Blob,
     types::{
-        AttributeDefinition, AttributeValue, BillingMode, KeySchemaElement, KeyType,
-        KeysAndAttributes, PutRequest, ScalarAttributeType, WriteRequest,
+        AttributeDefinition, AttributeValue, BillingMode, DeleteRequest, GlobalSecondaryIndex,
+        KeySchemaElement, KeyType, KeysAndAttributes, Projection, ProjectionType,
+        ProvisionedThroughput, Put, PutRequest, ReturnValue, ReturnValuesOnConditionCheckFailure,
+        ScalarAttributeType, SseSpecification, SseType, TableClass, TableStatus,
+        TimeToLiveSpecification, TimeToLiveStatus, TransactWriteItem, WriteRequest,
     },
     Client,
 };
 use aws_smithy_http::result::SdkError;
-use chrono::{NaiveDateTime, Utc};
+use chrono::{NaiveDate, NaiveDateTime, Utc};
 use cosmian_findex::{parameters::UID_LENGTH, EncryptedTable, Uid, UpsertData};
 use futures::StreamExt;
 
 use crate::{
+    compaction::{CompactionLockState, CompactionProgress, CompactionStats},
+    config,
     core::{Index, IndexesDatabase, MetadataDatabase, NewIndex, Table},
+    counters::IndexCounters,
     errors::Error,
+    metrics::StorageGauge,
+    settings::{Consistency, IndexSettings},
+    usage::{DailyUsage, UsageCounters, USAGE_RETENTION_DAYS},
 };
 
 /// DynamoDB implementation
@@ -48,14 +65,38 @@ use crate::{
 /// - Documentation on table creation
 /// - Try to remove clones everywhere
 /// - Split ID in two columns (index_id and uid) in entries and chains?
+/// - Delete the data of an index with a Query instead of a Scan (needs the split ID above)
 /// - Implement sizes (right now this implementation do not know the sizes of the tables for one index)
-/// - In the rare case of collusion for a `id` retry with a new one? :UniqueId
+/// - In the rare case of collision of a random `id` retry with a new one instead of returning a conflict?
+///
+/// The usage counters (see `usage.rs`) are in a 4th table, by index `id` and `day#endpoint`.
+/// They expire after `USAGE_RETENTION_DAYS` days with the DynamoDB TTL.
+///
+/// TTL is enabled on the entries and chains tables with the `expires_at` attribute (epoch seconds).
+/// Items of the indexes with a `ttl_seconds` get `expires_at` on every write, the other items
+/// don't have the attribute and never expire. Expired items are deleted by DynamoDB (usually
+/// within a few days) without scans.
 pub struct Database {
     client: Client,
 
     metadata_table_name: String,
     entries_table_name: String,
     chains_table_name: String,
+    usage_table_name: String,
+
+    /// Use strongly consistent reads on the entries table (`DYNAMODB_CONSISTENT_READS=true`).
+    /// Eventually consistent reads may miss an entry just upserted and break the Findex
+    /// retry loop, consistent reads cost twice as much.
+    consistent_entries_reads: bool,
+
+    /// Client for the conditional writes of the entries (and the reads of the conflicting
+    /// values) inside `AWS_DYNAMODB_ENTRIES_WRITE_REGION`. With Global Tables, the conditions
+    /// are only reliable when every instance checks them inside the same region, the other
+    /// requests stay in the nearest region (`client`). `None` to use `client`.
+    entries_writes_client: Option<Client>,
+
+    /// Reported in the metrics, see `ClientSettings`
+    max_attempts: u32,
 }
 
 /// These values are determined by the DynamoDB API
@@ -63,6 +104,15 @@ pub struct Database {
 /// of elements.
 const DYNAMODB_MAX_READ_ELEMENTS: usize = 100;
 const DYNAMODB_MAX_WRITE_ELEMENTS: usize = 25;
+const DYNAMODB_MAX_BATCH_WRITE_ATTEMPTS: u32 = 5;
+/// Attempts of the SDK for each call by default, with its own backoff
+const DEFAULT_DYNAMODB_MAX_ATTEMPTS: u32 = 10;
+
+/// Calls still throttled after the attempts of the SDK (counted in `errors.rs`), for all the
+/// DynamoDB clients of the instance
+pub(crate) static THROTTLED_CALLS: AtomicU64 = AtomicU64::new(0);
+/// Batch writes sent again for their unprocessed items
+static BATCH_WRITE_RETRIES: AtomicU64 = AtomicU64::new(0);
 
 /// DynomoDB doesn't provide a way to batch upsert requests,
 /// but we use async to do x of them in parallel. If this value
@@ -70,40 +120,202 @@ const DYNAMODB_MAX_WRITE_ELEMENTS: usize = 25;
 const DYNAMODB_NUMBER_OF_PARALLEL_UPSERT_REQUEST: usize = 30;
 const ENTRIES_AND_CHAINS_ID_COLUMN_NAME: &str = "id";
 const ENTRIES_AND_CHAINS_VALUE_COLUMN_NAME: &str = "value_bytes"; // 'value' is a reserved keyword in dynamodb
+const ENTRIES_AND_CHAINS_EXPIRES_AT_COLUMN_NAME: &str = "expires_at";
+const USAGE_INDEX_ID_COLUMN_NAME: &str = "id";
+const USAGE_DAY_ENDPOINT_COLUMN_NAME: &str = "day_endpoint";
 
-impl Database {
-    pub async fn create() -> Self {
-        let mut config_builder = aws_config::from_env()
-            .credentials_provider(EnvironmentVariableCredentialsProvider::new())
-            .retry_config(RetryConfigBuilder::new().max_attempts(10).build());
+/// Settings of the tables created on startup (existing tables are not updated)
+///
+/// - `DYNAMODB_BILLING_MODE`: `pay_per_request` (default) or `provisioned` with
+///   `DYNAMODB_READ_CAPACITY_UNITS` and `DYNAMODB_WRITE_CAPACITY_UNITS` (for each table and index)
+/// - `DYNAMODB_TABLE_CLASS`: `standard` (default) or `standard_infrequent_access`
+/// - `DYNAMODB_KMS_KEY_ID`: encrypt with this KMS key (`alias/aws/dynamodb` for the AWS managed key)
+///   instead of the AWS owned key
+/// - `DYNAMODB_METADATA_NAME_INDEX`: name of a global secondary index on the `name` of the indexes
+///   inside the metadata table
+struct TableSettings {
+    billing_mode: BillingMode,
+    provisioned_throughput: Option<ProvisionedThroughput>,
+    table_class: Option<TableClass>,
+    sse_specification: Option<SseSpecification>,
+    metadata_name_index: Option<String>,
+}
 
-        if let Ok(url) = env::var("AWS_DYNAMODB_ENDPOINT_URL") {
-            config_builder = config_builder.endpoint_url(url)
+impl TableSettings {
+    fn from_env() -> Self {
+        let capacity_units = |key: &str| -> i64 {
+            env::var(key)
+                .unwrap_or_else(|_| {
+                    panic!(
+                        "`{key}` env variable is required with `DYNAMODB_BILLING_MODE=provisioned`"
+                    )
+                })
+                .parse()
+                .unwrap_or_else(|_| panic!("`{key}` env variable must be a positive integer"))
+        };
+
+        let (billing_mode, provisioned_throughput) =
+            match env::var("DYNAMODB_BILLING_MODE").as_deref() {
+                Err(_) | Ok("pay_per_request") => (BillingMode::PayPerRequest, None),
+                Ok("provisioned") => (
+                    BillingMode::Provisioned,
+                    Some(
+                        ProvisionedThroughput::builder()
+                            .read_capacity_units(capacity_units("DYNAMODB_READ_CAPACITY_UNITS"))
+                            .write_capacity_units(capacity_units("DYNAMODB_WRITE_CAPACITY_UNITS"))
+                            .build(),
+                    ),
+                ),
+                Ok(billing_mode) => panic!("Unknown DynamoDB billing mode {billing_mode:?} (`pay_per_request` or `provisioned`)"),
+            };
+
+        let table_class = match env::var("DYNAMODB_TABLE_CLASS").as_deref() {
+            Err(_) => None,
+            Ok("standard") => Some(TableClass::Standard),
+            Ok("standard_infrequent_access") => Some(TableClass::StandardInfrequentAccess),
+            Ok(table_class) => panic!("Unknown DynamoDB table class {table_class:?} (`standard` or `standard_infrequent_access`)"),
+        };
+
+        let sse_specification = env::var("DYNAMODB_KMS_KEY_ID").ok().map(|kms_key_id| {
+            SseSpecification::builder()
+                .enabled(true)
+                .sse_type(SseType::Kms)
+                .kms_master_key_id(kms_key_id)
+                .build()
+        });
+
+        TableSettings {
+            billing_mode,
+            provisioned_throughput,
+            table_class,
+            sse_specification,
+            metadata_name_index: env::var("DYNAMODB_METADATA_NAME_INDEX").ok(),
         }
+    }
+}
 
-        let config = config_builder.load().await;
-        let client = aws_sdk_dynamodb::Client::new(&config);
+/// Retries and timeouts of the DynamoDB clients (the SDK defaults are kept when not set)
+///
+/// - `DYNAMODB_MAX_ATTEMPTS`: attempts of each call (10 by default, `1` disables the retries)
+/// - `DYNAMODB_INITIAL_BACKOFF_MS`: base of the exponential backoff (with jitter) between
+///   the attempts (1 second in the SDK)
+/// - `DYNAMODB_OPERATION_TIMEOUT_MS`: timeout of a call, all attempts included
+/// - `DYNAMODB_OPERATION_ATTEMPT_TIMEOUT_MS`: timeout of each attempt
+/// - `DYNAMODB_CONNECT_TIMEOUT_MS`: timeout to open a connection (3.1 seconds in the SDK)
+struct ClientSettings {
+    max_attempts: u32,
+    retry_config: RetryConfig,
+    timeout_config: TimeoutConfig,
+}
 
-        let metadata_table_name = env::var("DYNAMODB_METADATA_TABLE_NAME")
-            .unwrap_or_else(|_| "findex_cloud_metadata".to_string());
-        let entries_table_name = env::var("DYNAMODB_ENTRIES_TABLE_NAME")
-            .unwrap_or_else(|_| "findex_cloud_entries".to_string());
-        let chains_table_name = env::var("DYNAMODB_CHAINS_TABLE_NAME")
-            .unwrap_or_else(|_| "findex_cloud_chains".to_string());
+impl ClientSettings {
+    fn from_env() -> Self {
+        let milliseconds = |key: &str| -> Option<Duration> {
+            env::var(key).ok().map(|value| match value.parse() {
+                Ok(milliseconds) if milliseconds > 0 => Duration::from_millis(milliseconds),
+                _ => panic!("`{key}` env variable must be a positive number of milliseconds (found `{value}`)"),
+            })
+        };
+
+        let max_attempts = match env::var("DYNAMODB_MAX_ATTEMPTS") {
+            Ok(value) => match value.parse() {
+                Ok(max_attempts) if max_attempts > 0 => max_attempts,
+                _ => panic!(
+                    "`DYNAMODB_MAX_ATTEMPTS` env variable must be a positive integer (found `{value}`)"
+                ),
+            },
+            Err(_) => DEFAULT_DYNAMODB_MAX_ATTEMPTS,
+        };
+
+        let mut retry_config = RetryConfigBuilder::new().max_attempts(max_attempts);
+        if let Some(initial_backoff) = milliseconds("DYNAMODB_INITIAL_BACKOFF_MS") {
+            retry_config = retry_config.initial_backoff(initial_backoff);
+        }
+
+        let timeout_config = TimeoutConfig::builder()
+            .set_operation_timeout(milliseconds("DYNAMODB_OPERATION_TIMEOUT_MS"))
+            .set_operation_attempt_timeout(milliseconds("DYNAMODB_OPERATION_ATTEMPT_TIMEOUT_MS"))
+            .set_connect_timeout(milliseconds("DYNAMODB_CONNECT_TIMEOUT_MS"))
+            .build();
+
+        ClientSettings {
+            max_attempts,
+            retry_config: retry_config.build(),
+            timeout_config,
+        }
+    }
+}
+
+trait CreateTableExt {
+    fn apply_settings(self, settings: &TableSettings) -> Self;
+    fn apply_metadata_indexes(self, settings: &TableSettings) -> Self;
+}
+
+impl CreateTableExt for CreateTableFluentBuilder {
+    fn apply_settings(self, settings: &TableSettings) -> Self {
+        self.billing_mode(settings.billing_mode.clone())
+            .set_provisioned_throughput(settings.provisioned_throughput.clone())
+            .set_table_class(settings.table_class.clone())
+            .set_sse_specification(settings.sse_specification.clone())
+    }
+
+    fn apply_metadata_indexes(self, settings: &TableSettings) -> Self {
+        let Some(index_name) = &settings.metadata_name_index else {
+            return self;
+        };
+
+        self.attribute_definitions(
+            AttributeDefinition::builder()
+                .attribute_name("name")
+                .attribute_type(ScalarAttributeType::S)
+                .build(),
+        )
+        .global_secondary_indexes(
+            GlobalSecondaryIndex::builder()
+                .index_name(index_name)
+                .key_schema(
+                    KeySchemaElement::builder()
+                        .attribute_name("name")
+                        .key_type(KeyType::Hash)
+                        .build(),
+                )
+                .projection(
+                    Projection::builder()
+                        .projection_type(ProjectionType::KeysOnly)
+                        .build(),
+                )
+                .set_provisioned_throughput(settings.provisioned_throughput.clone())
+                .build(),
+        )
+    }
+}
+
+impl Database {
+    pub async fn create() -> Self {
+        let mut database = Self::connect(env::var("AWS_DYNAMODB_ENDPOINT_URL").ok(), None).await;
+        let Database {
+            client,
+            metadata_table_name,
+            entries_table_name,
+            chains_table_name,
+            usage_table_name,
+            ..
+        } = &database;
 
-        // Here we'll try to create the 3 DynamoDB tables.
-        // Note that we create all 3 tables even if the DynamoDB
+        // Here we'll try to create the 4 DynamoDB tables.
+        // Note that we create all 4 tables even if the DynamoDB
         // driver is only use for metadata only or indexes only
         // We may add in the futur an option to disable the table
         // creation.
-        // If we need different billing/networking configuration
-        // for our tables we can create them before starting Findex
-        // Cloud.
+        // The billing mode, table class and encryption can be configured
+        // with env variables (see `TableSettings`), for other settings
+        // create the tables before starting Findex Cloud.
+        let settings = TableSettings::from_env();
 
         try_create_table(
             client
                 .create_table()
-                .table_name(&metadata_table_name)
+                .table_name(metadata_table_name)
                 .attribute_definitions(
                     AttributeDefinition::builder()
                         .attribute_name("id")
@@ -116,7 +328,8 @@ impl Database {
                         .key_type(KeyType::Hash)
                         .build(),
                 )
-                .billing_mode(BillingMode::PayPerRequest)
+                .apply_settings(&settings)
+                .apply_metadata_indexes(&settings)
                 .send()
                 .await,
         )
@@ -127,7 +340,7 @@ impl Database {
         try_create_table(
             client
                 .create_table()
-                .table_name(&entries_table_name)
+                .table_name(entries_table_name)
                 .attribute_definitions(
                     AttributeDefinition::builder()
                         .attribute_name(ENTRIES_AND_CHAINS_ID_COLUMN_NAME)
@@ -140,7 +353,7 @@ impl Database {
                         .key_type(KeyType::Hash)
                         .build(),
                 )
-                .billing_mode(BillingMode::PayPerRequest)
+                .apply_settings(&settings)
                 .send()
                 .await,
         )
@@ -150,7 +363,7 @@ impl Database {
         try_create_table(
             client
                 .create_table()
-                .table_name(&chains_table_name)
+                .table_name(chains_table_name)
                 .attribute_definitions(
                     AttributeDefinition::builder()
                         .attribute_name(ENTRIES_AND_CHAINS_ID_COLUMN_NAME)
@@ -163,7 +376,7 @@ impl Database {
                         .key_type(KeyType::Hash)
                         .build(),
                 )
-                .billing_mode(BillingMode::PayPerRequest)
+                .apply_settings(&settings)
                 .send()
                 .await,
         )
@@ -171,11 +384,127 @@ impl Database {
             panic!("Fail to create table {chains_table_name} in DynamoDB ({err})")
         });
 
+        try_create_table(
+            client
+                .create_table()
+                .table_name(usage_table_name)
+                .attribute_definitions(
+                    AttributeDefinition::builder()
+                        .attribute_name(USAGE_INDEX_ID_COLUMN_NAME)
+                        .attribute_type(ScalarAttributeType::S)
+                        .build(),
+                )
+                .attribute_definitions(
+                    AttributeDefinition::builder()
+                        .attribute_name(USAGE_DAY_ENDPOINT_COLUMN_NAME)
+                        .attribute_type(ScalarAttributeType::S)
+                        .build(),
+                )
+                .key_schema(
+                    KeySchemaElement::builder()
+                        .attribute_name(USAGE_INDEX_ID_COLUMN_NAME)
+                        .key_type(KeyType::Hash)
+                        .build(),
+                )
+                .key_schema(
+                    KeySchemaElement::builder()
+                        .attribute_name(USAGE_DAY_ENDPOINT_COLUMN_NAME)
+                        .key_type(KeyType::Range)
+                        .build(),
+                )
+                .apply_settings(&settings)
+                .send()
+                .await,
+        )
+        .unwrap_or_else(|err| {
+            panic!("Fail to create table {usage_table_name} in DynamoDB ({err})")
+        });
+
+        for table_name in [entries_table_name, chains_table_name, usage_table_name] {
+            enable_ttl(client, table_name).await.unwrap_or_else(|err| {
+                panic!("Fail to enable TTL on table {table_name} in DynamoDB ({err:?})")
+            });
+        }
+
+        if let Ok(region) = env::var("AWS_DYNAMODB_ENTRIES_WRITE_REGION") {
+            database.entries_writes_client = Some(Self::client(None, Some(region)).await);
+        }
+
+        database
+    }
+
+    /// Connect to a read replica of the tables (for example a replica of a global table
+    /// inside the nearest region). The tables are not created, they should be
+    /// replicated from the primary.
+    pub async fn create_read_replica() -> Self {
+        let url = env::var("AWS_DYNAMODB_READ_REPLICA_ENDPOINT_URL").ok();
+        let region = env::var("AWS_DYNAMODB_READ_REPLICA_REGION").ok();
+        if url.is_none() && region.is_none() {
+            panic!("`AWS_DYNAMODB_READ_REPLICA_ENDPOINT_URL` or `AWS_DYNAMODB_READ_REPLICA_REGION` env variable is required to use a DynamoDB read replica");
+        }
+
+        Self::connect(url, region).await
+    }
+
+    async fn client(endpoint_url: Option<String>, region: Option<String>) -> Client {
+        let settings = ClientSettings::from_env();
+        let mut config_builder = aws_config::from_env()
+            .retry_config(settings.retry_config)
+            .timeout_config(settings.timeout_config);
+
+        // `aws_config::from_env()` uses the default credentials chain: env variables,
+        // profile files (`AWS_PROFILE`), web identity tokens (IRSA on EKS), ECS task roles
+        // and EC2 instance profiles.
+        config_builder = match credentials_from_files() {
+            Some(credentials) => config_builder.credentials_provider(credentials),
+            None => match env::var("AWS_CREDENTIALS_PROVIDER").as_deref() {
+                Err(_) | Ok("default") => config_builder,
+                Ok("environment") => config_builder
+                    .credentials_provider(EnvironmentVariableCredentialsProvider::new()),
+                Ok(other) => panic!(
+                    "Unknown `AWS_CREDENTIALS_PROVIDER` {other:?} (expected `default` or `environment`)"
+                ),
+            },
+        };
+
+        if let Some(url) = endpoint_url {
+            config_builder = config_builder.endpoint_url(url)
+        }
+
+        if let Some(region) = region {
+            config_builder = config_builder.region(Region::new(region));
+        }
+
+        let config = config_builder.load().await;
+        aws_sdk_dynamodb::Client::new(&config)
+    }
+
+    async fn connect(endpoint_url: Option<String>, region: Option<String>) -> Self {
+        let client = Self::client(endpoint_url, region).await;
+
+        let metadata_table_name = env::var("DYNAMODB_METADATA_TABLE_NAME")
+            .unwrap_or_else(|_| "findex_cloud_metadata".to_string());
+        let entries_table_name = env::var("DYNAMODB_ENTRIES_TABLE_NAME")
+            .unwrap_or_else(|_| "findex_cloud_entries".to_string());
+        let chains_table_name = env::var("DYNAMODB_CHAINS_TABLE_NAME")
+            .unwrap_or_else(|_| "findex_cloud_chains".to_string());
+        let usage_table_name = env::var("DYNAMODB_USAGE_TABLE_NAME")
+            .unwrap_or_else(|_| "findex_cloud_usage".to_string());
+
+        let consistent_entries_reads = matches!(
+            env::var("DYNAMODB_CONSISTENT_READS").as_deref(),
+            Ok("true") | Ok("1")
+        );
+
         Database {
             client,
             metadata_table_name,
             entries_table_name,
             chains_table_name,
+            usage_table_name,
+            consistent_entries_reads,
+            entries_writes_client: None,
+            max_attempts: ClientSettings::from_env().max_attempts,
         }
     }
 
@@ -186,12 +515,71 @@ impl Database {
         }
     }
 
-    /// Fail if the uid doesn't exist
+    fn is_consistent_read(&self, table: Table) -> bool {
+        matches!(table, Table::Entries) && self.consistent_entries_reads
+    }
+
+    async fn batch_get(
+        &self,
+        index: &Index,
+        table: Table,
+        uids: HashSet<Uid<UID_LENGTH>>,
+        consistent_read: bool,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        let mut uids_and_values = EncryptedTable::<UID_LENGTH>::with_capacity(uids.len());
+        if uids.is_empty() {
+            return Ok(uids_and_values);
+        }
+
+        let uids: Vec<_> = uids.into_iter().collect();
+
+        for chunk in uids.chunks(DYNAMODB_MAX_READ_ELEMENTS) {
+            let mut keys_and_attributes =
+                KeysAndAttributes::builder().consistent_read(consistent_read);
+
+            for uid in chunk {
+                keys_and_attributes = keys_and_attributes.keys(HashMap::from([(
+                    ENTRIES_AND_CHAINS_ID_COLUMN_NAME.to_string(),
+                    get_uid_attribute_value(index, uid),
+                )]));
+            }
+            let batch_get_item = self
+                .client
+                .batch_get_item()
+                .request_items(self.get_table_name(table), keys_and_attributes.build());
+
+            let results = batch_get_item.send().await?;
+
+            if let Some(responses) = results.responses() {
+                if let Some(items) = responses.get(self.get_table_name(table)) {
+                    for item in items {
+                        let id = extract_bytes(item, ENTRIES_AND_CHAINS_ID_COLUMN_NAME)?;
+                        let uid = extract_uid_from_stored_id(id)?;
+
+                        uids_and_values.insert(
+                            uid,
+                            extract_bytes(item, ENTRIES_AND_CHAINS_VALUE_COLUMN_NAME)?,
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(uids_and_values)
+    }
+
+    fn entries_writes_client(&self) -> &Client {
+        self.entries_writes_client.as_ref().unwrap_or(&self.client)
+    }
+
+    /// Fail if the uid doesn't exist. Read inside the region of the conditional writes
+    /// (see `entries_writes_client`).
     async fn fetch_value(&self, index: &Index, table: Table, uid: &[u8]) -> Result<Vec<u8>, Error> {
         let result = self
-            .client
+            .entries_writes_client()
             .get_item()
             .table_name(self.get_table_name(table))
+            .consistent_read(self.is_consistent_read(table))
             .key(
                 ENTRIES_AND_CHAINS_ID_COLUMN_NAME,
                 get_uid_attribute_value(index, uid),
@@ -211,6 +599,21 @@ impl Database {
         extract_bytes(item, ENTRIES_AND_CHAINS_VALUE_COLUMN_NAME)
     }
 
+    /// Stored value of an entry after a failed conditional write: returned with the error
+    /// (`ReturnValuesOnConditionCheckFailure`), or fetched when the item is missing from it
+    /// (DynamoDB-compatible servers ignoring the option).
+    async fn rejected_value(
+        &self,
+        index: &Index,
+        uid: &Uid<UID_LENGTH>,
+        item: Option<&HashMap<String, AttributeValue>>,
+    ) -> Result<Vec<u8>, Error> {
+        match item {
+            Some(item) => extract_bytes(item, ENTRIES_AND_CHAINS_VALUE_COLUMN_NAME),
+            None => self.fetch_value(index, Table::Entries, uid).await,
+        }
+    }
+
     async fn upsert_entry(
         &self,
         index: &Index,
@@ -226,18 +629,28 @@ impl Database {
             // I don't know if `update_item()` fail with a specific error code if the key doesn't
             // exists (it should fail since it's a `update_item()` and not a `put_item()`).
 
+            let mut update_expression =
+                format!("SET {} = :new", ENTRIES_AND_CHAINS_VALUE_COLUMN_NAME);
+            if index.ttl_seconds.is_some() {
+                update_expression.push_str(&format!(
+                    ", {} = :expires_at",
+                    ENTRIES_AND_CHAINS_EXPIRES_AT_COLUMN_NAME
+                ));
+            }
+
             let result = self
-                .client
+                .entries_writes_client()
                 .update_item()
                 .table_name(self.get_table_name(Table::Entries))
                 .key(
                     ENTRIES_AND_CHAINS_ID_COLUMN_NAME,
                     get_uid_attribute_value(index, &uid),
                 )
-                .update_expression(format!(
-                    "SET {} = :new",
-                    ENTRIES_AND_CHAINS_VALUE_COLUMN_NAME
-                ))
+                .update_expression(update_expression)
+                .set_expression_attribute_values(
+                    expires_at(index)
+                        .map(|expires_at| HashMap::from([(":expires_at".to_string(), expires_at)])),
+                )
                 .expression_attribute_values(
                     ":old",
                     AttributeValue::B(Blob::new(old_value.clone())),
@@ -247,23 +660,23 @@ impl Database {
                     AttributeValue::B(Blob::new(new_value.clone())),
                 )
                 .condition_expression(format!("{} = :old", ENTRIES_AND_CHAINS_VALUE_COLUMN_NAME))
+                .return_values_on_condition_check_failure(
+                    ReturnValuesOnConditionCheckFailure::AllOld,
+                )
                 .send()
                 .await;
 
-            // If the conditional expression fails, we need to fetch
-            // the stored value (it's impossible to return the value from an error
-            // in DynamoDB) for Findex to retry with the correct `old_value`
+            // If the conditional expression fails, the stored value comes back with the
+            // error for Findex to retry with the correct `old_value`
             match result {
                 Ok(_) => Ok(None),
-                Err(SdkError::ServiceError(err))
-                    if matches!(
-                        err.err(),
-                        UpdateItemError::ConditionalCheckFailedException { .. }
-                    ) =>
-                {
-                    let value = self.fetch_value(index, Table::Entries, &uid).await?;
-                    Ok(Some((uid, value)))
-                }
+                Err(SdkError::ServiceError(err)) => match err.err() {
+                    UpdateItemError::ConditionalCheckFailedException(exception) => {
+                        let value = self.rejected_value(index, &uid, exception.item()).await?;
+                        Ok(Some((uid, value)))
+                    }
+                    _ => Err(Error::from(SdkError::ServiceError(err))),
+                },
                 Err(err) => Err(Error::from(err)),
             }
         } else {
@@ -272,43 +685,94 @@ impl Database {
             // that the key doesn't already exist.
 
             let result = self
-                .client
+                .entries_writes_client()
                 .put_item()
                 .table_name(self.get_table_name(Table::Entries))
-                .item(
-                    ENTRIES_AND_CHAINS_ID_COLUMN_NAME,
-                    get_uid_attribute_value(index, &uid),
-                )
-                .item(
-                    ENTRIES_AND_CHAINS_VALUE_COLUMN_NAME,
-                    AttributeValue::B(Blob::new(new_value.clone())),
-                )
+                .set_item(Some(value_to_item(index, &uid, new_value.clone())))
                 .condition_expression(format!(
                     "attribute_not_exists({})",
                     ENTRIES_AND_CHAINS_ID_COLUMN_NAME
                 ))
+                .return_values_on_condition_check_failure(
+                    ReturnValuesOnConditionCheckFailure::AllOld,
+                )
                 .send()
                 .await;
 
-            // If the conditional expression fails, we need to fetch
-            // the stored value (it's impossible to return the value from an error
-            // in DynamoDB) for Findex to retry with the correct `old_value`
+            // If the conditional expression fails, the stored value comes back with the
+            // error for Findex to retry with the correct `old_value`
             match result {
                 Ok(_) => Ok(None),
-                Err(SdkError::ServiceError(err))
-                    if matches!(
-                        err.err(),
-                        PutItemError::ConditionalCheckFailedException { .. }
-                    ) =>
-                {
-                    let value = self.fetch_value(index, Table::Entries, &uid).await?;
-
-                    Ok(Some((uid, value)))
-                }
+                Err(SdkError::ServiceError(err)) => match err.err() {
+                    PutItemError::ConditionalCheckFailedException(exception) => {
+                        let value = self.rejected_value(index, &uid, exception.item()).await?;
+                        Ok(Some((uid, value)))
+                    }
+                    _ => Err(Error::from(SdkError::ServiceError(err))),
+                },
                 Err(err) => Err(Error::from(err)),
             }
         }
     }
+
+    /// Delete at most `DYNAMODB_MAX_WRITE_ELEMENTS` items.
+    async fn delete_items(&self, table: Table, ids: &[AttributeValue]) -> Result<(), Error> {
+        let requests = ids
+            .iter()
+            .map(|id| {
+                WriteRequest::builder()
+                    .delete_request(
+                        DeleteRequest::builder()
+                            .key(ENTRIES_AND_CHAINS_ID_COLUMN_NAME, id.clone())
+                            .build(),
+                    )
+                    .build()
+            })
+            .collect();
+
+        self.batch_write(table, requests, "deleted").await
+    }
+
+    /// Send at most `DYNAMODB_MAX_WRITE_ELEMENTS` write requests, the unprocessed items
+    /// (throttling) are retried with a backoff. Fails if some items are still unprocessed
+    /// after `DYNAMODB_MAX_BATCH_WRITE_ATTEMPTS` attempts (`action` is the past participle of
+    /// the requests for the error).
+    async fn batch_write(
+        &self,
+        table: Table,
+        mut requests: Vec<WriteRequest>,
+        action: &str,
+    ) -> Result<(), Error> {
+        for attempt in 0..DYNAMODB_MAX_BATCH_WRITE_ATTEMPTS {
+            if attempt > 0 {
+                BATCH_WRITE_RETRIES.fetch_add(1, Ordering::Relaxed);
+                actix_web::rt::time::sleep(Duration::from_millis(100 << attempt)).await;
+            }
+
+            let response = self
+                .client
+                .batch_write_item()
+                .request_items(self.get_table_name(table), requests)
+                .send()
+                .await?;
+
+            requests = response
+                .unprocessed_items()
+                .and_then(|items| items.get(self.get_table_name(table)))
+                .cloned()
+                .unwrap_or_default();
+
+            if requests.is_empty() {
+                return Ok(());
+            }
+        }
+
+        Err(Error::DynamoDb(format!(
+            "{} items of table {} were not {action} after {DYNAMODB_MAX_BATCH_WRITE_ATTEMPTS} attempts",
+            requests.len(),
+            self.get_table_name(table)
+        )))
+    }
 }
 
 #[async_trait]
@@ -317,59 +781,41 @@ impl IndexesDatabase for Database {
         Ok(())
     }
 
+    async fn set_sizes(&self, _indexes: &mut Vec<Index>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn supports_ttl(&self) -> bool {
+        true
+    }
+
     async fn fetch(
         &self,
         index: &Index,
         table: Table,
         uids: HashSet<Uid<UID_LENGTH>>,
     ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
-        let mut uids_and_values = EncryptedTable::<UID_LENGTH>::with_capacity(uids.len());
-        if uids.is_empty() {
-            return Ok(uids_and_values);
-        }
-
-        let uids: Vec<_> = uids.into_iter().collect();
+        self.batch_get(index, table, uids, self.is_consistent_read(table))
+            .await
+    }
 
-        for chunk in uids.chunks(DYNAMODB_MAX_READ_ELEMENTS) {
-            let mut keys_and_attributes = KeysAndAttributes::builder();
+    async fn fetch_with_consistency(
+        &self,
+        index: &Index,
+        table: Table,
+        uids: HashSet<Uid<UID_LENGTH>>,
+        consistency: Consistency,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        self.batch_get(index, table, uids, consistency == Consistency::Strong)
+            .await
+    }
 
-            for uid in chunk {
-                keys_and_attributes = keys_and_attributes.keys(HashMap::from([(
-                    ENTRIES_AND_CHAINS_ID_COLUMN_NAME.to_string(),
-                    get_uid_attribute_value(index, uid),
-                )]));
-            }
-            let batch_get_item = self
-                .client
-                .batch_get_item()
-                .request_items(self.get_table_name(table), keys_and_attributes.build());
-
-            let results = batch_get_item.send().await?;
-
-            if let Some(responses) = results.responses() {
-                if let Some(items) = responses.get(self.get_table_name(table)) {
-                    for item in items {
-                        let id = extract_bytes(item, ENTRIES_AND_CHAINS_ID_COLUMN_NAME)?;
-                        let uid = extract_uid_from_stored_id(id)?;
-
-                        uids_and_values.insert(
-                            uid,
-                            extract_bytes(item, ENTRIES_AND_CHAINS_VALUE_COLUMN_NAME)?,
-                        );
-                    }
-                }
-            }
-        }
-
-        Ok(uids_and_values)
-    }
-
-    async fn upsert_entries(
-        &self,
-        index: &Index,
-        data: UpsertData<UID_LENGTH>,
-    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
-        let mut rejected = EncryptedTable::<UID_LENGTH>::with_capacity(1);
+    async fn upsert_entries(
+        &self,
+        index: &Index,
+        data: UpsertData<UID_LENGTH>,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        let mut rejected = EncryptedTable::<UID_LENGTH>::with_capacity(1);
 
         // This function is using a loop instead of a batch_* function
         // because DynamoDB doesn't support conditional expression on batches.
@@ -393,39 +839,126 @@ impl IndexesDatabase for Database {
         index: &Index,
         data: EncryptedTable<UID_LENGTH>,
     ) -> Result<(), Error> {
-        let data: Vec<_> = data.into_iter().collect();
+        self.put_values(index, Table::Chains, data).await
+    }
 
-        for chunk in data.chunks(DYNAMODB_MAX_WRITE_ELEMENTS) {
-            self.client
-                .batch_write_item()
-                .request_items(
-                    self.get_table_name(Table::Chains),
-                    chunk
-                        .iter()
-                        .map(|(uid, value)| {
-                            WriteRequest::builder()
-                                .put_request(
-                                    PutRequest::builder()
-                                        .item(
-                                            ENTRIES_AND_CHAINS_ID_COLUMN_NAME,
-                                            get_uid_attribute_value(index, uid),
-                                        )
-                                        .item(
-                                            ENTRIES_AND_CHAINS_VALUE_COLUMN_NAME,
-                                            AttributeValue::B(Blob::new(value.clone())),
-                                        )
-                                        .build(),
-                                )
-                                .build()
-                        })
-                        .collect(),
+    /// IDs are not split between index ID and UID (see the TODO at the top of the file)
+    /// so we need to scan the whole table with a filter.
+    async fn fetch_all(
+        &self,
+        index: &Index,
+        table: Table,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        let mut uids_and_values = EncryptedTable::<UID_LENGTH>::default();
+        let mut exclusive_start_key = None;
+
+        loop {
+            let results = self
+                .client
+                .scan()
+                .table_name(self.get_table_name(table))
+                .filter_expression("begins_with(#id, :index_id)")
+                .expression_attribute_names("#id", ENTRIES_AND_CHAINS_ID_COLUMN_NAME)
+                .expression_attribute_values(
+                    ":index_id",
+                    AttributeValue::B(Blob::new(index.id.as_bytes())),
                 )
+                .set_exclusive_start_key(exclusive_start_key)
                 .send()
                 .await?;
+
+            for item in results.items().unwrap_or_default() {
+                let id = extract_bytes(item, ENTRIES_AND_CHAINS_ID_COLUMN_NAME)?;
+                let uid = extract_uid_from_stored_id(id)?;
+
+                uids_and_values.insert(
+                    uid,
+                    extract_bytes(item, ENTRIES_AND_CHAINS_VALUE_COLUMN_NAME)?,
+                );
+            }
+
+            exclusive_start_key = results.last_evaluated_key().cloned();
+            if exclusive_start_key.is_none() {
+                break;
+            }
+        }
+
+        Ok(uids_and_values)
+    }
+
+    /// Same scan as `fetch_all` (only the IDs are read) and the items are deleted by batches.
+    async fn delete_index_data(&self, index_id: &str) -> Result<(), Error> {
+        for table in [Table::Entries, Table::Chains] {
+            let mut exclusive_start_key = None;
+
+            loop {
+                let results = self
+                    .client
+                    .scan()
+                    .table_name(self.get_table_name(table))
+                    .filter_expression("begins_with(#id, :index_id)")
+                    .projection_expression("#id")
+                    .expression_attribute_names("#id", ENTRIES_AND_CHAINS_ID_COLUMN_NAME)
+                    .expression_attribute_values(
+                        ":index_id",
+                        AttributeValue::B(Blob::new(index_id.as_bytes())),
+                    )
+                    .set_exclusive_start_key(exclusive_start_key)
+                    .send()
+                    .await?;
+
+                let ids: Vec<_> = results
+                    .items()
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|item| item.get(ENTRIES_AND_CHAINS_ID_COLUMN_NAME).cloned())
+                    .collect();
+
+                for chunk in ids.chunks(DYNAMODB_MAX_WRITE_ELEMENTS) {
+                    self.delete_items(table, chunk).await?;
+                }
+
+                exclusive_start_key = results.last_evaluated_key().cloned();
+                if exclusive_start_key.is_none() {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn put_values(
+        &self,
+        index: &Index,
+        table: Table,
+        data: EncryptedTable<UID_LENGTH>,
+    ) -> Result<(), Error> {
+        let data: Vec<_> = data.into_iter().collect();
+
+        for chunk in data.chunks(DYNAMODB_MAX_WRITE_ELEMENTS) {
+            let requests = chunk
+                .iter()
+                .map(|(uid, value)| {
+                    WriteRequest::builder()
+                        .put_request(
+                            PutRequest::builder()
+                                .set_item(Some(value_to_item(index, uid, value.clone())))
+                                .build(),
+                        )
+                        .build()
+                })
+                .collect();
+
+            self.batch_write(table, requests, "written").await?;
         }
 
         Ok(())
     }
+
+    async fn storage_gauges(&self) -> Result<Vec<StorageGauge>, Error> {
+        Ok(storage_gauges(self.max_attempts))
+    }
 }
 
 #[async_trait]
@@ -465,6 +998,7 @@ impl MetadataDatabase for Database {
     async fn delete_index(&self, id: &str) -> Result<(), Error> {
         self.client
             .delete_item()
+            .table_name(&self.metadata_table_name)
             .key("id", AttributeValue::S(id.to_string()))
             .send()
             .await?;
@@ -472,50 +1006,708 @@ impl MetadataDatabase for Database {
         Ok(())
     }
 
-    async fn create_index(&self, new_index: NewIndex) -> Result<Index, Error> {
-        let index = Index {
-            id: new_index.id,
-            name: new_index.name,
-            fetch_entries_key: new_index.fetch_entries_key,
-            fetch_chains_key: new_index.fetch_chains_key,
-            upsert_entries_key: new_index.upsert_entries_key,
-            insert_chains_key: new_index.insert_chains_key,
-            size: Some(0),
-            created_at: Utc::now().naive_utc(),
+    async fn set_archived_at(
+        &self,
+        id: &str,
+        archived_at: Option<NaiveDateTime>,
+    ) -> Result<(), Error> {
+        let update = self
+            .client
+            .update_item()
+            .table_name(&self.metadata_table_name)
+            .key("id", AttributeValue::S(id.to_string()));
+
+        let update = match archived_at {
+            Some(archived_at) => update
+                .update_expression("SET archived_at = :archived_at")
+                .expression_attribute_values(
+                    ":archived_at",
+                    AttributeValue::S(archived_at.to_string()),
+                ),
+            None => update.update_expression("REMOVE archived_at"),
+        };
+
+        update.send().await?;
+
+        Ok(())
+    }
+
+    async fn get_compaction_stats(&self, id: &str) -> Result<CompactionStats, Error> {
+        let item = self
+            .client
+            .get_item()
+            .table_name(&self.metadata_table_name)
+            .key("id", AttributeValue::S(id.to_string()))
+            .projection_expression("last_compaction_at, writes_since_compaction")
+            .send()
+            .await?;
+
+        let Some(item) = item.item() else {
+            return Ok(CompactionStats::default());
         };
 
-        // This will override the previous index if the `id` is not unique
-        // :UniqueId
+        Ok(CompactionStats {
+            last_compaction_at: match item.get("last_compaction_at") {
+                Some(_) => Some(parse_date(
+                    &extract_string(item, "last_compaction_at")?,
+                    "last_compaction_at",
+                )?),
+                None => None,
+            },
+            writes_since_compaction: match item.get("writes_since_compaction") {
+                Some(_) => extract_number(item, "writes_since_compaction")?,
+                None => 0,
+            },
+        })
+    }
+
+    async fn add_writes_since_compaction(&self, id: &str, writes: u64) -> Result<u64, Error> {
+        let output = self
+            .client
+            .update_item()
+            .table_name(&self.metadata_table_name)
+            .key("id", AttributeValue::S(id.to_string()))
+            // Do not create an item for a deleted index
+            .condition_expression("attribute_exists(id)")
+            .update_expression("ADD writes_since_compaction :writes")
+            .expression_attribute_values(":writes", AttributeValue::N(writes.to_string()))
+            .return_values(ReturnValue::UpdatedNew)
+            .send()
+            .await?;
+
+        let attributes = output.attributes().ok_or_else(|| {
+            Error::DynamoDb("No attributes returned after the update".to_string())
+        })?;
+
+        extract_number(attributes, "writes_since_compaction")
+    }
+
+    async fn set_compacted(&self, id: &str, compacted_at: NaiveDateTime) -> Result<(), Error> {
         self.client
-            .put_item()
+            .update_item()
+            .table_name(&self.metadata_table_name)
+            .key("id", AttributeValue::S(id.to_string()))
+            .update_expression(
+                "SET last_compaction_at = :compacted_at, writes_since_compaction = :zero",
+            )
+            .expression_attribute_values(
+                ":compacted_at",
+                AttributeValue::S(compacted_at.to_string()),
+            )
+            .expression_attribute_values(":zero", AttributeValue::N("0".to_string()))
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    /// The expiration is saved as a timestamp to be compared inside the condition.
+    async fn acquire_compaction_lock(
+        &self,
+        id: &str,
+        token: &str,
+        expires_at: NaiveDateTime,
+        now: NaiveDateTime,
+    ) -> Result<bool, Error> {
+        let result = self
+            .client
+            .update_item()
+            .table_name(&self.metadata_table_name)
+            .key("id", AttributeValue::S(id.to_string()))
+            .condition_expression(
+                "attribute_exists(id) AND (attribute_not_exists(compaction_lock_token) OR compaction_lock_token = :token OR compaction_lock_expires_at < :now)",
+            )
+            .update_expression(
+                "SET compaction_lock_token = :token, compaction_lock_expires_at = :expires_at",
+            )
+            .expression_attribute_values(":token", AttributeValue::S(token.to_string()))
+            .expression_attribute_values(
+                ":expires_at",
+                AttributeValue::N(expires_at.timestamp().to_string()),
+            )
+            .expression_attribute_values(":now", AttributeValue::N(now.timestamp().to_string()))
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError(service_error))
+                if service_error.err().is_conditional_check_failed_exception() =>
+            {
+                Ok(false)
+            }
+            Err(err) => Err(Error::from(err)),
+        }
+    }
+
+    async fn release_compaction_lock(&self, id: &str, token: &str) -> Result<bool, Error> {
+        let result = self
+            .client
+            .update_item()
+            .table_name(&self.metadata_table_name)
+            .key("id", AttributeValue::S(id.to_string()))
+            .condition_expression("compaction_lock_token = :token")
+            .update_expression(
+                "REMOVE compaction_lock_token, compaction_lock_expires_at, compaction_progress",
+            )
+            .expression_attribute_values(":token", AttributeValue::S(token.to_string()))
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError(service_error))
+                if service_error.err().is_conditional_check_failed_exception() =>
+            {
+                Ok(false)
+            }
+            Err(err) => Err(Error::from(err)),
+        }
+    }
+
+    async fn get_compaction_lock(&self, id: &str) -> Result<Option<CompactionLockState>, Error> {
+        let item = self
+            .client
+            .get_item()
+            .table_name(&self.metadata_table_name)
+            .key("id", AttributeValue::S(id.to_string()))
+            .projection_expression(
+                "compaction_lock_token, compaction_lock_expires_at, compaction_progress",
+            )
+            .send()
+            .await?;
+
+        let Some(item) = item.item() else {
+            return Ok(None);
+        };
+        if item.get("compaction_lock_token").is_none() {
+            return Ok(None);
+        }
+
+        let expires_at = extract_number(item, "compaction_lock_expires_at")?;
+        Ok(Some(CompactionLockState {
+            token: extract_string(item, "compaction_lock_token")?,
+            expires_at: NaiveDateTime::from_timestamp_opt(expires_at as i64, 0).ok_or_else(
+                || Error::DynamoDb(format!("Invalid compaction lock expiration {expires_at}")),
+            )?,
+            progress: match item.get("compaction_progress") {
+                Some(_) => Some(serde_json::from_str(&extract_string(
+                    item,
+                    "compaction_progress",
+                )?)?),
+                None => None,
+            },
+        }))
+    }
+
+    async fn set_compaction_progress(
+        &self,
+        id: &str,
+        token: &str,
+        progress: Option<&CompactionProgress>,
+    ) -> Result<bool, Error> {
+        let update = self
+            .client
+            .update_item()
+            .table_name(&self.metadata_table_name)
+            .key("id", AttributeValue::S(id.to_string()))
+            .condition_expression("compaction_lock_token = :token")
+            .expression_attribute_values(":token", AttributeValue::S(token.to_string()));
+        let update = match progress {
+            Some(progress) => update
+                .update_expression("SET compaction_progress = :progress")
+                .expression_attribute_values(
+                    ":progress",
+                    AttributeValue::S(serde_json::to_string(progress)?),
+                ),
+            None => update.update_expression("REMOVE compaction_progress"),
+        };
+
+        match update.send().await {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError(service_error))
+                if service_error.err().is_conditional_check_failed_exception() =>
+            {
+                Ok(false)
+            }
+            Err(err) => Err(Error::from(err)),
+        }
+    }
+
+    async fn set_last_activity_at(
+        &self,
+        id: &str,
+        last_activity_at: NaiveDateTime,
+    ) -> Result<(), Error> {
+        let result = self
+            .client
+            .update_item()
+            .table_name(&self.metadata_table_name)
+            .key("id", AttributeValue::S(id.to_string()))
+            // Do not create an item for a deleted index
+            .condition_expression("attribute_exists(id)")
+            .update_expression("SET last_activity_at = :last_activity_at REMOVE stale_at")
+            .expression_attribute_values(
+                ":last_activity_at",
+                AttributeValue::S(last_activity_at.to_string()),
+            )
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(SdkError::ServiceError(service_error))
+                if service_error.err().is_conditional_check_failed_exception() =>
+            {
+                Ok(())
+            }
+            Err(err) => Err(Error::from(err)),
+        }
+    }
+
+    async fn set_stale_at(&self, id: &str, stale_at: NaiveDateTime) -> Result<(), Error> {
+        self.client
+            .update_item()
+            .table_name(&self.metadata_table_name)
+            .key("id", AttributeValue::S(id.to_string()))
+            .condition_expression("attribute_exists(id)")
+            .update_expression("SET stale_at = :stale_at")
+            .expression_attribute_values(":stale_at", AttributeValue::S(stale_at.to_string()))
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn add_usage(
+        &self,
+        id: &str,
+        day: NaiveDate,
+        endpoint: &str,
+        counters: &UsageCounters,
+    ) -> Result<(), Error> {
+        let expires_at = day
+            .and_hms_opt(0, 0, 0)
+            .map(|midnight| midnight.timestamp())
+            .unwrap_or_default()
+            + USAGE_RETENTION_DAYS * 24 * 60 * 60;
+
+        self.client
+            .update_item()
+            .table_name(&self.usage_table_name)
+            .key(USAGE_INDEX_ID_COLUMN_NAME, AttributeValue::S(id.to_string()))
+            .key(
+                USAGE_DAY_ENDPOINT_COLUMN_NAME,
+                AttributeValue::S(format!("{day}#{endpoint}")),
+            )
+            .update_expression(
+                "ADD requests :requests, errors :errors, bytes_received :bytes_received, bytes_sent :bytes_sent SET expires_at = :expires_at",
+            )
+            .expression_attribute_values(
+                ":requests",
+                AttributeValue::N(counters.requests.to_string()),
+            )
+            .expression_attribute_values(":errors", AttributeValue::N(counters.errors.to_string()))
+            .expression_attribute_values(
+                ":bytes_received",
+                AttributeValue::N(counters.bytes_received.to_string()),
+            )
+            .expression_attribute_values(
+                ":bytes_sent",
+                AttributeValue::N(counters.bytes_sent.to_string()),
+            )
+            .expression_attribute_values(":expires_at", AttributeValue::N(expires_at.to_string()))
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_usage(&self, id: &str, since: NaiveDate) -> Result<Vec<DailyUsage>, Error> {
+        let mut usage = vec![];
+        let mut exclusive_start_key = None;
+
+        loop {
+            let results = self
+                .client
+                .query()
+                .table_name(&self.usage_table_name)
+                .key_condition_expression("id = :id AND day_endpoint >= :since")
+                .expression_attribute_values(":id", AttributeValue::S(id.to_string()))
+                .expression_attribute_values(":since", AttributeValue::S(since.to_string()))
+                .set_exclusive_start_key(exclusive_start_key)
+                .send()
+                .await?;
+
+            for item in results.items().unwrap_or_default() {
+                let day_endpoint = extract_string(item, USAGE_DAY_ENDPOINT_COLUMN_NAME)?;
+                let (day, endpoint) = day_endpoint
+                    .split_once('#')
+                    .ok_or_else(|| Error::DynamoDb(format!("Wrong usage key '{day_endpoint}'")))?;
+                let counter = |key| match item.get(key) {
+                    Some(_) => extract_number(item, key),
+                    None => Ok(0),
+                };
+
+                usage.push(DailyUsage {
+                    day: NaiveDate::parse_from_str(day, "%Y-%m-%d").map_err(|_| {
+                        Error::DynamoDb(format!("Cannot parse day '{day}' inside usage key."))
+                    })?,
+                    endpoint: endpoint.to_string(),
+                    counters: UsageCounters {
+                        requests: counter("requests")?,
+                        errors: counter("errors")?,
+                        bytes_received: counter("bytes_received")?,
+                        bytes_sent: counter("bytes_sent")?,
+                    },
+                });
+            }
+
+            exclusive_start_key = results.last_evaluated_key().cloned();
+            if exclusive_start_key.is_none() {
+                break;
+            }
+        }
+
+        Ok(usage)
+    }
+
+    /// The old counters expire with the DynamoDB TTL.
+    async fn delete_usage_before(&self, _day: NaiveDate) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn add_request_counters(
+        &self,
+        id: &str,
+        increments: &IndexCounters,
+    ) -> Result<(), Error> {
+        let result = self
+            .client
+            .update_item()
             .table_name(&self.metadata_table_name)
-            .item("id", AttributeValue::S(index.id.clone()))
-            .item("name", AttributeValue::S(index.name.clone()))
-            .item(
-                "fetch_entries_key",
-                AttributeValue::B(Blob::new(index.fetch_entries_key.clone())),
+            .key("id", AttributeValue::S(id.to_string()))
+            // Do not create an item for a deleted index
+            .condition_expression("attribute_exists(id)")
+            .update_expression(
+                "ADD fetches :fetches, upserts :upserts, chain_inserts :chain_inserts, rejected_signatures :rejected_signatures",
+            )
+            .expression_attribute_values(
+                ":fetches",
+                AttributeValue::N(increments.fetches.to_string()),
             )
-            .item(
-                "fetch_chains_key",
-                AttributeValue::B(Blob::new(index.fetch_chains_key.clone())),
+            .expression_attribute_values(
+                ":upserts",
+                AttributeValue::N(increments.upserts.to_string()),
             )
-            .item(
-                "upsert_entries_key",
-                AttributeValue::B(Blob::new(index.upsert_entries_key.clone())),
+            .expression_attribute_values(
+                ":chain_inserts",
+                AttributeValue::N(increments.chain_inserts.to_string()),
             )
-            .item(
-                "insert_chains_key",
-                AttributeValue::B(Blob::new(index.insert_chains_key.clone())),
+            .expression_attribute_values(
+                ":rejected_signatures",
+                AttributeValue::N(increments.rejected_signatures.to_string()),
+            )
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(SdkError::ServiceError(service_error))
+                if service_error.err().is_conditional_check_failed_exception() =>
+            {
+                Ok(())
+            }
+            Err(err) => Err(Error::from(err)),
+        }
+    }
+
+    async fn get_settings(&self, id: &str) -> Result<Option<IndexSettings>, Error> {
+        let item = self
+            .client
+            .get_item()
+            .table_name(&self.metadata_table_name)
+            .key("id", AttributeValue::S(id.to_string()))
+            .projection_expression("settings")
+            .send()
+            .await?;
+
+        match item.item() {
+            Some(item) if item.contains_key("settings") => Ok(Some(serde_json::from_str(
+                &extract_string(item, "settings")?,
+            )?)),
+            _ => Ok(None),
+        }
+    }
+
+    async fn set_settings(&self, id: &str, settings: &IndexSettings) -> Result<(), Error> {
+        // The condition doesn't create an incomplete item for a deleted index.
+        let result = self
+            .client
+            .update_item()
+            .table_name(&self.metadata_table_name)
+            .key("id", AttributeValue::S(id.to_string()))
+            .update_expression(
+                "SET settings = :settings, settings_version = if_not_exists(settings_version, :one) + :one",
             )
-            .item(
-                "created_at",
-                AttributeValue::S(index.created_at.to_string()),
+            .condition_expression("attribute_exists(id)")
+            .expression_attribute_values(
+                ":settings",
+                AttributeValue::S(serde_json::to_string(settings)?),
             )
+            .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(SdkError::ServiceError(service_error))
+                if service_error.err().is_conditional_check_failed_exception() =>
+            {
+                Err(Error::BadRequest(format!("Unknown index for ID {id}")))
+            }
+            Err(err) => Err(Error::from(err)),
+        }
+    }
+
+    /// The settings saved before the versioning have the version 1.
+    async fn get_versioned_settings(
+        &self,
+        id: &str,
+    ) -> Result<Option<(IndexSettings, u64)>, Error> {
+        let item = self
+            .client
+            .get_item()
+            .table_name(&self.metadata_table_name)
+            .key("id", AttributeValue::S(id.to_string()))
+            .projection_expression("settings, settings_version")
             .send()
             .await?;
 
+        match item.item() {
+            Some(item) if item.contains_key("settings") => Ok(Some((
+                serde_json::from_str(&extract_string(item, "settings")?)?,
+                match item.get("settings_version") {
+                    Some(_) => extract_number(item, "settings_version")?,
+                    None => 1,
+                },
+            ))),
+            _ => Ok(None),
+        }
+    }
+
+    async fn replace_settings(
+        &self,
+        id: &str,
+        settings: &IndexSettings,
+        version: u64,
+    ) -> Result<Option<u64>, Error> {
+        let condition = match version {
+            0 => "attribute_exists(id) AND attribute_not_exists(settings)",
+            1 => "settings_version = :version OR (attribute_exists(settings) AND attribute_not_exists(settings_version))",
+            _ => "settings_version = :version",
+        };
+        let new_version = version + 1;
+
+        let mut update = self
+            .client
+            .update_item()
+            .table_name(&self.metadata_table_name)
+            .key("id", AttributeValue::S(id.to_string()))
+            .update_expression("SET settings = :settings, settings_version = :new_version")
+            .condition_expression(condition)
+            .expression_attribute_values(
+                ":settings",
+                AttributeValue::S(serde_json::to_string(settings)?),
+            )
+            .expression_attribute_values(
+                ":new_version",
+                AttributeValue::N(new_version.to_string()),
+            );
+        if version > 0 {
+            update = update
+                .expression_attribute_values(":version", AttributeValue::N(version.to_string()));
+        }
+
+        match update.send().await {
+            Ok(_) => Ok(Some(new_version)),
+            Err(SdkError::ServiceError(service_error))
+                if service_error.err().is_conditional_check_failed_exception() =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(Error::from(err)),
+        }
+    }
+
+    async fn storage_gauges(&self) -> Result<Vec<StorageGauge>, Error> {
+        Ok(storage_gauges(self.max_attempts))
+    }
+
+    async fn create_index(&self, new_index: NewIndex) -> Result<Index, Error> {
+        let index = new_index_to_index(new_index.validate()?);
+
+        self.client
+            .put_item()
+            .table_name(&self.metadata_table_name)
+            .condition_expression("attribute_not_exists(id)")
+            .set_item(Some(index_to_item(&index)))
+            .send()
+            .await
+            .map_err(|err| match &err {
+                SdkError::ServiceError(service_error)
+                    if service_error.err().is_conditional_check_failed_exception() =>
+                {
+                    Error::IndexAlreadyExists(index.id.clone())
+                }
+                _ => Error::from(err),
+            })?;
+
         Ok(index)
     }
+
+    async fn create_indexes(&self, new_indexes: Vec<NewIndex>) -> Result<Vec<Index>, Error> {
+        let indexes = new_indexes
+            .into_iter()
+            .map(|new_index| Ok(new_index_to_index(new_index.validate()?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let mut transact_items = Vec::with_capacity(indexes.len());
+        for index in &indexes {
+            transact_items.push(
+                TransactWriteItem::builder()
+                    .put(
+                        Put::builder()
+                            .table_name(&self.metadata_table_name)
+                            .condition_expression("attribute_not_exists(id)")
+                            .set_item(Some(index_to_item(index)))
+                            .build(),
+                    )
+                    .build(),
+            );
+        }
+
+        self.client
+            .transact_write_items()
+            .set_transact_items(Some(transact_items))
+            .send()
+            .await
+            .map_err(|err| {
+                if let SdkError::ServiceError(service_error) = &err {
+                    if let TransactWriteItemsError::TransactionCanceledException(exception) =
+                        service_error.err()
+                    {
+                        // Reasons are in the same order as the items
+                        let conflict = exception
+                            .cancellation_reasons()
+                            .unwrap_or_default()
+                            .iter()
+                            .position(|reason| reason.code() == Some("ConditionalCheckFailed"));
+
+                        if let Some(position) = conflict {
+                            return Error::IndexAlreadyExists(indexes[position].id.clone());
+                        }
+                    }
+                }
+
+                Error::from(err)
+            })?;
+
+        Ok(indexes)
+    }
+}
+
+/// Static credentials if one of `AWS_ACCESS_KEY_ID_FILE`, `AWS_SECRET_ACCESS_KEY_FILE` or
+/// `AWS_SESSION_TOKEN_FILE` is set (see `config::secret_from_env`), `None` to use the
+/// credentials provider configured by `AWS_CREDENTIALS_PROVIDER`.
+fn credentials_from_files() -> Option<Credentials> {
+    const VARIABLES: [&str; 3] = [
+        "AWS_ACCESS_KEY_ID",
+        "AWS_SECRET_ACCESS_KEY",
+        "AWS_SESSION_TOKEN",
+    ];
+    if VARIABLES
+        .iter()
+        .all(|name| env::var(format!("{name}_FILE")).is_err())
+    {
+        return None;
+    }
+
+    let required = |name: &str| {
+        config::secret_from_env(name).unwrap_or_else(|| {
+            panic!("`{name}` (or `{name}_FILE`) env variable is required with DynamoDB")
+        })
+    };
+
+    Some(Credentials::new(
+        required("AWS_ACCESS_KEY_ID"),
+        required("AWS_SECRET_ACCESS_KEY"),
+        config::secret_from_env("AWS_SESSION_TOKEN"),
+        None,
+        "FindexCloudSecretFiles",
+    ))
+}
+
+/// The fields are taken (`NewIndex` wipes its seeds on drop, they cannot be moved out).
+fn new_index_to_index(mut new_index: NewIndex) -> Index {
+    Index {
+        id: mem::take(&mut new_index.id),
+        name: mem::take(&mut new_index.name),
+        fetch_entries_key: mem::take(&mut new_index.fetch_entries_key),
+        fetch_chains_key: mem::take(&mut new_index.fetch_chains_key),
+        upsert_entries_key: mem::take(&mut new_index.upsert_entries_key),
+        insert_chains_key: mem::take(&mut new_index.insert_chains_key),
+        size: Some(0),
+        entries_size: Some(0),
+        chains_size: Some(0),
+        entries_count: Some(0),
+        chains_count: Some(0),
+        created_at: Utc::now().naive_utc(),
+        archived_at: None,
+        ttl_seconds: new_index.ttl_seconds,
+        last_activity_at: None,
+        stale_at: None,
+        fetches: 0,
+        upserts: 0,
+        chain_inserts: 0,
+        rejected_signatures: 0,
+    }
+}
+
+fn index_to_item(index: &Index) -> HashMap<String, AttributeValue> {
+    let mut item = HashMap::from([
+        ("id".to_string(), AttributeValue::S(index.id.clone())),
+        ("name".to_string(), AttributeValue::S(index.name.clone())),
+        (
+            "fetch_entries_key".to_string(),
+            AttributeValue::B(Blob::new(index.fetch_entries_key.clone())),
+        ),
+        (
+            "fetch_chains_key".to_string(),
+            AttributeValue::B(Blob::new(index.fetch_chains_key.clone())),
+        ),
+        (
+            "upsert_entries_key".to_string(),
+            AttributeValue::B(Blob::new(index.upsert_entries_key.clone())),
+        ),
+        (
+            "insert_chains_key".to_string(),
+            AttributeValue::B(Blob::new(index.insert_chains_key.clone())),
+        ),
+        (
+            "created_at".to_string(),
+            AttributeValue::S(index.created_at.to_string()),
+        ),
+    ]);
+
+    if let Some(ttl_seconds) = index.ttl_seconds {
+        item.insert(
+            "ttl_seconds".to_string(),
+            AttributeValue::N(ttl_seconds.to_string()),
+        );
+    }
+
+    item
 }
 
 /// Create the ID to store inside DynamoDB from Index `id` and `uid`
@@ -530,6 +1722,36 @@ fn get_uid_attribute_value(index: &Index, uid: &[u8]) -> AttributeValue {
     AttributeValue::B(Blob::new(id))
 }
 
+/// Item of the entries or chains table, with the `expires_at` attribute if the index has a TTL
+fn value_to_item(index: &Index, uid: &[u8], value: Vec<u8>) -> HashMap<String, AttributeValue> {
+    let mut item = HashMap::from([
+        (
+            ENTRIES_AND_CHAINS_ID_COLUMN_NAME.to_string(),
+            get_uid_attribute_value(index, uid),
+        ),
+        (
+            ENTRIES_AND_CHAINS_VALUE_COLUMN_NAME.to_string(),
+            AttributeValue::B(Blob::new(value)),
+        ),
+    ]);
+
+    if let Some(expires_at) = expires_at(index) {
+        item.insert(
+            ENTRIES_AND_CHAINS_EXPIRES_AT_COLUMN_NAME.to_string(),
+            expires_at,
+        );
+    }
+
+    item
+}
+
+/// In epoch seconds as expected by DynamoDB TTL
+fn expires_at(index: &Index) -> Option<AttributeValue> {
+    index
+        .ttl_seconds
+        .map(|ttl_seconds| AttributeValue::N((Utc::now().timestamp() + ttl_seconds).to_string()))
+}
+
 /// Extract the `uid` from the ID stored inside DynamoDB
 /// This function is the inverse of `get_uid_attribute_value`.
 fn extract_uid_from_stored_id(id: Vec<u8>) -> Result<Uid<UID_LENGTH>, Error> {
@@ -559,6 +1781,19 @@ fn extract_bytes(item: &HashMap<String, AttributeValue>, key: &str) -> Result<Ve
         .into_inner())
 }
 
+fn extract_number(item: &HashMap<String, AttributeValue>, key: &str) -> Result<u64, Error> {
+    item.get(key)
+        .ok_or_else(|| Error::DynamoDb(format!("{item:?} doesn't contains an '{key}' attribute.")))?
+        .as_n()
+        .ok()
+        .and_then(|number| number.parse().ok())
+        .ok_or_else(|| {
+            Error::DynamoDb(format!(
+                "{item:?} contains a '{key}' attribute but it's not a positive integer."
+            ))
+        })
+}
+
 fn extract_string(item: &HashMap<String, AttributeValue>, key: &str) -> Result<String, Error> {
     Ok(item
         .get(key)
@@ -590,8 +1825,86 @@ fn try_create_table(
     }
 }
 
+/// Enable TTL on the `expires_at` attribute once the table is active.
+/// Nothing is done if TTL is already enabled on this attribute.
+async fn enable_ttl(client: &Client, table_name: &str) -> Result<(), Error> {
+    // A new table is `CREATING` for a few seconds and TTL cannot be updated before it's `ACTIVE`.
+    for _ in 0..60 {
+        let table = client
+            .describe_table()
+            .table_name(table_name)
+            .send()
+            .await?;
+        if table.table().and_then(|table| table.table_status()) == Some(&TableStatus::Active) {
+            break;
+        }
+
+        actix_web::rt::time::sleep(Duration::from_secs(1)).await;
+    }
+
+    let ttl = client
+        .describe_time_to_live()
+        .table_name(table_name)
+        .send()
+        .await?;
+    if let Some(description) = ttl.time_to_live_description() {
+        if matches!(
+            description.time_to_live_status(),
+            Some(TimeToLiveStatus::Enabled | TimeToLiveStatus::Enabling)
+        ) {
+            return match description.attribute_name() {
+                Some(ENTRIES_AND_CHAINS_EXPIRES_AT_COLUMN_NAME) => Ok(()),
+                attribute_name => Err(Error::DynamoDb(format!(
+                    "TTL is already enabled on the attribute {attribute_name:?} instead of '{ENTRIES_AND_CHAINS_EXPIRES_AT_COLUMN_NAME}'"
+                ))),
+            };
+        }
+    }
+
+    client
+        .update_time_to_live()
+        .table_name(table_name)
+        .time_to_live_specification(
+            TimeToLiveSpecification::builder()
+                .enabled(true)
+                .attribute_name(ENTRIES_AND_CHAINS_EXPIRES_AT_COLUMN_NAME)
+                .build(),
+        )
+        .send()
+        .await?;
+
+    Ok(())
+}
+
 fn item_to_index(item: &HashMap<String, AttributeValue>) -> Result<Index, Error> {
     let created_at = extract_string(item, "created_at")?;
+    let archived_at = match item.get("archived_at") {
+        Some(_) => Some(parse_date(
+            &extract_string(item, "archived_at")?,
+            "archived_at",
+        )?),
+        None => None,
+    };
+    let ttl_seconds = match item.get("ttl_seconds") {
+        Some(_) => Some(extract_number(item, "ttl_seconds")? as i64),
+        None => None,
+    };
+    let last_activity_at = match item.get("last_activity_at") {
+        Some(_) => Some(parse_date(
+            &extract_string(item, "last_activity_at")?,
+            "last_activity_at",
+        )?),
+        None => None,
+    };
+    let stale_at = match item.get("stale_at") {
+        Some(_) => Some(parse_date(&extract_string(item, "stale_at")?, "stale_at")?),
+        None => None,
+    };
+    // Request counters are only written after the first flush (see `counters.rs`)
+    let counter = |key| match item.get(key) {
+        Some(_) => extract_number(item, key).map(|value| value as i64),
+        None => Ok(0),
+    };
 
     Ok(Index {
         id: extract_string(item, "id")?,
@@ -601,12 +1914,47 @@ fn item_to_index(item: &HashMap<String, AttributeValue>) -> Result<Index, Error>
         upsert_entries_key: extract_bytes(item, "upsert_entries_key")?,
         insert_chains_key: extract_bytes(item, "insert_chains_key")?,
         size: None,
-        created_at: NaiveDateTime::parse_from_str(&created_at, "%Y-%m-%d %H:%M:%S%.f").map_err(
-            |_| {
-                Error::DynamoDb(format!(
-                    "Cannot parse date '{created_at}' inside 'created_at' attribute."
-                ))
-            },
-        )?,
+        entries_size: None,
+        chains_size: None,
+        entries_count: None,
+        chains_count: None,
+        created_at: parse_date(&created_at, "created_at")?,
+        archived_at,
+        ttl_seconds,
+        last_activity_at,
+        stale_at,
+        fetches: counter("fetches")?,
+        upserts: counter("upserts")?,
+        chain_inserts: counter("chain_inserts")?,
+        rejected_signatures: counter("rejected_signatures")?,
     })
 }
+
+fn parse_date(date: &str, key: &str) -> Result<NaiveDateTime, Error> {
+    NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S%.f").map_err(|_| {
+        Error::DynamoDb(format!(
+            "Cannot parse date '{date}' inside '{key}' attribute."
+        ))
+    })
+}
+
+/// The counters are shared by all the DynamoDB clients of the instance.
+fn storage_gauges(max_attempts: u32) -> Vec<StorageGauge> {
+    vec![
+        StorageGauge::gauge(
+            "findex_cloud_dynamodb_max_attempts",
+            "Attempts of the DynamoDB client for each call (the retries are internal to the SDK).",
+            f64::from(max_attempts),
+        ),
+        StorageGauge::counter(
+            "findex_cloud_dynamodb_throttled_calls_total",
+            "DynamoDB calls still throttled after all the attempts of the client.",
+            THROTTLED_CALLS.load(Ordering::Relaxed) as f64,
+        ),
+        StorageGauge::counter(
+            "findex_cloud_dynamodb_batch_write_retries_total",
+            "BatchWriteItem calls sent again for their unprocessed items.",
+            BATCH_WRITE_RETRIES.load(Ordering::Relaxed) as f64,
+        ),
+    ]
+}
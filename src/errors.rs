@@ -5,13 +5,21 @@ use std::{
 
 use actix_web::{
     error::ResponseError,
-    http::{header::ContentType, StatusCode},
+    http::{
+        header::{ContentType, RETRY_AFTER},
+        StatusCode,
+    },
     web::Json,
     HttpResponse,
 };
 use cloudproof_findex::ser_de::SerializableSetError;
 use cosmian_findex::CoreError;
 
+#[cfg(feature = "dynamodb")]
+use aws_sdk_dynamodb::error::ProvideErrorMetadata;
+
+use crate::backoff::{self, BackoffHint, Overload};
+
 pub type Response<T> = Result<Json<T>, Error>;
 pub type ResponseBytes = Result<HttpResponse, Error>;
 
@@ -23,21 +31,135 @@ pub enum Error {
     WrongEncoding,
     Json,
     WrongIndexPublicId,
+    InvalidIndexName(String),
+    InvalidIndexId(String),
+    IndexAlreadyExists(String),
+    /// The index must be unarchived before being used
+    IndexArchived(String),
+    InvalidKeyLength {
+        key: &'static str,
+        length: usize,
+        expected: usize,
+    },
     Findex(String),
+    /// Upsert with an `old_value` of an entry which doesn't exist
+    /// (for example removed by a concurrent compaction)
+    #[cfg(any(feature = "lmmd", feature = "rocksdb"))]
+    EntryNotFound(String),
 
     #[cfg(feature = "rocksdb")]
     Rocksdb(rocksdb::Error),
     #[cfg(feature = "lmmd")]
-    Heed(heed::Error),
+    /// Message of the `heed::Error` (which is not `Send`)
+    Heed(String),
+    /// A stored value doesn't match its checksum (see `VALUES_CHECKSUMS`)
+    #[cfg(any(feature = "rocksdb", feature = "lmmd"))]
+    CorruptedValue(String),
+    /// The indexes database reached its maximum size
+    #[cfg(feature = "lmmd")]
+    StorageFull(String),
     #[cfg(feature = "dynamodb")]
     DynamoDb(String),
 
+    #[cfg(any(feature = "kafka", feature = "nats"))]
+    EventBus(String),
+
+    Unauthorized,
+    /// Mutations are refused during maintenance, `retry_after` is in seconds
+    Maintenance {
+        retry_after: u64,
+    },
+    /// Mutations are refused on a standby instance
+    #[cfg(feature = "replication")]
+    Standby,
+    /// The standby was promoted: mutations are refused on the old primary and the
+    /// records of the old primary are refused by the new one
+    #[cfg(feature = "replication")]
+    Fenced,
+    /// The primary lost the contact with the standby for longer than its lease, the standby
+    /// may promote itself: mutations are refused until the contact is back
+    #[cfg(feature = "replication")]
+    LeaseExpired,
+    /// The client should split the request in chunks of at most `max` UIDs
+    TooManyUids {
+        count: usize,
+        max: usize,
+    },
+    /// The body is larger than the `max_body_bytes` of the settings of the index
+    BodyTooLarge {
+        length: usize,
+        max: usize,
+    },
+    /// The estimated memory of the request is above `budget` (in bytes, see `limits.rs`)
+    MemoryBudgetExceeded {
+        estimated: usize,
+        budget: usize,
+    },
+    /// `retry_after` is in seconds
+    TooManyRequests {
+        retry_after: u64,
+    },
+    /// A monthly quota of the index is exhausted (see `quotas.rs`),
+    /// `retry_after` is in seconds (until the next month)
+    QuotaExceeded {
+        quota: &'static str,
+        retry_after: u64,
+    },
+
+    /// A call to the indexes or metadata database took too long (see `database_timeout.rs`)
+    DatabaseTimeout(String),
+
+    /// Same `Idempotency-Key` with another body (see `idempotency.rs`)
+    IdempotencyKeyReused,
+
+    /// The compaction lock of the index is held by someone else (see `compaction.rs`)
+    CompactionLocked(String),
+
+    /// Bulk loads are only allowed inside empty indexes (see `bulk_load.rs`)
+    IndexNotEmpty(String),
+
+    /// Updates of the settings need the `ETag` of the settings they modify in `If-Match`
+    /// (see `settings.rs`)
+    PreconditionRequired(String),
+    /// The settings were modified since the `ETag` sent in `If-Match`
+    PreconditionFailed(String),
+
+    /// The database is throttled or the written values are contended, the client should
+    /// retry after `retry_after_ms` (see `backoff.rs`)
+    Overloaded {
+        overload: Overload,
+        reason: String,
+        retry_after_ms: u64,
+    },
+
+    /// The indexes database driver doesn't implement this feature
+    Unsupported(String),
+    Internal(String),
+
     BadRequest(String),
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
-        write!(f, "{self:?}")?;
+        match self {
+            Self::TooManyUids { count, max } => write!(
+                f,
+                "TooManyUids: the request contains {count} UIDs but the maximum is {max}, split it into chunks of at most {max} UIDs"
+            )?,
+            Self::BodyTooLarge { length, max } => write!(
+                f,
+                "BodyTooLarge: the body has {length} bytes but the maximum for this index is {max} bytes"
+            )?,
+            Self::MemoryBudgetExceeded { estimated, budget } => write!(
+                f,
+                "MemoryBudgetExceeded: the request needs about {estimated} bytes of memory but the budget is {budget} bytes, split it into smaller requests"
+            )?,
+            Self::QuotaExceeded { quota, .. } => write!(
+                f,
+                "QuotaExceeded: the monthly {quota} quota of this index is exhausted"
+            )?,
+            _ => write!(f, "{self:?}")?,
+        }
 
         Ok(())
     }
@@ -45,9 +167,27 @@ impl Display for Error {
 
 impl ResponseError for Error {
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code())
-            .insert_header(ContentType::json())
-            .body(self.to_string())
+        let mut response = HttpResponse::build(self.status_code());
+        response.insert_header(ContentType::json());
+
+        if let Self::Maintenance { retry_after }
+        | Self::TooManyRequests { retry_after }
+        | Self::QuotaExceeded { retry_after, .. } = self
+        {
+            response.insert_header((RETRY_AFTER, retry_after.to_string()));
+        }
+
+        if let Self::Overloaded {
+            overload,
+            reason,
+            retry_after_ms,
+        } = self
+        {
+            response.insert_header((RETRY_AFTER, retry_after_ms.div_ceil(1000).to_string()));
+            return response.json(BackoffHint::new(*overload, reason, *retry_after_ms));
+        }
+
+        response.body(self.to_string())
     }
 
     fn status_code(&self) -> StatusCode {
@@ -62,12 +202,58 @@ impl ResponseError for Error {
             Self::WrongEncoding => StatusCode::BAD_REQUEST,
             Self::Json => StatusCode::BAD_REQUEST,
             Self::WrongIndexPublicId => StatusCode::BAD_REQUEST,
+            Self::InvalidIndexName(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::InvalidIndexId(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::IndexAlreadyExists(_) => StatusCode::CONFLICT,
+            Self::IndexArchived(_) => StatusCode::GONE,
+            Self::InvalidKeyLength { .. } => StatusCode::UNPROCESSABLE_ENTITY,
             Self::Findex(_) => StatusCode::BAD_REQUEST,
+            #[cfg(any(feature = "lmmd", feature = "rocksdb"))]
+            Self::EntryNotFound(_) => StatusCode::CONFLICT,
 
             #[cfg(feature = "rocksdb")]
             Self::Rocksdb(_) => StatusCode::INTERNAL_SERVER_ERROR,
             #[cfg(feature = "lmmd")]
             Self::Heed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            #[cfg(any(feature = "rocksdb", feature = "lmmd"))]
+            Self::CorruptedValue(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            #[cfg(feature = "lmmd")]
+            Self::StorageFull(_) => StatusCode::INSUFFICIENT_STORAGE,
+
+            #[cfg(any(feature = "kafka", feature = "nats"))]
+            Self::EventBus(_) => StatusCode::INTERNAL_SERVER_ERROR,
+
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Maintenance { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            #[cfg(feature = "replication")]
+            Self::Standby => StatusCode::SERVICE_UNAVAILABLE,
+            #[cfg(feature = "replication")]
+            Self::Fenced => StatusCode::CONFLICT,
+            #[cfg(feature = "replication")]
+            Self::LeaseExpired => StatusCode::SERVICE_UNAVAILABLE,
+            Self::TooManyUids { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::BodyTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::MemoryBudgetExceeded { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::QuotaExceeded { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::DatabaseTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::CompactionLocked(_) => StatusCode::CONFLICT,
+            Self::IndexNotEmpty(_) => StatusCode::CONFLICT,
+            Self::PreconditionRequired(_) => StatusCode::PRECONDITION_REQUIRED,
+            Self::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+
+            Self::Overloaded {
+                overload: Overload::Throttled,
+                ..
+            } => StatusCode::TOO_MANY_REQUESTS,
+            Self::Overloaded {
+                overload: Overload::Contention,
+                ..
+            } => StatusCode::SERVICE_UNAVAILABLE,
+
+            Self::Unsupported(_) => StatusCode::NOT_IMPLEMENTED,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
 
             Self::BadRequest(_) => StatusCode::BAD_REQUEST,
         }
@@ -84,21 +270,58 @@ impl From<sqlx::Error> for Error {
 #[cfg(feature = "rocksdb")]
 impl From<rocksdb::Error> for Error {
     fn from(err: rocksdb::Error) -> Self {
-        Error::Rocksdb(err)
+        match err.kind() {
+            rocksdb::ErrorKind::Busy
+            | rocksdb::ErrorKind::TimedOut
+            | rocksdb::ErrorKind::TryAgain => {
+                backoff::overloaded(Overload::Contention, err.into_string())
+            }
+            _ => Error::Rocksdb(err),
+        }
     }
 }
 
 #[cfg(feature = "lmmd")]
 impl From<heed::Error> for Error {
     fn from(err: heed::Error) -> Self {
-        Error::Heed(err)
+        match err {
+            heed::Error::Mdb(heed::MdbError::MapFull) => Error::StorageFull(
+                "the LMDB map is full, increase `LMDB_MAP_SIZE_MB` and restart the server"
+                    .to_string(),
+            ),
+            err => Error::Heed(err.to_string()),
+        }
     }
 }
 
 #[cfg(feature = "dynamodb")]
-impl<T> From<aws_smithy_http::result::SdkError<T>> for Error {
+impl<T: ProvideErrorMetadata> From<aws_smithy_http::result::SdkError<T>> for Error {
     fn from(err: aws_smithy_http::result::SdkError<T>) -> Self {
-        Error::DynamoDb(err.to_string())
+        let overload = match err.code() {
+            Some(
+                "ProvisionedThroughputExceededException"
+                | "ThrottlingException"
+                | "RequestLimitExceeded",
+            ) => Some(Overload::Throttled),
+            Some("TransactionConflictException") => Some(Overload::Contention),
+            _ => None,
+        };
+
+        match overload {
+            Some(overload) => {
+                if overload == Overload::Throttled {
+                    crate::dynamodb::THROTTLED_CALLS
+                        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                }
+                backoff::overloaded(
+                    overload,
+                    err.message()
+                        .unwrap_or("DynamoDB is overloaded")
+                        .to_string(),
+                )
+            }
+            None => Error::DynamoDb(err.to_string()),
+        }
     }
 }
 
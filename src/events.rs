@@ -0,0 +1,286 @@
+/// Publish a notification for every mutation applied to the indexes database
+/// (entries upserted and chains inserted) so that downstream consumers can
+/// replicate the data, compute analytics or invalidate their caches.
+///
+/// Events only contain the index ID, the UID and the type of operation. The
+/// values are never sent to the event bus (they are encrypted but we don't want
+/// to duplicate them in another system). Consumers need to call the fetch endpoints
+/// to get the values.
+///
+/// Two implementations exist: Kafka (through the Kafka REST Proxy to avoid linking
+/// `librdkafka`) and NATS (core protocol over TCP).
+use actix_web::web::Data;
+use async_trait::async_trait;
+use cosmian_findex::{parameters::UID_LENGTH, Uid};
+use serde::{Serialize, Serializer};
+
+use crate::errors::Error;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Mutation {
+    pub index_id: String,
+    #[serde(serialize_with = "serialize_uid")]
+    pub uid: Uid<UID_LENGTH>,
+    pub operation: Operation,
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    UpsertEntry,
+    InsertChain,
+}
+
+impl Mutation {
+    pub(crate) fn new(index_id: &str, uid: &Uid<UID_LENGTH>, operation: Operation) -> Self {
+        Mutation {
+            index_id: index_id.to_string(),
+            uid: *uid,
+            operation,
+        }
+    }
+}
+
+/// UIDs are sent as base64 strings
+pub(crate) fn serialize_uid<S: Serializer>(
+    uid: &Uid<UID_LENGTH>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(uid)
+}
+
+#[async_trait]
+pub(crate) trait EventBus: Sync + Send {
+    async fn publish(&self, mutations: &[Mutation]) -> Result<(), Error>;
+}
+
+/// Publish the mutations without blocking the response to the client.
+/// An error while publishing is only logged because the mutation is already
+/// applied to the database.
+pub(crate) fn publish_in_background(
+    event_bus: Option<Data<dyn EventBus>>,
+    mutations: Vec<Mutation>,
+) {
+    let Some(event_bus) = event_bus else {
+        return;
+    };
+
+    if mutations.is_empty() {
+        return;
+    }
+
+    actix_web::rt::spawn(async move {
+        if let Err(err) = event_bus.publish(&mutations).await {
+            log::error!(
+                "Cannot publish {} mutation(s) to the event bus ({err:?})",
+                mutations.len()
+            );
+        }
+    });
+}
+
+#[cfg(feature = "kafka")]
+pub(crate) mod kafka {
+    use std::{env, time::Duration};
+
+    use async_trait::async_trait;
+    use serde_json::json;
+
+    use super::{EventBus, Mutation};
+    use crate::errors::Error;
+
+    const DEFAULT_TIMEOUT_SECONDS: u64 = 10;
+    const DEFAULT_CONNECT_TIMEOUT_SECONDS: u64 = 5;
+
+    /// Publish to Kafka with the Confluent REST Proxy API v2.
+    /// The record key is the index ID so all the mutations of one index
+    /// go to the same partition (and keep their order).
+    ///
+    /// A request to the REST Proxy is abandoned after `KAFKA_TIMEOUT_SECONDS` (connection
+    /// after `KAFKA_CONNECT_TIMEOUT_SECONDS`), an unresponsive proxy would keep the
+    /// background publications (and their mutations) in memory forever.
+    pub(crate) struct Kafka {
+        client: reqwest::Client,
+        url: String,
+    }
+
+    impl Kafka {
+        pub(crate) fn create() -> Self {
+            let rest_proxy_url = env::var("KAFKA_REST_PROXY_URL").expect(
+                "`KAFKA_REST_PROXY_URL` env variable is required to use the Kafka event bus",
+            );
+            let topic =
+                env::var("KAFKA_TOPIC").unwrap_or_else(|_| "findex_cloud_mutations".to_string());
+
+            let client = reqwest::Client::builder()
+                .timeout(Duration::from_secs(seconds_from_env(
+                    "KAFKA_TIMEOUT_SECONDS",
+                    DEFAULT_TIMEOUT_SECONDS,
+                )))
+                .connect_timeout(Duration::from_secs(seconds_from_env(
+                    "KAFKA_CONNECT_TIMEOUT_SECONDS",
+                    DEFAULT_CONNECT_TIMEOUT_SECONDS,
+                )))
+                .build()
+                .expect("Cannot build the Kafka REST Proxy client");
+
+            Kafka {
+                client,
+                url: format!("{}/topics/{topic}", rest_proxy_url.trim_end_matches('/')),
+            }
+        }
+    }
+
+    fn seconds_from_env(name: &str, default: u64) -> u64 {
+        env::var(name)
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    }
+
+    #[async_trait]
+    impl EventBus for Kafka {
+        async fn publish(&self, mutations: &[Mutation]) -> Result<(), Error> {
+            let records: Vec<_> = mutations
+                .iter()
+                .map(|mutation| json!({ "key": mutation.index_id, "value": mutation }))
+                .collect();
+
+            let response = self
+                .client
+                .post(&self.url)
+                .header("Content-Type", "application/vnd.kafka.json.v2+json")
+                .json(&json!({ "records": records }))
+                .send()
+                .await
+                .map_err(|err| Error::EventBus(err.to_string()))?;
+
+            if !response.status().is_success() {
+                return Err(Error::EventBus(format!(
+                    "Kafka REST Proxy responded with status {}",
+                    response.status()
+                )));
+            }
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "nats")]
+pub(crate) mod nats {
+    use std::{env, sync::Arc};
+
+    use async_trait::async_trait;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::{tcp::OwnedWriteHalf, TcpStream},
+        sync::Mutex,
+    };
+
+    use super::{EventBus, Mutation};
+    use crate::errors::Error;
+
+    /// Minimal NATS publisher (only `CONNECT`, `PUB` and `PONG` messages
+    /// are sent). Mutations are published on the subject `{NATS_SUBJECT}.{index_id}`
+    /// to allow consumers to subscribe to a single index.
+    pub(crate) struct Nats {
+        address: String,
+        subject: String,
+        writer: Mutex<Option<Arc<Mutex<OwnedWriteHalf>>>>,
+    }
+
+    impl Nats {
+        pub(crate) async fn create() -> Self {
+            let nats = Nats {
+                address: env::var("NATS_ADDRESS").unwrap_or_else(|_| "127.0.0.1:4222".to_string()),
+                subject: env::var("NATS_SUBJECT")
+                    .unwrap_or_else(|_| "findex_cloud.mutations".to_string()),
+                writer: Mutex::new(None),
+            };
+
+            if let Err(err) = nats.connection().await {
+                panic!("Cannot connect to NATS at {} ({err:?})", nats.address);
+            }
+
+            nats
+        }
+
+        /// Return the current connection or open a new one
+        async fn connection(&self) -> Result<Arc<Mutex<OwnedWriteHalf>>, Error> {
+            let mut writer = self.writer.lock().await;
+
+            if let Some(writer) = writer.as_ref() {
+                return Ok(writer.clone());
+            }
+
+            let stream = TcpStream::connect(&self.address)
+                .await
+                .map_err(|err| Error::EventBus(err.to_string()))?;
+            let (reader, mut new_writer) = stream.into_split();
+            new_writer
+                .write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false}\r\n")
+                .await
+                .map_err(|err| Error::EventBus(err.to_string()))?;
+
+            let new_writer = Arc::new(Mutex::new(new_writer));
+
+            // The server sends `PING` regularly and closes the connection
+            // if the client doesn't respond.
+            let pong_writer = new_writer.clone();
+            actix_web::rt::spawn(async move {
+                let mut lines = BufReader::new(reader).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    if line.starts_with("PING") {
+                        let _ = pong_writer.lock().await.write_all(b"PONG\r\n").await;
+                    } else if line.starts_with("-ERR") {
+                        log::error!("NATS error: {line}");
+                    }
+                }
+            });
+
+            *writer = Some(new_writer.clone());
+
+            Ok(new_writer)
+        }
+
+        async fn try_publish(&self, mutations: &[Mutation]) -> Result<(), Error> {
+            let mut message = Vec::new();
+            for mutation in mutations {
+                let payload = serde_json::to_vec(mutation)?;
+                message.extend_from_slice(
+                    format!(
+                        "PUB {}.{} {}\r\n",
+                        self.subject,
+                        mutation.index_id,
+                        payload.len()
+                    )
+                    .as_bytes(),
+                );
+                message.extend_from_slice(&payload);
+                message.extend_from_slice(b"\r\n");
+            }
+
+            let connection = self.connection().await?;
+            let mut connection = connection.lock().await;
+            connection
+                .write_all(&message)
+                .await
+                .map_err(|err| Error::EventBus(err.to_string()))
+        }
+    }
+
+    #[async_trait]
+    impl EventBus for Nats {
+        async fn publish(&self, mutations: &[Mutation]) -> Result<(), Error> {
+            if self.try_publish(mutations).await.is_ok() {
+                return Ok(());
+            }
+
+            // The connection may have been closed by the server, retry once
+            // with a new connection.
+            *self.writer.lock().await = None;
+            self.try_publish(mutations).await
+        }
+    }
+}
@@ -0,0 +1,227 @@
+/// Export of the raw (encrypted) tables of an index for offline analysis.
+///
+/// `GET /admin/indexes/{id}/export` returns all the entries and chains of the index
+/// with the UIDs and values base64 encoded. The values stay encrypted, the server
+/// doesn't have the keys to decrypt them.
+///
+/// With the "sqlite" feature, `GET /admin/indexes/{id}/export/sqlite` returns the same tables
+/// as a SQLite file in the format of cloudproof_findex's `FindexSqlite` (`entry_table` and
+/// `chain_table`) to query the index locally with the index keys.
+///
+/// Reading a whole index is expensive for the indexes database (a full scan with DynamoDB)
+/// so only one export is allowed every `EXPORT_MIN_INTERVAL_SECONDS` (60 by default)
+/// for the whole server. Other requests are refused with a `429 Too Many Requests`.
+use std::{
+    collections::HashMap,
+    env,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    get,
+    web::{Data, Json},
+};
+#[cfg(feature = "sqlite")]
+use actix_web::{web, HttpResponse};
+use base64::{engine::general_purpose, Engine};
+use cosmian_findex::{parameters::UID_LENGTH, EncryptedTable, Uid};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin::Admin,
+    core::{Index, IndexesDatabase, Table},
+    errors::{Error, Response},
+};
+#[cfg(feature = "sqlite")]
+use crate::{config, errors::ResponseBytes};
+
+const DEFAULT_EXPORT_MIN_INTERVAL_IN_SECONDS: u64 = 60;
+
+pub(crate) struct ExportRateLimiter {
+    min_interval: Duration,
+    last_export: Mutex<Option<Instant>>,
+}
+
+impl ExportRateLimiter {
+    pub(crate) fn from_env() -> Self {
+        let min_interval = env::var("EXPORT_MIN_INTERVAL_SECONDS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_EXPORT_MIN_INTERVAL_IN_SECONDS);
+
+        ExportRateLimiter {
+            min_interval: Duration::from_secs(min_interval),
+            last_export: Mutex::new(None),
+        }
+    }
+
+    /// Fail if the last export started less than `min_interval` ago.
+    fn acquire(&self) -> Result<(), Error> {
+        let mut last_export = self
+            .last_export
+            .lock()
+            .map_err(|_| Error::Internal("Export lock is poisoned".to_string()))?;
+
+        if let Some(last_export) = *last_export {
+            let elapsed = last_export.elapsed();
+            if elapsed < self.min_interval {
+                return Err(Error::TooManyRequests {
+                    retry_after: (self.min_interval - elapsed).as_secs().max(1),
+                });
+            }
+        }
+
+        *last_export = Some(Instant::now());
+
+        Ok(())
+    }
+}
+
+/// Also the format of the archives (see `archive.rs`)
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Export {
+    pub(crate) entries: HashMap<String, String>,
+    pub(crate) chains: HashMap<String, String>,
+}
+
+pub(crate) fn encode(table: EncryptedTable<UID_LENGTH>) -> HashMap<String, String> {
+    table
+        .into_iter()
+        .map(|(uid, value)| {
+            (
+                general_purpose::STANDARD.encode(uid),
+                general_purpose::STANDARD.encode(value),
+            )
+        })
+        .collect()
+}
+
+pub(crate) fn decode(table: HashMap<String, String>) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+    let mut decoded = EncryptedTable::with_capacity(table.len());
+
+    for (uid, value) in table {
+        let uid: [u8; UID_LENGTH] = general_purpose::STANDARD
+            .decode(uid)
+            .ok()
+            .and_then(|uid| uid.try_into().ok())
+            .ok_or(Error::WrongEncoding)?;
+        let value = general_purpose::STANDARD
+            .decode(value)
+            .map_err(|_| Error::WrongEncoding)?;
+
+        decoded.insert(Uid::from(uid), value);
+    }
+
+    Ok(decoded)
+}
+
+#[get("/admin/indexes/{id}/export")]
+pub(crate) async fn export_index(
+    _admin: Admin,
+    index: Index,
+    rate_limiter: Data<ExportRateLimiter>,
+    indexes: Data<dyn IndexesDatabase>,
+) -> Response<Export> {
+    rate_limiter.acquire()?;
+
+    log::warn!("Exporting index {}", index.id);
+
+    Ok(Json(Export {
+        entries: encode(indexes.fetch_all(&index, Table::Entries).await?),
+        chains: encode(indexes.fetch_all(&index, Table::Chains).await?),
+    }))
+}
+
+#[cfg(feature = "sqlite")]
+#[get("/admin/indexes/{id}/export/sqlite")]
+pub(crate) async fn export_index_to_sqlite(
+    _admin: Admin,
+    index: Index,
+    rate_limiter: Data<ExportRateLimiter>,
+    indexes: Data<dyn IndexesDatabase>,
+) -> ResponseBytes {
+    rate_limiter.acquire()?;
+
+    log::warn!("Exporting index {} to SQLite", index.id);
+
+    let entries = indexes.fetch_all(&index, Table::Entries).await?;
+    let chains = indexes.fetch_all(&index, Table::Chains).await?;
+
+    // SQLite needs a real file, it's removed as soon as it's read.
+    let path = config::data_dir().join(format!(
+        "export_{}_{}.sqlite",
+        index.id,
+        chrono::Utc::now().timestamp_nanos()
+    ));
+    config::prepare_parent_directory(&path);
+
+    let bytes = match write_sqlite(&path, entries, chains).await {
+        Ok(()) => {
+            let path = path.clone();
+            web::block(move || std::fs::read(path))
+                .await
+                .map_err(|err| Error::Internal(err.to_string()))
+                .and_then(|result| {
+                    result.map_err(|err| {
+                        Error::Internal(format!("Cannot read the SQLite export ({err})"))
+                    })
+                })
+        }
+        Err(err) => Err(err),
+    };
+
+    let removed_path = path.clone();
+    if let Ok(Err(err)) = web::block(move || std::fs::remove_file(removed_path)).await {
+        log::error!("Cannot remove the SQLite export {} ({err})", path.display());
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/vnd.sqlite3")
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"{}.sqlite\"", index.id),
+        ))
+        .body(bytes?))
+}
+
+#[cfg(feature = "sqlite")]
+async fn write_sqlite(
+    path: &std::path::Path,
+    entries: EncryptedTable<UID_LENGTH>,
+    chains: EncryptedTable<UID_LENGTH>,
+) -> Result<(), Error> {
+    use sqlx::{sqlite::SqliteConnectOptions, ConnectOptions, Connection};
+
+    let mut db = SqliteConnectOptions::new()
+        .filename(path)
+        .create_if_missing(true)
+        .connect()
+        .await?;
+
+    let mut transaction = db.begin().await?;
+
+    // Same schema as cloudproof_findex's `FindexSqlite`.
+    for (table_name, table) in [("entry_table", entries), ("chain_table", chains)] {
+        sqlx::query(&format!(
+            "CREATE TABLE {table_name} (uid BLOB PRIMARY KEY, value BLOB NOT NULL)"
+        ))
+        .execute(&mut transaction)
+        .await?;
+
+        for (uid, value) in table {
+            sqlx::query(&format!(
+                "INSERT INTO {table_name} (uid, value) VALUES (?, ?)"
+            ))
+            .bind(uid.to_vec())
+            .bind(value)
+            .execute(&mut transaction)
+            .await?;
+        }
+    }
+
+    transaction.commit().await?;
+    db.close().await?;
+
+    Ok(())
+}
@@ -1,37 +1,717 @@
-use std::collections::HashSet;
-use std::fs;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ops::{Bound, Deref};
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    mpsc, Arc,
+};
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 use heed::types::*;
 use heed::EnvOpenOptions;
+use tokio::sync::oneshot;
+use zeroize::Zeroizing;
 
+use cloudproof_findex::cloud::INDEX_ID_LENGTH;
 use cosmian_findex::{parameters::UID_LENGTH, EncryptedTable, Uid, UpsertData};
 
 use crate::{
-    core::{Index, IndexesDatabase, Table},
+    changes::Change,
+    checksum::Checksums,
+    config,
+    core::{serialize_values, Index, IndexesDatabase, Table},
     errors::Error,
+    events::Mutation,
+    metrics::StorageGauge,
+    schema::{self, SCHEMA_UPGRADE_CURSOR_KEY, SCHEMA_VERSION_KEY},
+    scrub::ScrubBatch,
 };
 
+const DEFAULT_MAP_SIZE_IN_MB: usize = 4 * 1024;
+/// The map is grown on startup when the data file uses more than this part of it.
+const MAP_SIZE_GROWTH_THRESHOLD: f64 = 0.8;
+/// Number of values read at once by the schema upgrades
+const UPGRADE_BATCH_SIZE: usize = 10_000;
+const DEFAULT_WRITE_BATCH_MILLISECONDS: u64 = 2;
+/// Upserts and chain inserts inside a single write transaction at most
+const MAX_WRITE_BATCH_REQUESTS: usize = 256;
+
+/// The map size (the maximum size of the database) is `LMDB_MAP_SIZE_MB` (4GiB by default).
+/// LMDB cannot grow the map of an open environment (not supported by heed), writes beyond
+/// the map size fail with a `507 Insufficient Storage`. On startup, if the data file uses
+/// more than 80% of the map, the map size is doubled, so a restart is enough to continue.
+///
+/// LMDB allows a single write transaction at a time, so a transaction per request
+/// serializes the concurrent writers. All the writes (and the rewrites of the values fetched
+/// in an outdated format, without waiting for them) are sent to a writer thread, so the
+/// async threads never wait for the write lock. It merges the requests received during
+/// `LMDB_WRITE_BATCH_MILLISECONDS` (2 by default, `0` writes each request inside its own
+/// transaction) into one write transaction. Each request is applied inside a nested
+/// transaction: a failed request is rolled back without the other requests of the batch,
+/// and the requests see the writes of the previous ones.
 pub(crate) struct Database {
+    store: Store,
+    writer: Writer,
+}
+
+/// The environment, shared with the writer thread
+#[derive(Clone)]
+struct Store {
     env: heed::Env,
     db: heed::Database<ByteSlice, ByteSlice>,
+    checksums: Checksums,
+}
+
+impl Deref for Database {
+    type Target = Store;
+
+    fn deref(&self) -> &Store {
+        &self.store
+    }
+}
+
+enum Write {
+    UpsertEntries {
+        index: Index,
+        data: UpsertData<UID_LENGTH>,
+        reply: oneshot::Sender<Result<EncryptedTable<UID_LENGTH>, Error>>,
+    },
+    InsertChains {
+        index: Index,
+        data: EncryptedTable<UID_LENGTH>,
+        reply: oneshot::Sender<Result<(), Error>>,
+    },
+    PutValues {
+        index: Index,
+        table: Table,
+        data: EncryptedTable<UID_LENGTH>,
+        reply: oneshot::Sender<Result<(), Error>>,
+    },
+    AppendChanges {
+        index: Index,
+        mutations: Vec<Mutation>,
+        reply: oneshot::Sender<Result<(), Error>>,
+    },
+    DeleteIndexData {
+        index_id: String,
+        reply: oneshot::Sender<Result<(), Error>>,
+    },
+    RecomputeSize {
+        index: Index,
+        reply: oneshot::Sender<Result<(), Error>>,
+    },
+    /// Values fetched in an outdated format, a failure is only logged
+    Rewrite {
+        index: Index,
+        table: Table,
+        uids: Vec<Uid<UID_LENGTH>>,
+    },
+}
+
+impl Write {
+    /// Apply the write inside the transaction, `Some` rejected values for the upserts.
+    fn apply(
+        &self,
+        store: &Store,
+        txn: &mut heed::RwTxn,
+    ) -> Result<Option<EncryptedTable<UID_LENGTH>>, Error> {
+        match self {
+            Write::UpsertEntries { index, data, .. } => {
+                store.upsert_entries_in(txn, index, data).map(Some)
+            }
+            Write::InsertChains { index, data, .. } => {
+                store.insert_chains_in(txn, index, data).map(|()| None)
+            }
+            Write::PutValues {
+                index, table, data, ..
+            } => store.put_values_in(txn, index, *table, data).map(|()| None),
+            Write::AppendChanges {
+                index, mutations, ..
+            } => store
+                .append_changes_in(txn, index, mutations)
+                .map(|()| None),
+            Write::DeleteIndexData { index_id, .. } => {
+                store.delete_index_data_in(txn, index_id).map(|()| None)
+            }
+            Write::RecomputeSize { index, .. } => {
+                store.recompute_size_in(txn, index).map(|()| None)
+            }
+            Write::Rewrite { index, table, uids } => store
+                .rewrite_values_in(txn, index, *table, uids)
+                .map(|()| None),
+        }
+    }
+
+    /// Send the result once committed. The request may have been cancelled, the write is
+    /// committed anyway.
+    fn reply(self, result: Result<Option<EncryptedTable<UID_LENGTH>>, Error>) {
+        match self {
+            Write::UpsertEntries { reply, .. } => {
+                let _ =
+                    reply.send(result.map(|rejected| {
+                        rejected.unwrap_or_else(|| EncryptedTable::with_capacity(0))
+                    }));
+            }
+            Write::InsertChains { reply, .. }
+            | Write::PutValues { reply, .. }
+            | Write::AppendChanges { reply, .. }
+            | Write::DeleteIndexData { reply, .. }
+            | Write::RecomputeSize { reply, .. } => {
+                let _ = reply.send(result.map(|_| ()));
+            }
+            Write::Rewrite { index, .. } => {
+                if let Err(err) = result {
+                    log_rewrite_error(&index, &err);
+                }
+            }
+        }
+    }
+
+    /// The whole batch failed
+    fn reply_error(self, err: &Error) {
+        let err = match err {
+            Error::StorageFull(message) => Error::StorageFull(message.clone()),
+            err => Error::Internal(format!("Cannot write the batch of writes ({err:?})")),
+        };
+
+        self.reply(Err(err));
+    }
+}
+
+/// Sends the writes to the writer thread
+struct Writer {
+    sender: mpsc::Sender<Write>,
+    stats: Arc<WriterStats>,
+}
+
+#[derive(Default)]
+struct WriterStats {
+    batches: AtomicU64,
+    requests: AtomicU64,
 }
 
 impl Database {
     pub(crate) fn create() -> Self {
-        let indexes_url = "data/indexes.lmdb";
+        let indexes_url = config::lmdb_path();
+        config::prepare_directory(&indexes_url);
+
+        let configured_map_size = env::var("LMDB_MAP_SIZE_MB")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_MAP_SIZE_IN_MB)
+            * 1024
+            * 1024;
+
+        // Reopening with the configured size after a growth would make the file larger than
+        // the map, so the map is always at least twice the data file above the threshold.
+        let data_size = std::fs::metadata(indexes_url.join("data.mdb"))
+            .map(|metadata| metadata.len() as usize)
+            .unwrap_or(0);
+        let map_size = if data_size as f64 > configured_map_size as f64 * MAP_SIZE_GROWTH_THRESHOLD
+        {
+            // A multiple of the page size
+            let map_size = (data_size * 2).next_multiple_of(1024 * 1024);
+            log::warn!(
+                "LMDB data file uses {data_size} bytes of the {configured_map_size} bytes map, growing the map to {map_size} bytes (increase `LMDB_MAP_SIZE_MB`)"
+            );
+            map_size
+        } else {
+            configured_map_size
+        };
+
+        let store = Store::open(&indexes_url, map_size, Checksums::from_env());
 
-        fs::create_dir_all(indexes_url).expect("Cannot create LMDB directory");
+        let batch_window = Duration::from_millis(
+            env::var("LMDB_WRITE_BATCH_MILLISECONDS")
+                .ok()
+                .map(|value| {
+                    value.parse().unwrap_or_else(|_| {
+                        panic!("`LMDB_WRITE_BATCH_MILLISECONDS` must be a number (found `{value}`)")
+                    })
+                })
+                .unwrap_or(DEFAULT_WRITE_BATCH_MILLISECONDS),
+        );
+        let writer = store.start_writer(batch_window);
+
+        Database { store, writer }
+    }
+
+    /// `rewrite_values_in` after a fetch, sent to the writer thread without waiting for it:
+    /// a failure doesn't fail the fetch.
+    fn rewrite_outdated_values(&self, index: &Index, table: Table, uids: Vec<Uid<UID_LENGTH>>) {
+        if uids.is_empty() {
+            return;
+        }
 
+        let write = Write::Rewrite {
+            index: index.clone(),
+            table,
+            uids,
+        };
+        if self.writer.sender.send(write).is_err() {
+            log::warn!("Cannot rewrite the fetched values, the LMDB writer thread is stopped");
+        }
+    }
+
+    /// Send the write to the writer thread and wait for its result.
+    async fn write<T>(
+        &self,
+        write: Write,
+        reply: oneshot::Receiver<Result<T, Error>>,
+    ) -> Result<T, Error> {
+        self.writer
+            .sender
+            .send(write)
+            .map_err(|_| Error::Internal("The LMDB writer thread is stopped".to_string()))?;
+
+        reply.await.unwrap_or_else(|_| {
+            Err(Error::Internal(
+                "The LMDB writer thread is stopped".to_string(),
+            ))
+        })
+    }
+}
+
+impl Store {
+    fn open(path: &Path, map_size: usize, checksums: Checksums) -> Self {
         let env = EnvOpenOptions::new()
-            .map_size(4 * 1024 * 1024 * 1024)
-            .open(indexes_url)
+            .map_size(map_size)
+            .open(path)
             .expect("Cannot open database");
 
         // we will open the default unamed database
         let db = env.create_database(None).expect("Cannot create database");
 
-        Database { env, db }
+        let store = Store { env, db, checksums };
+        store
+            .check_schema()
+            .expect("Cannot read the schema version of the LMDB database");
+
+        store
+    }
+
+    /// The thread stops when the `Database` is dropped.
+    fn start_writer(&self, batch_window: Duration) -> Writer {
+        let (sender, receiver) = mpsc::channel();
+        let stats = Arc::new(WriterStats::default());
+
+        let store = self.clone();
+        let thread_stats = stats.clone();
+        std::thread::Builder::new()
+            .name("lmdb-writer".to_string())
+            .spawn(move || {
+                while let Ok(first) = receiver.recv() {
+                    let deadline = Instant::now() + batch_window;
+                    let mut batch = vec![first];
+                    while !batch_window.is_zero() && batch.len() < MAX_WRITE_BATCH_REQUESTS {
+                        let timeout = deadline.saturating_duration_since(Instant::now());
+                        match receiver.recv_timeout(timeout) {
+                            Ok(write) => batch.push(write),
+                            Err(_) => break,
+                        }
+                    }
+
+                    thread_stats.batches.fetch_add(1, Ordering::Relaxed);
+                    thread_stats
+                        .requests
+                        .fetch_add(batch.len() as u64, Ordering::Relaxed);
+                    store.write_batch(batch);
+                }
+            })
+            .expect("Cannot start the LMDB writer thread");
+
+        Writer { sender, stats }
+    }
+
+    /// Apply the writes inside one transaction and send their results once committed.
+    fn write_batch(&self, batch: Vec<Write>) {
+        let mut txn = match self.env.write_txn() {
+            Ok(txn) => txn,
+            Err(err) => {
+                let err = Error::from(err);
+                for write in batch {
+                    write.reply_error(&err);
+                }
+                return;
+            }
+        };
+
+        let mut results = Vec::with_capacity(batch.len());
+        for write in batch {
+            let result = match self.env.nested_write_txn(&mut txn) {
+                Ok(mut nested_txn) => {
+                    let result = write.apply(self, &mut nested_txn);
+                    // Dropping the nested transaction aborts the request only
+                    match result {
+                        Ok(rejected) => nested_txn.commit().map_err(Error::from).map(|()| rejected),
+                        Err(err) => Err(err),
+                    }
+                }
+                Err(err) => Err(Error::from(err)),
+            };
+            results.push((write, result));
+        }
+
+        if let Err(err) = txn.commit() {
+            let err = Error::from(err);
+            for (write, _) in results {
+                write.reply_error(&err);
+            }
+            return;
+        }
+
+        for (write, result) in results {
+            write.reply(result);
+        }
+    }
+
+    /// See `IndexesDatabase::upsert_entries`, returns the rejected values.
+    fn upsert_entries_in(
+        &self,
+        txn: &mut heed::RwTxn,
+        index: &Index,
+        data: &UpsertData<UID_LENGTH>,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        let mut rejected = EncryptedTable::<UID_LENGTH>::with_capacity(1);
+
+        for (uid, (old_value, new_value)) in data.iter() {
+            let key = key(index, Table::Entries, uid);
+            let new_value = self.checksums.wrap(new_value.clone());
+
+            let existing_value = self.db.get(txn, &key)?;
+            let existing_matches = match existing_value {
+                Some(existing_value) => {
+                    old_value.as_deref() == Some(self.checksums.verify(uid, existing_value)?)
+                }
+                None => old_value.is_none(),
+            };
+
+            if existing_matches {
+                let added_size =
+                    new_value.len() as i64 - existing_value.map_or(0, |value| value.len() as i64);
+                let added_count = if existing_value.is_none() { 1 } else { 0 };
+                self.add_to_sizes(txn, index, Table::Entries, added_size, added_count)?;
+
+                self.db.put(txn, &key, &new_value)?;
+            } else if let Some(existing_value) = existing_value {
+                let existing_value = self.checksums.verify(uid, existing_value)?.to_vec();
+                rejected.insert(*uid, existing_value);
+            } else {
+                // Nothing is committed, the client can retry the whole upsert.
+                return Err(Error::EntryNotFound(format!(
+                    "Receive an `old_value` but no existing value inside DB for UID {uid:?}"
+                )));
+            }
+        }
+
+        Ok(rejected)
+    }
+
+    /// See `IndexesDatabase::insert_chains`
+    fn insert_chains_in(
+        &self,
+        txn: &mut heed::RwTxn,
+        index: &Index,
+        data: &EncryptedTable<UID_LENGTH>,
+    ) -> Result<(), Error> {
+        let mut size = 0;
+        let mut count = 0;
+        for (uid, value) in data.iter() {
+            let key = key(index, Table::Chains, uid);
+            let value = self.checksums.wrap(value.clone());
+            match self.db.get(txn, &key)? {
+                Some(existing_value) => size -= existing_value.len() as i64,
+                None => count += 1,
+            }
+            size += value.len() as i64;
+            self.db.put(txn, &key, &value)?;
+        }
+
+        self.add_to_sizes(txn, index, Table::Chains, size, count)
+    }
+
+    /// See `IndexesDatabase::put_values`
+    fn put_values_in(
+        &self,
+        txn: &mut heed::RwTxn,
+        index: &Index,
+        table: Table,
+        data: &EncryptedTable<UID_LENGTH>,
+    ) -> Result<(), Error> {
+        let mut size = 0;
+        let mut count = 0;
+        for (uid, value) in data.iter() {
+            let key = key(index, table, uid);
+            let value = self.checksums.wrap(value.clone());
+            match self.db.get(txn, &key)? {
+                Some(existing_value) => size -= existing_value.len() as i64,
+                None => count += 1,
+            }
+            size += value.len() as i64;
+            self.db.put(txn, &key, &value)?;
+        }
+
+        self.add_to_sizes(txn, index, table, size, count)
+    }
+
+    /// See `IndexesDatabase::append_changes`. LMDB allows a single write transaction at a
+    /// time so the cursors are consecutive without other locks.
+    fn append_changes_in(
+        &self,
+        txn: &mut heed::RwTxn,
+        index: &Index,
+        mutations: &[Mutation],
+    ) -> Result<(), Error> {
+        let mut cursor = match self.db.get(txn, &changes_cursor_key(index))? {
+            Some(bytes) => bytes.try_into().map(u64::from_be_bytes).map_err(|_| {
+                Error::Internal("Corrupted cursor inside the changes log".to_string())
+            })?,
+            None => 0,
+        };
+        for mutation in mutations {
+            cursor += 1;
+            self.db.put(
+                txn,
+                &change_key(index, cursor),
+                &Change::serialize_value(mutation),
+            )?;
+        }
+
+        self.db
+            .put(txn, &changes_cursor_key(index), &cursor.to_be_bytes())?;
+
+        Ok(())
+    }
+
+    /// See `IndexesDatabase::delete_index_data`
+    fn delete_index_data_in(&self, txn: &mut heed::RwTxn, index_id: &str) -> Result<(), Error> {
+        let end = [index_id.as_bytes(), &[u8::MAX]].concat();
+        self.db.delete_range(
+            txn,
+            &(
+                Bound::Included(index_id.as_bytes()),
+                Bound::Included(&end[..]),
+            ),
+        )?;
+
+        Ok(())
+    }
+
+    /// See `IndexesDatabase::recompute_size`
+    fn recompute_size_in(&self, txn: &mut heed::RwTxn, index: &Index) -> Result<(), Error> {
+        let mut size = 0;
+        for table in [Table::Entries, Table::Chains] {
+            let mut table_size = 0;
+            let mut table_count = 0_i64;
+            let prefix = [index.id.as_bytes(), &[table_to_prefix(table) as u8][..]].concat();
+            for result in self.db.prefix_iter(txn, &prefix)? {
+                let (_, value) = result?;
+                table_size += value.len() as i64;
+                table_count += 1;
+            }
+
+            self.db.put(
+                txn,
+                &table_size_key(index, table),
+                &table_size.to_be_bytes(),
+            )?;
+            self.db.put(
+                txn,
+                &table_count_key(index, table),
+                &table_count.to_be_bytes(),
+            )?;
+            size += table_size;
+        }
+
+        self.db.put(txn, &size_key(index), &size.to_be_bytes())?;
+
+        Ok(())
+    }
+
+    /// See `schema.rs`
+    fn check_schema(&self) -> Result<(), Error> {
+        let txn = self.env.read_txn()?;
+        let stored_version = self.db.get(&txn, SCHEMA_VERSION_KEY)?.map(<[u8]>::to_vec);
+        let has_data = !self.db.is_empty(&txn)?;
+        drop(txn);
+
+        schema::check(
+            "LMDB",
+            stored_version.as_deref(),
+            has_data,
+            |version| self.upgrade_schema(version),
+            |version| {
+                let mut txn = self.env.write_txn()?;
+                self.db
+                    .put(&mut txn, SCHEMA_VERSION_KEY, &version.to_be_bytes())?;
+                txn.commit()?;
+                Ok(())
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Rewrite the keys from the schema `version` to `version + 1`.
+    fn upgrade_schema(&self, version: u32) -> Result<(), Error> {
+        match version {
+            1 => self.upgrade_values_format(),
+            _ => Err(Error::Internal(format!(
+                "No upgrade from the schema version {version}"
+            ))),
+        }
+    }
+
+    /// Schema 1 to 2: prefix all the values with the plain format byte (the schema 1 had no
+    /// checksums), the fetched values are then rewritten in the configured format.
+    ///
+    /// Each batch is committed in its own transaction with its size deltas and the upgrade
+    /// cursor (a single transaction could outgrow the map), an interrupted upgrade resumes
+    /// after the last committed batch (see `schema.rs`).
+    fn upgrade_values_format(&self) -> Result<(), Error> {
+        loop {
+            let mut txn = self.env.write_txn()?;
+            let from = match self.db.get(&txn, SCHEMA_UPGRADE_CURSOR_KEY)? {
+                Some(cursor) => cursor.to_vec(),
+                None => schema::first_index_key(),
+            };
+
+            let values = self
+                .db
+                .range(&txn, &(Bound::Excluded(&from[..]), Bound::Unbounded))?
+                .take(UPGRADE_BATCH_SIZE)
+                .map(|result| result.map(|(key, value)| (key.to_vec(), value.to_vec())))
+                .collect::<Result<Vec<_>, _>>()?;
+            let Some((last_key, _)) = values.last() else {
+                self.db.delete(&mut txn, SCHEMA_UPGRADE_CURSOR_KEY)?;
+                self.db
+                    .put(&mut txn, SCHEMA_VERSION_KEY, &2_u32.to_be_bytes())?;
+                txn.commit()?;
+                break;
+            };
+            let cursor = last_key.clone();
+
+            // Each value is one byte longer
+            let mut added_sizes: HashMap<Vec<u8>, i64> = HashMap::new();
+            for (key, value) in values {
+                let Some(table_size_prefix) = value_table_size_prefix(&key) else {
+                    continue;
+                };
+
+                let index_id = &key[..INDEX_ID_LENGTH];
+                for size_key in [
+                    [index_id, &[Prefix::Size as u8]].concat(),
+                    [index_id, &[table_size_prefix as u8]].concat(),
+                ] {
+                    *added_sizes.entry(size_key).or_default() += 1;
+                }
+
+                self.db
+                    .put(&mut txn, &key, &Checksums::add_format_byte(value))?;
+            }
+
+            for (size_key, added_size) in added_sizes {
+                let size = self.read_size(&txn, &size_key)?.unwrap_or(0);
+                self.db
+                    .put(&mut txn, &size_key, &(size + added_size).to_be_bytes())?;
+            }
+            self.db.put(&mut txn, SCHEMA_UPGRADE_CURSOR_KEY, &cursor)?;
+            txn.commit()?;
+        }
+
+        Ok(())
+    }
+
+    fn read_size<T>(&self, txn: &heed::RoTxn<T>, key: &[u8]) -> Result<Option<i64>, Error> {
+        self.db
+            .get(txn, key)?
+            .map(|bytes| {
+                bytes
+                    .try_into()
+                    .map(|bytes| usize::from_be_bytes(bytes) as i64)
+                    .map_err(|_| {
+                        Error::Internal(format!(
+                            "Corrupted size inside the indexes database ({} bytes instead of 8)",
+                            bytes.len()
+                        ))
+                    })
+            })
+            .transpose()
+    }
+
+    fn read_sizes<T>(&self, txn: &heed::RoTxn<T>, index: &mut Index) -> Result<(), Error> {
+        let size = self.read_size(txn, &size_key(index))?;
+        let entries_size = self.read_size(txn, &table_size_key(index, Table::Entries))?;
+        let chains_size = self.read_size(txn, &table_size_key(index, Table::Chains))?;
+        let entries_count = self.read_size(txn, &table_count_key(index, Table::Entries))?;
+        let chains_count = self.read_size(txn, &table_count_key(index, Table::Chains))?;
+
+        index.size = Some(size.unwrap_or(0));
+
+        // Indexes written before the sizes per table only have the total size
+        // until `recompute_size`.
+        let sizes_per_table =
+            entries_size.is_some() || chains_size.is_some() || size.unwrap_or(0) == 0;
+        index.entries_size = sizes_per_table.then(|| entries_size.unwrap_or(0));
+        index.chains_size = sizes_per_table.then(|| chains_size.unwrap_or(0));
+        index.entries_count = sizes_per_table.then(|| entries_count.unwrap_or(0));
+        index.chains_count = sizes_per_table.then(|| chains_count.unwrap_or(0));
+
+        Ok(())
+    }
+
+    /// Rewrite the fetched values stored in another format than the configured one. The
+    /// values are read again inside the write transaction, a value updated since the fetch is
+    /// already in the configured format.
+    fn rewrite_values_in(
+        &self,
+        txn: &mut heed::RwTxn,
+        index: &Index,
+        table: Table,
+        uids: &[Uid<UID_LENGTH>],
+    ) -> Result<(), Error> {
+        let mut size = 0;
+        for uid in uids {
+            let key = key(index, table, uid);
+            let Some(stored_value) = self.db.get(txn, &key)? else {
+                continue;
+            };
+            if self.checksums.is_current(stored_value) {
+                continue;
+            }
+
+            let value = self
+                .checksums
+                .wrap(self.checksums.verify(uid, stored_value)?.to_vec());
+            size += value.len() as i64 - stored_value.len() as i64;
+            self.db.put(txn, &key, &value)?;
+        }
+
+        self.add_to_sizes(txn, index, table, size, 0)
+    }
+
+    /// Add `added_size` bytes to the total size of the index and to the size of the table,
+    /// and `added_count` to the number of rows of the table.
+    fn add_to_sizes(
+        &self,
+        txn: &mut heed::RwTxn,
+        index: &Index,
+        table: Table,
+        added_size: i64,
+        added_count: i64,
+    ) -> Result<(), Error> {
+        for (key, added) in [
+            (size_key(index), added_size),
+            (table_size_key(index, table), added_size),
+            (table_count_key(index, table), added_count),
+        ] {
+            let value = self.read_size(txn, &key)?.unwrap_or(0);
+            self.db.put(txn, &key, &(value + added).to_be_bytes())?;
+        }
+
+        Ok(())
     }
 }
 
@@ -39,14 +719,15 @@ impl Database {
 impl IndexesDatabase for Database {
     async fn set_size(&self, index: &mut Index) -> Result<(), Error> {
         let txn = self.env.read_txn()?;
+        self.read_sizes(&txn, index)
+    }
 
-        index.size = Some(
-            self.db
-                .get(&txn, &size_key(index))?
-                .and_then(|bytes| bytes.try_into().ok())
-                .map(|bytes| usize::from_be_bytes(bytes) as i64)
-                .unwrap_or(0),
-        );
+    /// All the sizes are read inside the same transaction.
+    async fn set_sizes(&self, indexes: &mut Vec<Index>) -> Result<(), Error> {
+        let txn = self.env.read_txn()?;
+        for index in indexes {
+            self.read_sizes(&txn, index)?;
+        }
 
         Ok(())
     }
@@ -59,57 +740,65 @@ impl IndexesDatabase for Database {
     ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
         let mut uids_and_values = EncryptedTable::<UID_LENGTH>::with_capacity(uids.len());
 
+        let mut outdated_uids = Vec::new();
         let txn = self.env.read_txn()?;
         for uid in uids {
-            if let Some(value) = self.db.get(&txn, &key(index, table, &uid))? {
-                uids_and_values.insert(uid, value.to_vec());
+            if let Some(stored_value) = self.db.get(&txn, &key(index, table, &uid))? {
+                let value = self.checksums.verify(&uid, stored_value)?.to_vec();
+                if !self.checksums.is_current(stored_value) {
+                    outdated_uids.push(uid);
+                }
+                uids_and_values.insert(uid, value);
             }
         }
+        drop(txn);
+
+        self.rewrite_outdated_values(index, table, outdated_uids);
 
         Ok(uids_and_values)
     }
 
-    async fn upsert_entries(
+    /// The values are serialized from the memory map, without copying them inside an
+    /// `EncryptedTable` first (a large chains fetch was copied twice).
+    async fn fetch_serialized(
         &self,
         index: &Index,
-        data: UpsertData<UID_LENGTH>,
-    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
-        let mut rejected = EncryptedTable::<UID_LENGTH>::with_capacity(1);
+        table: Table,
+        uids: HashSet<Uid<UID_LENGTH>>,
+    ) -> Result<Zeroizing<Vec<u8>>, Error> {
+        let mut values = Vec::with_capacity(uids.len());
 
-        let mut txn = self.env.write_txn()?;
-        for (uid, (old_value, new_value)) in data {
-            let key = key(index, Table::Entries, &uid);
-
-            let existing_value = self.db.get(&txn, &key)?;
-
-            if existing_value == old_value.as_deref() {
-                if existing_value.is_none() {
-                    let size = self
-                        .db
-                        .get(&txn, &size_key(index))?
-                        .and_then(|bytes| bytes.try_into().ok())
-                        .map(|bytes| usize::from_be_bytes(bytes) as i64)
-                        .unwrap_or(0);
-
-                    self.db.put(
-                        &mut txn,
-                        &size_key(index),
-                        &(size + new_value.len() as i64).to_be_bytes(),
-                    )?;
+        let mut outdated_uids = Vec::new();
+        let txn = self.env.read_txn()?;
+        for uid in &uids {
+            if let Some(stored_value) = self.db.get(&txn, &key(index, table, uid))? {
+                values.push((uid, self.checksums.verify(uid, stored_value)?));
+                if !self.checksums.is_current(stored_value) {
+                    outdated_uids.push(*uid);
                 }
-
-                self.db.put(&mut txn, &key, &new_value)?;
-            } else if let Some(existing_value) = existing_value {
-                rejected.insert(uid, existing_value.to_vec());
-            } else {
-                log::error!(
-                    "Receive an `old_value` {old_value:?} but no existing value inside DB for UID {uid:?}."
-                );
             }
         }
-        txn.commit()?;
+        let bytes = serialize_values(&values);
+        drop(values);
+        drop(txn);
 
-        Ok(rejected)
+        self.rewrite_outdated_values(index, table, outdated_uids);
+
+        Ok(bytes)
+    }
+
+    async fn upsert_entries(
+        &self,
+        index: &Index,
+        data: UpsertData<UID_LENGTH>,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        let (reply, receiver) = oneshot::channel();
+        let write = Write::UpsertEntries {
+            index: index.clone(),
+            data,
+            reply,
+        };
+        self.write(write, receiver).await
     }
 
     async fn insert_chains(
@@ -117,24 +806,175 @@ impl IndexesDatabase for Database {
         index: &Index,
         data: EncryptedTable<UID_LENGTH>,
     ) -> Result<(), Error> {
-        let mut txn = self.env.write_txn()?;
-        let mut size = self
+        let (reply, receiver) = oneshot::channel();
+        let write = Write::InsertChains {
+            index: index.clone(),
+            data,
+            reply,
+        };
+        self.write(write, receiver).await
+    }
+
+    async fn put_values(
+        &self,
+        index: &Index,
+        table: Table,
+        data: EncryptedTable<UID_LENGTH>,
+    ) -> Result<(), Error> {
+        let (reply, receiver) = oneshot::channel();
+        let write = Write::PutValues {
+            index: index.clone(),
+            table,
+            data,
+            reply,
+        };
+        self.write(write, receiver).await
+    }
+
+    async fn fetch_all(
+        &self,
+        index: &Index,
+        table: Table,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        let txn = self.env.read_txn()?;
+        let prefix = [index.id.as_bytes(), &[table_to_prefix(table) as u8][..]].concat();
+        let mut uids_and_values = EncryptedTable::<UID_LENGTH>::default();
+
+        for result in self.db.prefix_iter(&txn, &prefix)? {
+            let (key, value) = result?;
+            let uid: [u8; UID_LENGTH] = key[prefix.len()..].try_into().map_err(|_| {
+                Error::Internal("Wrong key inside the indexes database".to_string())
+            })?;
+            let uid = Uid::from(uid);
+            let value = self.checksums.verify(&uid, value)?.to_vec();
+            uids_and_values.insert(uid, value);
+        }
+
+        Ok(uids_and_values)
+    }
+
+    async fn indexes_ids_with_data(&self) -> Result<HashSet<String>, Error> {
+        let mut ids = HashSet::new();
+        let txn = self.env.read_txn()?;
+
+        // All the keys start with the index ID, instead of reading all the keys
+        // we jump to the next ID after each ID found.
+        let mut from = schema::first_index_key();
+        while let Some(result) = self
             .db
-            .get(&txn, &size_key(index))?
-            .and_then(|bytes| bytes.try_into().ok())
-            .map(|bytes| usize::from_be_bytes(bytes) as i64)
-            .unwrap_or(0);
-        for (uid, value) in data {
-            size += value.len() as i64;
-            self.db
-                .put(&mut txn, &key(index, Table::Chains, &uid), &value)?;
+            .range(&txn, &(Bound::Included(&from[..]), Bound::Unbounded))?
+            .next()
+        {
+            let (key, _) = result?;
+            let id = &key[..INDEX_ID_LENGTH.min(key.len())];
+            ids.insert(String::from_utf8_lossy(id).to_string());
+            from = [id, &[u8::MAX]].concat();
         }
 
-        self.db
-            .put(&mut txn, &size_key(index), &size.to_be_bytes())?;
-        txn.commit()?;
+        Ok(ids)
+    }
 
-        Ok(())
+    async fn delete_index_data(&self, index_id: &str) -> Result<(), Error> {
+        let (reply, receiver) = oneshot::channel();
+        let write = Write::DeleteIndexData {
+            index_id: index_id.to_string(),
+            reply,
+        };
+        self.write(write, receiver).await
+    }
+
+    async fn recompute_size(&self, index: &Index) -> Result<(), Error> {
+        let (reply, receiver) = oneshot::channel();
+        let write = Write::RecomputeSize {
+            index: index.clone(),
+            reply,
+        };
+        self.write(write, receiver).await
+    }
+
+    async fn scrub(
+        &self,
+        index: &Index,
+        table: Table,
+        from: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<ScrubBatch, Error> {
+        let txn = self.env.read_txn()?;
+        let prefix = [index.id.as_bytes(), &[table_to_prefix(table) as u8][..]].concat();
+        let start = [&prefix[..], from.unwrap_or_default()].concat();
+        let mut batch = ScrubBatch::default();
+
+        for result in self
+            .db
+            .range(&txn, &(Bound::Included(&start[..]), Bound::Unbounded))?
+        {
+            let (key, value) = result?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+
+            let key = &key[prefix.len()..];
+            if from == Some(key) {
+                continue;
+            }
+
+            batch.check(self.checksums, key, value);
+            if batch.checked as usize >= limit {
+                batch.next = Some(key.to_vec());
+                break;
+            }
+        }
+
+        Ok(batch)
+    }
+
+    async fn append_changes(&self, index: &Index, mutations: &[Mutation]) -> Result<(), Error> {
+        let (reply, receiver) = oneshot::channel();
+        let write = Write::AppendChanges {
+            index: index.clone(),
+            mutations: mutations.to_vec(),
+            reply,
+        };
+        self.write(write, receiver).await
+    }
+
+    async fn storage_gauges(&self) -> Result<Vec<StorageGauge>, Error> {
+        Ok(vec![
+            StorageGauge::counter(
+                "findex_cloud_lmdb_write_batches_total",
+                "Write transactions of the LMDB writer thread.",
+                self.writer.stats.batches.load(Ordering::Relaxed) as f64,
+            ),
+            StorageGauge::counter(
+                "findex_cloud_lmdb_batched_writes_total",
+                "Writes applied by the LMDB writer thread.",
+                self.writer.stats.requests.load(Ordering::Relaxed) as f64,
+            ),
+        ])
+    }
+
+    async fn fetch_changes(
+        &self,
+        index: &Index,
+        since: u64,
+        limit: usize,
+    ) -> Result<Vec<Change>, Error> {
+        let start = change_key(index, since.saturating_add(1));
+        let end = change_key(index, u64::MAX);
+        let range = (Bound::Included(&start[..]), Bound::Included(&end[..]));
+
+        let txn = self.env.read_txn()?;
+        let mut changes = Vec::with_capacity(limit);
+        for result in self.db.range(&txn, &range)?.take(limit) {
+            let (key, value) = result?;
+            let cursor = key[key.len() - 8..]
+                .try_into()
+                .map(u64::from_be_bytes)
+                .map_err(|_| Error::Internal("Wrong key inside the changes log".to_string()))?;
+            changes.push(Change::deserialize_value(cursor, value)?);
+        }
+
+        Ok(changes)
     }
 }
 
@@ -144,6 +984,19 @@ pub(crate) enum Prefix {
     Entries,
     Chains,
     Size,
+    Changes,
+    ChangesCursor,
+    EntriesSize,
+    ChainsSize,
+    EntriesCount,
+    ChainsCount,
+}
+
+fn log_rewrite_error(index: &Index, err: &Error) {
+    log::warn!(
+        "Cannot rewrite the values of index {} in the current format ({err})",
+        index.id
+    );
 }
 
 fn table_to_prefix(table: Table) -> Prefix {
@@ -162,6 +1015,90 @@ fn key(index: &Index, table: Table, uid: &Uid<UID_LENGTH>) -> Vec<u8> {
     .concat()
 }
 
+/// The prefix of the size of the table if `key` is the key of a value
+fn value_table_size_prefix(key: &[u8]) -> Option<Prefix> {
+    if key.len() != INDEX_ID_LENGTH + 1 + UID_LENGTH {
+        return None;
+    }
+
+    match key[INDEX_ID_LENGTH] {
+        prefix if prefix == Prefix::Entries as u8 => Some(Prefix::EntriesSize),
+        prefix if prefix == Prefix::Chains as u8 => Some(Prefix::ChainsSize),
+        _ => None,
+    }
+}
+
 fn size_key(index: &Index) -> Vec<u8> {
     [(index.id.as_bytes()), &[Prefix::Size as u8][..]].concat()
 }
+
+fn table_size_key(index: &Index, table: Table) -> Vec<u8> {
+    let prefix = match table {
+        Table::Entries => Prefix::EntriesSize,
+        Table::Chains => Prefix::ChainsSize,
+    };
+
+    [(index.id.as_bytes()), &[prefix as u8][..]].concat()
+}
+
+fn table_count_key(index: &Index, table: Table) -> Vec<u8> {
+    let prefix = match table {
+        Table::Entries => Prefix::EntriesCount,
+        Table::Chains => Prefix::ChainsCount,
+    };
+
+    [(index.id.as_bytes()), &[prefix as u8][..]].concat()
+}
+
+fn change_key(index: &Index, cursor: u64) -> Vec<u8> {
+    [
+        (index.id.as_bytes()),
+        &[Prefix::Changes as u8][..],
+        &cursor.to_be_bytes(),
+    ]
+    .concat()
+}
+
+fn changes_cursor_key(index: &Index) -> Vec<u8> {
+    [(index.id.as_bytes()), &[Prefix::ChangesCursor as u8][..]].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP_SIZE: usize = 16 * 1024 * 1024;
+
+    #[actix_web::test]
+    async fn upsert_old_value_of_missing_uid() {
+        // Without and with batching
+        for batch_window in [Duration::ZERO, Duration::from_millis(1)] {
+            let directory = tempfile::tempdir().unwrap();
+            let store = Store::open(directory.path(), MAP_SIZE, Checksums::Crc32);
+            let writer = store.start_writer(batch_window);
+            let database = Database { store, writer };
+
+            let index = Index::for_tests(&"a".repeat(INDEX_ID_LENGTH));
+            let uid = Uid::from([1; UID_LENGTH]);
+            let mut old_values = EncryptedTable::<UID_LENGTH>::with_capacity(1);
+            old_values.insert(uid, b"old value".to_vec());
+            let mut new_values = EncryptedTable::<UID_LENGTH>::with_capacity(1);
+            new_values.insert(uid, b"new value".to_vec());
+
+            let result = database
+                .upsert_entries(&index, UpsertData::new(&old_values, new_values))
+                .await;
+            assert!(
+                matches!(result, Err(Error::EntryNotFound(_))),
+                "{batch_window:?}: {result:?}"
+            );
+
+            // Nothing is written
+            let fetched = database
+                .fetch(&index, Table::Entries, HashSet::from([uid]))
+                .await
+                .unwrap();
+            assert!(fetched.is_empty(), "{batch_window:?}");
+        }
+    }
+}
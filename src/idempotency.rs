@@ -0,0 +1,236 @@
+/// Idempotency keys for `upsert_entries` and `insert_chains`.
+///
+/// Clients can send an `Idempotency-Key` header (1 to 255 visible ASCII characters). The
+/// response of the first successful request with this key is kept for
+/// `IDEMPOTENCY_WINDOW_SECONDS` (300 by default, `0` disables the idempotency keys) and sent
+/// again, with an `Idempotent-Replayed: true` header, to the retries with the same key on the
+/// same index and endpoint. The mutations are not applied twice (nor published, logged in the
+/// changes log or counted for the compactions). A retry with the same key but another body
+/// is refused with a `422 Unprocessable Entity`.
+///
+/// At most `IDEMPOTENCY_MAX_KEYS` (10 000 by default) responses are kept, the oldest ones are
+/// evicted first. Responses are kept in memory: they are lost on restart and not shared between
+/// instances. A retry received while the first request is still running is not detected.
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    env,
+    future::{ready, Ready},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    dev::Payload,
+    web::{Bytes, Data},
+    FromRequest, HttpRequest, HttpResponse,
+};
+
+use crate::errors::Error;
+
+const DEFAULT_IDEMPOTENCY_WINDOW_SECONDS: u64 = 300;
+const DEFAULT_IDEMPOTENCY_MAX_KEYS: usize = 10_000;
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+
+pub(crate) struct IdempotencyCache {
+    window: Duration,
+    max_keys: usize,
+    responses: Mutex<Responses>,
+}
+
+#[derive(Default)]
+struct Responses {
+    by_key: HashMap<String, CachedResponse>,
+    /// Keys in insertion order, to evict the oldest responses
+    order: VecDeque<String>,
+}
+
+struct CachedResponse {
+    request_hash: u64,
+    content_type: &'static str,
+    body: Bytes,
+    expires_at: Instant,
+}
+
+impl IdempotencyCache {
+    pub(crate) fn from_env() -> Option<Data<IdempotencyCache>> {
+        let window_seconds = env::var("IDEMPOTENCY_WINDOW_SECONDS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_IDEMPOTENCY_WINDOW_SECONDS);
+
+        let max_keys = env::var("IDEMPOTENCY_MAX_KEYS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_IDEMPOTENCY_MAX_KEYS);
+
+        (window_seconds > 0 && max_keys > 0).then(|| {
+            Data::new(IdempotencyCache {
+                window: Duration::from_secs(window_seconds),
+                max_keys,
+                responses: Mutex::new(Responses::default()),
+            })
+        })
+    }
+
+    fn get(&self, key: &str, request_hash: u64) -> Result<Option<HttpResponse>, Error> {
+        let responses = self.lock()?;
+
+        let Some(cached) = responses
+            .by_key
+            .get(key)
+            .filter(|cached| cached.expires_at > Instant::now())
+        else {
+            return Ok(None);
+        };
+
+        if cached.request_hash != request_hash {
+            return Err(Error::IdempotencyKeyReused);
+        }
+
+        Ok(Some(
+            HttpResponse::Ok()
+                .content_type(cached.content_type)
+                .insert_header(("Idempotent-Replayed", "true"))
+                .body(cached.body.clone()),
+        ))
+    }
+
+    fn insert(&self, key: String, response: CachedResponse) -> Result<(), Error> {
+        let mut responses = self.lock()?;
+        let now = Instant::now();
+
+        // The keys are inserted in order so the expired ones are at the front.
+        while let Some(oldest) = responses.order.front() {
+            let expired = responses
+                .by_key
+                .get(oldest)
+                .map_or(true, |cached| cached.expires_at <= now);
+            if !expired && responses.by_key.len() < self.max_keys {
+                break;
+            }
+
+            if let Some(oldest) = responses.order.pop_front() {
+                responses.by_key.remove(&oldest);
+            }
+        }
+
+        if responses.by_key.insert(key.clone(), response).is_none() {
+            responses.order.push_back(key);
+        }
+
+        Ok(())
+    }
+
+    /// Free the expired responses (`cache_cleanup` job), returns the number of removed responses.
+    pub(crate) fn remove_expired(&self) -> Result<usize, Error> {
+        let mut responses = self.lock()?;
+        let now = Instant::now();
+        let mut removed = 0;
+
+        while let Some(oldest) = responses.order.front() {
+            let expired = responses
+                .by_key
+                .get(oldest)
+                .map_or(true, |cached| cached.expires_at <= now);
+            if !expired {
+                break;
+            }
+
+            if let Some(oldest) = responses.order.pop_front() {
+                responses.by_key.remove(&oldest);
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, Responses>, Error> {
+        self.responses
+            .lock()
+            .map_err(|_| Error::Internal("Idempotency cache lock is poisoned".to_string()))
+    }
+}
+
+/// Extractor of the `Idempotency-Key` header, the key is ignored if the idempotency
+/// keys are disabled.
+pub(crate) struct Idempotency {
+    cache: Option<Data<IdempotencyCache>>,
+    key: Option<String>,
+    request_hash: u64,
+}
+
+impl FromRequest for Idempotency {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let cache = req.app_data::<Data<IdempotencyCache>>().cloned();
+
+        let key = match req.headers().get("Idempotency-Key") {
+            Some(_) if cache.is_none() => None,
+            Some(value) => match value.to_str() {
+                Ok(key)
+                    if !key.is_empty()
+                        && key.len() <= MAX_IDEMPOTENCY_KEY_LENGTH
+                        && key.bytes().all(|byte| byte.is_ascii_graphic()) =>
+                {
+                    Some(key.to_string())
+                }
+                _ => {
+                    return ready(Err(Error::BadRequest(format!(
+                        "`Idempotency-Key` must contain 1 to {MAX_IDEMPOTENCY_KEY_LENGTH} visible ASCII characters"
+                    ))))
+                }
+            },
+            None => None,
+        };
+
+        ready(Ok(Idempotency {
+            cache,
+            key,
+            request_hash: 0,
+        }))
+    }
+}
+
+impl Idempotency {
+    /// Returns the response to send again if a request with the same key was already
+    /// handled. `body` is the verified body of the request.
+    pub(crate) fn start(
+        &mut self,
+        index_id: &str,
+        endpoint: &str,
+        body: &[u8],
+    ) -> Result<Option<HttpResponse>, Error> {
+        let (Some(cache), Some(key)) = (&self.cache, &mut self.key) else {
+            return Ok(None);
+        };
+
+        *key = format!("{index_id}/{endpoint}/{key}");
+
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        self.request_hash = hasher.finish();
+
+        cache.get(key, self.request_hash)
+    }
+
+    /// Keep the response of a successful request for the retries.
+    pub(crate) fn finish(self, content_type: &'static str, body: Bytes) -> Result<(), Error> {
+        let (Some(cache), Some(key)) = (self.cache, self.key) else {
+            return Ok(());
+        };
+
+        cache.insert(
+            key,
+            CachedResponse {
+                request_hash: self.request_hash,
+                content_type,
+                body,
+                expires_at: Instant::now() + cache.window,
+            },
+        )
+    }
+}
@@ -0,0 +1,224 @@
+/// Import of a local cloudproof_findex SQLite database (`FindexSqlite`) inside an index.
+///
+/// `POST /admin/indexes/{id}/import/sqlite` takes the SQLite file as the request body,
+/// saves it to a temporary file and copies its `entry_table` and `chain_table` into the
+/// index by batches of `IMPORT_BATCH_SIZE` rows (1 000 by default). Existing values with
+/// the same UIDs are overwritten. The index must have been created with the same keys
+/// as the local index to be usable by the clients.
+///
+/// The response is streamed as JSON lines: one progress line per batch and a last line
+/// with `done: true` (or an `error`), so long imports can be followed by the client.
+use std::{env, io::Write, path::PathBuf};
+
+use actix_web::{
+    post,
+    web::{self, Bytes, Data, Payload},
+    HttpResponse,
+};
+use cosmian_findex::{parameters::UID_LENGTH, EncryptedTable, Uid};
+use futures::{channel::mpsc, StreamExt, TryStreamExt};
+use serde::Serialize;
+use sqlx::{sqlite::SqliteConnectOptions, ConnectOptions, SqliteConnection};
+
+#[cfg(feature = "replication")]
+use crate::replication::{Shipper, Standby};
+use crate::{
+    admin::Admin,
+    config,
+    core::{Index, IndexesDatabase, Table},
+    errors::{Error, ResponseBytes},
+    maintenance::Maintenance,
+};
+
+const DEFAULT_IMPORT_BATCH_SIZE: usize = 1_000;
+
+#[derive(Serialize)]
+struct ImportProgress {
+    table: Option<Table>,
+    imported: u64,
+    total: u64,
+    done: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[post("/admin/indexes/{id}/import/sqlite")]
+pub(crate) async fn import_index_from_sqlite(
+    _admin: Admin,
+    index: Index,
+    mut payload: Payload,
+    indexes_db: Data<dyn IndexesDatabase>,
+    maintenance: Data<Maintenance>,
+    #[cfg(feature = "replication")] shipper: Option<Data<Shipper>>,
+    #[cfg(feature = "replication")] standby: Option<Data<Standby>>,
+) -> ResponseBytes {
+    maintenance.check_index(&index.id)?;
+    #[cfg(feature = "replication")]
+    Standby::check_writable(&standby)?;
+    #[cfg(feature = "replication")]
+    Shipper::check_writable(&shipper)?;
+
+    let path = config::data_dir().join(format!(
+        "import_{}_{}.sqlite",
+        index.id,
+        chrono::Utc::now().timestamp_nanos()
+    ));
+    config::prepare_parent_directory(&path);
+
+    // The upload is written chunk by chunk to never hold the whole file in memory.
+    let uploaded = async {
+        let mut file = open_file(path.clone()).await?;
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|err| Error::BadRequest(err.to_string()))?;
+            file = web::block(move || file.write_all(&chunk).map(|_| file))
+                .await
+                .map_err(|err| Error::Internal(err.to_string()))?
+                .map_err(|err| {
+                    Error::Internal(format!("Cannot write the SQLite upload ({err})"))
+                })?;
+        }
+
+        Ok::<_, Error>(())
+    }
+    .await;
+
+    if let Err(err) = uploaded {
+        remove_file(path).await;
+        return Err(err);
+    }
+
+    log::warn!("Importing a SQLite database inside index {}", index.id);
+
+    let (sender, receiver) = mpsc::unbounded();
+
+    actix_web::rt::spawn(async move {
+        let result = import(&path, &index, &indexes_db, &sender).await;
+        remove_file(path).await;
+
+        let last_line = match result {
+            Ok(()) => {
+                log::info!("SQLite database imported inside index {}", index.id);
+                ImportProgress {
+                    table: None,
+                    imported: 0,
+                    total: 0,
+                    done: true,
+                    error: None,
+                }
+            }
+            Err(err) => {
+                log::error!(
+                    "Cannot import SQLite database inside index {} ({err:?})",
+                    index.id
+                );
+                ImportProgress {
+                    table: None,
+                    imported: 0,
+                    total: 0,
+                    done: true,
+                    error: Some(err.to_string()),
+                }
+            }
+        };
+
+        send(&sender, &last_line);
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(receiver.map(Ok::<_, std::convert::Infallible>)))
+}
+
+async fn import(
+    path: &std::path::Path,
+    index: &Index,
+    indexes_db: &Data<dyn IndexesDatabase>,
+    sender: &mpsc::UnboundedSender<Bytes>,
+) -> Result<(), Error> {
+    let batch_size = env::var("IMPORT_BATCH_SIZE")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_IMPORT_BATCH_SIZE);
+
+    let mut db = SqliteConnectOptions::new()
+        .filename(path)
+        .read_only(true)
+        .connect()
+        .await?;
+
+    for (table_name, table) in [
+        ("entry_table", Table::Entries),
+        ("chain_table", Table::Chains),
+    ] {
+        let (total,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(*) FROM {table_name}"))
+            .fetch_one(&mut db)
+            .await?;
+        let total = total as u64;
+        let mut imported = 0;
+
+        let query = format!("SELECT uid, value FROM {table_name}");
+        let mut batches = rows(&mut db, &query).try_chunks(batch_size);
+        while let Some(batch) = batches.next().await {
+            let batch = batch.map_err(|err| err.1)?;
+            imported += batch.len() as u64;
+
+            let mut values = EncryptedTable::<UID_LENGTH>::with_capacity(batch.len());
+            for (uid, value) in batch {
+                values.insert(uid, value);
+            }
+            indexes_db.put_values(index, table, values).await?;
+
+            send(
+                sender,
+                &ImportProgress {
+                    table: Some(table),
+                    imported,
+                    total,
+                    done: false,
+                    error: None,
+                },
+            );
+        }
+    }
+
+    match indexes_db.recompute_size(index).await {
+        Ok(()) | Err(Error::Unsupported(_)) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+fn rows<'a>(
+    db: &'a mut SqliteConnection,
+    query: &'a str,
+) -> impl futures::Stream<Item = Result<(Uid<UID_LENGTH>, Vec<u8>), Error>> + 'a {
+    sqlx::query_as::<_, (Vec<u8>, Vec<u8>)>(query)
+        .fetch(db)
+        .map(|row| {
+            let (uid, value) = row?;
+            let uid: [u8; UID_LENGTH] = uid.try_into().map_err(|_| Error::WrongEncoding)?;
+
+            Ok((Uid::from(uid), value))
+        })
+}
+
+/// A closed channel only means the client went away, the import continues.
+fn send(sender: &mpsc::UnboundedSender<Bytes>, progress: &ImportProgress) {
+    if let Ok(mut line) = serde_json::to_vec(progress) {
+        line.push(b'\n');
+        let _ = sender.unbounded_send(Bytes::from(line));
+    }
+}
+
+async fn open_file(path: PathBuf) -> Result<std::fs::File, Error> {
+    web::block(move || std::fs::File::create(path))
+        .await
+        .map_err(|err| Error::Internal(err.to_string()))?
+        .map_err(|err| Error::Internal(format!("Cannot create the SQLite upload ({err})")))
+}
+
+async fn remove_file(path: PathBuf) {
+    let display = path.display().to_string();
+    if let Ok(Err(err)) = web::block(move || std::fs::remove_file(path)).await {
+        log::error!("Cannot remove the SQLite upload {display} ({err})");
+    }
+}
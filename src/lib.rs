@@ -0,0 +1,1705 @@
+#![feature(iter_next_chunk)]
+#![feature(iter_array_chunks)]
+#![cfg_attr(test, feature(test))]
+
+#[cfg(feature = "log_requests")]
+use crate::requests_log::RequestsLog;
+#[cfg(feature = "uid_sampling")]
+use crate::uid_sampling::UidSampler;
+
+use std::collections::HashSet;
+use std::env;
+use std::sync::Arc;
+
+use crate::access_tokens::{AccessToken, AccessTokens};
+use crate::admin::AdminApiKey;
+use crate::alerts::SignatureAlerts;
+use crate::archive::archive_store_from_env;
+use crate::changes::ChangesLog;
+use crate::compaction::Compactions;
+use crate::core::{wipe_table, IndexesDatabase, MetadataDatabase, NewIndex, Table};
+use crate::counters::{check_signature_and_count, count, Counter, RequestCounters};
+use crate::database_timeout::{IndexesDatabaseWithTimeout, MetadataDatabaseWithTimeout};
+use crate::deprecation::Deprecations;
+use crate::errors::Error;
+use crate::events::{publish_in_background, EventBus, Mutation, Operation};
+use crate::export::ExportRateLimiter;
+use crate::idempotency::{Idempotency, IdempotencyCache};
+use crate::limits::Limits;
+use crate::listeners::Listeners;
+use crate::maintenance::Maintenance;
+use crate::metrics::Metrics;
+use crate::protocol::{Protocol, ProtocolVersions};
+use crate::quotas::Quotas;
+use crate::request_logging::RequestLogging;
+use crate::retention::Retention;
+use crate::scheduler::JobContext;
+use crate::scrub::Scrubber;
+use crate::settings::Settings;
+use crate::timeouts::ServerTimeouts;
+use crate::timing::{ServerTiming, Timer};
+use crate::usage::Usage;
+use actix_web::dev::Service;
+use actix_web::web::PayloadConfig;
+
+use crate::{
+    core::{Index, MetadataCache},
+    errors::{Response, ResponseBytes},
+};
+use actix_cors::Cors;
+use actix_files as fs;
+use actix_web::{
+    delete, get,
+    http::header,
+    middleware::Logger,
+    post,
+    web::{scope, Bytes, Data, Json, Path, Query, ServiceConfig},
+    App, HttpRequest, HttpResponse, HttpServer,
+};
+use cloudproof_findex::{
+    cloud::{INDEX_ID_LENGTH, SIGNATURE_SEED_LENGTH},
+    ser_de::deserialize_set,
+};
+use cosmian_crypto_core::bytes_ser_de::Serializable;
+use cosmian_crypto_core::CsRng;
+use cosmian_findex::{parameters::UID_LENGTH, CoreError, EncryptedTable, Uid, UpsertData};
+use env_logger::Env;
+use rand::{distributions::Alphanumeric, Rng, RngCore, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::path::Path as FsPath;
+use zeroize::Zeroizing;
+
+mod access_tokens;
+mod admin;
+mod alerts;
+mod archive;
+mod backoff;
+mod backup;
+mod bulk_delete;
+mod bulk_load;
+mod cache;
+mod changes;
+mod check;
+mod compaction;
+mod conditional;
+mod config;
+mod core;
+mod counters;
+mod database_timeout;
+mod deprecation;
+mod errors;
+mod events;
+mod export;
+mod idempotency;
+mod limits;
+mod listeners;
+mod maintenance;
+mod metrics;
+pub mod plugin;
+mod protocol;
+mod quotas;
+mod replica;
+mod request_logging;
+mod retention;
+mod scheduler;
+mod scrub;
+mod settings;
+mod split;
+mod syslog;
+mod systemd;
+mod timeouts;
+mod timing;
+mod usage;
+#[cfg(all(windows, feature = "windows_service"))]
+mod windows;
+
+#[cfg(feature = "log_requests")]
+mod debug_logs;
+#[cfg(feature = "log_requests")]
+mod replay;
+#[cfg(feature = "log_requests")]
+mod requests_log;
+
+#[cfg(feature = "signature_debug")]
+mod signature_debug;
+#[cfg(feature = "uid_sampling")]
+mod uid_sampling;
+
+#[cfg(feature = "sqlite")]
+mod import;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+#[cfg(any(feature = "rocksdb", feature = "lmmd"))]
+mod checksum;
+#[cfg(feature = "lmmd")]
+mod heed;
+#[cfg(any(feature = "rocksdb", feature = "lmmd"))]
+mod schema;
+
+#[cfg(feature = "rocksdb")]
+mod rocksdb;
+
+#[cfg(feature = "dynamodb")]
+mod dynamodb;
+
+#[cfg(feature = "remote")]
+mod remote;
+#[cfg(feature = "replication")]
+mod replication;
+#[cfg(feature = "smtp")]
+mod smtp;
+#[cfg(feature = "webauthn")]
+mod webauthn;
+#[cfg(feature = "write_behind")]
+mod write_behind;
+
+#[cfg(feature = "replication")]
+use crate::replication::{Record, Shipper, Standby};
+
+#[derive(Deserialize)]
+struct IndexesQuery {
+    /// `csv` or `json`, instead of the `Accept` header
+    format: Option<String>,
+}
+
+/// JSON by default, CSV with `?format=csv` or `Accept: text/csv` (for the spreadsheets).
+#[get("/indexes")]
+async fn get_indexes(
+    req: HttpRequest,
+    query: Query<IndexesQuery>,
+    metadata_db: Data<dyn MetadataDatabase>,
+    indexes_db: Data<dyn IndexesDatabase>,
+) -> ResponseBytes {
+    let csv = match query.format.as_deref() {
+        Some("csv") => true,
+        Some("json") => false,
+        Some(format) => {
+            return Err(Error::BadRequest(format!(
+                "Unknown format `{format}` (please use `csv` or `json`)"
+            )))
+        }
+        None => req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .map_or(false, |accept| accept.contains("text/csv")),
+    };
+
+    let mut indexes = metadata_db.get_indexes().await?;
+    indexes_db.set_sizes(&mut indexes).await?;
+
+    if csv {
+        let mut response = HttpResponse::Ok();
+        response
+            .content_type("text/csv; charset=utf-8")
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"indexes.csv\"",
+            ));
+        Ok(conditional::respond(
+            &req,
+            response,
+            indexes_csv(&indexes).into_bytes(),
+        ))
+    } else {
+        let mut response = HttpResponse::Ok();
+        response.content_type(header::ContentType::json());
+        Ok(conditional::respond(
+            &req,
+            response,
+            serde_json::to_vec(&indexes)?,
+        ))
+    }
+}
+
+/// One line per index, the sizes and counts are empty when not available.
+fn indexes_csv(indexes: &[Index]) -> String {
+    let optional = |value: Option<i64>| value.map(|value| value.to_string()).unwrap_or_default();
+
+    let mut csv = String::from(
+        "id,name,size,entries_size,chains_size,entries_count,chains_count,created_at\r\n",
+    );
+    for index in indexes {
+        let fields = [
+            csv_field(&index.id),
+            csv_field(&index.name),
+            optional(index.size),
+            optional(index.entries_size),
+            optional(index.chains_size),
+            optional(index.entries_count),
+            optional(index.chains_count),
+            index.created_at.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+        ];
+        csv.push_str(&fields.join(","));
+        csv.push_str("\r\n");
+    }
+
+    csv
+}
+
+/// Quote the field if needed (RFC 4180). A field starting like a formula is prefixed with a
+/// `'` so a spreadsheet doesn't evaluate it.
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{value}")
+    } else {
+        value.to_string()
+    };
+
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+#[derive(Deserialize)]
+struct PostNewIndex {
+    /// Deterministic ID provided by the client (for example by infrastructure-as-code
+    /// tooling), a random ID is generated if `None`.
+    id: Option<String>,
+    name: String,
+    /// Seeds provided by the client (for example derived inside its KMS),
+    /// the missing ones are randomly generated.
+    fetch_entries_key: Option<Vec<u8>>,
+    fetch_chains_key: Option<Vec<u8>>,
+    upsert_entries_key: Option<Vec<u8>>,
+    insert_chains_key: Option<Vec<u8>>,
+    /// Expiration of the entries and chains after their last write,
+    /// only supported with DynamoDB.
+    ttl_seconds: Option<i64>,
+}
+
+#[post("/indexes")]
+async fn post_indexes(
+    body: Json<PostNewIndex>,
+    metadata_db: Data<dyn MetadataDatabase>,
+    indexes_db: Data<dyn IndexesDatabase>,
+    maintenance: Data<Maintenance>,
+    #[cfg(feature = "replication")] shipper: Option<Data<Shipper>>,
+    #[cfg(feature = "replication")] standby: Option<Data<Standby>>,
+) -> Response<Index> {
+    maintenance.check_server()?;
+    #[cfg(feature = "replication")]
+    Standby::check_writable(&standby)?;
+    #[cfg(feature = "replication")]
+    Shipper::check_writable(&shipper)?;
+
+    let mut rng = CsRng::from_entropy();
+    let index = metadata_db
+        .create_index(check_ttl_support(
+            new_index(body.into_inner(), &mut rng),
+            &indexes_db,
+        )?)
+        .await?;
+
+    #[cfg(feature = "replication")]
+    replication::ship(&shipper, || Record::put_index(&index));
+
+    Ok(Json(index))
+}
+
+/// Generate the missing ID and seeds
+fn new_index(body: PostNewIndex, rng: &mut CsRng) -> NewIndex {
+    let mut key_or_random = |key: Option<Vec<u8>>| {
+        key.unwrap_or_else(|| {
+            let mut key = vec![0; SIGNATURE_SEED_LENGTH];
+            rng.fill_bytes(&mut key);
+            key
+        })
+    };
+
+    let fetch_entries_key = key_or_random(body.fetch_entries_key);
+    let fetch_chains_key = key_or_random(body.fetch_chains_key);
+    let upsert_entries_key = key_or_random(body.upsert_entries_key);
+    let insert_chains_key = key_or_random(body.insert_chains_key);
+
+    let id: String = body.id.unwrap_or_else(|| {
+        rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(INDEX_ID_LENGTH)
+            .map(char::from)
+            .collect()
+    });
+
+    NewIndex {
+        id,
+        name: body.name,
+        fetch_entries_key,
+        fetch_chains_key,
+        upsert_entries_key,
+        insert_chains_key,
+        ttl_seconds: body.ttl_seconds,
+    }
+}
+
+/// Refuse the indexes with a TTL if the values wouldn't expire.
+#[allow(clippy::result_large_err)]
+fn check_ttl_support(
+    new_index: NewIndex,
+    indexes_db: &Data<dyn IndexesDatabase>,
+) -> Result<NewIndex, Error> {
+    if new_index.ttl_seconds.is_some() && !indexes_db.supports_ttl() {
+        return Err(Error::Unsupported(
+            "This indexes database doesn't support the TTL of the indexes".to_string(),
+        ));
+    }
+
+    Ok(new_index)
+}
+
+/// DynamoDB transactions are limited to 100 items
+const MAX_INDEXES_PER_BATCH: usize = 100;
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum BatchItemStatus {
+    Created,
+    Invalid,
+    Conflict,
+    /// Valid but not created because another index of the batch failed
+    NotCreated,
+}
+
+#[derive(Serialize)]
+struct BatchItemResult {
+    status: BatchItemStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<Index>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Create all the indexes or none of them. The response contains one result per
+/// index, in the same order as the request.
+#[post("/indexes/batch")]
+async fn post_indexes_batch(
+    body: Json<Vec<PostNewIndex>>,
+    metadata_db: Data<dyn MetadataDatabase>,
+    indexes_db: Data<dyn IndexesDatabase>,
+    maintenance: Data<Maintenance>,
+    #[cfg(feature = "replication")] shipper: Option<Data<Shipper>>,
+    #[cfg(feature = "replication")] standby: Option<Data<Standby>>,
+) -> ResponseBytes {
+    maintenance.check_server()?;
+    #[cfg(feature = "replication")]
+    Standby::check_writable(&standby)?;
+    #[cfg(feature = "replication")]
+    Shipper::check_writable(&shipper)?;
+
+    if body.len() > MAX_INDEXES_PER_BATCH {
+        return Err(Error::BadRequest(format!(
+            "Cannot create {} indexes in a single batch, the maximum is {MAX_INDEXES_PER_BATCH}",
+            body.len()
+        )));
+    }
+
+    let mut rng = CsRng::from_entropy();
+    let validations: Vec<_> = body
+        .into_inner()
+        .into_iter()
+        .map(|body| check_ttl_support(new_index(body, &mut rng), &indexes_db)?.validate())
+        .collect();
+
+    let mut ids = HashSet::with_capacity(validations.len());
+    let duplicates: Vec<_> = validations
+        .iter()
+        .map(|validation| match validation {
+            Ok(new_index) => !ids.insert(new_index.id.clone()),
+            Err(_) => false,
+        })
+        .collect();
+
+    if validations.iter().any(Result::is_err) || duplicates.contains(&true) {
+        let results: Vec<_> = validations
+            .into_iter()
+            .zip(duplicates)
+            .map(|(validation, duplicate)| match validation {
+                Err(err) => BatchItemResult {
+                    status: BatchItemStatus::Invalid,
+                    index: None,
+                    error: Some(err.to_string()),
+                },
+                Ok(new_index) if duplicate => BatchItemResult {
+                    status: BatchItemStatus::Invalid,
+                    index: None,
+                    error: Some(format!("ID {} is used twice in the batch", new_index.id)),
+                },
+                Ok(_) => BatchItemResult {
+                    status: BatchItemStatus::NotCreated,
+                    index: None,
+                    error: None,
+                },
+            })
+            .collect();
+
+        return Ok(HttpResponse::UnprocessableEntity().json(results));
+    }
+
+    let new_indexes: Vec<_> = validations.into_iter().flatten().collect();
+    let ids: Vec<_> = new_indexes
+        .iter()
+        .map(|new_index| new_index.id.clone())
+        .collect();
+
+    match metadata_db.create_indexes(new_indexes).await {
+        Ok(indexes) => {
+            #[cfg(feature = "replication")]
+            for index in &indexes {
+                replication::ship(&shipper, || Record::put_index(index));
+            }
+
+            let results: Vec<_> = indexes
+                .into_iter()
+                .map(|index| BatchItemResult {
+                    status: BatchItemStatus::Created,
+                    index: Some(index),
+                    error: None,
+                })
+                .collect();
+
+            Ok(HttpResponse::Ok().json(results))
+        }
+        Err(Error::IndexAlreadyExists(conflicting_id)) => {
+            let results: Vec<_> = ids
+                .into_iter()
+                .map(|id| {
+                    if id == conflicting_id {
+                        BatchItemResult {
+                            status: BatchItemStatus::Conflict,
+                            index: None,
+                            error: Some(Error::IndexAlreadyExists(id).to_string()),
+                        }
+                    } else {
+                        BatchItemResult {
+                            status: BatchItemStatus::NotCreated,
+                            index: None,
+                            error: None,
+                        }
+                    }
+                })
+                .collect();
+
+            Ok(HttpResponse::Conflict().json(results))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+#[get("/indexes/{id}")]
+async fn get_index(
+    req: HttpRequest,
+    id: Path<String>,
+    metadata_cache: Data<MetadataCache>,
+    metadata_db: Data<dyn MetadataDatabase>,
+    indexes_db: Data<dyn IndexesDatabase>,
+) -> ResponseBytes {
+    // Not read from the cache to return the current request counters
+    // and activity (see `counters.rs` and `retention.rs`)
+    let index = metadata_db.get_index(&id).await?;
+
+    if let Some(mut index) = index {
+        metadata_cache.insert(index.clone());
+        indexes_db.set_size(&mut index).await?;
+        let mut response = HttpResponse::Ok();
+        response.content_type(header::ContentType::json());
+        Ok(conditional::respond(
+            &req,
+            response,
+            serde_json::to_vec(&index)?,
+        ))
+    } else {
+        Err(Error::BadRequest(format!("Unknown index for ID {id}")))
+    }
+}
+
+#[delete("/indexes/{id}")]
+async fn delete_index(
+    // Here we take only the ID of the index because we don't need the full index info.
+    id: Path<String>,
+    metadata_cache: Data<MetadataCache>,
+    metadata_db: Data<dyn MetadataDatabase>,
+    indexes_db: Data<dyn IndexesDatabase>,
+    maintenance: Data<Maintenance>,
+    #[cfg(feature = "replication")] shipper: Option<Data<Shipper>>,
+    #[cfg(feature = "replication")] standby: Option<Data<Standby>>,
+) -> Response<()> {
+    maintenance.check_index(&id)?;
+    #[cfg(feature = "replication")]
+    Standby::check_writable(&standby)?;
+    #[cfg(feature = "replication")]
+    Shipper::check_writable(&shipper)?;
+
+    metadata_db.delete_index(&id).await?;
+    metadata_cache.remove(&id);
+
+    // Deleting the data can be long (a full scan with DynamoDB). The index is already
+    // unreachable, if it fails the data is orphaned (see `check.rs`).
+    let id_to_purge = id.to_string();
+    actix_web::rt::spawn(async move {
+        match indexes_db.delete_index_data(&id_to_purge).await {
+            Ok(()) | Err(Error::Unsupported(_)) => {}
+            Err(err) => {
+                log::error!("Cannot delete the data of index {id_to_purge} ({err:?})")
+            }
+        }
+    });
+
+    #[cfg(feature = "replication")]
+    replication::ship(&shipper, || Record::DeleteIndex { id: id.to_string() });
+
+    Ok(Json(()))
+}
+
+#[post("/indexes/{id}/fetch_entries")]
+#[allow(clippy::too_many_arguments)]
+async fn fetch_entries(
+    index: Index,
+    access_token: AccessToken,
+    (Settings(settings), protocol): (Settings, Protocol),
+    bytes: Bytes,
+    indexes: Data<dyn IndexesDatabase>,
+    limits: Data<Limits>,
+    server_timing: Option<Data<ServerTiming>>,
+    retention: Option<Data<Retention>>,
+    request_counters: Option<Data<RequestCounters>>,
+    request_logging: Data<RequestLogging>,
+    #[cfg(feature = "log_requests")] requests_log: Data<RequestsLog>,
+    #[cfg(feature = "uid_sampling")] uid_sampler: Data<UidSampler>,
+) -> ResponseBytes {
+    limits.check_request(&index.id, &settings, bytes.len())?;
+
+    let mut timer = Timer::start();
+
+    let bytes = count(
+        &request_counters,
+        &index.id,
+        Counter::Fetches,
+        access_token.check_fetch_body(bytes, &index, &index.fetch_entries_key),
+    )?;
+    timer.mark("signature");
+    Retention::record_activity(&retention, &index.id);
+
+    let uids = deserialize_set::<CoreError, Uid<UID_LENGTH>>(&bytes)?;
+    timer.mark("deserialization");
+
+    limits.check_uids_count(&settings, uids.len())?;
+
+    #[cfg(feature = "uid_sampling")]
+    uid_sampler.record(&index.id, Table::Entries, &uids);
+
+    #[cfg(feature = "log_requests")]
+    let cloned_uids = uids.clone();
+    let logged_uids = request_logging.is_enabled(&index.id).then(|| uids.clone());
+
+    let mut uids_and_values =
+        settings::fetch(&indexes, &index, &settings, Table::Entries, uids).await?;
+    timer.mark("backend");
+
+    limits.check_fetch_memory(bytes.len(), &uids_and_values)?;
+
+    if let Some(uids) = logged_uids {
+        request_logging.log_fetch("fetch_entries", &index.id, &uids, &uids_and_values);
+    }
+
+    #[cfg(feature = "log_requests")]
+    crate::debug_logs::save_fetch_log(
+        "fetch_entries",
+        &index.id,
+        &requests_log,
+        cloned_uids,
+        &uids_and_values,
+    )?;
+
+    let bytes = response_body(uids_and_values.serialize()?);
+    wipe_table(&mut uids_and_values);
+    timer.mark("serialization");
+
+    let mut response = HttpResponse::Ok();
+    timer.insert_header(&server_timing, &mut response);
+    protocol.insert_header(&mut response);
+
+    Ok(response
+        .content_type("application/octet-stream")
+        .body(bytes))
+}
+
+#[post("/indexes/{id}/fetch_chains")]
+#[allow(clippy::too_many_arguments)]
+async fn fetch_chains(
+    index: Index,
+    access_token: AccessToken,
+    (Settings(settings), protocol): (Settings, Protocol),
+    bytes: Bytes,
+    indexes: Data<dyn IndexesDatabase>,
+    limits: Data<Limits>,
+    server_timing: Option<Data<ServerTiming>>,
+    retention: Option<Data<Retention>>,
+    request_counters: Option<Data<RequestCounters>>,
+    request_logging: Data<RequestLogging>,
+    #[cfg(feature = "log_requests")] requests_log: Data<RequestsLog>,
+    #[cfg(feature = "uid_sampling")] uid_sampler: Data<UidSampler>,
+) -> ResponseBytes {
+    limits.check_request(&index.id, &settings, bytes.len())?;
+
+    let mut timer = Timer::start();
+
+    let bytes = count(
+        &request_counters,
+        &index.id,
+        Counter::Fetches,
+        access_token.check_fetch_body(bytes, &index, &index.fetch_chains_key),
+    )?;
+    timer.mark("signature");
+    Retention::record_activity(&retention, &index.id);
+
+    let uids = deserialize_set::<CoreError, Uid<UID_LENGTH>>(&bytes)?;
+    timer.mark("deserialization");
+
+    limits.check_uids_count(&settings, uids.len())?;
+
+    #[cfg(feature = "uid_sampling")]
+    uid_sampler.record(&index.id, Table::Chains, &uids);
+
+    #[cfg(feature = "log_requests")]
+    let cloned_uids = uids.clone();
+    let logged_uids = request_logging.is_enabled(&index.id).then(|| uids.clone());
+
+    // Without logging nor consistency, the values are only serialized: the indexes database
+    // writes them directly inside the response (see `IndexesDatabase::fetch_serialized`).
+    #[cfg(not(feature = "log_requests"))]
+    if logged_uids.is_none() && settings.consistency.is_none() {
+        let body = response_body(
+            indexes
+                .fetch_serialized(&index, Table::Chains, uids)
+                .await?,
+        );
+        timer.mark("backend");
+
+        limits.check_serialized_fetch_memory(bytes.len(), body.len())?;
+
+        let mut response = HttpResponse::Ok();
+        timer.insert_header(&server_timing, &mut response);
+        protocol.insert_header(&mut response);
+
+        return Ok(response.content_type("application/octet-stream").body(body));
+    }
+
+    let mut uids_and_values =
+        settings::fetch(&indexes, &index, &settings, Table::Chains, uids).await?;
+    timer.mark("backend");
+
+    limits.check_fetch_memory(bytes.len(), &uids_and_values)?;
+
+    if let Some(uids) = logged_uids {
+        request_logging.log_fetch("fetch_chains", &index.id, &uids, &uids_and_values);
+    }
+
+    #[cfg(feature = "log_requests")]
+    crate::debug_logs::save_fetch_log(
+        "fetch_chains",
+        &index.id,
+        &requests_log,
+        cloned_uids,
+        &uids_and_values,
+    )?;
+
+    let bytes = response_body(uids_and_values.serialize()?);
+    wipe_table(&mut uids_and_values);
+    timer.mark("serialization");
+
+    let mut response = HttpResponse::Ok();
+    timer.insert_header(&server_timing, &mut response);
+    protocol.insert_header(&mut response);
+
+    Ok(response
+        .content_type("application/octet-stream")
+        .body(bytes))
+}
+
+#[post("/indexes/{id}/upsert_entries")]
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+async fn upsert_entries(
+    bytes: Bytes,
+    index: Index,
+    indexes: Data<dyn IndexesDatabase>,
+    event_bus: Option<Data<dyn EventBus>>,
+    changes_log: Option<Data<ChangesLog>>,
+    maintenance: Data<Maintenance>,
+    limits: Data<Limits>,
+    server_timing: Option<Data<ServerTiming>>,
+    // Grouped because actix-web handlers are limited to 12 extractors
+    (
+        metrics,
+        compactions,
+        mut idempotency,
+        retention,
+        request_counters,
+        Settings(settings),
+        request_logging,
+        protocol,
+    ): (
+        Data<Metrics>,
+        Data<Compactions>,
+        Idempotency,
+        Option<Data<Retention>>,
+        Option<Data<RequestCounters>>,
+        Settings,
+        Data<RequestLogging>,
+        Protocol,
+    ),
+    #[cfg(feature = "replication")] shipper: Option<Data<Shipper>>,
+    #[cfg(feature = "replication")] standby: Option<Data<Standby>>,
+    #[cfg(feature = "log_requests")] requests_log: Data<RequestsLog>,
+) -> ResponseBytes {
+    maintenance.check_index(&index.id)?;
+    #[cfg(feature = "replication")]
+    Standby::check_writable(&standby)?;
+    #[cfg(feature = "replication")]
+    Shipper::check_writable(&shipper)?;
+    limits.check_request(&index.id, &settings, bytes.len())?;
+
+    let mut timer = Timer::start();
+
+    let bytes = check_signature_and_count(
+        &request_counters,
+        bytes,
+        &index,
+        &index.upsert_entries_key,
+        Counter::Upserts,
+    )?;
+    timer.mark("signature");
+    Retention::record_activity(&retention, &index.id);
+
+    if let Some(response) = idempotency.start(&index.id, "upsert_entries", &bytes)? {
+        return Ok(response);
+    }
+
+    limits.check_body_memory(bytes.len())?;
+    let data = UpsertData::<UID_LENGTH>::deserialize(&bytes)?;
+    timer.mark("deserialization");
+
+    limits.check_uids_count(&settings, data.len())?;
+
+    #[cfg(feature = "replication")]
+    let mut new_values: EncryptedTable<UID_LENGTH> = if shipper.is_some() {
+        data.iter()
+            .map(|(uid, (_, new_value))| (*uid, new_value.clone()))
+            .collect()
+    } else {
+        EncryptedTable::with_capacity(0)
+    };
+
+    let uids: Vec<_> = if event_bus.is_some() || changes_log.is_some() {
+        data.keys().copied().collect()
+    } else {
+        vec![]
+    };
+
+    #[cfg(feature = "log_requests")]
+    let upsert_log_data = crate::debug_logs::upsert_log_data(&data);
+    let logged_uids: Option<Vec<_>> = request_logging
+        .is_enabled(&index.id)
+        .then(|| data.keys().copied().collect());
+
+    let upserted = data.len();
+    let mut rejected = indexes.upsert_entries(&index, data).await?;
+    metrics.record_upsert(&index.id, rejected.len());
+    Compactions::record_writes_in_background(
+        &compactions,
+        &index,
+        (upserted - rejected.len()) as u64,
+    );
+
+    #[cfg(feature = "log_requests")]
+    crate::debug_logs::save_upsert_log(&index.id, &requests_log, upsert_log_data, &rejected)?;
+    if let Some(uids) = logged_uids {
+        request_logging.log_upsert(&index.id, &uids, &rejected);
+    }
+
+    let mutations: Vec<_> = uids
+        .iter()
+        .filter(|uid| !rejected.contains_key(uid))
+        .map(|uid| Mutation::new(&index.id, uid, Operation::UpsertEntry))
+        .collect();
+    changes::append(changes_log, &indexes, &index, &mutations).await;
+    timer.mark("backend");
+
+    publish_in_background(event_bus, mutations);
+
+    #[cfg(feature = "replication")]
+    replication::ship(&shipper, || {
+        Record::put_values(
+            &index,
+            Table::Entries,
+            new_values
+                .iter()
+                .filter(|(uid, _)| !rejected.contains_key(uid)),
+        )
+    });
+    #[cfg(feature = "replication")]
+    wipe_table(&mut new_values);
+
+    let bytes = Bytes::from(response_body(rejected.serialize()?));
+    wipe_table(&mut rejected);
+    timer.mark("serialization");
+
+    idempotency.finish("application/octet-stream", bytes.clone())?;
+
+    let mut response = HttpResponse::Ok();
+    timer.insert_header(&server_timing, &mut response);
+    protocol.insert_header(&mut response);
+
+    Ok(response
+        .content_type("application/octet-stream")
+        .body(bytes))
+}
+
+#[post("/indexes/{id}/insert_chains")]
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+async fn insert_chains(
+    index: Index,
+    bytes: Bytes,
+    indexes: Data<dyn IndexesDatabase>,
+    event_bus: Option<Data<dyn EventBus>>,
+    changes_log: Option<Data<ChangesLog>>,
+    maintenance: Data<Maintenance>,
+    server_timing: Option<Data<ServerTiming>>,
+    // Grouped because actix-web handlers are limited to 12 extractors
+    (
+        compactions,
+        retention,
+        request_counters,
+        limits,
+        Settings(settings),
+        request_logging,
+        protocol,
+    ): (
+        Data<Compactions>,
+        Option<Data<Retention>>,
+        Option<Data<RequestCounters>>,
+        Data<Limits>,
+        Settings,
+        Data<RequestLogging>,
+        Protocol,
+    ),
+    mut idempotency: Idempotency,
+    #[cfg(feature = "replication")] shipper: Option<Data<Shipper>>,
+    #[cfg(feature = "replication")] standby: Option<Data<Standby>>,
+    #[cfg(feature = "log_requests")] requests_log: Data<RequestsLog>,
+) -> ResponseBytes {
+    maintenance.check_index(&index.id)?;
+    #[cfg(feature = "replication")]
+    Standby::check_writable(&standby)?;
+    #[cfg(feature = "replication")]
+    Shipper::check_writable(&shipper)?;
+    limits.check_request(&index.id, &settings, bytes.len())?;
+
+    let mut timer = Timer::start();
+
+    let bytes = check_signature_and_count(
+        &request_counters,
+        bytes,
+        &index,
+        &index.insert_chains_key,
+        Counter::ChainInserts,
+    )?;
+    timer.mark("signature");
+    Retention::record_activity(&retention, &index.id);
+
+    if let Some(response) = idempotency.start(&index.id, "insert_chains", &bytes)? {
+        return Ok(response);
+    }
+
+    limits.check_body_memory(bytes.len())?;
+    let data = EncryptedTable::<UID_LENGTH>::deserialize(&bytes)?;
+    timer.mark("deserialization");
+
+    #[cfg(feature = "replication")]
+    let record = shipper
+        .as_ref()
+        .map(|_| Record::put_values(&index, Table::Chains, data.iter()));
+
+    let mutations: Vec<_> = if event_bus.is_some() || changes_log.is_some() {
+        data.keys()
+            .map(|uid| Mutation::new(&index.id, uid, Operation::InsertChain))
+            .collect()
+    } else {
+        vec![]
+    };
+
+    #[cfg(feature = "log_requests")]
+    crate::debug_logs::save_insert_log(&index.id, &requests_log, &data)?;
+    if request_logging.is_enabled(&index.id) {
+        request_logging.log_insert(&index.id, &data);
+    }
+
+    let inserted = data.len();
+    indexes.insert_chains(&index, data).await?;
+    Compactions::record_writes_in_background(&compactions, &index, inserted as u64);
+
+    changes::append(changes_log, &indexes, &index, &mutations).await;
+    timer.mark("backend");
+
+    publish_in_background(event_bus, mutations);
+
+    #[cfg(feature = "replication")]
+    if let Some(record) = record {
+        replication::ship(&shipper, || record);
+    }
+
+    idempotency.finish("application/json", Bytes::from_static(b"null"))?;
+
+    let mut response = HttpResponse::Ok();
+    timer.insert_header(&server_timing, &mut response);
+
+    Ok(protocol.insert_chains_response(response))
+}
+
+/// Move the serialized response out of its `Zeroizing` without copying it: actix-web needs
+/// an owned buffer and frees it without wiping it (see the "zeroize_on_free" feature).
+fn response_body(mut serialized: Zeroizing<Vec<u8>>) -> Vec<u8> {
+    std::mem::take(&mut *serialized)
+}
+
+/// Entry point of the `findex_cloud` binary (server and CLI commands). Custom backends must
+/// be registered before (see `plugin`).
+pub async fn run() -> std::io::Result<()> {
+    // Windows starts the services inside `C:\Windows\System32` and without stderr.
+    #[cfg(all(windows, feature = "windows_service"))]
+    let default_log_output = if windows::is_service_run() {
+        windows::use_executable_directory();
+        "eventlog"
+    } else {
+        "stderr"
+    };
+    #[cfg(not(all(windows, feature = "windows_service")))]
+    let default_log_output = "stderr";
+
+    if FsPath::new(".env").exists() {
+        dotenv::dotenv().expect("Cannot load env");
+    }
+
+    match env::var("LOG_OUTPUT")
+        .as_deref()
+        .unwrap_or(default_log_output)
+    {
+        "stderr" => env_logger::Builder::from_env(Env::default().default_filter_or("debug")).init(),
+        "syslog" => syslog::init("debug"),
+        #[cfg(all(windows, feature = "windows_service"))]
+        "eventlog" => windows::init_event_log("debug"),
+        output => {
+            panic!("Unknown `LOG_OUTPUT` {output} (please use `stderr`, `syslog` or `eventlog`)")
+        }
+    }
+
+    let mut args = env::args().skip(1);
+    match args.next().as_deref() {
+        None | Some("serve") => serve().await,
+        Some("check") => {
+            let mut repair = false;
+            for arg in args {
+                match arg.as_str() {
+                    "--repair" => repair = true,
+                    _ => usage(),
+                }
+            }
+
+            let (indexes_database, metadata_database) = databases().await;
+            match check::check(
+                metadata_database.get_ref(),
+                indexes_database.get_ref(),
+                repair,
+            )
+            .await
+            {
+                Ok(report) => {
+                    print!("{report}");
+                    if !report.is_healthy() {
+                        std::process::exit(1);
+                    }
+                }
+                Err(err) => {
+                    eprintln!("Cannot check the databases ({err})");
+                    std::process::exit(1);
+                }
+            }
+
+            Ok(())
+        }
+        Some("backup") => {
+            if args.next().is_some() {
+                usage();
+            }
+
+            let indexes_database = indexes_database(
+                env::var("INDEXES_DATABASE_TYPE")
+                    .as_deref()
+                    .unwrap_or("rocksdb"),
+            )
+            .await;
+            match indexes_database.backup().await {
+                Ok(backup) => println!("Backup {} created ({} bytes)", backup.id, backup.size),
+                Err(err) => {
+                    eprintln!("Cannot create the backup ({err})");
+                    std::process::exit(1);
+                }
+            }
+
+            Ok(())
+        }
+        Some("restore") => {
+            let backup_id: Option<u32> =
+                args.next().map(|id| id.parse().unwrap_or_else(|_| usage()));
+            if args.next().is_some() {
+                usage();
+            }
+
+            #[cfg(feature = "rocksdb")]
+            match crate::rocksdb::restore(backup_id) {
+                Ok(backup) => println!("Backup {} restored", backup.id),
+                Err(err) => {
+                    eprintln!("Cannot restore the backup ({err})");
+                    std::process::exit(1);
+                }
+            }
+            #[cfg(not(feature = "rocksdb"))]
+            panic!("Cannot restore {backup_id:?} because `findex_cloud` wasn't compiled with \"rocksdb\" feature.");
+
+            #[cfg(feature = "rocksdb")]
+            Ok(())
+        }
+        Some("compact") => {
+            let index_id = args.next();
+            if args.next().is_some() {
+                usage();
+            }
+
+            #[cfg(feature = "rocksdb")]
+            match crate::rocksdb::compact(index_id.as_deref()) {
+                Ok((before, after)) => {
+                    println!(
+                        "Compaction done, SST files: {before} bytes before, {after} bytes after"
+                    )
+                }
+                Err(err) => {
+                    eprintln!("Cannot compact the indexes database ({err})");
+                    std::process::exit(1);
+                }
+            }
+            #[cfg(not(feature = "rocksdb"))]
+            panic!("Cannot compact {index_id:?} because `findex_cloud` wasn't compiled with \"rocksdb\" feature.");
+
+            #[cfg(feature = "rocksdb")]
+            Ok(())
+        }
+        Some("remote") => {
+            #[cfg(feature = "remote")]
+            remote::run(args).await;
+            #[cfg(not(feature = "remote"))]
+            panic!("Cannot run remote commands because `findex_cloud` wasn't compiled with \"remote\" feature.");
+
+            #[cfg(feature = "remote")]
+            Ok(())
+        }
+        Some("replay") => {
+            #[cfg(feature = "log_requests")]
+            replay::run(args).await;
+            #[cfg(not(feature = "log_requests"))]
+            panic!("Cannot replay a requests log because `findex_cloud` wasn't compiled with \"log_requests\" feature.");
+
+            #[cfg(feature = "log_requests")]
+            Ok(())
+        }
+        Some("windows-service") => {
+            #[cfg(all(windows, feature = "windows_service"))]
+            return windows::run(args);
+            #[cfg(not(all(windows, feature = "windows_service")))]
+            panic!("Cannot run the Windows service commands because `findex_cloud` wasn't compiled for Windows with \"windows_service\" feature.");
+        }
+        Some(_) => usage(),
+    }
+}
+
+/// Start the server (`findex_cloud serve`)
+async fn serve() -> std::io::Result<()> {
+    #[cfg(feature = "smtp")]
+    smtp::init_from_env();
+    #[cfg(not(feature = "smtp"))]
+    if env::var("SMTP_HOST").is_ok() {
+        panic!(
+            "Cannot load `SMTP_HOST` because `findex_cloud` wasn't compiled with \"smtp\" feature."
+        );
+    }
+
+    let (indexes_database, metadata_database) = databases().await;
+
+    if env::var("STARTUP_CHECK").as_deref() != Ok("false") {
+        startup_check(&indexes_database, &metadata_database).await;
+    }
+
+    match start_server(
+        Network::Ipv4AndIpv6,
+        indexes_database.clone(),
+        metadata_database.clone(),
+    )
+    .await
+    {
+        Ok(_) => Ok(()),
+        Err(_) => start_server(Network::Ipv4Only, indexes_database, metadata_database).await,
+    }
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage:
+    findex_cloud [serve]          Start the server
+    findex_cloud check [--repair] Check the integrity of the databases (--repair deletes the orphaned data and recomputes the sizes)
+    findex_cloud backup           Create a backup of the indexes database (RocksDB only)
+    findex_cloud restore [ID]     Rebuild the indexes database from a backup, the latest by default (RocksDB only)
+    findex_cloud compact [INDEX]  Compact the indexes database, or only the keys of one index, to reclaim the space of the deleted values (RocksDB only)
+    findex_cloud remote COMMAND   Administrate a running server at `FINDEX_CLOUD_URL` with `ADMIN_API_KEY` (\"remote\" feature):
+        indexes | create NAME | delete INDEX | delete-matching FILTER | stats INDEX | usage INDEX
+        export INDEX | cache | flush-cache [INDEX] | backup | backups | metrics
+    findex_cloud replay FILE [--backend TYPE] [--timing]
+                                  Replay a captured requests log against an indexes database (\"log_requests\" feature)
+    findex_cloud windows-service install | uninstall | run
+                                  Register the server as a Windows service (\"windows_service\" feature)"
+    );
+    std::process::exit(2);
+}
+
+async fn startup_check(
+    indexes_database: &Data<dyn IndexesDatabase>,
+    metadata_database: &Data<dyn MetadataDatabase>,
+) {
+    match check::check(
+        metadata_database.get_ref(),
+        indexes_database.get_ref(),
+        false,
+    )
+    .await
+    {
+        Ok(report) if report.is_healthy() => {
+            log::info!("Startup integrity check: {report}")
+        }
+        Ok(report) => {
+            log::warn!(
+                "Startup integrity check found problems (run `findex_cloud check --repair` to fix them): {report}"
+            );
+            #[cfg(feature = "smtp")]
+            smtp::notify(smtp::Alert::HealthCheckFailed {
+                check: "startup integrity",
+                error: report.to_string(),
+            });
+        }
+        Err(err) => {
+            log::error!("Cannot run startup integrity check ({err})");
+            #[cfg(feature = "smtp")]
+            smtp::notify(smtp::Alert::HealthCheckFailed {
+                check: "startup integrity",
+                error: err.to_string(),
+            });
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Network {
+    Ipv4AndIpv6,
+    Ipv4Only,
+}
+
+async fn indexes_database(indexes_database_type: &str) -> Arc<dyn IndexesDatabase> {
+    match indexes_database_type {
+        #[cfg(feature = "lmmd")]
+        "lmmd" => Arc::new(crate::heed::Database::create()),
+        #[cfg(not(feature = "lmmd"))]
+        "lmmd" => panic!("Cannot load `INDEXES_DATABASE_TYPE=lmmd` because `findex_cloud` wasn't compiled with \"lmmd\" feature."),
+
+        #[cfg(feature = "rocksdb")]
+        "rocksdb" => Arc::new(crate::rocksdb::Database::create()),
+        #[cfg(not(feature = "rocksdb"))]
+        "rocksdb" => panic!("Cannot load `INDEXES_DATABASE_TYPE=rocksdb` because `findex_cloud` wasn't compiled with \"rocksdb\" feature."),
+
+        #[cfg(feature = "dynamodb")]
+        "dynamodb" => Arc::new(crate::dynamodb::Database::create().await),
+        #[cfg(not(feature = "dynamodb"))]
+        "dynamodb" => panic!("Cannot load `INDEXES_DATABASE_TYPE=dynamodb` because `findex_cloud` wasn't compiled with \"dynamodb\" feature."),
+
+        indexes_database_type => match plugin::indexes_database(indexes_database_type) {
+            Some(indexes_database) => indexes_database.await,
+            None => panic!("Unknown `INDEXES_DATABASE_TYPE` env variable `{indexes_database_type}` (please use `rocksdb`, `dynamodb`, `lmmd` or a registered backend)"),
+        },
+    }
+}
+
+/// Local databases (RocksDB and LMDB) cannot be opened twice, only remote
+/// databases can be used as read replicas.
+async fn indexes_read_replica(replica_database_type: &str) -> Arc<dyn IndexesDatabase> {
+    match replica_database_type {
+        #[cfg(feature = "dynamodb")]
+        "dynamodb" => Arc::new(crate::dynamodb::Database::create_read_replica().await),
+        #[cfg(not(feature = "dynamodb"))]
+        "dynamodb" => panic!("Cannot load `INDEXES_READ_REPLICA_DATABASE_TYPE=dynamodb` because `findex_cloud` wasn't compiled with \"dynamodb\" feature."),
+
+        replica_database_type => panic!("Unsupported `INDEXES_READ_REPLICA_DATABASE_TYPE` env variable `{replica_database_type}` (please use `dynamodb`)"),
+    }
+}
+
+async fn databases() -> (Data<dyn IndexesDatabase>, Data<dyn MetadataDatabase>) {
+    let indexes_database_type =
+        env::var("INDEXES_DATABASE_TYPE").unwrap_or_else(|_| "rocksdb".to_string());
+    let indexes_database = indexes_database(&indexes_database_type).await;
+
+    let indexes_database: Arc<dyn IndexesDatabase> =
+        match env::var("INDEXES_READ_REPLICA_DATABASE_TYPE") {
+            Ok(replica_type) => Arc::new(crate::replica::Database::new(
+                indexes_database,
+                indexes_read_replica(&replica_type).await,
+            )),
+            Err(_) => indexes_database,
+        };
+
+    // The read replica only serves the entries when the chains are elsewhere.
+    let indexes_database: Arc<dyn IndexesDatabase> =
+        match env::var("INDEXES_CHAINS_DATABASE_TYPE") {
+            Ok(chains_type) if chains_type == indexes_database_type => panic!(
+                "`INDEXES_CHAINS_DATABASE_TYPE` must be different from `INDEXES_DATABASE_TYPE` ({chains_type})"
+            ),
+            Ok(chains_type) => Arc::new(crate::split::Database::new(
+                indexes_database,
+                crate::indexes_database(&chains_type).await,
+            )),
+            Err(_) => indexes_database,
+        };
+
+    // `METADATA_DATABASE_URL` selects the implementation with its scheme.
+    let metadata_database_url = crate::config::secret_from_env("METADATA_DATABASE_URL");
+    let metadata_database_type = match &metadata_database_url {
+        Some(_) if env::var("METADATA_DATABASE_TYPE").is_ok() => {
+            panic!("Set either `METADATA_DATABASE_URL` or `METADATA_DATABASE_TYPE`, not both")
+        }
+        Some(url) => match url.split_once("://") {
+            Some((scheme, _)) => scheme.to_string(),
+            None => panic!("`METADATA_DATABASE_URL` must start with `sqlite://`"),
+        },
+        None => env::var("METADATA_DATABASE_TYPE").unwrap_or_else(|_| "sqlite".to_string()),
+    };
+
+    let metadata_database: Arc<dyn MetadataDatabase> = match metadata_database_type.as_str() {
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Arc::new(crate::sqlite::Database::create(metadata_database_url).await),
+            #[cfg(not(feature = "sqlite"))]
+            "sqlite" => panic!("Cannot load `METADATA_DATABASE_TYPE=sqlite` because `findex_cloud` wasn't compiled with \"sqlite\" feature."),
+
+            #[cfg(feature = "dynamodb")]
+            "dynamodb" => Arc::new(crate::dynamodb::Database::create().await),
+            #[cfg(not(feature = "dynamodb"))]
+            "dynamodb" => panic!("Cannot load `METADATA_DATABASE_TYPE=dynamodb` because `findex_cloud` wasn't compiled with \"dynamodb\" feature."),
+
+            "postgres" | "postgresql" | "mysql" if metadata_database_url.is_some() => panic!("Unsupported `METADATA_DATABASE_URL`: only SQLite is implemented as an SQL metadata database"),
+
+            metadata_database_type => match plugin::metadata_database(metadata_database_type) {
+                Some(metadata_database) => metadata_database.await,
+                None => panic!("Unknown `METADATA_DATABASE_TYPE` env variable `{metadata_database_type}` (please use `sqlite`, `dynamodb` or a registered backend)"),
+            },
+        };
+
+    let indexes_database: Arc<dyn IndexesDatabase> = if env::var("WRITE_BEHIND").as_deref()
+        == Ok("true")
+    {
+        #[cfg(feature = "write_behind")]
+        {
+            Arc::new(
+                crate::write_behind::Database::open(indexes_database, metadata_database.clone())
+                    .await,
+            )
+        }
+        #[cfg(not(feature = "write_behind"))]
+        panic!("Cannot load `WRITE_BEHIND=true` because `findex_cloud` wasn't compiled with \"write_behind\" feature.")
+    } else {
+        indexes_database
+    };
+
+    match database_timeout::timeout_from_env() {
+        Some(timeout) => (
+            Data::from(
+                Arc::new(IndexesDatabaseWithTimeout::new(indexes_database, timeout))
+                    as Arc<dyn IndexesDatabase>,
+            ),
+            Data::from(
+                Arc::new(MetadataDatabaseWithTimeout::new(metadata_database, timeout))
+                    as Arc<dyn MetadataDatabase>,
+            ),
+        ),
+        None => (Data::from(indexes_database), Data::from(metadata_database)),
+    }
+}
+
+/// The API is available at the root (for compatibility with existing clients)
+/// and under `/api` (to be routed by an API gateway or to serve the UI from
+/// another domain).
+fn configure_api(cfg: &mut ServiceConfig) {
+    cfg.service(get_index)
+        .service(get_indexes)
+        .service(post_indexes_batch)
+        .service(post_indexes)
+        .service(archive::archive_index)
+        .service(archive::unarchive_index)
+        .service(compaction::get_stats)
+        .service(compaction::post_compaction)
+        .service(compaction::acquire_compaction_lock)
+        .service(compaction::release_compaction_lock)
+        .service(compaction::post_compaction_progress)
+        .service(compaction::get_compaction_progress)
+        .service(compaction::admin_release_compaction_lock)
+        .service(usage::get_usage)
+        .service(access_tokens::post_access_token)
+        .service(delete_index)
+        .service(bulk_delete::bulk_delete_indexes)
+        .service(fetch_entries)
+        .service(fetch_chains)
+        .service(upsert_entries)
+        .service(insert_chains)
+        .service(bulk_load::bulk_load)
+        .service(settings::get_settings)
+        .service(settings::put_settings)
+        .service(changes::get_changes)
+        .service(maintenance::get_maintenance)
+        .service(scheduler::get_jobs)
+        .service(retention::inactive_indexes)
+        .service(maintenance::put_maintenance)
+        .service(maintenance::put_index_maintenance)
+        .service(request_logging::get_request_logging)
+        .service(request_logging::put_request_logging)
+        .service(export::export_index)
+        .service(backup::post_backup)
+        .service(backup::get_backups)
+        .service(metrics::get_metrics)
+        .service(cache::get_cache)
+        .service(cache::flush_cache);
+
+    #[cfg(feature = "sqlite")]
+    cfg.service(export::export_index_to_sqlite)
+        .service(import::import_index_from_sqlite);
+
+    #[cfg(feature = "log_requests")]
+    cfg.service(crate::debug_logs::set_time_diff)
+        .service(crate::debug_logs::post_reset_requests_log)
+        .service(crate::debug_logs::get_requests_log)
+        .service(crate::debug_logs::query_requests_log)
+        .service(crate::debug_logs::export_entries_for_index)
+        .service(crate::debug_logs::export_chains_for_index);
+
+    #[cfg(feature = "uid_sampling")]
+    cfg.service(uid_sampling::get_uid_samples)
+        .service(uid_sampling::delete_uid_samples);
+
+    #[cfg(feature = "signature_debug")]
+    cfg.service(signature_debug::debug_signature)
+        .service(signature_debug::get_test_vectors);
+}
+
+async fn start_server(
+    network: Network,
+    indexes_database: Data<dyn IndexesDatabase>,
+    metadata_database: Data<dyn MetadataDatabase>,
+) -> std::io::Result<()> {
+    let metadata_cache: Data<MetadataCache> = Data::new(Default::default());
+
+    let event_bus: Option<Data<dyn EventBus>> = match env::var("EVENT_BUS_TYPE").as_deref() {
+            Err(_) | Ok("none") => None,
+
+            #[cfg(feature = "kafka")]
+            Ok("kafka") => Some(Data::from(Arc::new(crate::events::kafka::Kafka::create()) as Arc<dyn EventBus>)),
+            #[cfg(not(feature = "kafka"))]
+            Ok("kafka") => panic!("Cannot load `EVENT_BUS_TYPE=kafka` because `findex_cloud` wasn't compiled with \"kafka\" feature."),
+
+            #[cfg(feature = "nats")]
+            Ok("nats") => Some(Data::from(Arc::new(crate::events::nats::Nats::create().await) as Arc<dyn EventBus>)),
+            #[cfg(not(feature = "nats"))]
+            Ok("nats") => panic!("Cannot load `EVENT_BUS_TYPE=nats` because `findex_cloud` wasn't compiled with \"nats\" feature."),
+
+            Ok(event_bus_type) => panic!("Unknown `EVENT_BUS_TYPE` env variable `{event_bus_type}` (please use `none`, `kafka` or `nats`)"),
+        };
+
+    let changes_log = ChangesLog::from_env();
+    let server_timing = ServerTiming::from_env();
+    let admin_api_key = AdminApiKey::from_env();
+    #[cfg(feature = "webauthn")]
+    let webauthn = crate::webauthn::WebAuthn::from_env(admin_api_key.is_some());
+    #[cfg(not(feature = "webauthn"))]
+    if env::var("WEBAUTHN_RP_ID").is_ok() {
+        panic!("Cannot load `WEBAUTHN_RP_ID` because `findex_cloud` wasn't compiled with \"webauthn\" feature.");
+    }
+    let maintenance: Data<Maintenance> = Data::new(Default::default());
+    let request_logging: Data<RequestLogging> = Data::new(Default::default());
+    let export_rate_limiter = Data::new(ExportRateLimiter::from_env());
+    let metrics: Data<Metrics> = Data::new(Default::default());
+    let limits = Data::new(Limits::from_env());
+    let protocol_versions = Data::new(ProtocolVersions::from_env());
+    let deprecations = Data::new(Deprecations::from_env());
+    let access_tokens = Data::new(AccessTokens::from_env());
+    let archive_store = archive_store_from_env();
+    let compactions = Data::new(Compactions::from_env(
+        metadata_database.clone(),
+        indexes_database.clone(),
+    ));
+    let idempotency_cache = IdempotencyCache::from_env();
+    let retention = Retention::from_env();
+    let usage = Usage::from_env();
+    let request_counters = RequestCounters::from_env(SignatureAlerts::from_env(metrics.clone()));
+
+    if let Some(request_counters) = &request_counters {
+        RequestCounters::start(request_counters.clone(), metadata_database.clone());
+    }
+
+    if let Some(usage) = &usage {
+        Usage::start(usage.clone(), metadata_database.clone());
+    }
+
+    let quotas = Quotas::from_env();
+    if let Some(quotas) = &quotas {
+        if usage.is_none() {
+            panic!("The monthly quotas need the usage tracking, remove `USAGE_FLUSH_SECONDS=0`");
+        }
+
+        Quotas::start(quotas.clone(), metadata_database.clone());
+    }
+
+    if let Some(scrubber) = Scrubber::from_env() {
+        scrubber.start(
+            metadata_database.clone(),
+            indexes_database.clone(),
+            metrics.clone(),
+        );
+    }
+
+    #[cfg(feature = "replication")]
+    let (shipper, standby) = match env::var("REPLICATION_ROLE").as_deref() {
+        Err(_) | Ok("none") => (None, None),
+        Ok("primary") => (Some(Data::new(Shipper::create())), None),
+        Ok("standby") => (None, Some(Data::new(Standby::create()))),
+        Ok(role) => panic!("Unknown `REPLICATION_ROLE` env variable `{role}` (please use `none`, `primary` or `standby`)"),
+    };
+    #[cfg(feature = "replication")]
+    if let Some(standby) = &standby {
+        Standby::start(
+            standby.clone(),
+            metadata_database.clone(),
+            indexes_database.clone(),
+        );
+    }
+    #[cfg(not(feature = "replication"))]
+    if matches!(
+        env::var("REPLICATION_ROLE").as_deref(),
+        Ok("primary") | Ok("standby")
+    ) {
+        panic!("Cannot load `REPLICATION_ROLE` because `findex_cloud` wasn't compiled with \"replication\" feature.");
+    }
+
+    #[cfg(feature = "replication")]
+    let is_standby = standby.is_some();
+    #[cfg(not(feature = "replication"))]
+    let is_standby = false;
+
+    if is_standby && retention.is_some() {
+        log::info!("Retention policy not applied on a standby");
+    }
+
+    let jobs = scheduler::start(
+        JobContext {
+            metadata_db: metadata_database.clone(),
+            indexes_db: indexes_database.clone(),
+            metadata_cache: metadata_cache.clone(),
+            idempotency_cache: idempotency_cache.clone(),
+            retention: retention.clone().filter(|_| !is_standby),
+            #[cfg(feature = "replication")]
+            shipper: shipper.clone(),
+        },
+        metrics.clone(),
+    );
+
+    #[cfg(feature = "log_requests")]
+    let requests_log = Data::new(RequestsLog::create().await);
+
+    #[cfg(feature = "uid_sampling")]
+    let uid_sampler = Data::new(UidSampler::from_env());
+
+    #[cfg(feature = "signature_debug")]
+    log::warn!("`signature_debug` feature is enabled, never use this build in production");
+
+    let static_ui_dir = crate::config::static_ui_dir();
+    let timeouts = ServerTimeouts::from_env();
+    let listeners = Listeners::from_env();
+    let server_listeners = listeners.clone();
+
+    let mut server = HttpServer::new(move || {
+        let deprecations = deprecations.clone();
+        let deprecations_metrics = metrics.clone();
+        let mut app = App::new()
+            .wrap_fn(move |req, srv| {
+                let response = srv.call(req);
+                let deprecations = deprecations.clone();
+                let metrics = deprecations_metrics.clone();
+                async move {
+                    let mut response = response.await?;
+                    deprecations.mark(&metrics, &mut response);
+                    Ok(response)
+                }
+            })
+            .wrap(Cors::permissive())
+            .wrap(Logger::default())
+            .app_data(metadata_cache.clone())
+            .app_data(indexes_database.clone())
+            .app_data(metadata_database.clone())
+            .app_data(maintenance.clone())
+            .app_data(request_logging.clone())
+            .app_data(export_rate_limiter.clone())
+            .app_data(metrics.clone())
+            .app_data(limits.clone())
+            .app_data(protocol_versions.clone())
+            .app_data(access_tokens.clone())
+            .app_data(compactions.clone())
+            .app_data(jobs.clone())
+            .app_data(PayloadConfig::new(50_000_000))
+            .configure(configure_api)
+            .service(scope("/api").configure(configure_api));
+
+        if let Some(admin_api_key) = &admin_api_key {
+            app = app.app_data(admin_api_key.clone());
+        }
+
+        if let Some(event_bus) = &event_bus {
+            app = app.app_data(event_bus.clone());
+        }
+
+        if let Some(changes_log) = &changes_log {
+            app = app.app_data(changes_log.clone());
+        }
+
+        if let Some(server_timing) = &server_timing {
+            app = app.app_data(server_timing.clone());
+        }
+
+        if let Some(idempotency_cache) = &idempotency_cache {
+            app = app.app_data(idempotency_cache.clone());
+        }
+
+        if let Some(retention) = &retention {
+            app = app.app_data(retention.clone());
+        }
+
+        if let Some(usage) = &usage {
+            app = app.app_data(usage.clone());
+        }
+
+        if let Some(request_counters) = &request_counters {
+            app = app.app_data(request_counters.clone());
+        }
+
+        if let Some(quotas) = &quotas {
+            app = app.app_data(quotas.clone());
+        }
+
+        if let Some(archive_store) = &archive_store {
+            app = app.app_data(archive_store.clone());
+        }
+
+        #[cfg(feature = "replication")]
+        {
+            if let Some(shipper) = &shipper {
+                app = app
+                    .app_data(shipper.clone())
+                    .service(crate::replication::health)
+                    .service(crate::replication::fence_primary);
+            }
+
+            if let Some(standby) = &standby {
+                app = app
+                    .app_data(standby.clone())
+                    .service(crate::replication::apply)
+                    .service(crate::replication::promote)
+                    .service(crate::replication::admin_promote)
+                    .service(crate::replication::get_standby_status);
+            }
+        }
+
+        #[cfg(feature = "log_requests")]
+        {
+            app = app.app_data(requests_log.clone());
+        }
+
+        #[cfg(feature = "uid_sampling")]
+        {
+            app = app.app_data(uid_sampler.clone());
+        }
+
+        #[cfg(feature = "webauthn")]
+        if let Some(webauthn) = &webauthn {
+            app = app
+                .app_data(webauthn.clone())
+                .service(crate::webauthn::login_page)
+                .service(crate::webauthn::register_start)
+                .service(crate::webauthn::register_finish)
+                .service(crate::webauthn::login_start)
+                .service(crate::webauthn::login_finish)
+                .service(crate::webauthn::logout)
+                .service(crate::webauthn::get_credentials)
+                .service(crate::webauthn::delete_credential);
+        }
+
+        if let Some(static_ui_dir) = &static_ui_dir {
+            let files = fs::Files::new("/", static_ui_dir).index_file("index.html");
+            // Redirected to the login page without a passkey session (if enabled)
+            #[cfg(feature = "webauthn")]
+            let files = scope("")
+                .wrap_fn(crate::webauthn::protect_ui)
+                .service(files);
+            app = app.service(files);
+        }
+
+        if let Some(listeners) = &listeners {
+            app = app.app_data(listeners.clone());
+        }
+
+        app.wrap_fn(usage::track).wrap_fn(listeners::restrict)
+    })
+    .client_request_timeout(timeouts.client_request)
+    .client_disconnect_timeout(timeouts.client_disconnect)
+    .keep_alive(timeouts.keep_alive)
+    .shutdown_timeout(timeouts.shutdown);
+
+    let activated_listeners = systemd::activated_listeners();
+    match &server_listeners {
+        _ if !activated_listeners.is_empty() => {
+            for listener in activated_listeners {
+                listener.set_nonblocking(true)?;
+                server = server.listen(listener)?;
+            }
+        }
+        Some(listeners) => {
+            for address in listeners.addresses() {
+                server = server.bind(address)?;
+            }
+        }
+        None => {
+            server = server.bind(("0.0.0.0", 8080))?;
+
+            // If IPv6 is not available do not bind it (for example inside Docker).
+            if network == Network::Ipv4AndIpv6 {
+                server = server.bind("[::1]:8080")?;
+            }
+        }
+    }
+
+    let server = server.run();
+    systemd::notify("READY=1");
+    systemd::start_watchdog();
+
+    let result = server.await;
+    systemd::notify("STOPPING=1");
+    result
+}
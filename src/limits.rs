@@ -0,0 +1,124 @@
+/// Limits on the size of the Findex callbacks requests.
+///
+/// A single fetch or upsert with hundreds of thousands of UIDs pins a worker
+/// and sends a huge batch to the indexes database. Requests with more than
+/// `MAX_UIDS_PER_REQUEST` UIDs (10 000 by default) are refused with a
+/// `413 Payload Too Large`, clients should split them into smaller chunks.
+///
+/// A few requests with big values can also use all the memory. With
+/// `REQUEST_MEMORY_BUDGET_MB` (disabled by default), a request is refused with a
+/// `413 Payload Too Large` when its estimated memory is above the budget: the body and
+/// its decoded copy before the deserialization, and for the fetches the body, the values
+/// read from the indexes database and their serialized copy before the serialization.
+///
+/// The settings of an index can override the maximum number of UIDs, limit the size of the
+/// bodies and the requests per second (see `settings.rs`).
+use std::env;
+
+use cosmian_findex::{parameters::UID_LENGTH, EncryptedTable};
+
+use crate::{
+    errors::Error,
+    settings::{IndexSettings, RateLimits},
+};
+
+const DEFAULT_MAX_UIDS_PER_REQUEST: usize = 10_000;
+
+pub(crate) struct Limits {
+    max_uids_per_request: usize,
+    /// In bytes, `None` if disabled
+    request_memory_budget: Option<usize>,
+    rate_limits: RateLimits,
+}
+
+impl Limits {
+    pub(crate) fn from_env() -> Self {
+        Limits {
+            max_uids_per_request: env::var("MAX_UIDS_PER_REQUEST")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(DEFAULT_MAX_UIDS_PER_REQUEST),
+            request_memory_budget: env::var("REQUEST_MEMORY_BUDGET_MB")
+                .ok()
+                .and_then(|value| value.parse::<usize>().ok())
+                .filter(|megabytes| *megabytes > 0)
+                .map(|megabytes| megabytes * 1024 * 1024),
+            rate_limits: RateLimits::default(),
+        }
+    }
+
+    /// Called before the signature check of a Findex callback.
+    pub(crate) fn check_request(
+        &self,
+        index_id: &str,
+        settings: &IndexSettings,
+        body_length: usize,
+    ) -> Result<(), Error> {
+        if let Some(max) = settings.max_body_bytes {
+            if body_length > max {
+                return Err(Error::BodyTooLarge {
+                    length: body_length,
+                    max,
+                });
+            }
+        }
+
+        self.rate_limits.check(index_id, settings)
+    }
+
+    pub(crate) fn check_uids_count(
+        &self,
+        settings: &IndexSettings,
+        count: usize,
+    ) -> Result<(), Error> {
+        let max = settings
+            .max_uids_per_request
+            .unwrap_or(self.max_uids_per_request);
+        if count > max {
+            return Err(Error::TooManyUids { count, max });
+        }
+
+        Ok(())
+    }
+
+    /// `check_fetch_memory` of a result serialized by the indexes database (see
+    /// `IndexesDatabase::fetch_serialized`): the body and the serialized values.
+    pub(crate) fn check_serialized_fetch_memory(
+        &self,
+        body_length: usize,
+        result_length: usize,
+    ) -> Result<(), Error> {
+        self.check_memory(body_length + result_length)
+    }
+
+    fn check_memory(&self, estimated: usize) -> Result<(), Error> {
+        match self.request_memory_budget {
+            Some(budget) if estimated > budget => {
+                Err(Error::MemoryBudgetExceeded { estimated, budget })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Called before the deserialization of an upsert or an insert: the body and its
+    /// decoded copy.
+    pub(crate) fn check_body_memory(&self, body_length: usize) -> Result<(), Error> {
+        self.check_memory(2 * body_length)
+    }
+
+    /// Called before the serialization of the result of a fetch: the body, the values
+    /// read from the indexes database and their serialized copy.
+    pub(crate) fn check_fetch_memory(
+        &self,
+        body_length: usize,
+        result: &EncryptedTable<UID_LENGTH>,
+    ) -> Result<(), Error> {
+        if self.request_memory_budget.is_none() {
+            return Ok(());
+        }
+
+        let result_size: usize = result.values().map(|value| UID_LENGTH + value.len()).sum();
+
+        self.check_memory(body_length + 2 * result_size)
+    }
+}
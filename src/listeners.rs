@@ -0,0 +1,108 @@
+/// Several listeners with different roles, to never expose the management endpoints to the
+/// internet.
+///
+/// `LISTENERS` is a JSON array of listeners, for example
+/// `[{"address": "0.0.0.0:8080", "role": "callbacks"}, {"address": "127.0.0.1:9090", "role": "management"}]`:
+/// - `callbacks`: only the Findex callbacks (`fetch_entries`, `fetch_chains`, `upsert_entries`
+///   and `insert_chains`),
+/// - `management`: everything except the Findex callbacks (indexes management, admin endpoints,
+///   metrics, web UI…),
+/// - `all`: every endpoint.
+///
+/// The other requests are answered with a `404 Not Found`, like an unknown route. Without
+/// `LISTENERS`, the server listens on `0.0.0.0:8080` and `[::1]:8080` with the `all` role.
+use std::{collections::HashMap, env, future::Future, net::SocketAddr};
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse},
+    web::Data,
+    HttpResponse,
+};
+use serde::Deserialize;
+
+use crate::usage;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Role {
+    Callbacks,
+    Management,
+    All,
+}
+
+#[derive(Deserialize, Debug)]
+struct Listener {
+    address: SocketAddr,
+    role: Role,
+}
+
+/// Present in the app data only if `LISTENERS` is set.
+pub(crate) struct Listeners {
+    roles: HashMap<SocketAddr, Role>,
+}
+
+impl Listeners {
+    pub(crate) fn from_env() -> Option<Data<Listeners>> {
+        let json = env::var("LISTENERS").ok()?;
+        let listeners: Vec<Listener> = serde_json::from_str(&json)
+            .unwrap_or_else(|err| panic!("Cannot parse `LISTENERS` ({err})"));
+
+        if listeners.is_empty() {
+            panic!("`LISTENERS` must contain at least one listener");
+        }
+
+        let mut roles = HashMap::new();
+        for listener in listeners {
+            if roles.insert(listener.address, listener.role).is_some() {
+                panic!(
+                    "`LISTENERS` contains the address {} twice",
+                    listener.address
+                );
+            }
+        }
+
+        Some(Data::new(Listeners { roles }))
+    }
+
+    pub(crate) fn addresses(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.roles.keys()
+    }
+
+    fn allows(&self, local_addr: &SocketAddr, path: &str) -> bool {
+        match self.roles.get(local_addr) {
+            Some(Role::All) => true,
+            Some(Role::Callbacks) => usage::callback(path).is_some(),
+            Some(Role::Management) => usage::callback(path).is_none(),
+            None => false,
+        }
+    }
+}
+
+/// Middleware refusing the requests outside the role of the listener which received them
+/// (see `App::wrap_fn`).
+pub(crate) fn restrict<S, B>(
+    req: ServiceRequest,
+    service: &S,
+) -> impl Future<Output = Result<ServiceResponse<EitherBody<B>>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let allowed = req.app_data::<Data<Listeners>>().map_or(true, |listeners| {
+        listeners.allows(&req.app_config().local_addr(), req.match_info().as_str())
+    });
+
+    let response = if allowed {
+        Ok(service.call(req))
+    } else {
+        Err(req.into_response(HttpResponse::NotFound().finish()))
+    };
+
+    async move {
+        match response {
+            Ok(response) => Ok(response.await?.map_into_left_body()),
+            Err(refused) => Ok(refused.map_into_right_body()),
+        }
+    }
+}
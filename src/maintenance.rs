@@ -0,0 +1,140 @@
+/// Maintenance mode for the whole server or for a single index.
+///
+/// During maintenance (backups, migrations, compactions…) the mutations
+/// (index creation and deletion, `upsert_entries`, `insert_chains`) are refused with a
+/// `503 Service Unavailable` and a `Retry-After` header. The fetches keep working.
+///
+/// The state is kept in memory: a restart disables the maintenance mode.
+use std::{collections::HashMap, sync::RwLock};
+
+use actix_web::{
+    get, put,
+    web::{Data, Json, Path},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin::Admin,
+    errors::{Error, Response},
+};
+
+const DEFAULT_RETRY_AFTER_IN_SECONDS: u64 = 60;
+
+#[derive(Default)]
+pub(crate) struct Maintenance {
+    server: RwLock<Option<u64>>,
+    indexes: RwLock<HashMap<String, u64>>,
+}
+
+impl Maintenance {
+    /// Fail if the server is in maintenance.
+    pub(crate) fn check_server(&self) -> Result<(), Error> {
+        if let Ok(server) = self.server.read() {
+            if let Some(retry_after) = *server {
+                return Err(Error::Maintenance { retry_after });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fail if the server or the index is in maintenance.
+    pub(crate) fn check_index(&self, index_id: &str) -> Result<(), Error> {
+        self.check_server()?;
+
+        if let Ok(indexes) = self.indexes.read() {
+            if let Some(retry_after) = indexes.get(index_id) {
+                return Err(Error::Maintenance {
+                    retry_after: *retry_after,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct MaintenanceToggle {
+    enabled: bool,
+    /// Value of the `Retry-After` header in seconds
+    retry_after: Option<u64>,
+}
+
+impl MaintenanceToggle {
+    fn retry_after(&self) -> Option<u64> {
+        self.enabled
+            .then(|| self.retry_after.unwrap_or(DEFAULT_RETRY_AFTER_IN_SECONDS))
+    }
+}
+
+#[derive(Serialize)]
+struct MaintenanceStatus {
+    /// `Retry-After` in seconds if the server is in maintenance
+    server: Option<u64>,
+    /// `Retry-After` in seconds for each index in maintenance
+    indexes: HashMap<String, u64>,
+}
+
+#[get("/admin/maintenance")]
+pub(crate) async fn get_maintenance(
+    _admin: Admin,
+    maintenance: Data<Maintenance>,
+) -> Response<MaintenanceStatus> {
+    let server = *maintenance
+        .server
+        .read()
+        .map_err(|_| Error::Internal("Maintenance lock is poisoned".to_string()))?;
+    let indexes = maintenance
+        .indexes
+        .read()
+        .map_err(|_| Error::Internal("Maintenance lock is poisoned".to_string()))?
+        .clone();
+
+    Ok(Json(MaintenanceStatus { server, indexes }))
+}
+
+#[put("/admin/maintenance")]
+pub(crate) async fn put_maintenance(
+    _admin: Admin,
+    body: Json<MaintenanceToggle>,
+    maintenance: Data<Maintenance>,
+) -> Response<()> {
+    *maintenance
+        .server
+        .write()
+        .map_err(|_| Error::Internal("Maintenance lock is poisoned".to_string()))? =
+        body.retry_after();
+
+    log::warn!(
+        "Maintenance mode {} for the server",
+        if body.enabled { "enabled" } else { "disabled" }
+    );
+
+    Ok(Json(()))
+}
+
+#[put("/admin/indexes/{id}/maintenance")]
+pub(crate) async fn put_index_maintenance(
+    _admin: Admin,
+    id: Path<String>,
+    body: Json<MaintenanceToggle>,
+    maintenance: Data<Maintenance>,
+) -> Response<()> {
+    let mut indexes = maintenance
+        .indexes
+        .write()
+        .map_err(|_| Error::Internal("Maintenance lock is poisoned".to_string()))?;
+
+    match body.retry_after() {
+        Some(retry_after) => indexes.insert(id.to_string(), retry_after),
+        None => indexes.remove(id.as_str()),
+    };
+
+    log::warn!(
+        "Maintenance mode {} for index {id}",
+        if body.enabled { "enabled" } else { "disabled" }
+    );
+
+    Ok(Json(()))
+}
@@ -0,0 +1,487 @@
+/// Metrics in the Prometheus text format (`GET /metrics`, protected by the admin API key).
+///
+/// Upsert contention: when the old value sent by the client doesn't match the stored value,
+/// the UID is rejected and the client runs another round (fetch the new value, merge, upsert
+/// again). The number of rejected UIDs per `upsert_entries` request is tracked per index.
+/// A high rate of requests with rejections means many clients write the same keywords at the
+/// same time and should shard them (or write less concurrently).
+///
+/// Percentiles are computed from a histogram with power of two buckets, so they are upper bounds
+/// (a p99 of 7 means between 4 and 7 rejected UIDs). Metrics are kept in memory and reset on restart.
+///
+/// The background scrubbing (see `scrub.rs`) reports the number of checked items, the number
+/// of corrupted items per index found by the last pass and the date of the last pass.
+///
+/// The scheduled jobs (see `scheduler.rs`) report their runs, failures and last duration.
+///
+/// The requests to a deprecated route or with a deprecated protocol version are counted (see
+/// `deprecation.rs`), and the spikes of rejected signatures per index (see `alerts.rs`).
+///
+/// The indexes and metadata databases report the gauges of their storage layer (connection
+/// pool, client retries, pending compactions…, see `IndexesDatabase::storage_gauges`) with a
+/// `database` label (`indexes` or `metadata`), read when the metrics are scraped.
+use std::{collections::HashMap, fmt::Write, sync::RwLock, time::Duration};
+
+use actix_web::{get, web::Data, HttpResponse};
+
+use crate::{
+    admin::Admin,
+    core::{IndexesDatabase, MetadataDatabase},
+    errors::Error,
+};
+
+const QUANTILES: [f64; 4] = [0.5, 0.9, 0.99, 1.0];
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    upserts: RwLock<HashMap<String, Histogram>>,
+    scrub: RwLock<ScrubMetrics>,
+    jobs: RwLock<HashMap<&'static str, JobMetrics>>,
+    /// By label name and value
+    deprecated_requests: RwLock<HashMap<(&'static str, String), u64>>,
+    /// By index ID
+    signature_alerts: RwLock<HashMap<String, u64>>,
+}
+
+#[derive(Default)]
+struct JobMetrics {
+    runs: u64,
+    failures: u64,
+    last_duration: Duration,
+}
+
+#[derive(Default)]
+struct ScrubMetrics {
+    checked_items: u64,
+    /// Found by the last pass
+    corrupted_items: HashMap<String, u64>,
+    last_completed_at: Option<i64>,
+}
+
+impl Metrics {
+    pub(crate) fn record_upsert(&self, index_id: &str, rejected_uids: usize) {
+        if let Ok(mut upserts) = self.upserts.write() {
+            upserts
+                .entry(index_id.to_string())
+                .or_default()
+                .record(rejected_uids as u64);
+        }
+    }
+
+    pub(crate) fn record_scrubbed_items(&self, checked: u64) {
+        if let Ok(mut scrub) = self.scrub.write() {
+            scrub.checked_items += checked;
+        }
+    }
+
+    pub(crate) fn set_scrub_corrupted_items(&self, index_id: &str, corrupted: u64) {
+        if let Ok(mut scrub) = self.scrub.write() {
+            scrub
+                .corrupted_items
+                .insert(index_id.to_string(), corrupted);
+        }
+    }
+
+    pub(crate) fn record_scrub_completed(&self, timestamp: i64) {
+        if let Ok(mut scrub) = self.scrub.write() {
+            scrub.last_completed_at = Some(timestamp);
+        }
+    }
+
+    pub(crate) fn record_job(&self, name: &'static str, duration: Duration, success: bool) {
+        if let Ok(mut jobs) = self.jobs.write() {
+            let job = jobs.entry(name).or_default();
+            job.runs += 1;
+            job.last_duration = duration;
+            if !success {
+                job.failures += 1;
+            }
+        }
+    }
+
+    pub(crate) fn record_deprecated_request(&self, label: &'static str, value: &str) {
+        if let Ok(mut deprecated_requests) = self.deprecated_requests.write() {
+            *deprecated_requests
+                .entry((label, value.to_string()))
+                .or_default() += 1;
+        }
+    }
+
+    pub(crate) fn record_signature_alert(&self, index_id: &str) {
+        if let Ok(mut signature_alerts) = self.signature_alerts.write() {
+            *signature_alerts.entry(index_id.to_string()).or_default() += 1;
+        }
+    }
+}
+
+/// Value read from the storage layer of a database when the metrics are scraped
+#[derive(Debug, Clone)]
+pub struct StorageGauge {
+    /// Prometheus metric name, `findex_cloud_<backend>_…`
+    pub name: &'static str,
+    pub help: &'static str,
+    /// `gauge` or `counter`
+    pub kind: &'static str,
+    pub labels: Vec<(&'static str, String)>,
+    pub value: f64,
+}
+
+impl StorageGauge {
+    pub fn gauge(name: &'static str, help: &'static str, value: f64) -> Self {
+        StorageGauge {
+            name,
+            help,
+            kind: "gauge",
+            labels: Vec::new(),
+            value,
+        }
+    }
+
+    pub fn counter(name: &'static str, help: &'static str, value: f64) -> Self {
+        StorageGauge {
+            kind: "counter",
+            ..Self::gauge(name, help, value)
+        }
+    }
+
+    pub fn with_label(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.labels.push((name, value.into()));
+        self
+    }
+}
+
+/// Bucket 0 counts the zeros, bucket `i` counts the values between `2^(i-1)` and `2^i - 1`.
+#[derive(Default)]
+struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    /// Number of non-zero values
+    non_zero: u64,
+    sum: u64,
+    max: u64,
+}
+
+impl Histogram {
+    fn record(&mut self, value: u64) {
+        let bucket = (u64::BITS - value.leading_zeros()) as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+        self.max = self.max.max(value);
+        if value > 0 {
+            self.non_zero += 1;
+        }
+    }
+
+    /// Upper bound of the bucket containing the quantile
+    fn quantile(&self, quantile: f64) -> u64 {
+        let rank = (quantile * self.count as f64).ceil().max(1.) as u64;
+
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let upper_bound = if bucket == 0 {
+                    0
+                } else {
+                    u64::MAX >> (64 - bucket)
+                };
+                return upper_bound.min(self.max);
+            }
+        }
+
+        self.max
+    }
+}
+
+#[get("/metrics")]
+pub(crate) async fn get_metrics(
+    _admin: Admin,
+    metrics: Data<Metrics>,
+    indexes_db: Data<dyn IndexesDatabase>,
+    metadata_db: Data<dyn MetadataDatabase>,
+) -> Result<HttpResponse, Error> {
+    // Read before the locks of the metrics, a missing gauge doesn't fail the whole scrape.
+    let mut storage_gauges = Vec::new();
+    for (database, gauges) in [
+        ("indexes", indexes_db.storage_gauges().await),
+        ("metadata", metadata_db.storage_gauges().await),
+    ] {
+        match gauges {
+            Ok(gauges) => storage_gauges.extend(
+                gauges
+                    .into_iter()
+                    .map(|gauge| gauge.with_label("database", database)),
+            ),
+            Err(err) => {
+                log::error!("Cannot read the storage gauges of the {database} database ({err:?})")
+            }
+        }
+    }
+
+    let upserts = metrics
+        .upserts
+        .read()
+        .map_err(|_| Error::Internal("Metrics lock is poisoned".to_string()))?;
+
+    let scrub = metrics
+        .scrub
+        .read()
+        .map_err(|_| Error::Internal("Metrics lock is poisoned".to_string()))?;
+
+    let jobs = metrics
+        .jobs
+        .read()
+        .map_err(|_| Error::Internal("Metrics lock is poisoned".to_string()))?;
+
+    let deprecated_requests = metrics
+        .deprecated_requests
+        .read()
+        .map_err(|_| Error::Internal("Metrics lock is poisoned".to_string()))?;
+
+    let signature_alerts = metrics
+        .signature_alerts
+        .read()
+        .map_err(|_| Error::Internal("Metrics lock is poisoned".to_string()))?;
+
+    let body = render_upserts(&upserts)
+        .and_then(|mut body| {
+            render_scrub(&scrub, &mut body)?;
+            render_jobs(&jobs, &mut body)?;
+            render_deprecated_requests(&deprecated_requests, &mut body)?;
+            render_signature_alerts(&signature_alerts, &mut body)?;
+            render_storage_gauges(&storage_gauges, &mut body)?;
+            Ok(body)
+        })
+        .map_err(|_| Error::Internal("Cannot render metrics".to_string()))?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
+
+fn render_upserts(upserts: &HashMap<String, Histogram>) -> Result<String, std::fmt::Error> {
+    let mut ids: Vec<_> = upserts.keys().collect();
+    ids.sort();
+
+    let mut body = String::new();
+
+    writeln!(
+        body,
+        "# HELP findex_cloud_upsert_requests_total Number of upsert_entries requests."
+    )?;
+    writeln!(body, "# TYPE findex_cloud_upsert_requests_total counter")?;
+    for id in &ids {
+        writeln!(
+            body,
+            "findex_cloud_upsert_requests_total{{index_id=\"{id}\"}} {}",
+            upserts[*id].count
+        )?;
+    }
+
+    writeln!(body, "# HELP findex_cloud_upsert_rejected_requests_total Number of upsert_entries requests with at least one rejected UID (the client needs another round).")?;
+    writeln!(
+        body,
+        "# TYPE findex_cloud_upsert_rejected_requests_total counter"
+    )?;
+    for id in &ids {
+        writeln!(
+            body,
+            "findex_cloud_upsert_rejected_requests_total{{index_id=\"{id}\"}} {}",
+            upserts[*id].non_zero
+        )?;
+    }
+
+    writeln!(
+        body,
+        "# HELP findex_cloud_upsert_rejections Rejected UIDs per upsert_entries request."
+    )?;
+    writeln!(body, "# TYPE findex_cloud_upsert_rejections summary")?;
+    for id in &ids {
+        let histogram = &upserts[*id];
+        for quantile in QUANTILES {
+            writeln!(
+                body,
+                "findex_cloud_upsert_rejections{{index_id=\"{id}\",quantile=\"{quantile}\"}} {}",
+                histogram.quantile(quantile)
+            )?;
+        }
+        writeln!(
+            body,
+            "findex_cloud_upsert_rejections_sum{{index_id=\"{id}\"}} {}",
+            histogram.sum
+        )?;
+        writeln!(
+            body,
+            "findex_cloud_upsert_rejections_count{{index_id=\"{id}\"}} {}",
+            histogram.count
+        )?;
+    }
+
+    Ok(body)
+}
+
+fn render_scrub(scrub: &ScrubMetrics, body: &mut String) -> Result<(), std::fmt::Error> {
+    writeln!(
+        body,
+        "# HELP findex_cloud_scrub_checked_items_total Number of items checked by the scrubbing."
+    )?;
+    writeln!(
+        body,
+        "# TYPE findex_cloud_scrub_checked_items_total counter"
+    )?;
+    writeln!(
+        body,
+        "findex_cloud_scrub_checked_items_total {}",
+        scrub.checked_items
+    )?;
+
+    let mut ids: Vec<_> = scrub.corrupted_items.keys().collect();
+    ids.sort();
+
+    writeln!(body, "# HELP findex_cloud_scrub_corrupted_items Corrupted items found by the last scrubbing of the index.")?;
+    writeln!(body, "# TYPE findex_cloud_scrub_corrupted_items gauge")?;
+    for id in ids {
+        writeln!(
+            body,
+            "findex_cloud_scrub_corrupted_items{{index_id=\"{id}\"}} {}",
+            scrub.corrupted_items[id]
+        )?;
+    }
+
+    if let Some(last_completed_at) = scrub.last_completed_at {
+        writeln!(body, "# HELP findex_cloud_scrub_last_completed_timestamp_seconds Date of the end of the last scrubbing.")?;
+        writeln!(
+            body,
+            "# TYPE findex_cloud_scrub_last_completed_timestamp_seconds gauge"
+        )?;
+        writeln!(
+            body,
+            "findex_cloud_scrub_last_completed_timestamp_seconds {last_completed_at}"
+        )?;
+    }
+
+    Ok(())
+}
+
+fn render_jobs(
+    jobs: &HashMap<&'static str, JobMetrics>,
+    body: &mut String,
+) -> Result<(), std::fmt::Error> {
+    let mut names: Vec<_> = jobs.keys().collect();
+    names.sort();
+
+    writeln!(
+        body,
+        "# HELP findex_cloud_job_runs_total Number of runs of the scheduled job."
+    )?;
+    writeln!(body, "# TYPE findex_cloud_job_runs_total counter")?;
+    for name in &names {
+        writeln!(
+            body,
+            "findex_cloud_job_runs_total{{job=\"{name}\"}} {}",
+            jobs[*name].runs
+        )?;
+    }
+
+    writeln!(
+        body,
+        "# HELP findex_cloud_job_failures_total Number of failed runs of the scheduled job."
+    )?;
+    writeln!(body, "# TYPE findex_cloud_job_failures_total counter")?;
+    for name in &names {
+        writeln!(
+            body,
+            "findex_cloud_job_failures_total{{job=\"{name}\"}} {}",
+            jobs[*name].failures
+        )?;
+    }
+
+    writeln!(body, "# HELP findex_cloud_job_last_duration_seconds Duration of the last run of the scheduled job.")?;
+    writeln!(body, "# TYPE findex_cloud_job_last_duration_seconds gauge")?;
+    for name in &names {
+        writeln!(
+            body,
+            "findex_cloud_job_last_duration_seconds{{job=\"{name}\"}} {}",
+            jobs[*name].last_duration.as_secs_f64()
+        )?;
+    }
+
+    Ok(())
+}
+
+fn render_deprecated_requests(
+    deprecated_requests: &HashMap<(&'static str, String), u64>,
+    body: &mut String,
+) -> Result<(), std::fmt::Error> {
+    let mut labels: Vec<_> = deprecated_requests.keys().collect();
+    labels.sort();
+
+    writeln!(body, "# HELP findex_cloud_deprecated_requests_total Number of requests to a deprecated route or with a deprecated protocol version.")?;
+    writeln!(
+        body,
+        "# TYPE findex_cloud_deprecated_requests_total counter"
+    )?;
+    for label in labels {
+        let (name, value) = label;
+        writeln!(
+            body,
+            "findex_cloud_deprecated_requests_total{{{name}=\"{value}\"}} {}",
+            deprecated_requests[label]
+        )?;
+    }
+
+    Ok(())
+}
+
+fn render_signature_alerts(
+    signature_alerts: &HashMap<String, u64>,
+    body: &mut String,
+) -> Result<(), std::fmt::Error> {
+    let mut ids: Vec<_> = signature_alerts.keys().collect();
+    ids.sort();
+
+    writeln!(body, "# HELP findex_cloud_signature_alerts_total Number of spikes of requests refused by the signature check.")?;
+    writeln!(body, "# TYPE findex_cloud_signature_alerts_total counter")?;
+    for id in ids {
+        writeln!(
+            body,
+            "findex_cloud_signature_alerts_total{{index_id=\"{id}\"}} {}",
+            signature_alerts[id]
+        )?;
+    }
+
+    Ok(())
+}
+
+fn render_storage_gauges(
+    gauges: &[StorageGauge],
+    body: &mut String,
+) -> Result<(), std::fmt::Error> {
+    let mut names: Vec<_> = gauges.iter().map(|gauge| gauge.name).collect();
+    names.sort_unstable();
+    names.dedup();
+
+    for name in names {
+        let mut gauges = gauges.iter().filter(|gauge| gauge.name == name).peekable();
+        if let Some(first) = gauges.peek() {
+            writeln!(body, "# HELP {name} {}", first.help)?;
+            writeln!(body, "# TYPE {name} {}", first.kind)?;
+        }
+
+        for gauge in gauges {
+            let labels: Vec<_> = gauge
+                .labels
+                .iter()
+                .map(|(label, value)| format!("{label}=\"{value}\""))
+                .collect();
+            writeln!(body, "{name}{{{}}} {}", labels.join(","), gauge.value)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,83 @@
+/// Public API to add storage backends without forking the server.
+///
+/// A downstream crate depends on `findex_cloud`, implements `IndexesDatabase` and/or
+/// `MetadataDatabase` (with `async_trait`) and registers a factory under a name before
+/// starting the server:
+///
+/// ```ignore
+/// #[actix_web::main]
+/// async fn main() -> std::io::Result<()> {
+///     findex_cloud::plugin::register_indexes_database("custom", || async {
+///         Arc::new(CustomDatabase::connect().await) as Arc<dyn IndexesDatabase>
+///     });
+///
+///     findex_cloud::run().await
+/// }
+/// ```
+///
+/// The backend is then selected with `INDEXES_DATABASE_TYPE=custom` (or
+/// `METADATA_DATABASE_TYPE=custom`). The built-in names (`rocksdb`, `lmmd`, `dynamodb`,
+/// `sqlite`) cannot be overridden. The factories are called once on startup, and by the CLI
+/// commands opening the databases.
+use std::{collections::BTreeMap, future::Future, sync::Arc, sync::Mutex};
+
+use futures::future::{FutureExt, LocalBoxFuture};
+
+pub use crate::{
+    backoff::Overload,
+    backup::BackupInfo,
+    changes::Change,
+    compaction::{CompactionLockState, CompactionProgress, CompactionStats},
+    core::{Index, IndexesDatabase, MetadataCache, MetadataDatabase, NewIndex, Table},
+    counters::IndexCounters,
+    errors::Error,
+    events::Mutation,
+    metrics::StorageGauge,
+    scrub::ScrubBatch,
+    settings::{Consistency, IndexSettings},
+    usage::{DailyUsage, UsageCounters},
+};
+
+type Factory<T> = Arc<dyn Fn() -> LocalBoxFuture<'static, Arc<T>> + Send + Sync>;
+
+static INDEXES_DATABASES: Mutex<BTreeMap<String, Factory<dyn IndexesDatabase>>> =
+    Mutex::new(BTreeMap::new());
+static METADATA_DATABASES: Mutex<BTreeMap<String, Factory<dyn MetadataDatabase>>> =
+    Mutex::new(BTreeMap::new());
+
+/// Make `INDEXES_DATABASE_TYPE=<name>` use the database returned by `factory`.
+pub fn register_indexes_database<F, Fut>(name: &str, factory: F)
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Arc<dyn IndexesDatabase>> + 'static,
+{
+    if let Ok(mut factories) = INDEXES_DATABASES.lock() {
+        factories.insert(name.to_string(), Arc::new(move || factory().boxed_local()));
+    }
+}
+
+/// Make `METADATA_DATABASE_TYPE=<name>` use the database returned by `factory`.
+pub fn register_metadata_database<F, Fut>(name: &str, factory: F)
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Arc<dyn MetadataDatabase>> + 'static,
+{
+    if let Ok(mut factories) = METADATA_DATABASES.lock() {
+        factories.insert(name.to_string(), Arc::new(move || factory().boxed_local()));
+    }
+}
+
+pub(crate) fn indexes_database(
+    name: &str,
+) -> Option<LocalBoxFuture<'static, Arc<dyn IndexesDatabase>>> {
+    // Clone the factory to not hold the lock while it runs
+    let factory = INDEXES_DATABASES.lock().ok()?.get(name).cloned()?;
+    Some(factory())
+}
+
+pub(crate) fn metadata_database(
+    name: &str,
+) -> Option<LocalBoxFuture<'static, Arc<dyn MetadataDatabase>>> {
+    let factory = METADATA_DATABASES.lock().ok()?.get(name).cloned()?;
+    Some(factory())
+}
@@ -0,0 +1,132 @@
+/// Version of the Findex callbacks protocol spoken with a client, so the server can be
+/// upgraded without breaking the clients already deployed (mobile apps are not updated
+/// at the same time as the server).
+///
+/// A client sends its version in the `X-Findex-Protocol-Version` header of the Findex
+/// callbacks. Without the header, the `protocol_version` of the index settings is used (for
+/// the clients sending no header at all), then the current version. The version used is
+/// returned in the same header of the response, with a `Deprecation: true` header when
+/// it's not the current one.
+///
+/// Versions:
+/// 1. the previous cloudproof generation: `insert_chains` replies with an empty body,
+/// 2. the current one: `insert_chains` replies with a JSON `null`.
+///
+/// The versions older than `PROTOCOL_MIN_VERSION` (1 by default) are refused with a
+/// `400 Bad Request`. A change of the serialization or of the semantics of a callback must
+/// increment `CURRENT_PROTOCOL_VERSION` and keep the adapters of the previous version here.
+use std::{env, future::Future, pin::Pin};
+
+use actix_web::{
+    dev::Payload, web::Data, FromRequest, HttpRequest, HttpResponse, HttpResponseBuilder,
+};
+
+use crate::{
+    core::{MetadataCache, MetadataDatabase},
+    errors::Error,
+    settings,
+};
+
+pub(crate) const CURRENT_PROTOCOL_VERSION: u8 = 2;
+/// Oldest version with adapters
+pub(crate) const OLDEST_PROTOCOL_VERSION: u8 = 1;
+pub(crate) const PROTOCOL_VERSION_HEADER: &str = "X-Findex-Protocol-Version";
+
+pub(crate) struct ProtocolVersions {
+    min_version: u8,
+}
+
+impl ProtocolVersions {
+    pub(crate) fn from_env() -> Self {
+        let min_version = match env::var("PROTOCOL_MIN_VERSION") {
+            Ok(value) => match value.parse() {
+                Ok(version)
+                    if (OLDEST_PROTOCOL_VERSION..=CURRENT_PROTOCOL_VERSION)
+                        .contains(&version) =>
+                {
+                    version
+                }
+                _ => panic!("`PROTOCOL_MIN_VERSION` must be between {OLDEST_PROTOCOL_VERSION} and {CURRENT_PROTOCOL_VERSION} (found `{value}`)"),
+            },
+            Err(_) => OLDEST_PROTOCOL_VERSION,
+        };
+
+        ProtocolVersions { min_version }
+    }
+}
+
+/// Protocol version of the Findex callback
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Protocol(u8);
+
+impl Protocol {
+    fn new(version: u8, versions: &ProtocolVersions) -> Result<Self, Error> {
+        if version < versions.min_version || version > CURRENT_PROTOCOL_VERSION {
+            return Err(Error::BadRequest(format!(
+                "Unsupported Findex protocol version {version}, this server supports the versions {} to {CURRENT_PROTOCOL_VERSION}: please upgrade the client",
+                versions.min_version
+            )));
+        }
+
+        Ok(Protocol(version))
+    }
+
+    pub(crate) fn insert_header(self, response: &mut HttpResponseBuilder) {
+        response.insert_header((PROTOCOL_VERSION_HEADER, self.0.to_string()));
+        if self.0 < CURRENT_PROTOCOL_VERSION {
+            response.insert_header(("Deprecation", "true"));
+        }
+    }
+
+    /// Response of a successful `insert_chains`
+    pub(crate) fn insert_chains_response(self, mut response: HttpResponseBuilder) -> HttpResponse {
+        self.insert_header(&mut response);
+
+        match self.0 {
+            1 => response.finish(),
+            _ => response.json(()),
+        }
+    }
+}
+
+impl FromRequest for Protocol {
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+
+        Box::pin(async move {
+            let versions = req.app_data::<Data<ProtocolVersions>>().unwrap();
+
+            if let Some(header) = req.headers().get(PROTOCOL_VERSION_HEADER) {
+                let version = header
+                    .to_str()
+                    .ok()
+                    .and_then(|value| value.trim().parse().ok())
+                    .ok_or_else(|| {
+                        Error::BadRequest(format!("Invalid `{PROTOCOL_VERSION_HEADER}` header"))
+                    })?;
+
+                return Protocol::new(version, versions);
+            }
+
+            let metadata_cache = req.app_data::<Data<MetadataCache>>().unwrap();
+            let metadata_database = req.app_data::<Data<dyn MetadataDatabase>>().unwrap();
+            let Some(id) = req.match_info().get("id") else {
+                return Ok(Protocol(CURRENT_PROTOCOL_VERSION));
+            };
+
+            let settings =
+                settings::settings_with_cache(metadata_database.get_ref(), metadata_cache, id)
+                    .await?;
+
+            Protocol::new(
+                settings
+                    .protocol_version
+                    .unwrap_or(CURRENT_PROTOCOL_VERSION),
+                versions,
+            )
+        })
+    }
+}
@@ -0,0 +1,269 @@
+/// Monthly quotas of requests and bytes per index, for freemium offers.
+///
+/// The defaults are `QUOTA_MONTHLY_REQUESTS` (requests to the Findex callbacks) and
+/// `QUOTA_MONTHLY_BYTES` (bytes received and sent by these requests).
+/// `QUOTA_INDEX_OVERRIDES` overrides them per index with a JSON object, for example
+/// `{"abcde": {"requests": 1000000, "bytes": null}}` (`null` removes the quota for this
+/// index, a missing field keeps the default).
+///
+/// The requests are counted like the usage report (see `usage.rs`, required by the quotas)
+/// and the quotas reset at the beginning of each month (UTC). Once a quota is exhausted,
+/// the Findex callbacks of the index are refused with a `429 Too Many Requests` and a
+/// `Retry-After` until the end of the month. The refused requests are not counted.
+///
+/// The totals of the month are read from the usage counters on startup, then counted in
+/// memory: with several instances, each one only sees the requests it served since its
+/// startup (plus the saved ones), the quotas are approximate.
+///
+/// With the "smtp" feature, an email is sent when a quota is exhausted (see `smtp.rs`).
+#[cfg(feature = "smtp")]
+use std::collections::HashSet;
+use std::{collections::HashMap, env, sync::Mutex};
+
+use actix_web::web::Data;
+use chrono::{Datelike, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{alerts::explicit_null, core::MetadataDatabase, errors::Error};
+
+#[derive(Clone, Copy, Debug)]
+struct Limits {
+    requests: Option<u64>,
+    bytes: Option<u64>,
+}
+
+/// Per index override, `Some(None)` removes the default quota.
+#[derive(Deserialize, Clone, Copy, Debug)]
+struct IndexLimits {
+    #[serde(default, deserialize_with = "explicit_null")]
+    requests: Option<Option<u64>>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    bytes: Option<Option<u64>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct MonthlyUsage {
+    /// First day of the month
+    month: NaiveDate,
+    requests: u64,
+    bytes: u64,
+}
+
+/// Quotas of an index, returned by the usage report
+#[derive(Serialize, Debug)]
+pub(crate) struct QuotasStatus {
+    requests: Option<QuotaStatus>,
+    bytes: Option<QuotaStatus>,
+    resets_on: NaiveDate,
+}
+
+#[derive(Serialize, Debug)]
+struct QuotaStatus {
+    limit: u64,
+    used: u64,
+}
+
+/// Present in the app data only if a quota is configured.
+pub(crate) struct Quotas {
+    defaults: Limits,
+    per_index: HashMap<String, IndexLimits>,
+    /// Totals of the current month, by index ID
+    usage: Mutex<HashMap<String, MonthlyUsage>>,
+    /// Exhausted quotas already notified by email
+    #[cfg(feature = "smtp")]
+    notified: Mutex<HashSet<(String, &'static str, NaiveDate)>>,
+}
+
+fn current_month() -> NaiveDate {
+    let today = Utc::now().date_naive();
+    today.with_day(1).unwrap_or(today)
+}
+
+fn next_month(month: NaiveDate) -> NaiveDate {
+    let (year, month) = match month.month() {
+        12 => (month.year() + 1, 1),
+        month_number => (month.year(), month_number + 1),
+    };
+
+    NaiveDate::from_ymd_opt(year, month, 1).unwrap_or(NaiveDate::MAX)
+}
+
+impl Quotas {
+    pub(crate) fn from_env() -> Option<Data<Quotas>> {
+        let quota = |name: &str| {
+            env::var(name).ok().map(|value| {
+                value
+                    .parse()
+                    .unwrap_or_else(|_| panic!("`{name}` must be a number (found `{value}`)"))
+            })
+        };
+
+        let defaults = Limits {
+            requests: quota("QUOTA_MONTHLY_REQUESTS"),
+            bytes: quota("QUOTA_MONTHLY_BYTES"),
+        };
+
+        let per_index: HashMap<String, IndexLimits> = match env::var("QUOTA_INDEX_OVERRIDES") {
+            Ok(json) => serde_json::from_str(&json)
+                .unwrap_or_else(|err| panic!("Cannot parse `QUOTA_INDEX_OVERRIDES` ({err})")),
+            Err(_) => HashMap::new(),
+        };
+
+        if defaults.requests.is_none() && defaults.bytes.is_none() && per_index.is_empty() {
+            return None;
+        }
+
+        Some(Data::new(Quotas {
+            defaults,
+            per_index,
+            usage: Mutex::new(HashMap::new()),
+            #[cfg(feature = "smtp")]
+            notified: Mutex::new(HashSet::new()),
+        }))
+    }
+
+    fn limits(&self, index_id: &str) -> Limits {
+        let Some(overrides) = self.per_index.get(index_id) else {
+            return self.defaults;
+        };
+
+        Limits {
+            requests: overrides.requests.unwrap_or(self.defaults.requests),
+            bytes: overrides.bytes.unwrap_or(self.defaults.bytes),
+        }
+    }
+
+    /// Totals of the current month
+    fn usage(&self, index_id: &str) -> (u64, u64) {
+        let month = current_month();
+
+        self.usage
+            .lock()
+            .ok()
+            .and_then(|usage| usage.get(index_id).copied())
+            .filter(|usage| usage.month == month)
+            .map_or((0, 0), |usage| (usage.requests, usage.bytes))
+    }
+
+    /// Read the totals of the current month from the usage counters.
+    pub(crate) fn start(quotas: Data<Self>, metadata_db: Data<dyn MetadataDatabase>) {
+        actix_web::rt::spawn(async move {
+            let month = current_month();
+
+            let indexes = match metadata_db.get_indexes().await {
+                Ok(indexes) => indexes,
+                Err(err) => {
+                    log::error!("Cannot list the indexes to load the quotas usage ({err:?})");
+                    return;
+                }
+            };
+
+            for index in indexes {
+                let saved = match metadata_db.get_usage(&index.id, month).await {
+                    Ok(saved) => saved,
+                    Err(err) => {
+                        log::error!(
+                            "Cannot load the quotas usage of index {} ({err:?})",
+                            index.id
+                        );
+                        continue;
+                    }
+                };
+
+                let (requests, bytes) = saved.iter().fold((0, 0), |(requests, bytes), usage| {
+                    (
+                        requests + usage.counters.requests,
+                        bytes + usage.counters.bytes_received + usage.counters.bytes_sent,
+                    )
+                });
+                quotas.record(&index.id, month, requests, bytes);
+            }
+        });
+    }
+
+    fn record(&self, index_id: &str, month: NaiveDate, requests: u64, bytes: u64) {
+        let Ok(mut usage) = self.usage.lock() else {
+            return;
+        };
+
+        let usage = usage.entry(index_id.to_string()).or_insert(MonthlyUsage {
+            month,
+            requests: 0,
+            bytes: 0,
+        });
+
+        if usage.month != month {
+            *usage = MonthlyUsage {
+                month,
+                requests: 0,
+                bytes: 0,
+            };
+        }
+
+        usage.requests += requests;
+        usage.bytes += bytes;
+    }
+
+    /// Called for every request to a Findex callback of a known index
+    pub(crate) fn record_request(&self, index_id: &str, bytes: u64) {
+        self.record(index_id, current_month(), 1, bytes);
+    }
+
+    /// Called before the Findex callbacks
+    #[cfg_attr(not(feature = "smtp"), allow(unused_variables))]
+    pub(crate) fn check(&self, index_id: &str) -> Result<(), Error> {
+        let limits = self.limits(index_id);
+        let (requests, bytes) = self.usage(index_id);
+
+        for (quota, limit, used) in [
+            ("requests", limits.requests, requests),
+            ("bytes", limits.bytes, bytes),
+        ] {
+            if let Some(limit) = limit.filter(|limit| used >= *limit) {
+                #[cfg(feature = "smtp")]
+                self.notify_exhausted(index_id, quota, limit);
+
+                let resets_at = next_month(current_month())
+                    .and_hms_opt(0, 0, 0)
+                    .map_or(0, |midnight| midnight.timestamp());
+
+                return Err(Error::QuotaExceeded {
+                    quota,
+                    retry_after: (resets_at - Utc::now().timestamp()).max(1) as u64,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Once per index, quota and month
+    #[cfg(feature = "smtp")]
+    fn notify_exhausted(&self, index_id: &str, quota: &'static str, limit: u64) {
+        let newly_exhausted = self.notified.lock().map_or(false, |mut notified| {
+            notified.insert((index_id.to_string(), quota, current_month()))
+        });
+
+        if newly_exhausted {
+            crate::smtp::notify(crate::smtp::Alert::QuotaExceeded {
+                index_id: index_id.to_string(),
+                quota,
+                limit,
+            });
+        }
+    }
+
+    pub(crate) fn status(&self, index_id: &str) -> QuotasStatus {
+        let limits = self.limits(index_id);
+        let (requests, bytes) = self.usage(index_id);
+
+        QuotasStatus {
+            requests: limits.requests.map(|limit| QuotaStatus {
+                limit,
+                used: requests,
+            }),
+            bytes: limits.bytes.map(|limit| QuotaStatus { limit, used: bytes }),
+            resets_on: next_month(current_month()),
+        }
+    }
+}
@@ -0,0 +1,121 @@
+/// Remote administration of a running server (`findex_cloud remote <COMMAND>`).
+///
+/// The commands call the HTTP API of the server at `FINDEX_CLOUD_URL`
+/// (`http://localhost:8080` by default) with `ADMIN_API_KEY` as bearer token, so they
+/// don't need access to the data directories. The response body is printed on stdout
+/// (JSON, or the raw export), an error response exits with code 1.
+use std::{
+    env,
+    io::{self, Write},
+};
+
+use reqwest::{Method, RequestBuilder};
+
+use crate::{config, usage};
+
+const DEFAULT_FINDEX_CLOUD_URL: &str = "http://localhost:8080";
+
+struct Remote {
+    client: reqwest::Client,
+    url: String,
+    admin_api_key: Option<String>,
+}
+
+impl Remote {
+    fn from_env() -> Self {
+        Remote {
+            client: reqwest::Client::new(),
+            url: env::var("FINDEX_CLOUD_URL")
+                .unwrap_or_else(|_| DEFAULT_FINDEX_CLOUD_URL.to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            admin_api_key: config::secret_from_env("ADMIN_API_KEY").filter(|key| !key.is_empty()),
+        }
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self.client.request(method, format!("{}{path}", self.url));
+
+        match &self.admin_api_key {
+            Some(admin_api_key) => request.bearer_auth(admin_api_key),
+            None => request,
+        }
+    }
+}
+
+pub(crate) async fn run(mut args: impl Iterator<Item = String>) {
+    let remote = Remote::from_env();
+
+    let command = args.next().unwrap_or_else(|| usage());
+    let argument = args.next();
+    if args.next().is_some() {
+        usage();
+    }
+
+    let request = match (command.as_str(), argument) {
+        ("indexes", None) => remote.request(Method::GET, "/indexes"),
+        ("create", Some(name)) => remote
+            .request(Method::POST, "/indexes")
+            .json(&serde_json::json!({ "name": name })),
+        ("delete", Some(index_id)) => {
+            remote.request(Method::DELETE, &format!("/indexes/{index_id}"))
+        }
+        ("delete-matching", Some(filter)) => {
+            let filter: serde_json::Value = serde_json::from_str(&filter).unwrap_or_else(|err| {
+                eprintln!("The filter must be a JSON object ({err})");
+                std::process::exit(2);
+            });
+            remote
+                .request(Method::POST, "/admin/indexes/delete")
+                .json(&filter)
+        }
+        ("stats", Some(index_id)) => {
+            remote.request(Method::GET, &format!("/indexes/{index_id}/stats"))
+        }
+        ("usage", Some(index_id)) => {
+            remote.request(Method::GET, &format!("/indexes/{index_id}/usage"))
+        }
+        ("export", Some(index_id)) => {
+            remote.request(Method::GET, &format!("/admin/indexes/{index_id}/export"))
+        }
+        ("cache", None) => remote.request(Method::GET, "/admin/cache"),
+        ("flush-cache", index_id) => remote
+            .request(Method::POST, "/admin/cache/flush")
+            .query(&[("index_id", index_id)]),
+        ("backup", None) => remote.request(Method::POST, "/admin/backups"),
+        ("backups", None) => remote.request(Method::GET, "/admin/backups"),
+        ("metrics", None) => remote.request(Method::GET, "/metrics"),
+        _ => usage(),
+    };
+
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(err) => {
+            eprintln!("Cannot reach the server at {} ({err})", remote.url);
+            std::process::exit(1);
+        }
+    };
+
+    let status = response.status();
+    let body = match response.bytes().await {
+        Ok(body) => body,
+        Err(err) => {
+            eprintln!("Cannot read the response of the server ({err})");
+            std::process::exit(1);
+        }
+    };
+
+    if !status.is_success() {
+        eprintln!(
+            "The server responded {status}: {}",
+            String::from_utf8_lossy(&body)
+        );
+        std::process::exit(1);
+    }
+
+    let mut stdout = io::stdout();
+    if let Err(err) = stdout.write_all(&body).and_then(|_| writeln!(stdout)) {
+        eprintln!("Cannot write the response ({err})");
+        std::process::exit(1);
+    }
+}
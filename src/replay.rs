@@ -0,0 +1,406 @@
+/// Replay of a captured requests log (`log_requests` feature) against an indexes database,
+/// to compare the storage drivers with a real workload:
+///
+/// ```text
+/// findex_cloud replay FILE [--backend TYPE] [--timing]
+/// ```
+///
+/// `FILE` is a requests log written by the `file` sink (or one of its gzipped segments).
+/// The operations are replayed in order against `--backend` (`INDEXES_DATABASE_TYPE` by
+/// default), as fast as possible or, with `--timing`, with the delays between the captured
+/// requests. The replay writes into the database: use an empty `DATA_DIR` (or a dedicated
+/// table).
+///
+/// The log doesn't contain the upserted entry values (only their presence), they are
+/// replaced by random values of the size of the entry values fetched in the log. The old
+/// value of an upsert is the last value written by the replay (or read from the database
+/// for the UIDs written before the capture), or a wrong value if the captured upsert was
+/// rejected.
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{BufRead, BufReader, Read},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose, Engine as _};
+use chrono::Utc;
+use cosmian_findex::{parameters::UID_LENGTH, EncryptedTable, Uid, UpsertData};
+use flate2::read::GzDecoder;
+use rand::RngCore;
+use serde::Deserialize;
+
+use crate::{
+    core::{Index, IndexesDatabase, Table},
+    errors::Error,
+    usage,
+};
+
+/// Size of the upserted values if no entry value was fetched in the log
+const DEFAULT_ENTRY_VALUE_LENGTH: usize = 64;
+
+#[derive(Deserialize)]
+struct CapturedLine {
+    /// Milliseconds
+    date: i128,
+    #[serde(rename = "type")]
+    log_type: String,
+    index_id: String,
+    data: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct CapturedUpsert {
+    old_value: bool,
+    new_value: bool,
+    rejected: bool,
+}
+
+#[derive(Default)]
+struct Stats {
+    requests: usize,
+    uids: usize,
+    errors: usize,
+    /// Rejected upserts (captured, replayed)
+    rejections: (usize, usize),
+    latencies: Vec<Duration>,
+}
+
+struct Replay {
+    indexes_database: Arc<dyn IndexesDatabase>,
+    indexes: HashMap<String, Index>,
+    /// Last entry value written by the replay for each UID of each index
+    entries: HashMap<(String, Uid<UID_LENGTH>), Vec<u8>>,
+    entry_value_lengths: HashMap<String, usize>,
+    stats: HashMap<String, Stats>,
+}
+
+pub(crate) async fn run(mut args: impl Iterator<Item = String>) {
+    let path = args.next().unwrap_or_else(|| usage());
+    let mut backend = None;
+    let mut timing = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--backend" => backend = Some(args.next().unwrap_or_else(|| usage())),
+            "--timing" => timing = true,
+            _ => usage(),
+        }
+    }
+
+    let backend = backend
+        .or_else(|| std::env::var("INDEXES_DATABASE_TYPE").ok())
+        .unwrap_or_else(|| "rocksdb".to_string());
+
+    let lines = read_lines(&path).unwrap_or_else(|err| {
+        eprintln!("Cannot read the requests log {path} ({err})");
+        std::process::exit(1);
+    });
+
+    let mut replay = Replay {
+        indexes_database: crate::indexes_database(&backend).await,
+        indexes: HashMap::new(),
+        entries: HashMap::new(),
+        entry_value_lengths: HashMap::new(),
+        stats: HashMap::new(),
+    };
+    for line in &lines {
+        replay.learn_entry_value_length(line);
+    }
+
+    let first_date = lines.first().map_or(0, |line| line.date);
+    let started_at = Instant::now();
+    for line in lines {
+        if timing {
+            let offset = Duration::from_millis((line.date - first_date).max(0) as u64);
+            if let Some(delay) = offset.checked_sub(started_at.elapsed()) {
+                actix_web::rt::time::sleep(delay).await;
+            }
+        }
+
+        if let Err(err) = replay.replay(line).await {
+            log::warn!("Cannot replay a request ({err})");
+        }
+    }
+
+    print!("{}", replay.report(&backend, started_at.elapsed()));
+}
+
+fn read_lines(path: &str) -> Result<Vec<CapturedLine>, std::io::Error> {
+    let file = fs::File::open(path)?;
+    let reader: Box<dyn Read> = if path.ends_with(".gz") {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+
+    let mut lines = Vec::new();
+    for (number, line) in BufReader::new(reader).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(line) => lines.push(line),
+            Err(err) => log::warn!("Skipping the line {} of {path} ({err})", number + 1),
+        }
+    }
+
+    Ok(lines)
+}
+
+fn decode_uid(uid: &str) -> Result<Uid<UID_LENGTH>, Error> {
+    let bytes: [u8; UID_LENGTH] = general_purpose::STANDARD_NO_PAD
+        .decode(uid)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Error::BadRequest(format!("Invalid UID {uid} in the requests log")))?;
+
+    Ok(Uid::from(bytes))
+}
+
+fn decode_value(value: &str) -> Result<Vec<u8>, Error> {
+    general_purpose::STANDARD_NO_PAD
+        .decode(value)
+        .map_err(|_| Error::BadRequest("Invalid value in the requests log".to_string()))
+}
+
+fn random_value(length: usize) -> Vec<u8> {
+    let mut value = vec![0; length];
+    rand::thread_rng().fill_bytes(&mut value);
+    value
+}
+
+impl Replay {
+    fn learn_entry_value_length(&mut self, line: &CapturedLine) {
+        if line.log_type != "fetch_entries" || self.entry_value_lengths.contains_key(&line.index_id)
+        {
+            return;
+        }
+
+        let fetched: Option<HashMap<String, Option<String>>> =
+            serde_json::from_value(line.data.clone()).ok();
+        let length = fetched
+            .and_then(|values| {
+                values
+                    .into_values()
+                    .flatten()
+                    .find_map(|value| decode_value(&value).ok())
+            })
+            .map(|value| value.len());
+        if let Some(length) = length {
+            self.entry_value_lengths
+                .insert(line.index_id.clone(), length);
+        }
+    }
+
+    /// The drivers only read the ID and the TTL of the index.
+    fn index(&mut self, index_id: &str) -> Index {
+        self.indexes
+            .entry(index_id.to_string())
+            .or_insert_with(|| Index {
+                id: index_id.to_string(),
+                name: format!("Replay of {index_id}"),
+                fetch_entries_key: vec![],
+                fetch_chains_key: vec![],
+                upsert_entries_key: vec![],
+                insert_chains_key: vec![],
+                size: None,
+                entries_size: None,
+                chains_size: None,
+                entries_count: None,
+                chains_count: None,
+                created_at: Utc::now().naive_utc(),
+                archived_at: None,
+                ttl_seconds: None,
+                last_activity_at: None,
+                stale_at: None,
+                fetches: 0,
+                upserts: 0,
+                chain_inserts: 0,
+                rejected_signatures: 0,
+            })
+            .clone()
+    }
+
+    async fn replay(&mut self, line: CapturedLine) -> Result<(), Error> {
+        let index = self.index(&line.index_id);
+
+        let (uids, result, started_at) = match line.log_type.as_str() {
+            "fetch_entries" | "fetch_chains" => {
+                let table = if line.log_type == "fetch_entries" {
+                    Table::Entries
+                } else {
+                    Table::Chains
+                };
+                let captured: HashMap<String, Option<String>> = serde_json::from_value(line.data)?;
+                let uids = captured
+                    .keys()
+                    .map(|uid| decode_uid(uid))
+                    .collect::<Result<HashSet<_>, _>>()?;
+
+                let count = uids.len();
+                let started_at = Instant::now();
+                let result = self
+                    .indexes_database
+                    .fetch(&index, table, uids)
+                    .await
+                    .map(|_| ());
+                (count, result, started_at)
+            }
+            "insert_chains" => {
+                let captured: HashMap<String, String> = serde_json::from_value(line.data)?;
+                let mut data = EncryptedTable::<UID_LENGTH>::with_capacity(captured.len());
+                for (uid, value) in &captured {
+                    data.insert(decode_uid(uid)?, decode_value(value)?);
+                }
+
+                let count = data.len();
+                let started_at = Instant::now();
+                let result = self.indexes_database.insert_chains(&index, data).await;
+                (count, result, started_at)
+            }
+            "upsert_entries" => {
+                let captured: HashMap<String, CapturedUpsert> = serde_json::from_value(line.data)?;
+                let data = self.upsert_data(&index, &captured).await?;
+                let captured_rejections =
+                    captured.values().filter(|upsert| upsert.rejected).count();
+
+                let count = captured.len();
+                let started_at = Instant::now();
+                let result = self
+                    .indexes_database
+                    .upsert_entries(&index, UpsertData::new(&data.0, data.1.clone()))
+                    .await
+                    .map(|rejected| {
+                        for (uid, value) in data.1.iter() {
+                            if !rejected.contains_key(uid) {
+                                self.entries.insert((index.id.clone(), *uid), value.clone());
+                            }
+                        }
+                        let stats = self.stats.entry(line.log_type.clone()).or_default();
+                        stats.rejections.0 += captured_rejections;
+                        stats.rejections.1 += rejected.len();
+                    });
+                (count, result, started_at)
+            }
+            log_type => {
+                return Err(Error::BadRequest(format!(
+                    "Unknown request type {log_type} in the requests log"
+                )))
+            }
+        };
+
+        let latency = started_at.elapsed();
+        let stats = self.stats.entry(line.log_type).or_default();
+        stats.requests += 1;
+        stats.uids += uids;
+        stats.latencies.push(latency);
+        if result.is_err() {
+            stats.errors += 1;
+        }
+
+        result
+    }
+
+    /// Old and new values of a captured upsert, the old values unknown to the replay are
+    /// read from the database (outside of the measured latency).
+    async fn upsert_data(
+        &mut self,
+        index: &Index,
+        captured: &HashMap<String, CapturedUpsert>,
+    ) -> Result<(EncryptedTable<UID_LENGTH>, EncryptedTable<UID_LENGTH>), Error> {
+        let uids = captured
+            .iter()
+            .map(|(uid, upsert)| Ok((decode_uid(uid)?, upsert)))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let unknown: HashSet<_> = uids
+            .iter()
+            .filter(|(uid, upsert)| {
+                upsert.old_value && !self.entries.contains_key(&(index.id.clone(), *uid))
+            })
+            .map(|(uid, _)| *uid)
+            .collect();
+        if !unknown.is_empty() {
+            let stored = self
+                .indexes_database
+                .fetch(index, Table::Entries, unknown)
+                .await?;
+            for (uid, value) in stored.iter() {
+                self.entries.insert((index.id.clone(), *uid), value.clone());
+            }
+        }
+
+        let default_length = self
+            .entry_value_lengths
+            .get(&index.id)
+            .copied()
+            .unwrap_or(DEFAULT_ENTRY_VALUE_LENGTH);
+
+        let mut old_values = EncryptedTable::with_capacity(uids.len());
+        let mut new_values = EncryptedTable::with_capacity(uids.len());
+        for (uid, upsert) in uids {
+            let current = self.entries.get(&(index.id.clone(), uid));
+            let length = current.map_or(default_length, Vec::len);
+
+            if upsert.old_value {
+                let old_value = match current {
+                    Some(current) if !upsert.rejected => current.clone(),
+                    _ => random_value(length),
+                };
+                old_values.insert(uid, old_value);
+            }
+
+            let new_value = if upsert.new_value {
+                random_value(length)
+            } else {
+                vec![]
+            };
+            new_values.insert(uid, new_value);
+        }
+
+        Ok((old_values, new_values))
+    }
+
+    fn report(&self, backend: &str, duration: Duration) -> String {
+        let mut report = format!(
+            "Replay against {backend} done in {:.3}s\n",
+            duration.as_secs_f64()
+        );
+
+        let mut log_types: Vec<_> = self.stats.keys().collect();
+        log_types.sort();
+        for log_type in log_types {
+            let stats = &self.stats[log_type];
+            let mut latencies = stats.latencies.clone();
+            latencies.sort();
+            let percentile = |p: usize| {
+                latencies
+                    .get((latencies.len() * p / 100).min(latencies.len().saturating_sub(1)))
+                    .map_or(0.0, |latency| latency.as_secs_f64() * 1000.0)
+            };
+            let total: Duration = latencies.iter().sum();
+
+            report.push_str(&format!(
+                "{log_type}: {} requests ({} UIDs, {} errors), \
+                mean {:.3}ms, p50 {:.3}ms, p99 {:.3}ms, max {:.3}ms\n",
+                stats.requests,
+                stats.uids,
+                stats.errors,
+                total.as_secs_f64() * 1000.0 / latencies.len().max(1) as f64,
+                percentile(50),
+                percentile(99),
+                percentile(100),
+            ));
+            if log_type == "upsert_entries" {
+                report.push_str(&format!(
+                    "upsert_entries: {} rejected UIDs captured, {} rejected UIDs replayed\n",
+                    stats.rejections.0, stats.rejections.1
+                ));
+            }
+        }
+
+        report
+    }
+}
@@ -0,0 +1,217 @@
+/// Send the fetches (`fetch_entries` and `fetch_chains`) to a read replica
+/// and everything else (upserts, inserts, sizes, changes log) to the primary.
+///
+/// If the replica returns an error, the fetch is retried on the primary. The replica
+/// may be a little behind the primary, it's not a problem for Findex because
+/// the upserts are checked against the primary (with the `old_value`).
+use std::{collections::HashSet, sync::Arc};
+
+use async_trait::async_trait;
+use cosmian_findex::{parameters::UID_LENGTH, EncryptedTable, Uid, UpsertData};
+use zeroize::Zeroizing;
+
+use crate::{
+    backup::BackupInfo,
+    changes::Change,
+    core::{Index, IndexesDatabase, Table},
+    errors::Error,
+    events::Mutation,
+    metrics::StorageGauge,
+    scrub::ScrubBatch,
+    settings::Consistency,
+};
+
+pub(crate) struct Database {
+    primary: Arc<dyn IndexesDatabase>,
+    replica: Arc<dyn IndexesDatabase>,
+}
+
+impl Database {
+    pub(crate) fn new(
+        primary: Arc<dyn IndexesDatabase>,
+        replica: Arc<dyn IndexesDatabase>,
+    ) -> Self {
+        Database { primary, replica }
+    }
+}
+
+#[async_trait]
+impl IndexesDatabase for Database {
+    async fn set_size(&self, index: &mut Index) -> Result<(), Error> {
+        self.primary.set_size(index).await
+    }
+
+    async fn set_sizes(&self, indexes: &mut Vec<Index>) -> Result<(), Error> {
+        self.primary.set_sizes(indexes).await
+    }
+
+    async fn fetch(
+        &self,
+        index: &Index,
+        table: Table,
+        uids: HashSet<Uid<UID_LENGTH>>,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        match self.replica.fetch(index, table, uids.clone()).await {
+            Ok(uids_and_values) => return Ok(uids_and_values),
+            Err(err) => log::warn!(
+                "Cannot fetch {table:?} from the read replica for index {} ({err:?}), fallback to the primary",
+                index.id
+            ),
+        }
+
+        self.primary.fetch(index, table, uids).await
+    }
+
+    async fn fetch_serialized(
+        &self,
+        index: &Index,
+        table: Table,
+        uids: HashSet<Uid<UID_LENGTH>>,
+    ) -> Result<Zeroizing<Vec<u8>>, Error> {
+        match self
+            .replica
+            .fetch_serialized(index, table, uids.clone())
+            .await
+        {
+            Ok(bytes) => return Ok(bytes),
+            Err(err) => log::warn!(
+                "Cannot fetch {table:?} from the read replica for index {} ({err:?}), fallback to the primary",
+                index.id
+            ),
+        }
+
+        self.primary.fetch_serialized(index, table, uids).await
+    }
+
+    async fn upsert_entries(
+        &self,
+        index: &Index,
+        data: UpsertData<UID_LENGTH>,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        self.primary.upsert_entries(index, data).await
+    }
+
+    async fn insert_chains(
+        &self,
+        index: &Index,
+        data: EncryptedTable<UID_LENGTH>,
+    ) -> Result<(), Error> {
+        self.primary.insert_chains(index, data).await
+    }
+
+    async fn put_values(
+        &self,
+        index: &Index,
+        table: Table,
+        data: EncryptedTable<UID_LENGTH>,
+    ) -> Result<(), Error> {
+        self.primary.put_values(index, table, data).await
+    }
+
+    async fn fetch_all(
+        &self,
+        index: &Index,
+        table: Table,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        self.primary.fetch_all(index, table).await
+    }
+
+    /// Strongly consistent fetches skip the replica, which may be behind the primary.
+    async fn fetch_with_consistency(
+        &self,
+        index: &Index,
+        table: Table,
+        uids: HashSet<Uid<UID_LENGTH>>,
+        consistency: Consistency,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        if consistency == Consistency::Eventual {
+            match self
+                .replica
+                .fetch_with_consistency(index, table, uids.clone(), consistency)
+                .await
+            {
+                Ok(uids_and_values) => return Ok(uids_and_values),
+                Err(err) => log::warn!(
+                    "Cannot fetch {table:?} from the read replica for index {} ({err:?}), fallback to the primary",
+                    index.id
+                ),
+            }
+        }
+
+        self.primary
+            .fetch_with_consistency(index, table, uids, consistency)
+            .await
+    }
+
+    async fn indexes_ids_with_data(&self) -> Result<HashSet<String>, Error> {
+        self.primary.indexes_ids_with_data().await
+    }
+
+    async fn delete_index_data(&self, index_id: &str) -> Result<(), Error> {
+        self.primary.delete_index_data(index_id).await
+    }
+
+    async fn backup(&self) -> Result<BackupInfo, Error> {
+        self.primary.backup().await
+    }
+
+    async fn backups(&self) -> Result<Vec<BackupInfo>, Error> {
+        self.primary.backups().await
+    }
+
+    fn supports_ttl(&self) -> bool {
+        self.primary.supports_ttl()
+    }
+
+    async fn recompute_size(&self, index: &Index) -> Result<(), Error> {
+        self.primary.recompute_size(index).await
+    }
+
+    async fn scrub(
+        &self,
+        index: &Index,
+        table: Table,
+        from: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<ScrubBatch, Error> {
+        self.primary.scrub(index, table, from, limit).await
+    }
+
+    async fn append_changes(&self, index: &Index, mutations: &[Mutation]) -> Result<(), Error> {
+        self.primary.append_changes(index, mutations).await
+    }
+
+    async fn fetch_changes(
+        &self,
+        index: &Index,
+        since: u64,
+        limit: usize,
+    ) -> Result<Vec<Change>, Error> {
+        self.primary.fetch_changes(index, since, limit).await
+    }
+
+    /// Gauges of both databases, with a `replica` label.
+    async fn storage_gauges(&self) -> Result<Vec<StorageGauge>, Error> {
+        let mut gauges: Vec<_> = self
+            .primary
+            .storage_gauges()
+            .await?
+            .into_iter()
+            .map(|gauge| gauge.with_label("replica", "false"))
+            .collect();
+        gauges.extend(
+            self.replica
+                .storage_gauges()
+                .await?
+                .into_iter()
+                .map(|gauge| gauge.with_label("replica", "true")),
+        );
+
+        Ok(gauges)
+    }
+
+    #[cfg(feature = "log_requests")]
+    async fn fetch_all_as_json(&self, index: &Index, table: Table) -> Result<String, Error> {
+        self.primary.fetch_all_as_json(index, table).await
+    }
+}
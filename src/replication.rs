@@ -0,0 +1,657 @@
+/// Ship the mutations of a primary instance to a warm standby instance.
+///
+/// The primary (`REPLICATION_ROLE=primary`) sends every index creation, index
+/// deletion, settings update and written value (the accepted upserts and the inserted chains)
+/// to `{REPLICATION_STANDBY_URL}/replication/apply`, in order. The standby
+/// (`REPLICATION_ROLE=standby`) writes them to its own databases without checks.
+/// Both instances share the same `REPLICATION_KEY`, sent as a bearer token.
+///
+/// The records are queued in memory and the primary retries forever (with a backoff)
+/// while the standby is unreachable, so a crash of the primary loses the records not
+/// shipped yet. The standby is "warm", not synchronous.
+///
+/// The standby refuses mutations from clients (`503`) until it's promoted with
+/// `POST /replication/promote` or `POST /admin/replication/promote` (failover switch).
+/// After promotion, records from the old primary are refused.
+///
+/// Hot standby: every `REPLICATION_HEALTH_CHECK_SECONDS` the standby checks that its
+/// databases are reachable and, with `REPLICATION_PRIMARY_URL`, calls the health check of
+/// the primary. After `REPLICATION_FAILOVER_AFTER_CHECKS` failed health checks in a row,
+/// the standby promotes itself (only if its databases are reachable).
+///
+/// Fencing: the old primary must stop writing to its databases once the standby is
+/// promoted. The promoted standby asks it to fence itself (`POST /replication/fence`)
+/// and refuses its records with `409`, which also fences it when it comes back. A fenced
+/// primary refuses mutations and writes a marker file inside `DATA_DIR` so it refuses to
+/// start again as a primary.
+///
+/// Lease: the `409` and the fencing call need the network back, so with the automatic failover the primary also
+/// refuses mutations once it has not reached the standby (shipped records or an empty
+/// heartbeat) nor been health-checked by it for half of the failover window
+/// (`REPLICATION_HEALTH_CHECK_SECONDS` × `REPLICATION_FAILOVER_AFTER_CHECKS`, the same on
+/// both instances). An isolated primary then stops writing before the standby promotes
+/// itself.
+use std::{
+    collections::HashSet,
+    env, fs, mem,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    get,
+    http::StatusCode,
+    post,
+    web::{Data, Json},
+};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use base64::{engine::general_purpose, Engine};
+use cosmian_findex::{parameters::UID_LENGTH, EncryptedTable, Uid};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::{
+    admin::{check_bearer_token, Admin},
+    config,
+    core::{Index, IndexesDatabase, MetadataCache, MetadataDatabase, NewIndex, Table},
+    errors::{Error, Response},
+    settings::IndexSettings,
+};
+
+/// Maximum number of records sent in one request to the standby
+const MAX_RECORDS_PER_REQUEST: usize = 100;
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+const DEFAULT_HEALTH_CHECK_SECONDS: u64 = 5;
+const DEFAULT_FAILOVER_AFTER_CHECKS: u32 = 3;
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Record {
+    PutIndex {
+        id: String,
+        name: String,
+        fetch_entries_key: Vec<u8>,
+        fetch_chains_key: Vec<u8>,
+        upsert_entries_key: Vec<u8>,
+        insert_chains_key: Vec<u8>,
+        #[serde(default)]
+        ttl_seconds: Option<i64>,
+    },
+    DeleteIndex {
+        id: String,
+    },
+    /// UIDs and values are base64 encoded
+    PutValues {
+        index_id: String,
+        table: Table,
+        values: Vec<(String, String)>,
+    },
+    PutSettings {
+        index_id: String,
+        settings: IndexSettings,
+    },
+}
+
+impl Record {
+    pub(crate) fn put_index(index: &Index) -> Self {
+        Record::PutIndex {
+            id: index.id.clone(),
+            name: index.name.clone(),
+            fetch_entries_key: index.fetch_entries_key.clone(),
+            fetch_chains_key: index.fetch_chains_key.clone(),
+            upsert_entries_key: index.upsert_entries_key.clone(),
+            insert_chains_key: index.insert_chains_key.clone(),
+            ttl_seconds: index.ttl_seconds,
+        }
+    }
+
+    pub(crate) fn put_values<'a>(
+        index: &Index,
+        table: Table,
+        values: impl Iterator<Item = (&'a Uid<UID_LENGTH>, &'a Vec<u8>)>,
+    ) -> Self {
+        Record::PutValues {
+            index_id: index.id.clone(),
+            table,
+            values: values
+                .map(|(uid, value)| {
+                    (
+                        general_purpose::STANDARD.encode(uid),
+                        general_purpose::STANDARD.encode(value),
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+fn health_check_interval() -> Duration {
+    Duration::from_secs(
+        env::var("REPLICATION_HEALTH_CHECK_SECONDS")
+            .ok()
+            .map(|seconds| {
+                seconds.parse().expect(
+                    "`REPLICATION_HEALTH_CHECK_SECONDS` env variable must be a number of seconds",
+                )
+            })
+            .unwrap_or(DEFAULT_HEALTH_CHECK_SECONDS),
+    )
+}
+
+fn failover_after_checks() -> u32 {
+    env::var("REPLICATION_FAILOVER_AFTER_CHECKS")
+        .ok()
+        .map(|checks| {
+            checks
+                .parse()
+                .expect("`REPLICATION_FAILOVER_AFTER_CHECKS` env variable must be a number")
+        })
+        .unwrap_or(DEFAULT_FAILOVER_AFTER_CHECKS)
+}
+
+fn replication_key() -> String {
+    config::secret_from_env("REPLICATION_KEY").expect(
+        "`REPLICATION_KEY` (or `REPLICATION_KEY_FILE`) env variable is required to use replication",
+    )
+}
+
+/// Marker of a fenced primary, kept across restarts
+fn fenced_marker_path() -> PathBuf {
+    config::data_dir().join("replication_fenced")
+}
+
+/// Primary side: queue the records and send them in the background.
+pub(crate) struct Shipper {
+    key: String,
+    sender: UnboundedSender<Record>,
+    fenced: Arc<AtomicBool>,
+    /// `None` without automatic failover
+    lease: Option<Duration>,
+    /// Last records accepted by the standby or health check from it
+    last_contact: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Shipper {
+    pub(crate) fn create() -> Self {
+        let standby_url = env::var("REPLICATION_STANDBY_URL").expect(
+            "`REPLICATION_STANDBY_URL` env variable is required when `REPLICATION_ROLE=primary`",
+        );
+        let url = format!("{}/replication/apply", standby_url.trim_end_matches('/'));
+
+        let marker = fenced_marker_path();
+        if marker.exists() {
+            panic!(
+                "This instance was fenced after a failover to the standby ({} exists), restart it with `REPLICATION_ROLE=standby` (or remove the file once the data is synchronized)",
+                marker.display()
+            );
+        }
+
+        // Half of the failover window, so the lease expires before the standby promotes itself
+        let failover_window = health_check_interval() * failover_after_checks();
+        let lease = (!failover_window.is_zero()).then_some(failover_window / 2);
+
+        let key = replication_key();
+        let fenced = Arc::new(AtomicBool::new(false));
+        let last_contact = Arc::new(Mutex::new(None));
+        let (sender, receiver) = unbounded_channel();
+        actix_web::rt::spawn(ship_records(
+            url,
+            key.clone(),
+            receiver,
+            fenced.clone(),
+            // Renewed a few times during the lease while no record is shipped
+            lease.map(|lease| lease / 3),
+            last_contact.clone(),
+        ));
+
+        Shipper {
+            key,
+            sender,
+            fenced,
+            lease,
+            last_contact,
+        }
+    }
+
+    /// Fail if the primary was fenced after a failover or if its lease expired.
+    pub(crate) fn check_writable(shipper: &Option<Data<Shipper>>) -> Result<(), Error> {
+        match shipper {
+            Some(shipper) if shipper.fenced.load(Ordering::SeqCst) => Err(Error::Fenced),
+            Some(shipper) if shipper.lease_expired() => Err(Error::LeaseExpired),
+            _ => Ok(()),
+        }
+    }
+
+    fn lease_expired(&self) -> bool {
+        let Some(lease) = self.lease else {
+            return false;
+        };
+
+        self.last_contact.lock().map_or(true, |last_contact| {
+            last_contact.map_or(true, |last_contact| last_contact.elapsed() > lease)
+        })
+    }
+}
+
+fn renew_lease(last_contact: &Mutex<Option<Instant>>) {
+    if let Ok(mut last_contact) = last_contact.lock() {
+        *last_contact = Some(Instant::now());
+    }
+}
+
+/// Refuse the mutations from now on, and after a restart.
+fn fence(fenced: &AtomicBool) {
+    if fenced.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    log::error!("The standby was promoted, this instance is fenced and refuses mutations");
+
+    let marker = fenced_marker_path();
+    if let Err(err) = fs::write(&marker, b"") {
+        log::error!(
+            "Cannot write the fencing marker {} ({err})",
+            marker.display()
+        );
+    }
+}
+
+/// Queue the record if replication is enabled.
+pub(crate) fn ship(shipper: &Option<Data<Shipper>>, record: impl FnOnce() -> Record) {
+    if let Some(shipper) = shipper {
+        if shipper.sender.send(record()).is_err() {
+            log::error!("Replication task is stopped, cannot ship record to the standby");
+        }
+    }
+}
+
+/// Without records to ship during `heartbeat_interval`, an empty batch is sent to renew the
+/// lease (and to be fenced by a promoted standby).
+async fn ship_records(
+    url: String,
+    key: String,
+    mut receiver: UnboundedReceiver<Record>,
+    fenced: Arc<AtomicBool>,
+    heartbeat_interval: Option<Duration>,
+    last_contact: Arc<Mutex<Option<Instant>>>,
+) {
+    let client = reqwest::Client::new();
+
+    // The lease starts expired, the first heartbeat is sent at once
+    let mut first_heartbeat = heartbeat_interval.is_some();
+    loop {
+        let mut records = match heartbeat_interval {
+            Some(_) if mem::take(&mut first_heartbeat) => vec![],
+            Some(interval) => match actix_web::rt::time::timeout(interval, receiver.recv()).await {
+                Ok(Some(record)) => vec![record],
+                Ok(None) => return,
+                // Nothing to ship during the interval, the empty batch is the heartbeat
+                Err(_) => vec![],
+            },
+            None => match receiver.recv().await {
+                Some(record) => vec![record],
+                None => return,
+            },
+        };
+        while !records.is_empty() && records.len() < MAX_RECORDS_PER_REQUEST {
+            match receiver.try_recv() {
+                Ok(record) => records.push(record),
+                Err(_) => break,
+            }
+        }
+
+        // Records must be applied in order so we retry the same batch
+        // until the standby accepts it.
+        let mut delay = Duration::from_secs(1);
+        loop {
+            let result = client
+                .post(&url)
+                .bearer_auth(&key)
+                .json(&records)
+                .send()
+                .await;
+
+            match result.and_then(|response| response.error_for_status()) {
+                Ok(_) => {
+                    renew_lease(&last_contact);
+                    break;
+                }
+                // The standby was promoted, the records will never be applied.
+                Err(err) if err.status() == Some(StatusCode::CONFLICT) => {
+                    fence(&fenced);
+                    return;
+                }
+                // The next heartbeat is sent after the interval
+                Err(err) if records.is_empty() => {
+                    log::warn!("Cannot send the heartbeat to the standby ({err})");
+                    break;
+                }
+                Err(err) => {
+                    log::warn!(
+                        "Cannot ship {} record(s) to the standby, retrying in {delay:?} ({err})",
+                        records.len()
+                    );
+                    actix_web::rt::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+            }
+        }
+    }
+}
+
+/// Health check of the primary called by the standby, fails once fenced.
+#[get("/replication/health")]
+pub(crate) async fn health(auth: BearerAuth, shipper: Data<Shipper>) -> Response<()> {
+    check_bearer_token(auth.token(), &shipper.key)?;
+
+    if shipper.fenced.load(Ordering::SeqCst) {
+        return Err(Error::Fenced);
+    }
+    renew_lease(&shipper.last_contact);
+
+    Ok(Json(()))
+}
+
+#[post("/replication/fence")]
+pub(crate) async fn fence_primary(auth: BearerAuth, shipper: Data<Shipper>) -> Response<()> {
+    check_bearer_token(auth.token(), &shipper.key)?;
+
+    fence(&shipper.fenced);
+
+    Ok(Json(()))
+}
+
+/// Standby side
+pub(crate) struct Standby {
+    key: String,
+    promoted: AtomicBool,
+    /// `REPLICATION_PRIMARY_URL`, to check the primary and fence it on promotion
+    primary_url: Option<String>,
+    backends_reachable: AtomicBool,
+    primary_reachable: AtomicBool,
+}
+
+impl Standby {
+    pub(crate) fn create() -> Self {
+        Standby {
+            key: replication_key(),
+            promoted: AtomicBool::new(false),
+            primary_url: env::var("REPLICATION_PRIMARY_URL")
+                .ok()
+                .map(|url| url.trim_end_matches('/').to_string()),
+            backends_reachable: AtomicBool::new(true),
+            primary_reachable: AtomicBool::new(true),
+        }
+    }
+
+    /// Check the databases and the primary in the background, promote the standby
+    /// when the primary is down.
+    pub(crate) fn start(
+        standby: Data<Standby>,
+        metadata_db: Data<dyn MetadataDatabase>,
+        indexes_db: Data<dyn IndexesDatabase>,
+    ) {
+        let interval = health_check_interval();
+        let failover_after_checks = failover_after_checks();
+
+        if interval.is_zero() {
+            return;
+        }
+
+        actix_web::rt::spawn(async move {
+            let client = reqwest::Client::new();
+            let mut failed_checks = 0;
+
+            while !standby.promoted.load(Ordering::SeqCst) {
+                actix_web::rt::time::sleep(interval).await;
+
+                let backends_reachable = match check_backends(&metadata_db, &indexes_db).await {
+                    Ok(()) => true,
+                    Err(err) => {
+                        log::error!("Standby cannot reach its databases ({err:?})");
+                        #[cfg(feature = "smtp")]
+                        if standby.backends_reachable.load(Ordering::SeqCst) {
+                            crate::smtp::notify(crate::smtp::Alert::HealthCheckFailed {
+                                check: "standby databases",
+                                error: err.to_string(),
+                            });
+                        }
+                        false
+                    }
+                };
+                let were_reachable = standby
+                    .backends_reachable
+                    .swap(backends_reachable, Ordering::SeqCst);
+                if backends_reachable && !were_reachable {
+                    log::info!("Standby can reach its databases again");
+                }
+
+                let Some(primary_url) = &standby.primary_url else {
+                    continue;
+                };
+
+                match standby.check_primary(&client, primary_url).await {
+                    Ok(()) => {
+                        if failed_checks > 0 {
+                            log::info!("Primary is healthy again");
+                        }
+                        failed_checks = 0;
+                    }
+                    Err(err) => {
+                        failed_checks += 1;
+                        log::warn!("Primary health check failed {failed_checks} time(s) ({err})");
+                        #[cfg(feature = "smtp")]
+                        if failed_checks == 1 {
+                            crate::smtp::notify(crate::smtp::Alert::HealthCheckFailed {
+                                check: "primary",
+                                error: err.to_string(),
+                            });
+                        }
+                    }
+                }
+                standby
+                    .primary_reachable
+                    .store(failed_checks == 0, Ordering::SeqCst);
+
+                if failover_after_checks > 0 && failed_checks >= failover_after_checks {
+                    if backends_reachable {
+                        standby.promote("the primary failed its health checks");
+                    } else {
+                        log::error!("Primary is down but the standby cannot reach its databases, not promoting");
+                    }
+                }
+            }
+        });
+    }
+
+    async fn check_primary(
+        &self,
+        client: &reqwest::Client,
+        primary_url: &str,
+    ) -> Result<(), String> {
+        client
+            .get(format!("{primary_url}/replication/health"))
+            .bearer_auth(&self.key)
+            .timeout(HEALTH_CHECK_TIMEOUT)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+
+    /// Accept the mutations from the clients and fence the old primary.
+    fn promote(&self, reason: &str) {
+        if self.promoted.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        log::warn!("Standby promoted ({reason}), mutations from clients are now accepted");
+
+        // Best effort: a primary unreachable now is fenced by the `409` on its next records.
+        if let Some(primary_url) = self.primary_url.clone() {
+            let key = self.key.clone();
+            actix_web::rt::spawn(async move {
+                let result = reqwest::Client::new()
+                    .post(format!("{primary_url}/replication/fence"))
+                    .bearer_auth(key)
+                    .timeout(HEALTH_CHECK_TIMEOUT)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status());
+
+                match result {
+                    Ok(_) => log::info!("Old primary fenced"),
+                    Err(err) => log::warn!("Cannot fence the old primary ({err})"),
+                }
+            });
+        }
+    }
+
+    /// Fail if the instance is a standby not promoted yet.
+    pub(crate) fn check_writable(standby: &Option<Data<Standby>>) -> Result<(), Error> {
+        match standby {
+            Some(standby) if !standby.promoted.load(Ordering::SeqCst) => Err(Error::Standby),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[post("/replication/apply")]
+pub(crate) async fn apply(
+    auth: BearerAuth,
+    records: Json<Vec<Record>>,
+    standby: Data<Standby>,
+    metadata_cache: Data<MetadataCache>,
+    metadata_db: Data<dyn MetadataDatabase>,
+    indexes_db: Data<dyn IndexesDatabase>,
+) -> Response<()> {
+    check_bearer_token(auth.token(), &standby.key)?;
+
+    // The sender is an old primary, `409` fences it.
+    if standby.promoted.load(Ordering::SeqCst) {
+        return Err(Error::Fenced);
+    }
+
+    for record in records.into_inner() {
+        match record {
+            Record::PutIndex {
+                id,
+                name,
+                fetch_entries_key,
+                fetch_chains_key,
+                upsert_entries_key,
+                insert_chains_key,
+                ttl_seconds,
+            } => {
+                if metadata_db.get_index(&id).await?.is_none() {
+                    metadata_db
+                        .create_index(NewIndex {
+                            id,
+                            name,
+                            fetch_entries_key,
+                            fetch_chains_key,
+                            upsert_entries_key,
+                            insert_chains_key,
+                            ttl_seconds,
+                        })
+                        .await?;
+                }
+            }
+            Record::DeleteIndex { id } => {
+                metadata_db.delete_index(&id).await?;
+                metadata_cache.remove(&id);
+            }
+            Record::PutValues {
+                index_id,
+                table,
+                values,
+            } => {
+                let index = metadata_db
+                    .get_index_with_cache(&metadata_cache, &index_id)
+                    .await?
+                    .ok_or_else(|| Error::BadRequest(format!("Unknown index for ID {index_id}")))?;
+
+                let mut data = EncryptedTable::<UID_LENGTH>::with_capacity(values.len());
+                for (uid, value) in values {
+                    let uid: [u8; UID_LENGTH] = general_purpose::STANDARD
+                        .decode(uid)
+                        .ok()
+                        .and_then(|uid| uid.try_into().ok())
+                        .ok_or(Error::WrongEncoding)?;
+                    let value = general_purpose::STANDARD
+                        .decode(value)
+                        .map_err(|_| Error::WrongEncoding)?;
+
+                    data.insert(Uid::from(uid), value);
+                }
+
+                indexes_db.put_values(&index, table, data).await?;
+            }
+            Record::PutSettings { index_id, settings } => {
+                metadata_db.set_settings(&index_id, &settings).await?;
+                metadata_cache.remove(&index_id);
+            }
+        }
+    }
+
+    Ok(Json(()))
+}
+
+#[post("/replication/promote")]
+pub(crate) async fn promote(auth: BearerAuth, standby: Data<Standby>) -> Response<()> {
+    check_bearer_token(auth.token(), &standby.key)?;
+
+    standby.promote("promotion requested");
+
+    Ok(Json(()))
+}
+
+#[post("/admin/replication/promote")]
+pub(crate) async fn admin_promote(_admin: Admin, standby: Data<Standby>) -> Response<()> {
+    standby.promote("promotion requested by an administrator");
+
+    Ok(Json(()))
+}
+
+#[derive(Serialize)]
+struct StandbyStatus {
+    promoted: bool,
+    backends_reachable: bool,
+    /// `None` without `REPLICATION_PRIMARY_URL`
+    primary_reachable: Option<bool>,
+}
+
+#[get("/admin/replication")]
+pub(crate) async fn get_standby_status(
+    _admin: Admin,
+    standby: Data<Standby>,
+) -> Response<StandbyStatus> {
+    Ok(Json(StandbyStatus {
+        promoted: standby.promoted.load(Ordering::SeqCst),
+        backends_reachable: standby.backends_reachable.load(Ordering::SeqCst),
+        primary_reachable: standby
+            .primary_url
+            .as_ref()
+            .map(|_| standby.primary_reachable.load(Ordering::SeqCst)),
+    }))
+}
+
+/// Read the metadata and the data of one index, like the startup check.
+async fn check_backends(
+    metadata_db: &Data<dyn MetadataDatabase>,
+    indexes_db: &Data<dyn IndexesDatabase>,
+) -> Result<(), Error> {
+    let indexes = metadata_db.get_indexes().await?;
+    if let Some(index) = indexes.first() {
+        let uids = HashSet::from([Uid::<UID_LENGTH>::from([0; UID_LENGTH])]);
+        indexes_db.fetch(index, Table::Entries, uids).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,186 @@
+/// Request logging enabled at runtime for a single index, to trace a problematic index on a
+/// production build (the `log_requests` feature captures every request of every index and
+/// must be enabled at compile time).
+///
+/// `PUT /admin/indexes/{id}/request_logging` with `{"enabled": true, "duration_seconds": 600}`
+/// logs the Findex callbacks of the index until the duration expires (1 hour by default, 1 day
+/// at most) or until it's disabled with `{"enabled": false}`. `GET /admin/request_logging`
+/// returns the remaining seconds of each logged index.
+///
+/// Each callback is a JSON line of the application logs (target `findex_cloud::requests`,
+/// `info` level) with the UIDs and the sizes of the values, never the values themselves.
+/// The state is kept in memory, per instance: a restart disables the logging.
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+    time::{Duration, Instant, SystemTime},
+};
+
+use actix_web::{
+    get, put,
+    web::{Data, Json, Path},
+};
+use base64::{engine::general_purpose, Engine};
+use cosmian_findex::{parameters::UID_LENGTH, EncryptedTable, Uid};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin::Admin,
+    errors::{Error, Response},
+};
+
+const DEFAULT_DURATION_SECONDS: u64 = 60 * 60;
+const MAX_DURATION_SECONDS: u64 = 24 * 60 * 60;
+const LOG_TARGET: &str = "findex_cloud::requests";
+
+#[derive(Default)]
+pub(crate) struct RequestLogging {
+    /// Expiry of the logging of each index
+    indexes: RwLock<HashMap<String, Instant>>,
+}
+
+#[derive(Deserialize)]
+struct RequestLoggingToggle {
+    enabled: bool,
+    duration_seconds: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct LoggedValue {
+    uid: String,
+    /// Size of the value in bytes, `None` if the UID has no value
+    size: Option<usize>,
+}
+
+impl RequestLogging {
+    pub(crate) fn is_enabled(&self, index_id: &str) -> bool {
+        match self.indexes.read() {
+            Ok(indexes) => indexes
+                .get(index_id)
+                .map_or(false, |expiry| *expiry > Instant::now()),
+            Err(_) => false,
+        }
+    }
+
+    /// Log a `fetch_entries` or `fetch_chains` with the requested UIDs and the sizes of the
+    /// found values.
+    pub(crate) fn log_fetch<'a>(
+        &self,
+        log_type: &str,
+        index_id: &str,
+        uids: impl IntoIterator<Item = &'a Uid<UID_LENGTH>>,
+        uids_and_values: &EncryptedTable<UID_LENGTH>,
+    ) {
+        let data: Vec<_> = uids
+            .into_iter()
+            .map(|uid| LoggedValue {
+                uid: general_purpose::STANDARD_NO_PAD.encode(uid),
+                size: uids_and_values.get(uid).map(Vec::len),
+            })
+            .collect();
+
+        log_line(log_type, index_id, data);
+    }
+
+    /// Log an `upsert_entries` with the upserted and the rejected UIDs.
+    pub(crate) fn log_upsert(
+        &self,
+        index_id: &str,
+        uids: &[Uid<UID_LENGTH>],
+        rejected: &EncryptedTable<UID_LENGTH>,
+    ) {
+        let encode = |uid: &Uid<UID_LENGTH>| general_purpose::STANDARD_NO_PAD.encode(uid);
+
+        log_line(
+            "upsert_entries",
+            index_id,
+            serde_json::json!({
+                "upserted": uids.iter().map(encode).collect::<Vec<_>>(),
+                "rejected": rejected.keys().map(encode).collect::<Vec<_>>(),
+            }),
+        );
+    }
+
+    /// Log an `insert_chains` with the inserted UIDs and the sizes of the values.
+    pub(crate) fn log_insert(&self, index_id: &str, uids_and_values: &EncryptedTable<UID_LENGTH>) {
+        let data: Vec<_> = uids_and_values
+            .iter()
+            .map(|(uid, value)| LoggedValue {
+                uid: general_purpose::STANDARD_NO_PAD.encode(uid),
+                size: Some(value.len()),
+            })
+            .collect();
+
+        log_line("insert_chains", index_id, data);
+    }
+}
+
+fn log_line(log_type: &str, index_id: &str, data: impl Serialize) {
+    let date = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis());
+
+    match serde_json::to_string(&serde_json::json!({
+        "date": date,
+        "type": log_type,
+        "index_id": index_id,
+        "data": data,
+    })) {
+        Ok(line) => log::info!(target: LOG_TARGET, "{line}"),
+        Err(err) => {
+            log::error!("Cannot serialize the {log_type} request of index {index_id} ({err})")
+        }
+    }
+}
+
+#[get("/admin/request_logging")]
+pub(crate) async fn get_request_logging(
+    _admin: Admin,
+    request_logging: Data<RequestLogging>,
+) -> Response<HashMap<String, u64>> {
+    let now = Instant::now();
+
+    Ok(Json(
+        request_logging
+            .indexes
+            .read()
+            .map_err(|_| Error::Internal("Request logging lock is poisoned".to_string()))?
+            .iter()
+            .filter(|(_, expiry)| **expiry > now)
+            .map(|(index_id, expiry)| (index_id.clone(), expiry.duration_since(now).as_secs()))
+            .collect(),
+    ))
+}
+
+#[put("/admin/indexes/{id}/request_logging")]
+pub(crate) async fn put_request_logging(
+    _admin: Admin,
+    id: Path<String>,
+    body: Json<RequestLoggingToggle>,
+    request_logging: Data<RequestLogging>,
+) -> Response<()> {
+    let duration_seconds = body.duration_seconds.unwrap_or(DEFAULT_DURATION_SECONDS);
+    if duration_seconds == 0 || duration_seconds > MAX_DURATION_SECONDS {
+        return Err(Error::BadRequest(format!(
+            "`duration_seconds` must be between 1 and {MAX_DURATION_SECONDS}"
+        )));
+    }
+
+    let mut indexes = request_logging
+        .indexes
+        .write()
+        .map_err(|_| Error::Internal("Request logging lock is poisoned".to_string()))?;
+
+    let now = Instant::now();
+    indexes.retain(|_, expiry| *expiry > now);
+
+    if body.enabled {
+        indexes.insert(id.to_string(), now + Duration::from_secs(duration_seconds));
+        log::warn!("Request logging enabled for index {id} during {duration_seconds} seconds");
+    } else {
+        indexes.remove(id.as_str());
+        log::warn!("Request logging disabled for index {id}");
+    }
+
+    Ok(Json(()))
+}
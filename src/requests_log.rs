@@ -0,0 +1,900 @@
+/// Storage of the requests log (see `debug_logs.rs`).
+///
+/// The log lines are sent to a channel and written by a background task in
+/// batches, the handlers never wait for the sink. Writing synchronously to the file
+/// inside the handlers (with a lock) added a sync point between requests which
+/// changed the timing patterns we want to measure.
+///
+/// The sink is chosen with `REQUESTS_LOG_SINK`:
+/// - `file` (default): JSON lines inside `REQUESTS_LOG_PATH`, rotated and compressed
+///   (see `file::File` for the size caps)
+/// - `sqlite`: table `requests_log` inside `REQUESTS_LOG_SQLITE_PATH` (requires the "sqlite" feature)
+/// - `kafka`: records sent to `REQUESTS_LOG_KAFKA_TOPIC` with the Kafka REST Proxy
+///   at `KAFKA_REST_PROXY_URL` (requires the "kafka" feature). Logs cannot be read back from Kafka.
+/// - `clickhouse`: rows inserted into `REQUESTS_LOG_CLICKHOUSE_TABLE` with the HTTP interface
+///   at `REQUESTS_LOG_CLICKHOUSE_URL` (requires the "clickhouse" feature), for SQL analysis of
+///   long captures. Logs cannot be read back from Findex Cloud.
+use std::{
+    env,
+    sync::{Arc, RwLock},
+};
+
+use async_trait::async_trait;
+use serde::Serialize;
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+
+use crate::{debug_logs::TimeDiffInMilliseconds, errors::Error};
+
+/// Maximum number of lines written in one batch
+const MAX_LINES_PER_BATCH: usize = 1_000;
+
+#[derive(Serialize, Debug)]
+pub(crate) struct LogLine {
+    /// Timestamp in milliseconds (shifted by `set_time_diff`)
+    pub(crate) date: i128,
+    #[serde(rename = "type")]
+    pub(crate) log_type: String,
+    pub(crate) index_id: String,
+    pub(crate) data: serde_json::Value,
+}
+
+/// Filter of `RequestsLog::query`, `None` fields match all the lines.
+#[derive(Debug, Default)]
+pub(crate) struct LogFilter {
+    /// Inclusive, in milliseconds
+    pub(crate) from: Option<i64>,
+    /// Inclusive, in milliseconds
+    pub(crate) to: Option<i64>,
+    pub(crate) index_id: Option<String>,
+    pub(crate) log_type: Option<String>,
+}
+
+impl LogFilter {
+    fn matches(&self, date: i64, log_type: &str, index_id: Option<&str>) -> bool {
+        self.from.map_or(true, |from| date >= from)
+            && self.to.map_or(true, |to| date <= to)
+            && self
+                .log_type
+                .as_deref()
+                .map_or(true, |expected| expected == log_type)
+            && self
+                .index_id
+                .as_deref()
+                .map_or(true, |expected| Some(expected) == index_id)
+    }
+}
+
+/// A line with its position inside the log, used as pagination cursor
+/// (line number for the file sink, row ID for the SQLite sink).
+pub(crate) struct CursorLine {
+    pub(crate) cursor: u64,
+    pub(crate) line: String,
+}
+
+#[async_trait]
+pub(crate) trait RequestsLogSink: Sync + Send {
+    async fn write(&self, lines: &[LogLine]) -> Result<(), Error>;
+
+    /// All the lines as JSON strings, in order
+    async fn read_all(&self) -> Result<Vec<String>, Error>;
+
+    async fn reset(&self) -> Result<(), Error>;
+
+    /// At most `limit` lines matching the filter with a cursor strictly greater
+    /// than `since`, in order.
+    async fn query(
+        &self,
+        filter: &LogFilter,
+        since: u64,
+        limit: usize,
+    ) -> Result<Vec<CursorLine>, Error>;
+}
+
+enum Message {
+    Line(LogLine),
+    /// Answer when all the previous lines are written
+    Flush(oneshot::Sender<()>),
+}
+
+pub(crate) struct RequestsLog {
+    pub(crate) time_diff: RwLock<TimeDiffInMilliseconds>,
+    sender: UnboundedSender<Message>,
+    sink: Arc<dyn RequestsLogSink>,
+}
+
+impl RequestsLog {
+    pub(crate) async fn create() -> Self {
+        let sink: Arc<dyn RequestsLogSink> = match env::var("REQUESTS_LOG_SINK").as_deref() {
+            Ok("file") | Err(_) => Arc::new(file::File::create()),
+            #[cfg(feature = "sqlite")]
+            Ok("sqlite") => Arc::new(sqlite::Sqlite::create().await),
+            #[cfg(not(feature = "sqlite"))]
+            Ok("sqlite") => panic!("Cannot use the SQLite requests log sink because `findex_cloud` wasn't compiled with \"sqlite\" feature."),
+            #[cfg(feature = "kafka")]
+            Ok("kafka") => Arc::new(kafka::Kafka::create()),
+            #[cfg(not(feature = "kafka"))]
+            Ok("kafka") => panic!("Cannot use the Kafka requests log sink because `findex_cloud` wasn't compiled with \"kafka\" feature."),
+            #[cfg(feature = "clickhouse")]
+            Ok("clickhouse") => Arc::new(clickhouse::ClickHouse::create().await),
+            #[cfg(not(feature = "clickhouse"))]
+            Ok("clickhouse") => panic!("Cannot use the ClickHouse requests log sink because `findex_cloud` wasn't compiled with \"clickhouse\" feature."),
+            Ok(sink) => panic!("Unknown `REQUESTS_LOG_SINK` {sink}"),
+        };
+
+        let (sender, receiver) = unbounded_channel();
+        actix_web::rt::spawn(write_lines(sink.clone(), receiver));
+
+        RequestsLog {
+            time_diff: RwLock::new(Default::default()),
+            sender,
+            sink,
+        }
+    }
+
+    /// Queue the line, never wait for the sink.
+    pub(crate) fn log(&self, line: LogLine) {
+        if self.sender.send(Message::Line(line)).is_err() {
+            log::error!("Requests log task is stopped, cannot log request");
+        }
+    }
+
+    /// Wait for all the queued lines to be written.
+    pub(crate) async fn flush(&self) -> Result<(), Error> {
+        let (sender, receiver) = oneshot::channel();
+        self.sender
+            .send(Message::Flush(sender))
+            .map_err(|_| Error::Internal("Requests log task is stopped".to_string()))?;
+
+        receiver
+            .await
+            .map_err(|_| Error::Internal("Requests log task is stopped".to_string()))
+    }
+
+    pub(crate) async fn read_all(&self) -> Result<Vec<String>, Error> {
+        self.flush().await?;
+        self.sink.read_all().await
+    }
+
+    pub(crate) async fn reset(&self) -> Result<(), Error> {
+        self.flush().await?;
+        self.sink.reset().await
+    }
+
+    pub(crate) async fn query(
+        &self,
+        filter: &LogFilter,
+        since: u64,
+        limit: usize,
+    ) -> Result<Vec<CursorLine>, Error> {
+        self.flush().await?;
+        self.sink.query(filter, since, limit).await
+    }
+}
+
+async fn write_lines(sink: Arc<dyn RequestsLogSink>, mut receiver: UnboundedReceiver<Message>) {
+    while let Some(message) = receiver.recv().await {
+        let mut lines = vec![];
+        let mut flushes = vec![];
+
+        let mut next_message = Some(message);
+        while let Some(message) = next_message.take() {
+            match message {
+                Message::Line(line) => lines.push(line),
+                Message::Flush(flush) => {
+                    flushes.push(flush);
+                    // Lines after the flush are written with the next batch.
+                    break;
+                }
+            }
+
+            if lines.len() < MAX_LINES_PER_BATCH {
+                next_message = receiver.try_recv().ok();
+            }
+        }
+
+        if !lines.is_empty() {
+            if let Err(err) = sink.write(&lines).await {
+                log::error!(
+                    "Cannot write {} requests log line(s) ({err:?})",
+                    lines.len()
+                );
+            }
+        }
+
+        for flush in flushes {
+            let _ = flush.send(());
+        }
+    }
+}
+
+/// JSON lines inside a file, rotated when it's too big or too old.
+///
+/// Rotated segments are compressed next to the file as `{file_name}.{first_cursor}-{last_cursor}.gz`
+/// so the cursors (line numbers since the last reset) stay the same after a rotation. When
+/// the rotated segments take more than `REQUESTS_LOG_MAX_TOTAL_SIZE_MB` the oldest are deleted.
+mod file {
+    use std::{
+        env,
+        fs::{self, OpenOptions},
+        io::{self, BufRead, BufReader, ErrorKind, Write},
+        path::{Path, PathBuf},
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
+    use async_trait::async_trait;
+    use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+    use serde::Deserialize;
+
+    use super::{CursorLine, LogFilter, LogLine, RequestsLogSink};
+    use crate::{config, errors::Error};
+
+    const DEFAULT_MAX_SIZE_IN_MB: u64 = 100;
+    const DEFAULT_MAX_TOTAL_SIZE_IN_MB: u64 = 1024;
+
+    struct Rotation {
+        /// Rotate when the file is bigger (in bytes)
+        max_size: u64,
+        /// Rotate when the file is older (checked on each write)
+        max_age: Option<Duration>,
+        /// Delete the oldest segments when the rotated segments are bigger (in bytes)
+        max_total_size: u64,
+    }
+
+    impl Rotation {
+        fn from_env() -> Self {
+            let env_u64 = |name: &str| env::var(name).ok().and_then(|value| value.parse().ok());
+
+            Rotation {
+                max_size: env_u64("REQUESTS_LOG_MAX_SIZE_MB").unwrap_or(DEFAULT_MAX_SIZE_IN_MB)
+                    * 1024
+                    * 1024,
+                max_age: env_u64("REQUESTS_LOG_ROTATION_INTERVAL_SECONDS").map(Duration::from_secs),
+                max_total_size: env_u64("REQUESTS_LOG_MAX_TOTAL_SIZE_MB")
+                    .unwrap_or(DEFAULT_MAX_TOTAL_SIZE_IN_MB)
+                    * 1024
+                    * 1024,
+            }
+        }
+    }
+
+    /// The file currently written
+    struct Current {
+        /// Cursor of the first line of the file
+        first_cursor: u64,
+        lines: u64,
+        size: u64,
+        opened_at: Instant,
+    }
+
+    /// First cursor and lines of a segment or of the current file
+    type SegmentReader = (u64, Box<dyn BufRead>);
+
+    struct Segment {
+        first_cursor: u64,
+        last_cursor: u64,
+        path: PathBuf,
+    }
+
+    pub(crate) struct File {
+        path: PathBuf,
+        rotation: Rotation,
+        current: Mutex<Current>,
+    }
+
+    impl File {
+        pub(crate) fn create() -> Self {
+            let path = config::requests_log_path();
+            config::prepare_parent_directory(&path);
+
+            let mut file = File {
+                path,
+                rotation: Rotation::from_env(),
+                current: Mutex::new(Current {
+                    first_cursor: 1,
+                    lines: 0,
+                    size: 0,
+                    opened_at: Instant::now(),
+                }),
+            };
+
+            let first_cursor = file
+                .segments()
+                .unwrap_or_else(|e| panic!("Cannot list requests log segments ({e:?})"))
+                .last()
+                .map_or(1, |segment| segment.last_cursor + 1);
+            let (lines, size) = match fs::File::open(&file.path) {
+                Ok(contents) => (
+                    BufReader::new(contents).lines().count() as u64,
+                    fs::metadata(&file.path).map_or(0, |metadata| metadata.len()),
+                ),
+                Err(_) => (0, 0),
+            };
+            *file.current.get_mut().expect("Lock is new") = Current {
+                first_cursor,
+                lines,
+                size,
+                opened_at: Instant::now(),
+            };
+
+            file
+        }
+
+        fn lock(&self) -> Result<std::sync::MutexGuard<'_, Current>, Error> {
+            self.current
+                .lock()
+                .map_err(|_| Error::Internal("Requests log lock is poisoned".to_string()))
+        }
+
+        fn io_error(&self, err: io::Error) -> Error {
+            Error::Internal(format!(
+                "Cannot access requests log {} ({err})",
+                self.path.display()
+            ))
+        }
+
+        /// Rotated segments, oldest first
+        fn segments(&self) -> Result<Vec<Segment>, Error> {
+            let directory = match self.path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            let prefix = format!(
+                "{}.",
+                self.path
+                    .file_name()
+                    .map(|name| name.to_string_lossy())
+                    .unwrap_or_default()
+            );
+
+            let mut segments = vec![];
+            for entry in fs::read_dir(directory).map_err(|err| self.io_error(err))? {
+                let path = entry.map_err(|err| self.io_error(err))?.path();
+                let Some(name) = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                else {
+                    continue;
+                };
+
+                let cursors = name
+                    .strip_prefix(&prefix)
+                    .and_then(|name| name.strip_suffix(".gz"))
+                    .and_then(|cursors| cursors.split_once('-'))
+                    .and_then(|(first, last)| Some((first.parse().ok()?, last.parse().ok()?)));
+
+                if let Some((first_cursor, last_cursor)) = cursors {
+                    segments.push(Segment {
+                        first_cursor,
+                        last_cursor,
+                        path,
+                    });
+                }
+            }
+
+            segments.sort_by_key(|segment| segment.first_cursor);
+
+            Ok(segments)
+        }
+
+        /// Compress the current file to a new segment and apply the retention.
+        fn rotate(&self, current: &mut Current) -> Result<(), Error> {
+            if current.lines > 0 {
+                let last_cursor = current.first_cursor + current.lines - 1;
+                let segment_path = PathBuf::from(format!(
+                    "{}.{}-{last_cursor}.gz",
+                    self.path.display(),
+                    current.first_cursor
+                ));
+
+                let mut reader = fs::File::open(&self.path).map_err(|err| self.io_error(err))?;
+                let mut encoder = GzEncoder::new(
+                    fs::File::create(segment_path).map_err(|err| self.io_error(err))?,
+                    Compression::default(),
+                );
+                io::copy(&mut reader, &mut encoder).map_err(|err| self.io_error(err))?;
+                encoder.finish().map_err(|err| self.io_error(err))?;
+                fs::remove_file(&self.path).map_err(|err| self.io_error(err))?;
+
+                current.first_cursor = last_cursor + 1;
+            }
+
+            current.lines = 0;
+            current.size = 0;
+            current.opened_at = Instant::now();
+
+            let segments = self.segments()?;
+            let sizes: Vec<u64> = segments
+                .iter()
+                .map(|segment| fs::metadata(&segment.path).map_or(0, |metadata| metadata.len()))
+                .collect();
+            let mut total_size: u64 = sizes.iter().sum();
+            for (segment, size) in segments.iter().zip(sizes) {
+                if total_size <= self.rotation.max_total_size {
+                    break;
+                }
+
+                log::warn!(
+                    "Deleting requests log segment {} (retention cap reached)",
+                    segment.path.display()
+                );
+                fs::remove_file(&segment.path).map_err(|err| self.io_error(err))?;
+                total_size -= size;
+            }
+
+            Ok(())
+        }
+
+        /// Readers of the segments and current file containing cursors
+        /// strictly greater than `since`, in order, with their first cursor.
+        fn readers(&self, since: u64) -> Result<Vec<SegmentReader>, Error> {
+            let current = self.lock()?;
+
+            let mut readers: Vec<SegmentReader> = vec![];
+            for segment in self.segments()? {
+                if segment.last_cursor <= since {
+                    continue;
+                }
+
+                let file = fs::File::open(&segment.path).map_err(|err| self.io_error(err))?;
+                readers.push((
+                    segment.first_cursor,
+                    Box::new(BufReader::new(GzDecoder::new(file))),
+                ));
+            }
+
+            match fs::File::open(&self.path) {
+                Ok(file) => readers.push((current.first_cursor, Box::new(BufReader::new(file)))),
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(self.io_error(err)),
+            }
+
+            Ok(readers)
+        }
+    }
+
+    #[async_trait]
+    impl RequestsLogSink for File {
+        async fn write(&self, lines: &[LogLine]) -> Result<(), Error> {
+            let mut contents = Vec::new();
+            for line in lines {
+                serde_json::to_writer(&mut contents, line)?;
+                contents.push(b'\n');
+            }
+
+            let mut current = self.lock()?;
+
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .and_then(|mut file| file.write_all(&contents))
+                .map_err(|err| self.io_error(err))?;
+
+            current.lines += lines.len() as u64;
+            current.size += contents.len() as u64;
+
+            let too_old = self
+                .rotation
+                .max_age
+                .map_or(false, |max_age| current.opened_at.elapsed() >= max_age);
+            if current.size >= self.rotation.max_size || too_old {
+                self.rotate(&mut current)?;
+            }
+
+            Ok(())
+        }
+
+        async fn read_all(&self) -> Result<Vec<String>, Error> {
+            let mut lines = vec![];
+            for (_, reader) in self.readers(0)? {
+                for line in reader.lines() {
+                    lines.push(line.map_err(|err| self.io_error(err))?);
+                }
+            }
+
+            Ok(lines)
+        }
+
+        async fn reset(&self) -> Result<(), Error> {
+            let mut current = self.lock()?;
+
+            for segment in self.segments()? {
+                fs::remove_file(&segment.path).map_err(|err| self.io_error(err))?;
+            }
+            let _ = fs::remove_file(&self.path); // Don't want to crash if the file doesn't exists
+
+            current.first_cursor = 1;
+            current.lines = 0;
+            current.size = 0;
+            current.opened_at = Instant::now();
+
+            Ok(())
+        }
+
+        /// Read the files line by line to not load long captures in memory.
+        async fn query(
+            &self,
+            filter: &LogFilter,
+            since: u64,
+            limit: usize,
+        ) -> Result<Vec<CursorLine>, Error> {
+            /// Only the fields needed to filter, to not deserialize the data
+            #[derive(Deserialize)]
+            struct Fields<'a> {
+                date: i64,
+                #[serde(rename = "type")]
+                log_type: &'a str,
+                index_id: Option<&'a str>,
+            }
+
+            let mut lines = vec![];
+            for (first_cursor, reader) in self.readers(since)? {
+                for (line, cursor) in reader.lines().zip(first_cursor..) {
+                    if cursor <= since {
+                        continue;
+                    }
+
+                    let line = line.map_err(|err| self.io_error(err))?;
+                    let fields: Fields = serde_json::from_str(&line)?;
+                    if filter.matches(fields.date, fields.log_type, fields.index_id) {
+                        lines.push(CursorLine { cursor, line });
+                        if lines.len() >= limit {
+                            return Ok(lines);
+                        }
+                    }
+                }
+            }
+
+            Ok(lines)
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::{env, path::PathBuf, str::FromStr};
+
+    use async_trait::async_trait;
+    use sqlx::{
+        sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+        SqlitePool,
+    };
+
+    use super::{CursorLine, LogFilter, LogLine, RequestsLogSink};
+    use crate::{config, errors::Error};
+
+    /// Lines are stored with their date and type to be able to filter them.
+    /// The table is created on startup, it's not inside the migrations of the
+    /// metadata database because it's another database.
+    pub(crate) struct Sqlite(SqlitePool);
+
+    impl Sqlite {
+        pub(crate) async fn create() -> Self {
+            let path = env::var("REQUESTS_LOG_SQLITE_PATH")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| config::data_dir().join("requests_log.sqlite"));
+            config::prepare_parent_directory(&path);
+
+            let options = SqliteConnectOptions::from_str(&format!("sqlite://{}", path.display()))
+                .unwrap_or_else(|e| panic!("Invalid requests log path {} ({e})", path.display()))
+                .create_if_missing(true);
+            let pool = SqlitePoolOptions::new()
+                .connect_with(options)
+                .await
+                .unwrap_or_else(|e| {
+                    panic!(
+                        "Cannot connect to requests log database at {} ({e})",
+                        path.display()
+                    )
+                });
+
+            sqlx::query(
+                "CREATE TABLE IF NOT EXISTS requests_log (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    date INTEGER NOT NULL,
+                    type TEXT NOT NULL,
+                    index_id TEXT NOT NULL,
+                    line TEXT NOT NULL
+                )",
+            )
+            .execute(&pool)
+            .await
+            .unwrap_or_else(|e| panic!("Cannot create requests log table ({e})"));
+
+            Sqlite(pool)
+        }
+    }
+
+    #[async_trait]
+    impl RequestsLogSink for Sqlite {
+        async fn write(&self, lines: &[LogLine]) -> Result<(), Error> {
+            let mut transaction = self.0.begin().await?;
+
+            for line in lines {
+                sqlx::query(
+                    "INSERT INTO requests_log (date, type, index_id, line) VALUES ($1, $2, $3, $4)",
+                )
+                .bind(line.date as i64)
+                .bind(&line.log_type)
+                .bind(&line.index_id)
+                .bind(serde_json::to_string(line)?)
+                .execute(&mut transaction)
+                .await?;
+            }
+
+            transaction.commit().await?;
+
+            Ok(())
+        }
+
+        async fn read_all(&self) -> Result<Vec<String>, Error> {
+            Ok(
+                sqlx::query_scalar("SELECT line FROM requests_log ORDER BY id")
+                    .fetch_all(&self.0)
+                    .await?,
+            )
+        }
+
+        async fn reset(&self) -> Result<(), Error> {
+            sqlx::query("DELETE FROM requests_log")
+                .execute(&self.0)
+                .await?;
+
+            Ok(())
+        }
+
+        async fn query(
+            &self,
+            filter: &LogFilter,
+            since: u64,
+            limit: usize,
+        ) -> Result<Vec<CursorLine>, Error> {
+            let rows: Vec<(i64, String)> = sqlx::query_as(
+                "SELECT id, line FROM requests_log
+                WHERE id > $1
+                    AND ($2 IS NULL OR date >= $2)
+                    AND ($3 IS NULL OR date <= $3)
+                    AND ($4 IS NULL OR index_id = $4)
+                    AND ($5 IS NULL OR type = $5)
+                ORDER BY id
+                LIMIT $6",
+            )
+            .bind(since as i64)
+            .bind(filter.from)
+            .bind(filter.to)
+            .bind(&filter.index_id)
+            .bind(&filter.log_type)
+            .bind(limit as i64)
+            .fetch_all(&self.0)
+            .await?;
+
+            Ok(rows
+                .into_iter()
+                .map(|(cursor, line)| CursorLine {
+                    cursor: cursor as u64,
+                    line,
+                })
+                .collect())
+        }
+    }
+}
+
+#[cfg(feature = "kafka")]
+mod kafka {
+    use std::env;
+
+    use async_trait::async_trait;
+    use serde_json::json;
+
+    use super::{CursorLine, LogFilter, LogLine, RequestsLogSink};
+    use crate::errors::Error;
+
+    /// Send the lines to Kafka with the Confluent REST Proxy API v2
+    /// (see `events::kafka`). The record key is the log type.
+    pub(crate) struct Kafka {
+        client: reqwest::Client,
+        url: String,
+    }
+
+    impl Kafka {
+        pub(crate) fn create() -> Self {
+            let rest_proxy_url = env::var("KAFKA_REST_PROXY_URL").expect(
+                "`KAFKA_REST_PROXY_URL` env variable is required to use the Kafka requests log sink",
+            );
+            let topic = env::var("REQUESTS_LOG_KAFKA_TOPIC")
+                .unwrap_or_else(|_| "findex_cloud_requests_log".to_string());
+
+            Kafka {
+                client: reqwest::Client::new(),
+                url: format!("{}/topics/{topic}", rest_proxy_url.trim_end_matches('/')),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl RequestsLogSink for Kafka {
+        async fn write(&self, lines: &[LogLine]) -> Result<(), Error> {
+            let records: Vec<_> = lines
+                .iter()
+                .map(|line| json!({ "key": line.log_type, "value": line }))
+                .collect();
+
+            let response = self
+                .client
+                .post(&self.url)
+                .header("Content-Type", "application/vnd.kafka.json.v2+json")
+                .json(&json!({ "records": records }))
+                .send()
+                .await
+                .map_err(|err| Error::Internal(err.to_string()))?;
+
+            if !response.status().is_success() {
+                return Err(Error::Internal(format!(
+                    "Kafka REST Proxy responded with status {}",
+                    response.status()
+                )));
+            }
+
+            Ok(())
+        }
+
+        async fn read_all(&self) -> Result<Vec<String>, Error> {
+            Err(Error::Unsupported(
+                "Requests logs sent to Kafka cannot be read from Findex Cloud".to_string(),
+            ))
+        }
+
+        async fn reset(&self) -> Result<(), Error> {
+            Err(Error::Unsupported(
+                "Requests logs sent to Kafka cannot be reset from Findex Cloud".to_string(),
+            ))
+        }
+
+        async fn query(
+            &self,
+            _filter: &LogFilter,
+            _since: u64,
+            _limit: usize,
+        ) -> Result<Vec<CursorLine>, Error> {
+            Err(Error::Unsupported(
+                "Requests logs sent to Kafka cannot be queried from Findex Cloud".to_string(),
+            ))
+        }
+    }
+}
+
+#[cfg(feature = "clickhouse")]
+mod clickhouse {
+    use std::env;
+
+    use async_trait::async_trait;
+    use serde_json::json;
+
+    use super::{CursorLine, LogFilter, LogLine, RequestsLogSink};
+    use crate::{config, errors::Error};
+
+    /// Insert the lines with the ClickHouse HTTP interface (`JSONEachRow` format). The
+    /// table is created on startup if it doesn't exist, sorted by index and date for
+    /// the analysis of the access patterns of an index. `data` is the logged JSON as a
+    /// string (query it with the `JSONExtract*` functions).
+    pub(crate) struct ClickHouse {
+        client: reqwest::Client,
+        url: String,
+        table: String,
+        user: Option<String>,
+        password: Option<String>,
+    }
+
+    impl ClickHouse {
+        pub(crate) async fn create() -> Self {
+            let url = env::var("REQUESTS_LOG_CLICKHOUSE_URL").expect(
+                "`REQUESTS_LOG_CLICKHOUSE_URL` env variable is required to use the ClickHouse requests log sink",
+            );
+            let table = env::var("REQUESTS_LOG_CLICKHOUSE_TABLE")
+                .unwrap_or_else(|_| "findex_cloud_requests_log".to_string());
+
+            let clickhouse = ClickHouse {
+                client: reqwest::Client::new(),
+                url: url.trim_end_matches('/').to_string(),
+                table,
+                user: env::var("REQUESTS_LOG_CLICKHOUSE_USER").ok(),
+                password: config::secret_from_env("REQUESTS_LOG_CLICKHOUSE_PASSWORD"),
+            };
+
+            clickhouse
+                .execute(
+                    &format!(
+                        "CREATE TABLE IF NOT EXISTS {} (
+                            date Int64,
+                            type LowCardinality(String),
+                            index_id String,
+                            data String
+                        ) ENGINE = MergeTree ORDER BY (index_id, date)",
+                        clickhouse.table
+                    ),
+                    String::new(),
+                )
+                .await
+                .unwrap_or_else(|e| {
+                    panic!("Cannot create requests log table in ClickHouse ({e:?})")
+                });
+
+            clickhouse
+        }
+
+        async fn execute(&self, query: &str, body: String) -> Result<(), Error> {
+            let mut request = self.client.post(&self.url).query(&[("query", query)]);
+            if let Some(user) = &self.user {
+                request = request.header("X-ClickHouse-User", user);
+            }
+            if let Some(password) = &self.password {
+                request = request.header("X-ClickHouse-Key", password);
+            }
+
+            let response = request
+                .body(body)
+                .send()
+                .await
+                .map_err(|err| Error::Internal(err.to_string()))?;
+
+            if !response.status().is_success() {
+                let status = response.status();
+                let message = response.text().await.unwrap_or_default();
+                return Err(Error::Internal(format!(
+                    "ClickHouse responded with status {status} ({})",
+                    message.trim()
+                )));
+            }
+
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl RequestsLogSink for ClickHouse {
+        async fn write(&self, lines: &[LogLine]) -> Result<(), Error> {
+            let mut rows = String::new();
+            for line in lines {
+                rows.push_str(&serde_json::to_string(&json!({
+                    "date": line.date as i64,
+                    "type": line.log_type,
+                    "index_id": line.index_id,
+                    "data": serde_json::to_string(&line.data)?,
+                }))?);
+                rows.push('\n');
+            }
+
+            self.execute(
+                &format!("INSERT INTO {} FORMAT JSONEachRow", self.table),
+                rows,
+            )
+            .await
+        }
+
+        async fn read_all(&self) -> Result<Vec<String>, Error> {
+            Err(Error::Unsupported(
+                "Requests logs sent to ClickHouse cannot be read from Findex Cloud".to_string(),
+            ))
+        }
+
+        async fn reset(&self) -> Result<(), Error> {
+            self.execute(&format!("TRUNCATE TABLE {}", self.table), String::new())
+                .await
+        }
+
+        async fn query(
+            &self,
+            _filter: &LogFilter,
+            _since: u64,
+            _limit: usize,
+        ) -> Result<Vec<CursorLine>, Error> {
+            Err(Error::Unsupported(
+                "Requests logs sent to ClickHouse cannot be queried from Findex Cloud".to_string(),
+            ))
+        }
+    }
+}
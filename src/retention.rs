@@ -0,0 +1,332 @@
+/// Retention policy for the stale indexes, to clean up the abandoned indexes (tests, demos…).
+///
+/// The Findex callbacks (fetch, upsert and insert) record the activity of their index in
+/// memory, saved inside the metadata database (`last_activity_at`) by the `retention` job
+/// (every hour by default, see `scheduler.rs`). An index
+/// without activity for `RETENTION_STALE_AFTER_DAYS` days (disabled by default, the creation
+/// date is used for the indexes never used) is flagged as stale (`stale_at`). With
+/// `RETENTION_PURGE=true`, a stale index is deleted (metadata and data) after
+/// `RETENTION_GRACE_PERIOD_DAYS` days (7 by default), otherwise it stays flagged. Any
+/// activity during the grace period removes the flag.
+///
+/// With the "webhooks" feature, `RETENTION_WEBHOOK_URL` receives a `POST` when an index is
+/// flagged (`"event": "stale"`) and when it is purged (`"event": "purged"`). The archived
+/// indexes are ignored, and the policy doesn't run on a warm standby (the purges are
+/// shipped by the primary).
+///
+/// `GET /admin/reports/inactive_indexes?days=N` (with the admin API key) lists the indexes
+/// without activity for `N` days (`RETENTION_STALE_AFTER_DAYS` by default), the oldest
+/// activity first, to contact their users before the purge.
+use std::{collections::HashMap, env, mem, sync::Mutex};
+
+use actix_web::{
+    get,
+    web::{Data, Json, Query},
+};
+use chrono::{NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "replication")]
+use crate::replication::{self, Record, Shipper};
+use crate::{
+    admin::Admin,
+    core::{Index, IndexesDatabase, MetadataCache, MetadataDatabase},
+    errors::{Error, Response},
+};
+
+const DEFAULT_RETENTION_GRACE_PERIOD_DAYS: i64 = 7;
+
+pub(crate) struct Retention {
+    stale_after: chrono::Duration,
+    grace_period: chrono::Duration,
+    purge: bool,
+    /// Last activity of the indexes, not saved yet inside the metadata database
+    pending_activity: Mutex<HashMap<String, NaiveDateTime>>,
+    #[cfg(feature = "webhooks")]
+    webhook_url: Option<String>,
+}
+
+impl Retention {
+    pub(crate) fn from_env() -> Option<Data<Retention>> {
+        let stale_after_days: i64 = env::var("RETENTION_STALE_AFTER_DAYS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|days| *days > 0)?;
+
+        let grace_period_days = env::var("RETENTION_GRACE_PERIOD_DAYS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|days| *days >= 0)
+            .unwrap_or(DEFAULT_RETENTION_GRACE_PERIOD_DAYS);
+
+        #[cfg(not(feature = "webhooks"))]
+        if env::var("RETENTION_WEBHOOK_URL").is_ok() {
+            panic!("Cannot load `RETENTION_WEBHOOK_URL` because `findex_cloud` wasn't compiled with \"webhooks\" feature.");
+        }
+
+        Some(Data::new(Retention {
+            stale_after: chrono::Duration::days(stale_after_days),
+            grace_period: chrono::Duration::days(grace_period_days),
+            purge: env::var("RETENTION_PURGE").as_deref() == Ok("true"),
+            pending_activity: Mutex::new(HashMap::new()),
+            #[cfg(feature = "webhooks")]
+            webhook_url: env::var("RETENTION_WEBHOOK_URL").ok(),
+        }))
+    }
+
+    /// Called by the Findex callbacks after the signature check.
+    pub(crate) fn record_activity(retention: &Option<Data<Retention>>, index_id: &str) {
+        if let Some(retention) = retention {
+            if let Ok(mut pending_activity) = retention.pending_activity.lock() {
+                pending_activity.insert(index_id.to_string(), Utc::now().naive_utc());
+            }
+        }
+    }
+
+    /// Save the activity and apply the policy to every index (`retention` job).
+    pub(crate) async fn run(
+        &self,
+        metadata_db: &Data<dyn MetadataDatabase>,
+        indexes_db: &Data<dyn IndexesDatabase>,
+        metadata_cache: &MetadataCache,
+        #[cfg(feature = "replication")] shipper: &Option<Data<Shipper>>,
+    ) -> Result<(), Error> {
+        self.save_activity(metadata_db).await;
+
+        // A fenced primary doesn't purge anymore, the new primary does.
+        #[cfg(feature = "replication")]
+        if Shipper::check_writable(shipper).is_err() {
+            return Ok(());
+        }
+
+        for index in metadata_db.get_indexes().await? {
+            if let Err(err) = self
+                .apply(
+                    &index,
+                    metadata_db,
+                    indexes_db,
+                    metadata_cache,
+                    #[cfg(feature = "replication")]
+                    shipper,
+                )
+                .await
+            {
+                log::error!(
+                    "Cannot apply the retention policy to index {} ({err:?})",
+                    index.id
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn save_activity(&self, metadata_db: &Data<dyn MetadataDatabase>) {
+        let pending_activity = match self.pending_activity.lock() {
+            Ok(mut pending_activity) => mem::take(&mut *pending_activity),
+            Err(_) => return,
+        };
+
+        for (index_id, last_activity_at) in pending_activity {
+            if let Err(err) = metadata_db
+                .set_last_activity_at(&index_id, last_activity_at)
+                .await
+            {
+                log::error!("Cannot save the last activity of index {index_id} ({err:?})");
+
+                // Retried at the next check, unless a newer activity was recorded
+                if let Ok(mut pending_activity) = self.pending_activity.lock() {
+                    pending_activity.entry(index_id).or_insert(last_activity_at);
+                }
+            }
+        }
+    }
+
+    async fn apply(
+        &self,
+        index: &Index,
+        metadata_db: &Data<dyn MetadataDatabase>,
+        indexes_db: &Data<dyn IndexesDatabase>,
+        metadata_cache: &MetadataCache,
+        #[cfg(feature = "replication")] shipper: &Option<Data<Shipper>>,
+    ) -> Result<(), Error> {
+        if index.archived_at.is_some() {
+            return Ok(());
+        }
+
+        // Activity recorded since `save_activity()`
+        let Some(last_activity_at) = self.last_activity_at(index) else {
+            return Ok(());
+        };
+
+        let now = Utc::now().naive_utc();
+        if now - last_activity_at < self.stale_after {
+            return Ok(());
+        }
+
+        match index.stale_at {
+            None => {
+                metadata_db.set_stale_at(&index.id, now).await?;
+
+                let purge_at = self.purge.then(|| now + self.grace_period);
+                log::warn!(
+                    "Index {} is stale (no activity since {last_activity_at}), purge at {purge_at:?}",
+                    index.id
+                );
+
+                #[cfg(feature = "webhooks")]
+                self.notify(&index.id, "stale", last_activity_at, purge_at)
+                    .await;
+            }
+            Some(stale_at) if self.purge && now - stale_at >= self.grace_period => {
+                metadata_db.delete_index(&index.id).await?;
+                metadata_cache.remove(&index.id);
+
+                // The index is already unreachable, if it fails the data is orphaned
+                // (see `check.rs`).
+                match indexes_db.delete_index_data(&index.id).await {
+                    Ok(()) | Err(Error::Unsupported(_)) => {}
+                    Err(err) => {
+                        log::error!("Cannot delete the data of index {} ({err:?})", index.id)
+                    }
+                }
+
+                #[cfg(feature = "replication")]
+                replication::ship(shipper, || Record::DeleteIndex {
+                    id: index.id.clone(),
+                });
+
+                log::warn!(
+                    "Stale index {} purged (no activity since {last_activity_at})",
+                    index.id
+                );
+
+                #[cfg(feature = "webhooks")]
+                self.notify(&index.id, "purged", last_activity_at, None)
+                    .await;
+            }
+            Some(_) => {}
+        }
+
+        Ok(())
+    }
+
+    /// Last activity of the index, `None` if it's active since the last `save_activity()`
+    fn last_activity_at(&self, index: &Index) -> Option<NaiveDateTime> {
+        let active = self
+            .pending_activity
+            .lock()
+            .map_or(true, |pending_activity| {
+                pending_activity.contains_key(&index.id)
+            });
+
+        (!active).then(|| index.last_activity_at.unwrap_or(index.created_at))
+    }
+
+    #[cfg(feature = "webhooks")]
+    async fn notify(
+        &self,
+        index_id: &str,
+        event: &str,
+        last_activity_at: NaiveDateTime,
+        purge_at: Option<NaiveDateTime>,
+    ) {
+        let Some(webhook_url) = &self.webhook_url else {
+            return;
+        };
+
+        let result = reqwest::Client::new()
+            .post(webhook_url)
+            .json(&serde_json::json!({
+                "index_id": index_id,
+                "event": event,
+                "last_activity_at": last_activity_at,
+                "purge_at": purge_at,
+            }))
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+
+        if let Err(err) = result {
+            log::error!("Cannot notify the retention webhook for index {index_id} ({err})");
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InactiveIndexesQuery {
+    days: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct InactiveIndex {
+    id: String,
+    name: String,
+    size: Option<i64>,
+    created_at: NaiveDateTime,
+    /// The creation date for the indexes never used since the retention policy is enabled
+    last_activity_at: NaiveDateTime,
+    stale_at: Option<NaiveDateTime>,
+    fetches: i64,
+    upserts: i64,
+    chain_inserts: i64,
+}
+
+#[derive(Serialize)]
+struct InactiveIndexesReport {
+    days: i64,
+    indexes: Vec<InactiveIndex>,
+}
+
+#[get("/admin/reports/inactive_indexes")]
+pub(crate) async fn inactive_indexes(
+    _admin: Admin,
+    query: Query<InactiveIndexesQuery>,
+    retention: Option<Data<Retention>>,
+    metadata_db: Data<dyn MetadataDatabase>,
+    indexes_db: Data<dyn IndexesDatabase>,
+) -> Response<InactiveIndexesReport> {
+    let Some(retention) = retention else {
+        return Err(Error::Unsupported(
+            "The activity of the indexes is only tracked with a retention policy (set `RETENTION_STALE_AFTER_DAYS`)".to_string(),
+        ));
+    };
+
+    let days = query.days.unwrap_or(retention.stale_after.num_days());
+    if days < 0 {
+        return Err(Error::BadRequest("`days` must be positive".to_string()));
+    }
+    let inactive_since = Utc::now().naive_utc() - chrono::Duration::days(days);
+
+    let mut indexes: Vec<_> = metadata_db
+        .get_indexes()
+        .await?
+        .into_iter()
+        .filter(|index| index.archived_at.is_none())
+        .filter(|index| {
+            retention
+                .last_activity_at(index)
+                .map_or(false, |last_activity_at| last_activity_at <= inactive_since)
+        })
+        .collect();
+    indexes_db.set_sizes(&mut indexes).await?;
+
+    let mut indexes: Vec<_> = indexes
+        .into_iter()
+        .map(|index| InactiveIndex {
+            last_activity_at: index.last_activity_at.unwrap_or(index.created_at),
+            id: index.id,
+            name: index.name,
+            size: index.size,
+            created_at: index.created_at,
+            stale_at: index.stale_at,
+            fetches: index.fetches,
+            upserts: index.upserts,
+            chain_inserts: index.chain_inserts,
+        })
+        .collect();
+    indexes.sort_by_key(|index| index.last_activity_at);
+
+    Ok(Json(InactiveIndexesReport { days, indexes }))
+}
@@ -1,81 +1,406 @@
-use std::{collections::HashSet, iter::zip};
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs,
+    iter::zip,
+    num::NonZeroUsize,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+};
 
+use actix_web::web;
 use async_trait::async_trait;
+use cloudproof_findex::cloud::INDEX_ID_LENGTH;
 use cosmian_findex::{parameters::UID_LENGTH, EncryptedTable, Uid, UpsertData};
-use rocksdb::{MergeOperands, Options, TransactionDB, TransactionDBOptions};
+use rocksdb::{
+    backup::{BackupEngine, BackupEngineInfo, BackupEngineOptions, RestoreOptions},
+    BottommostLevelCompaction, CompactOptions, Direction, Env, ErrorKind, IteratorMode,
+    MergeOperands, OptimisticTransactionDB, Options, TransactionDB, TransactionDBOptions,
+    WriteBatch, WriteBatchWithTransaction, DB,
+};
 
 use crate::{
+    backup::BackupInfo,
+    changes::Change,
+    checksum::Checksums,
+    config,
     core::{Index, IndexesDatabase, Table},
     errors::Error,
+    events::Mutation,
+    metrics::StorageGauge,
+    schema::{self, SCHEMA_UPGRADE_CURSOR_KEY, SCHEMA_VERSION_KEY},
+    scrub::ScrubBatch,
 };
 
-pub(crate) struct Database(TransactionDB);
+const DEFAULT_BACKUPS_TO_KEEP: usize = 7;
+/// Number of keys copied to the backup staging database in one write
+const BACKUP_BATCH_SIZE: usize = 10_000;
+/// Number of values rewritten in one write by the schema upgrades
+const UPGRADE_BATCH_SIZE: usize = 10_000;
+/// RocksDB properties reported in the metrics: (property, metric name, help)
+const ROCKSDB_GAUGES: [(&str, &str, &str); 6] = [
+    (
+        "rocksdb.compaction-pending",
+        "findex_cloud_rocksdb_compaction_pending",
+        "1 if at least one RocksDB compaction is pending.",
+    ),
+    (
+        "rocksdb.num-running-compactions",
+        "findex_cloud_rocksdb_running_compactions",
+        "Number of RocksDB compactions running.",
+    ),
+    (
+        "rocksdb.estimate-pending-compaction-bytes",
+        "findex_cloud_rocksdb_pending_compaction_bytes",
+        "Estimated bytes to rewrite by the RocksDB compactions.",
+    ),
+    (
+        "rocksdb.cur-size-all-mem-tables",
+        "findex_cloud_rocksdb_memtables_bytes",
+        "Size of the active and unflushed immutable RocksDB memtables.",
+    ),
+    (
+        "rocksdb.num-immutable-mem-table",
+        "findex_cloud_rocksdb_immutable_memtables",
+        "Number of immutable RocksDB memtables waiting to be flushed.",
+    ),
+    (
+        "rocksdb.actual-delayed-write-rate",
+        "findex_cloud_rocksdb_delayed_write_rate",
+        "Write rate (bytes per second) imposed by RocksDB when the writes are stalled, 0 otherwise.",
+    ),
+];
+
+/// Minimum number of UIDs upserted by each blocking task of `upsert_entries`
+const MIN_UPSERT_CHUNK_SIZE: usize = 256;
+
+/// Cloned into the blocking tasks running the RocksDB calls (see `blocking`).
+#[derive(Clone)]
+pub(crate) struct Database {
+    db: Arc<Db>,
+    /// Locked while appending to the changes log to give consecutive cursors to the changes
+    changes_lock: Arc<Mutex<()>>,
+    /// Locked while a backup is running (only one `BackupEngine` can write inside the
+    /// backup directory)
+    backup_lock: Arc<Mutex<()>>,
+    /// Configured by `VALUES_CHECKSUMS`
+    checksums: Checksums,
+    /// Number of blocking tasks sharing a large `upsert_entries`
+    /// (`ROCKSDB_UPSERT_PARALLELISM`)
+    upsert_parallelism: usize,
+}
+
+/// `ROCKSDB_TRANSACTIONS` chooses how the transactions of `upsert_entries` are isolated:
+/// - `pessimistic` (default): a `TransactionDB` locks the key when it's read, a concurrent
+///   upsert of the same key waits for the lock (10ms at most) and is rejected after,
+/// - `optimistic`: an `OptimisticTransactionDB` takes no lock and detects the conflicts on
+///   commit, the UID is then rejected. Faster when the same keys are rarely written at the
+///   same time.
+///
+/// A rejected UID is returned with its current value, like a wrong `old_value`, so the client
+/// runs another round. The database files are the same, the mode can change on restart.
+enum Db {
+    Pessimistic(TransactionDB),
+    Optimistic(OptimisticTransactionDB),
+}
+
+/// Run `$body` with `$db` bound to the inner database of a `Db`, whatever its type.
+macro_rules! with_db {
+    ($database:expr, |$db:ident| $body:expr) => {
+        match $database {
+            Db::Pessimistic($db) => $body,
+            Db::Optimistic($db) => $body,
+        }
+    };
+}
+
+type KeyValueResult = Result<(Box<[u8]>, Box<[u8]>), rocksdb::Error>;
+
+impl Db {
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, rocksdb::Error> {
+        with_db!(self, |db| db.get(key))
+    }
+
+    fn put(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<(), rocksdb::Error> {
+        with_db!(self, |db| db.put(key, value))
+    }
+
+    fn merge(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<(), rocksdb::Error> {
+        with_db!(self, |db| db.merge(key, value))
+    }
+
+    fn multi_get(
+        &self,
+        keys: impl IntoIterator<Item = Vec<u8>>,
+    ) -> Vec<Result<Option<Vec<u8>>, rocksdb::Error>> {
+        with_db!(self, |db| db.multi_get(keys))
+    }
+
+    fn write(&self, batch: WriteBatchWithTransaction<true>) -> Result<(), rocksdb::Error> {
+        with_db!(self, |db| db.write(batch))
+    }
+
+    fn iterator<'a>(
+        &'a self,
+        mode: IteratorMode<'a>,
+    ) -> Box<dyn Iterator<Item = KeyValueResult> + 'a> {
+        with_db!(self, |db| Box::new(db.iterator(mode)))
+    }
+
+    /// `None` with the pessimistic transactions: the `TransactionDB` of this version of the
+    /// rocksdb crate doesn't give access to the properties of its database.
+    fn property_int_value(&self, name: &str) -> Result<Option<u64>, rocksdb::Error> {
+        match self {
+            Db::Pessimistic(_) => Ok(None),
+            Db::Optimistic(db) => db.property_int_value(name),
+        }
+    }
+}
+
+/// The commit of an optimistic transaction failed because the keys it read were written
+/// since.
+fn is_conflict(err: &rocksdb::Error) -> bool {
+    matches!(err.kind(), ErrorKind::Busy | ErrorKind::TryAgain)
+}
 
 impl Database {
     pub(crate) fn create() -> Self {
-        let indexes_url = "data/indexes_rocksdb";
+        let indexes_url = config::rocksdb_path();
+        config::prepare_directory(&indexes_url);
 
+        let optimistic = match env::var("ROCKSDB_TRANSACTIONS").as_deref() {
+            Ok("pessimistic") | Err(_) => false,
+            Ok("optimistic") => true,
+            Ok(value) => panic!(
+                "`ROCKSDB_TRANSACTIONS` must be `pessimistic` or `optimistic` (found `{value}`)"
+            ),
+        };
+
+        let upsert_parallelism = match env::var("ROCKSDB_UPSERT_PARALLELISM") {
+            Ok(value) => match value.parse() {
+                Ok(parallelism) if parallelism > 0 => parallelism,
+                _ => panic!(
+                    "`ROCKSDB_UPSERT_PARALLELISM` must be a positive number (found `{value}`)"
+                ),
+            },
+            Err(_) => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        };
+
+        Self::open(
+            &indexes_url,
+            optimistic,
+            Checksums::from_env(),
+            upsert_parallelism,
+        )
+    }
+
+    fn open(
+        path: &Path,
+        optimistic: bool,
+        checksums: Checksums,
+        upsert_parallelism: usize,
+    ) -> Self {
         let mut opts = Options::default();
         opts.create_if_missing(true);
         opts.set_merge_operator_associative("add", merge_add);
         opts.set_max_open_files(10);
-        let mut txn_db_opts = TransactionDBOptions::default();
-        txn_db_opts.set_txn_lock_timeout(10);
 
-        let transaction_db: TransactionDB = TransactionDB::open(&opts, &txn_db_opts, indexes_url)
-            .expect("Cannot open RocksDB database");
+        let db = if optimistic {
+            Db::Optimistic(
+                OptimisticTransactionDB::open(&opts, path).expect("Cannot open RocksDB database"),
+            )
+        } else {
+            let mut txn_db_opts = TransactionDBOptions::default();
+            txn_db_opts.set_txn_lock_timeout(10);
+
+            Db::Pessimistic(
+                TransactionDB::open(&opts, &txn_db_opts, path)
+                    .expect("Cannot open RocksDB database"),
+            )
+        };
 
-        Database(transaction_db)
+        let database = Database {
+            db: Arc::new(db),
+            changes_lock: Arc::new(Mutex::new(())),
+            backup_lock: Arc::new(Mutex::new(())),
+            checksums,
+            upsert_parallelism,
+        };
+        database.check_schema();
+
+        database
     }
-}
 
-#[async_trait]
-impl IndexesDatabase for Database {
-    async fn set_size(&self, index: &mut Index) -> Result<(), Error> {
-        index.size = Some(
-            self.0
-                .get(size_key(index))?
-                .and_then(|bytes| bytes.try_into().ok())
-                .map(|bytes| usize::from_be_bytes(bytes) as i64)
-                .unwrap_or(0),
+    /// See `schema.rs`
+    fn check_schema(&self) {
+        let stored_version = self
+            .db
+            .get(SCHEMA_VERSION_KEY)
+            .expect("Cannot read the schema version of the RocksDB database");
+        let has_data = self.db.iterator(IteratorMode::Start).next().is_some();
+
+        schema::check(
+            "RocksDB",
+            stored_version.as_deref(),
+            has_data,
+            |version| self.upgrade_schema(version),
+            |version| Ok(self.db.put(SCHEMA_VERSION_KEY, version.to_be_bytes())?),
         );
+    }
+
+    /// Rewrite the keys from the schema `version` to `version + 1`.
+    fn upgrade_schema(&self, version: u32) -> Result<(), Error> {
+        match version {
+            1 => self.upgrade_values_format(),
+            _ => Err(Error::Internal(format!(
+                "No upgrade from the schema version {version}"
+            ))),
+        }
+    }
+
+    /// Schema 1 to 2: prefix all the values with the plain format byte (the schema 1 had no
+    /// checksums), the fetched values are then rewritten in the configured format.
+    ///
+    /// Each batch is written with its size deltas and the upgrade cursor, an interrupted
+    /// upgrade resumes after the last written batch (see `schema.rs`).
+    fn upgrade_values_format(&self) -> Result<(), Error> {
+        let from = match self.db.get(SCHEMA_UPGRADE_CURSOR_KEY)? {
+            Some(cursor) => {
+                log::warn!("Resuming the interrupted RocksDB schema upgrade");
+                cursor
+            }
+            None => schema::first_index_key(),
+        };
+
+        let mut batch = WriteBatchWithTransaction::<true>::default();
+        let mut batch_length = 0;
+        // Each value is one byte longer
+        let mut added_sizes: HashMap<Vec<u8>, usize> = HashMap::new();
+
+        for result in self
+            .db
+            .iterator(IteratorMode::From(&from, Direction::Forward))
+        {
+            let (key, value) = result?;
+            // Already rewritten by the interrupted upgrade
+            if *key == *from {
+                continue;
+            }
+            let Some(table_size_prefix) = value_table_size_prefix(&key) else {
+                continue;
+            };
+
+            let index_id = &key[..INDEX_ID_LENGTH];
+            for size_key in [
+                [index_id, &[Prefix::Size as u8]].concat(),
+                [index_id, &[table_size_prefix as u8]].concat(),
+            ] {
+                *added_sizes.entry(size_key).or_default() += 1;
+            }
+
+            batch.put(&key, Checksums::add_format_byte(value.into_vec()));
+            batch_length += 1;
+            if batch_length == UPGRADE_BATCH_SIZE {
+                for (size_key, added_size) in added_sizes.drain() {
+                    batch.merge(size_key, added_size.to_be_bytes());
+                }
+                batch.put(SCHEMA_UPGRADE_CURSOR_KEY, &key);
+                self.db.write(std::mem::take(&mut batch))?;
+                batch_length = 0;
+            }
+        }
+
+        for (size_key, added_size) in added_sizes {
+            batch.merge(size_key, added_size.to_be_bytes());
+        }
+        batch.delete(SCHEMA_UPGRADE_CURSOR_KEY);
+        batch.put(SCHEMA_VERSION_KEY, 2_u32.to_be_bytes());
+        self.db.write(batch)?;
 
         Ok(())
     }
 
-    async fn fetch(
+    /// Rewrite a fetched value stored in another format than the configured one, only if it
+    /// didn't change since it was read.
+    fn rewrite_value(
         &self,
         index: &Index,
         table: Table,
-        uids: HashSet<Uid<UID_LENGTH>>,
-    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
-        let mut uids_and_values = EncryptedTable::<UID_LENGTH>::with_capacity(uids.len());
+        uid: &Uid<UID_LENGTH>,
+        stored_value: &[u8],
+        value: &[u8],
+    ) -> Result<(), Error> {
+        let key = key(index, table, uid);
+        let new_value = self.checksums.wrap(value.to_vec());
 
-        let values = self
-            .0
-            .multi_get(uids.iter().map(|uid| key(index, table, uid)));
+        with_db!(&*self.db, |db| {
+            let transaction = db.transaction();
+            if transaction.get_for_update(&key, true)?.as_deref() != Some(stored_value) {
+                transaction.rollback()?;
+                return Ok(());
+            }
 
-        for (uid, value) in zip(uids.into_iter(), values.into_iter()) {
-            let value = value?;
-            if let Some(value) = value {
-                uids_and_values.insert(uid, value);
+            let delta = size_delta(Some(stored_value), &new_value);
+            transaction.merge(size_key(index), delta)?;
+            transaction.merge(table_size_key(index, table), delta)?;
+            transaction.put(&key, new_value)?;
+            transaction.commit()?;
+        });
+
+        Ok(())
+    }
+
+    /// Read the sizes of all the indexes with a single `multi_get`.
+    fn read_sizes(&self, indexes: &mut [Index]) -> Result<(), Error> {
+        let values = self.db.multi_get(indexes.iter().flat_map(|index| {
+            [
+                size_key(index),
+                table_size_key(index, Table::Entries),
+                table_size_key(index, Table::Chains),
+                table_count_key(index, Table::Entries),
+                table_count_key(index, Table::Chains),
+            ]
+        }));
+
+        for (index, values) in zip(indexes.iter_mut(), values.chunks(5)) {
+            let mut sizes = [None; 5];
+            for (size, value) in zip(&mut sizes, values) {
+                *size = value
+                    .clone()?
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .map(|bytes| usize::from_be_bytes(bytes) as i64);
             }
+            let [size, entries_size, chains_size, entries_count, chains_count] = sizes;
+
+            index.size = Some(size.unwrap_or(0));
+
+            // Indexes written before the sizes per table only have the total size
+            // until `recompute_size`.
+            let sizes_per_table =
+                entries_size.is_some() || chains_size.is_some() || size.unwrap_or(0) == 0;
+            index.entries_size = sizes_per_table.then(|| entries_size.unwrap_or(0));
+            index.chains_size = sizes_per_table.then(|| chains_size.unwrap_or(0));
+            index.entries_count = sizes_per_table.then(|| entries_count.unwrap_or(0));
+            index.chains_count = sizes_per_table.then(|| chains_count.unwrap_or(0));
         }
 
-        Ok(uids_and_values)
+        Ok(())
     }
 
-    async fn upsert_entries(
+    /// Upsert of a chunk of `upsert_entries`, each UID is upserted inside its own
+    /// transaction.
+    fn upsert_chunk(
         &self,
         index: &Index,
-        data: UpsertData<UID_LENGTH>,
+        data: Vec<(Uid<UID_LENGTH>, (Option<Vec<u8>>, Vec<u8>))>,
     ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
         let mut rejected = EncryptedTable::<UID_LENGTH>::with_capacity(1);
 
-        for (uid, (old_value, new_value)) in data {
+        with_db!(&*self.db, |db| for (uid, (old_value, new_value)) in data {
             let key = key(index, Table::Entries, &uid);
+            let new_value = self.checksums.wrap(new_value);
 
-            let transaction = self.0.transaction();
+            let transaction = db.transaction();
 
             let existing_value = match transaction.get_for_update(&key, true) {
                 Ok(existing_value) => existing_value,
@@ -84,39 +409,355 @@ impl IndexesDatabase for Database {
 
                     let mut retry = 3;
                     let value = loop {
-                        if let Some(value) = self.0.get(&key)? {
+                        if let Some(value) = self.db.get(&key)? {
                             break value;
                         }
 
                         retry -= 1;
                         if retry <= 0 {
-                            return Err(Error::Rocksdb(err));
+                            return Err(err.into());
                         }
                     };
 
+                    let value = self.checksums.verify(&uid, &value)?.to_vec();
                     rejected.insert(uid, value);
                     continue;
                 }
                 err => err?,
             };
 
-            if existing_value == old_value {
+            let existing_matches = match &existing_value {
+                Some(existing_value) => {
+                    old_value.as_deref() == Some(self.checksums.verify(&uid, existing_value)?)
+                }
+                None => old_value.is_none(),
+            };
+
+            if existing_matches {
+                let delta = size_delta(existing_value.as_deref(), &new_value);
+                transaction.merge(size_key(index), delta)?;
+                transaction.merge(table_size_key(index, Table::Entries), delta)?;
                 if existing_value.is_none() {
-                    transaction.merge(size_key(index), new_value.len().to_be_bytes())?;
+                    transaction.merge(
+                        table_count_key(index, Table::Entries),
+                        1_usize.to_be_bytes(),
+                    )?;
                 }
 
                 transaction.put(&key, new_value)?;
-                transaction.commit()?;
+                match transaction.commit() {
+                    Ok(()) => {}
+                    // Optimistic transaction: written by a concurrent upsert since it was read
+                    Err(err) if is_conflict(&err) => {
+                        let Some(value) = self.db.get(&key)? else {
+                            return Err(err.into());
+                        };
+                        let value = self.checksums.verify(&uid, &value)?.to_vec();
+                        rejected.insert(uid, value);
+                    }
+                    Err(err) => return Err(err.into()),
+                }
             } else {
                 transaction.rollback()?;
                 if let Some(existing_value) = existing_value {
+                    let existing_value = self.checksums.verify(&uid, &existing_value)?.to_vec();
                     rejected.insert(uid, existing_value);
                 } else {
-                    log::error!(
-                        "Receive an `old_value` {old_value:?} but no existing value inside DB for UID {uid:?}."
-                    );
+                    // The UIDs upserted before this one are committed, a retry of the whole
+                    // upsert gets them back as rejected values.
+                    return Err(Error::EntryNotFound(format!(
+                        "Receive an `old_value` but no existing value inside DB for UID {uid:?}"
+                    )));
                 }
             }
+        });
+
+        Ok(rejected)
+    }
+}
+
+/// Backups of the indexes database inside `ROCKSDB_BACKUP_DIR` (`<DATA_DIR>/backups_rocksdb`
+/// by default), the last `ROCKSDB_BACKUPS_TO_KEEP` (7 by default) are kept.
+///
+/// With the optimistic transactions, the live database is backed up by the `BackupEngine`
+/// (after a flush of the memtables): the backups are incremental, the SST files already
+/// inside a previous backup are shared. The `TransactionDB` of the pessimistic transactions
+/// gives access to neither the `BackupEngine` nor the checkpoints in this version of the
+/// rocksdb crate, so a snapshot of the database is first copied into a plain database
+/// (`staging`) while the server continues to serve the requests, and this copy is backed
+/// up: every backup is a full copy and needs the size of the database on the disk while
+/// it runs.
+fn backup_engine(backup_dir: &Path) -> Result<BackupEngine, Error> {
+    BackupEngine::open(
+        &BackupEngineOptions::new(backup_dir.join("engine"))?,
+        &Env::new()?,
+    )
+    .map_err(Error::from)
+}
+
+fn backup(db: &Db) -> Result<BackupInfo, Error> {
+    let backup_dir = config::rocksdb_backup_dir();
+    config::prepare_directory(&backup_dir);
+
+    let mut engine = backup_engine(&backup_dir)?;
+    match db {
+        Db::Optimistic(db) => engine.create_new_backup_flush(db, true)?,
+        Db::Pessimistic(db) => backup_copy(&mut engine, &backup_dir, db)?,
+    }
+
+    let backups_to_keep = env::var("ROCKSDB_BACKUPS_TO_KEEP")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_BACKUPS_TO_KEEP);
+    engine.purge_old_backups(backups_to_keep)?;
+
+    engine
+        .get_backup_info()
+        .into_iter()
+        .max_by_key(|info| info.backup_id)
+        .map(backup_info)
+        .ok_or_else(|| Error::Internal("The backup is not listed by the backup engine".to_string()))
+}
+
+/// Back up a copy of a snapshot of `db` (see `backup_engine`).
+fn backup_copy(
+    engine: &mut BackupEngine,
+    backup_dir: &Path,
+    db: &TransactionDB,
+) -> Result<(), Error> {
+    let staging_path = backup_dir.join("staging");
+    if staging_path.exists() {
+        // Leftover of an interrupted backup
+        fs::remove_dir_all(&staging_path).map_err(|err| {
+            Error::Internal(format!("Cannot remove the backup staging database ({err})"))
+        })?;
+    }
+
+    let mut opts = Options::default();
+    opts.create_if_missing(true);
+    let staging = DB::open(&opts, &staging_path)?;
+
+    let snapshot = db.snapshot();
+    let mut batch = WriteBatch::default();
+    for result in snapshot.iterator(IteratorMode::Start) {
+        let (key, value) = result?;
+        batch.put(key, value);
+
+        if batch.len() >= BACKUP_BATCH_SIZE {
+            staging.write(std::mem::take(&mut batch))?;
+        }
+    }
+    staging.write(batch)?;
+
+    let result = engine.create_new_backup_flush(&staging, true);
+
+    drop(staging);
+    if let Err(err) = fs::remove_dir_all(&staging_path) {
+        log::error!("Cannot remove the backup staging database ({err})");
+    }
+
+    result.map_err(Error::from)
+}
+
+fn backup_info(info: BackupEngineInfo) -> BackupInfo {
+    BackupInfo {
+        id: info.backup_id,
+        timestamp: info.timestamp,
+        size: info.size,
+        files: info.num_files,
+    }
+}
+
+/// Rebuild the indexes database from a backup (the latest if `None`), the server
+/// must be stopped. The current database is replaced.
+pub(crate) fn restore(backup_id: Option<u32>) -> Result<BackupInfo, Error> {
+    let backup_dir = config::rocksdb_backup_dir();
+    let mut engine = backup_engine(&backup_dir)?;
+
+    let info = engine
+        .get_backup_info()
+        .into_iter()
+        .filter(|info| backup_id.map_or(true, |backup_id| info.backup_id == backup_id))
+        .max_by_key(|info| info.backup_id)
+        .ok_or_else(|| {
+            Error::BadRequest(format!("No backup found inside {}", backup_dir.display()))
+        })?;
+
+    engine.verify_backup(info.backup_id)?;
+
+    let db_path = config::rocksdb_path();
+    engine.restore_from_backup(
+        &db_path,
+        &db_path,
+        &RestoreOptions::default(),
+        info.backup_id,
+    )?;
+
+    Ok(backup_info(info))
+}
+
+/// Manual compaction of the indexes database (only the keys of one index if `index_id` is
+/// set) to reclaim the space of the deleted and overwritten values. Returns the size of the
+/// SST files before and after the compaction.
+///
+/// The `TransactionDB` of this version of the rocksdb crate cannot run a manual compaction,
+/// so the database is opened as a plain database and the server must be stopped.
+pub(crate) fn compact(index_id: Option<&str>) -> Result<(u64, u64), Error> {
+    let mut opts = Options::default();
+    opts.set_merge_operator_associative("add", merge_add);
+    let db = DB::open(&opts, config::rocksdb_path())?;
+
+    let before = sst_files_size(&db)?;
+
+    // The deleted values are often in the last level, which is skipped by default.
+    let mut compact_opts = CompactOptions::default();
+    compact_opts.set_bottommost_level_compaction(BottommostLevelCompaction::Force);
+    match index_id {
+        Some(index_id) => {
+            let end = [index_id.as_bytes(), &[u8::MAX]].concat();
+            db.compact_range_opt(Some(index_id.as_bytes()), Some(end), &compact_opts);
+        }
+        None => db.compact_range_opt(None::<&[u8]>, None::<&[u8]>, &compact_opts),
+    }
+
+    Ok((before, sst_files_size(&db)?))
+}
+
+fn sst_files_size(db: &DB) -> Result<u64, Error> {
+    Ok(db.live_files()?.iter().map(|file| file.size as u64).sum())
+}
+
+/// Run RocksDB calls on the blocking threads of actix-web: they can block for a long time
+/// (large upserts, iterations over an index, write stalls while RocksDB compacts) and would
+/// stop the other requests served by the same worker.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, Error> + Send + 'static,
+) -> Result<T, Error> {
+    web::block(f)
+        .await
+        .map_err(|err| Error::Internal(err.to_string()))?
+}
+
+#[async_trait]
+impl IndexesDatabase for Database {
+    async fn backup(&self) -> Result<BackupInfo, Error> {
+        let database = self.clone();
+        blocking(move || {
+            let Ok(_lock) = database.backup_lock.try_lock() else {
+                return Err(Error::TooManyRequests { retry_after: 60 });
+            };
+
+            backup(&database.db)
+                .map_err(|err| Error::Internal(format!("Cannot create the backup ({err})")))
+        })
+        .await
+    }
+
+    async fn backups(&self) -> Result<Vec<BackupInfo>, Error> {
+        blocking(|| {
+            let backup_dir = config::rocksdb_backup_dir();
+            if !backup_dir.join("engine").exists() {
+                return Ok(vec![]);
+            }
+
+            Ok(backup_engine(&backup_dir)?
+                .get_backup_info()
+                .into_iter()
+                .map(backup_info)
+                .collect())
+        })
+        .await
+    }
+
+    async fn set_size(&self, index: &mut Index) -> Result<(), Error> {
+        let database = self.clone();
+        let mut sized_index = index.clone();
+        *index = blocking(move || {
+            database.read_sizes(std::slice::from_mut(&mut sized_index))?;
+            Ok(sized_index)
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn set_sizes(&self, indexes: &mut Vec<Index>) -> Result<(), Error> {
+        let database = self.clone();
+        let mut sized_indexes = indexes.clone();
+        *indexes = blocking(move || {
+            database.read_sizes(&mut sized_indexes)?;
+            Ok(sized_indexes)
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn fetch(
+        &self,
+        index: &Index,
+        table: Table,
+        uids: HashSet<Uid<UID_LENGTH>>,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        let database = self.clone();
+        let index = index.clone();
+        blocking(move || {
+            let index = &index;
+            let mut uids_and_values = EncryptedTable::<UID_LENGTH>::with_capacity(uids.len());
+
+            let values = database
+                .db
+                .multi_get(uids.iter().map(|uid| key(index, table, uid)));
+
+            for (uid, value) in zip(uids.into_iter(), values.into_iter()) {
+                let value = value?;
+                if let Some(stored_value) = value {
+                    let value = database.checksums.verify(&uid, &stored_value)?.to_vec();
+                    if !database.checksums.is_current(&stored_value) {
+                        if let Err(err) =
+                            database.rewrite_value(index, table, &uid, &stored_value, &value)
+                        {
+                            log::warn!(
+                                "Cannot rewrite the value of UID {uid:?} of index {} in the current format ({err})",
+                                index.id
+                            );
+                        }
+                    }
+                    uids_and_values.insert(uid, value);
+                }
+            }
+
+            Ok(uids_and_values)
+        })
+        .await
+    }
+
+    async fn upsert_entries(
+        &self,
+        index: &Index,
+        data: UpsertData<UID_LENGTH>,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        // Each UID is upserted inside its own transaction, so the chunks are independent.
+        let data: Vec<_> = data.into_iter().collect();
+        let chunk_size = data
+            .len()
+            .div_ceil(self.upsert_parallelism)
+            .max(MIN_UPSERT_CHUNK_SIZE);
+
+        let mut chunks = Vec::with_capacity(data.len().div_ceil(chunk_size));
+        let mut data = data.into_iter().peekable();
+        while data.peek().is_some() {
+            let chunk: Vec<_> = data.by_ref().take(chunk_size).collect();
+            let database = self.clone();
+            let index = index.clone();
+            chunks.push(blocking(move || database.upsert_chunk(&index, chunk)));
+        }
+
+        let mut rejected = EncryptedTable::<UID_LENGTH>::with_capacity(1);
+        for chunk_rejected in futures::future::try_join_all(chunks).await? {
+            for (uid, value) in chunk_rejected {
+                rejected.insert(uid, value);
+            }
         }
 
         Ok(rejected)
@@ -127,42 +768,357 @@ impl IndexesDatabase for Database {
         index: &Index,
         data: EncryptedTable<UID_LENGTH>,
     ) -> Result<(), Error> {
-        let mut size = 0;
-        for (uid, value) in data {
-            size += value.len();
-            self.0.put(key(index, Table::Chains, &uid), value)?;
-        }
+        let database = self.clone();
+        let index = index.clone();
+        blocking(move || {
+            let index = &index;
+            let mut size = 0_usize;
+            let mut count = 0_usize;
+            for (uid, value) in data {
+                let key = key(index, Table::Chains, &uid);
+                let value = database.checksums.wrap(value);
+                let existing_value = database.db.get(&key)?;
+                size = size.wrapping_add(usize::from_be_bytes(size_delta(
+                    existing_value.as_deref(),
+                    &value,
+                )));
+                if existing_value.is_none() {
+                    count += 1;
+                }
+                database.db.put(key, value)?;
+            }
 
-        self.0.merge(size_key(index), size.to_be_bytes())?;
+            database.db.merge(size_key(index), size.to_be_bytes())?;
+            database
+                .db
+                .merge(table_size_key(index, Table::Chains), size.to_be_bytes())?;
+            database
+                .db
+                .merge(table_count_key(index, Table::Chains), count.to_be_bytes())?;
 
-        Ok(())
+            Ok(())
+        })
+        .await
+    }
+
+    async fn put_values(
+        &self,
+        index: &Index,
+        table: Table,
+        data: EncryptedTable<UID_LENGTH>,
+    ) -> Result<(), Error> {
+        let database = self.clone();
+        let index = index.clone();
+        blocking(move || {
+            let index = &index;
+            with_db!(&*database.db, |db| {
+                let transaction = db.transaction();
+
+                let mut size = 0_usize;
+                let mut count = 0_usize;
+                for (uid, value) in data {
+                    let key = key(index, table, &uid);
+                    let value = database.checksums.wrap(value);
+                    let existing_value = transaction.get(&key)?;
+                    size = size.wrapping_add(usize::from_be_bytes(size_delta(
+                        existing_value.as_deref(),
+                        &value,
+                    )));
+                    if existing_value.is_none() {
+                        count += 1;
+                    }
+                    transaction.put(key, value)?;
+                }
+
+                transaction.merge(size_key(index), size.to_be_bytes())?;
+                transaction.merge(table_size_key(index, table), size.to_be_bytes())?;
+                transaction.merge(table_count_key(index, table), count.to_be_bytes())?;
+                transaction.commit()?;
+            });
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn fetch_all(
+        &self,
+        index: &Index,
+        table: Table,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        let database = self.clone();
+        let index = index.clone();
+        blocking(move || {
+            let index = &index;
+            let prefix = prefix(index, table);
+            let mut uids_and_values = EncryptedTable::<UID_LENGTH>::default();
+
+            for result in database
+                .db
+                .iterator(IteratorMode::From(&prefix, Direction::Forward))
+            {
+                let (key, value) = result?;
+                if !key.starts_with(&prefix) {
+                    break;
+                }
+
+                let uid: [u8; UID_LENGTH] = key[prefix.len()..].try_into().map_err(|_| {
+                    Error::Internal("Wrong key inside the indexes database".to_string())
+                })?;
+                let uid = Uid::from(uid);
+                let value = database.checksums.verify(&uid, &value)?.to_vec();
+                uids_and_values.insert(uid, value);
+            }
+
+            Ok(uids_and_values)
+        })
+        .await
+    }
+
+    async fn indexes_ids_with_data(&self) -> Result<HashSet<String>, Error> {
+        let database = self.clone();
+        blocking(move || {
+            let mut ids = HashSet::new();
+
+            // All the keys start with the index ID, instead of reading all the keys
+            // we jump to the next ID after each ID found.
+            let mut from = schema::first_index_key();
+            while let Some(result) = database
+                .db
+                .iterator(IteratorMode::From(&from, Direction::Forward))
+                .next()
+            {
+                let (key, _) = result?;
+                let id = &key[..INDEX_ID_LENGTH.min(key.len())];
+                ids.insert(String::from_utf8_lossy(id).to_string());
+                from = [id, &[u8::MAX]].concat();
+            }
+
+            Ok(ids)
+        })
+        .await
+    }
+
+    async fn delete_index_data(&self, index_id: &str) -> Result<(), Error> {
+        let database = self.clone();
+        let index_id = index_id.to_string();
+        blocking(move || {
+            let index_id = index_id.as_str();
+            let mut batch = WriteBatchWithTransaction::<true>::default();
+
+            for result in database
+                .db
+                .iterator(IteratorMode::From(index_id.as_bytes(), Direction::Forward))
+            {
+                let (key, _) = result?;
+                if !key.starts_with(index_id.as_bytes()) {
+                    break;
+                }
+
+                batch.delete(key);
+            }
+
+            database.db.write(batch)?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn recompute_size(&self, index: &Index) -> Result<(), Error> {
+        let database = self.clone();
+        let index = index.clone();
+        blocking(move || {
+            let index = &index;
+            let mut size = 0;
+
+            for table in [Table::Entries, Table::Chains] {
+                let mut table_size = 0;
+                let mut table_count = 0_usize;
+                let prefix = prefix(index, table);
+                for result in database
+                    .db
+                    .iterator(IteratorMode::From(&prefix, Direction::Forward))
+                {
+                    let (key, value) = result?;
+                    if !key.starts_with(&prefix) {
+                        break;
+                    }
+
+                    table_size += value.len();
+                    table_count += 1;
+                }
+
+                database
+                    .db
+                    .put(table_size_key(index, table), table_size.to_be_bytes())?;
+                database
+                    .db
+                    .put(table_count_key(index, table), table_count.to_be_bytes())?;
+                size += table_size;
+            }
+
+            database.db.put(size_key(index), size.to_be_bytes())?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn scrub(
+        &self,
+        index: &Index,
+        table: Table,
+        from: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<ScrubBatch, Error> {
+        let database = self.clone();
+        let index = index.clone();
+        let from = from.map(<[u8]>::to_vec);
+        blocking(move || {
+            let index = &index;
+            let from = from.as_deref();
+            let prefix = prefix(index, table);
+            let start = [&prefix[..], from.unwrap_or_default()].concat();
+            let mut batch = ScrubBatch::default();
+
+            for result in database
+                .db
+                .iterator(IteratorMode::From(&start, Direction::Forward))
+            {
+                let (key, value) = result?;
+                if !key.starts_with(&prefix) {
+                    break;
+                }
+
+                let key = &key[prefix.len()..];
+                if from == Some(key) {
+                    continue;
+                }
+
+                batch.check(database.checksums, key, &value);
+                if batch.checked as usize >= limit {
+                    batch.next = Some(key.to_vec());
+                    break;
+                }
+            }
+
+            Ok(batch)
+        })
+        .await
+    }
+
+    async fn append_changes(&self, index: &Index, mutations: &[Mutation]) -> Result<(), Error> {
+        let database = self.clone();
+        let index = index.clone();
+        let mutations = mutations.to_vec();
+        blocking(move || {
+            let index = &index;
+            let _lock = database
+                .changes_lock
+                .lock()
+                .map_err(|_| Error::Internal("Changes log mutex is poisoned".to_string()))?;
+
+            let mut cursor = database
+                .db
+                .get(changes_cursor_key(index))?
+                .and_then(|bytes| bytes.try_into().ok())
+                .map(u64::from_be_bytes)
+                .unwrap_or(0);
+
+            with_db!(&*database.db, |db| {
+                let transaction = db.transaction();
+                for mutation in &mutations {
+                    cursor += 1;
+                    transaction
+                        .put(change_key(index, cursor), Change::serialize_value(mutation))?;
+                }
+                transaction.put(changes_cursor_key(index), cursor.to_be_bytes())?;
+                transaction.commit()?;
+            });
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn fetch_changes(
+        &self,
+        index: &Index,
+        since: u64,
+        limit: usize,
+    ) -> Result<Vec<Change>, Error> {
+        let database = self.clone();
+        let index = index.clone();
+        blocking(move || {
+            let index = &index;
+            let prefix = [(index.id.as_bytes()), &[Prefix::Changes as u8][..]].concat();
+            let start = change_key(index, since.saturating_add(1));
+
+            let mut changes = Vec::with_capacity(limit);
+            for result in database
+                .db
+                .iterator(IteratorMode::From(&start, Direction::Forward))
+            {
+                let (key, value) = result?;
+                if !key.starts_with(&prefix) || changes.len() >= limit {
+                    break;
+                }
+
+                let cursor = key[prefix.len()..]
+                    .try_into()
+                    .map(u64::from_be_bytes)
+                    .map_err(|_| Error::Internal("Wrong key inside the changes log".to_string()))?;
+                changes.push(Change::deserialize_value(cursor, &value)?);
+            }
+
+            Ok(changes)
+        })
+        .await
+    }
+
+    async fn storage_gauges(&self) -> Result<Vec<StorageGauge>, Error> {
+        let mut gauges = Vec::with_capacity(ROCKSDB_GAUGES.len());
+        for (property, name, help) in ROCKSDB_GAUGES {
+            if let Some(value) = self.db.property_int_value(property)? {
+                gauges.push(StorageGauge::gauge(name, help, value as f64));
+            }
+        }
+
+        Ok(gauges)
     }
 
     #[cfg(feature = "log_requests")]
     async fn fetch_all_as_json(&self, index: &Index, table: Table) -> Result<String, Error> {
-        use base64::{engine::general_purpose, Engine};
-        use rocksdb::{Direction, IteratorMode};
-
-        let prefix = prefix(index, table);
-
-        let iter = self
-            .0
-            .iterator(IteratorMode::From(&prefix, Direction::Forward));
-
-        let contents_with_commas = iter
-            .filter_map(|result| result.ok())
-            .take_while(|(key, _)| key.starts_with(&prefix))
-            .map(|(key, value)| {
-                format!(
-                    "\"{}\":\"{}\"",
-                    general_purpose::STANDARD_NO_PAD.encode(key),
-                    general_purpose::STANDARD_NO_PAD.encode(value)
-                )
-            })
-            .collect::<Vec<_>>()
-            .join(",\n");
+        let database = self.clone();
+        let index = index.clone();
+        blocking(move || {
+            use base64::{engine::general_purpose, Engine};
+
+            let index = &index;
+
+            let prefix = prefix(index, table);
 
-        Ok(format!("[{contents_with_commas}]"))
+            let iter = database
+                .db
+                .iterator(IteratorMode::From(&prefix, Direction::Forward));
+
+            let contents_with_commas = iter
+                .filter_map(|result| result.ok())
+                .take_while(|(key, _)| key.starts_with(&prefix))
+                .map(|(key, value)| {
+                    format!(
+                        "\"{}\":\"{}\"",
+                        general_purpose::STANDARD_NO_PAD.encode(key),
+                        general_purpose::STANDARD_NO_PAD.encode(value)
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(",\n");
+
+            Ok(format!("[{contents_with_commas}]"))
+        })
+        .await
     }
 }
 
@@ -172,6 +1128,12 @@ pub(crate) enum Prefix {
     Entries,
     Chains,
     Size,
+    Changes,
+    ChangesCursor,
+    EntriesSize,
+    ChainsSize,
+    EntriesCount,
+    ChainsCount,
 }
 
 fn table_to_prefix(table: Table) -> Prefix {
@@ -185,6 +1147,19 @@ fn key(index: &Index, table: Table, uid: &Uid<UID_LENGTH>) -> Vec<u8> {
     [&prefix(index, table), uid.as_ref()].concat()
 }
 
+/// The prefix of the size of the table if `key` is the key of a value
+fn value_table_size_prefix(key: &[u8]) -> Option<Prefix> {
+    if key.len() != INDEX_ID_LENGTH + 1 + UID_LENGTH {
+        return None;
+    }
+
+    match key[INDEX_ID_LENGTH] {
+        prefix if prefix == Prefix::Entries as u8 => Some(Prefix::EntriesSize),
+        prefix if prefix == Prefix::Chains as u8 => Some(Prefix::ChainsSize),
+        _ => None,
+    }
+}
+
 fn prefix(index: &Index, table: Table) -> Vec<u8> {
     [(index.id.as_bytes()), &[table_to_prefix(table) as u8][..]].concat()
 }
@@ -193,6 +1168,47 @@ fn size_key(index: &Index) -> Vec<u8> {
     [(index.id.as_bytes()), &[Prefix::Size as u8][..]].concat()
 }
 
+fn table_size_key(index: &Index, table: Table) -> Vec<u8> {
+    let prefix = match table {
+        Table::Entries => Prefix::EntriesSize,
+        Table::Chains => Prefix::ChainsSize,
+    };
+
+    [(index.id.as_bytes()), &[prefix as u8][..]].concat()
+}
+
+fn table_count_key(index: &Index, table: Table) -> Vec<u8> {
+    let prefix = match table {
+        Table::Entries => Prefix::EntriesCount,
+        Table::Chains => Prefix::ChainsCount,
+    };
+
+    [(index.id.as_bytes()), &[prefix as u8][..]].concat()
+}
+
+fn change_key(index: &Index, cursor: u64) -> Vec<u8> {
+    [
+        (index.id.as_bytes()),
+        &[Prefix::Changes as u8][..],
+        &cursor.to_be_bytes(),
+    ]
+    .concat()
+}
+
+fn changes_cursor_key(index: &Index) -> Vec<u8> {
+    [(index.id.as_bytes()), &[Prefix::ChangesCursor as u8][..]].concat()
+}
+
+/// Size change when `old_value` is replaced by `new_value` as a merge operand
+/// (a negative delta is encoded in two's complement, see `merge_add`)
+fn size_delta(old_value: Option<&[u8]>, new_value: &[u8]) -> [u8; 8] {
+    let delta = new_value.len() as i64 - old_value.map_or(0, |value| value.len() as i64);
+
+    (delta as u64).to_be_bytes()
+}
+
+/// The sizes decrease when a value is replaced by a shorter one, the operands
+/// are added with wrapping to subtract the two's complement negative deltas.
 fn merge_add(
     _key: &[u8],
     existing_value: Option<&[u8]>,
@@ -201,18 +1217,104 @@ fn merge_add(
     let mut result = 0;
 
     if let Some(existing_value) = existing_value {
-        result += match existing_value.try_into().map(usize::from_be_bytes) {
+        result = match existing_value.try_into().map(usize::from_be_bytes) {
             Ok(value) => value,
             Err(_) => return None,
         };
     }
 
     for operand in operands {
-        result += match operand.try_into().map(usize::from_be_bytes) {
-            Ok(value) => value,
+        result = match operand.try_into().map(usize::from_be_bytes) {
+            Ok(value) => result.wrapping_add(value),
             Err(_) => return None,
         };
     }
 
     Some(result.to_be_bytes().to_vec())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    const LARGE_UPSERT_UIDS: u32 = 200_000;
+
+    /// A large upsert runs on the blocking threads, the requests served by the same worker
+    /// are not delayed.
+    #[actix_web::test]
+    async fn cheap_request_during_large_upsert() {
+        let directory = tempfile::tempdir().unwrap();
+        let database = Database::open(directory.path(), false, Checksums::Disabled, 2);
+        let index = Index::for_tests(&"a".repeat(INDEX_ID_LENGTH));
+
+        let old_values = EncryptedTable::<UID_LENGTH>::with_capacity(0);
+        let mut new_values =
+            EncryptedTable::<UID_LENGTH>::with_capacity(LARGE_UPSERT_UIDS as usize);
+        for i in 0..LARGE_UPSERT_UIDS {
+            let mut uid = [0; UID_LENGTH];
+            uid[..4].copy_from_slice(&i.to_be_bytes());
+            new_values.insert(Uid::from(uid), vec![1; 64]);
+        }
+        let upsert_data = UpsertData::new(&old_values, new_values);
+
+        let upsert = async {
+            let result = database.upsert_entries(&index, upsert_data).await;
+            (result, Instant::now())
+        };
+        let cheap_request = async {
+            // Let the upsert start
+            actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+            let result = database
+                .fetch(
+                    &index,
+                    Table::Entries,
+                    HashSet::from([Uid::from([0; UID_LENGTH])]),
+                )
+                .await;
+            (result, Instant::now())
+        };
+
+        let ((upserted, upsert_end), (fetched, cheap_request_end)) =
+            futures::future::join(upsert, cheap_request).await;
+
+        assert!(upserted.unwrap().is_empty());
+        fetched.unwrap();
+        assert!(
+            cheap_request_end < upsert_end,
+            "the cheap request waited for the upsert"
+        );
+    }
+
+    #[actix_web::test]
+    async fn upsert_old_value_of_missing_uid() {
+        // Pessimistic and optimistic transactions
+        for optimistic in [false, true] {
+            let directory = tempfile::tempdir().unwrap();
+            let database = Database::open(directory.path(), optimistic, Checksums::Crc32, 2);
+            let index = Index::for_tests(&"a".repeat(INDEX_ID_LENGTH));
+
+            let uid = Uid::from([1; UID_LENGTH]);
+            let mut old_values = EncryptedTable::<UID_LENGTH>::with_capacity(1);
+            old_values.insert(uid, b"old value".to_vec());
+            let mut new_values = EncryptedTable::<UID_LENGTH>::with_capacity(1);
+            new_values.insert(uid, b"new value".to_vec());
+
+            let result = database
+                .upsert_entries(&index, UpsertData::new(&old_values, new_values))
+                .await;
+            assert!(
+                matches!(result, Err(Error::EntryNotFound(_))),
+                "optimistic {optimistic}: {result:?}"
+            );
+
+            // Nothing is written
+            let fetched = database
+                .fetch(&index, Table::Entries, HashSet::from([uid]))
+                .await
+                .unwrap();
+            assert!(fetched.is_empty(), "optimistic {optimistic}");
+        }
+    }
+}
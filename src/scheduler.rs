@@ -0,0 +1,299 @@
+/// Scheduler of the periodic maintenance jobs.
+///
+/// Each job runs on the cron expression of its `SCHEDULE_{JOB}` env variable (UTC, 5 fields
+/// or 6 with the seconds first, for example `SCHEDULE_BACKUP="0 3 * * *"`), `off` disables it:
+/// - `backup`: online backup of the indexes database (see `backup.rs`), disabled by default,
+/// - `retention`: save the activity and apply the retention policy (see `retention.rs`),
+///   every hour by default when the policy is enabled,
+/// - `gc`: delete the orphaned data of the deleted indexes (see `check.rs`), disabled by default,
+/// - `size_refresh`: recompute the sizes of the indexes, disabled by default,
+/// - `cache_cleanup`: flush the metadata cache and the expired idempotency responses,
+///   disabled by default.
+///
+/// A random delay between 0 and `SCHEDULE_JITTER_SECONDS` (0 by default) is added before each
+/// run so several instances don't hit the databases at the same time. A run is never started
+/// while the previous run of the same job is still running.
+///
+/// `GET /admin/jobs` lists the jobs with their last and next runs, the runs are also
+/// counted in the metrics (see `metrics.rs`).
+use std::{
+    env,
+    str::FromStr,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    get,
+    web::{Data, Json},
+};
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use rand::Rng;
+use serde::Serialize;
+
+#[cfg(feature = "replication")]
+use crate::replication::Shipper;
+use crate::{
+    admin::Admin,
+    check,
+    core::{IndexesDatabase, MetadataCache, MetadataDatabase},
+    errors::{Error, Response},
+    idempotency::IdempotencyCache,
+    metrics::Metrics,
+    retention::Retention,
+};
+
+const HOURLY: &str = "0 0 * * * *";
+
+#[derive(Clone, Copy)]
+enum Job {
+    Backup,
+    Retention,
+    Gc,
+    SizeRefresh,
+    CacheCleanup,
+}
+
+impl Job {
+    const ALL: [Job; 5] = [
+        Job::Backup,
+        Job::Retention,
+        Job::Gc,
+        Job::SizeRefresh,
+        Job::CacheCleanup,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Job::Backup => "backup",
+            Job::Retention => "retention",
+            Job::Gc => "gc",
+            Job::SizeRefresh => "size_refresh",
+            Job::CacheCleanup => "cache_cleanup",
+        }
+    }
+
+    fn default_expression(self, context: &JobContext) -> Option<&'static str> {
+        match self {
+            Job::Retention if context.retention.is_some() => Some(HOURLY),
+            _ => None,
+        }
+    }
+
+    async fn run(self, context: &JobContext) -> Result<(), Error> {
+        match self {
+            Job::Backup => {
+                let backup = context.indexes_db.backup().await?;
+                log::info!("Backup {} created ({} bytes)", backup.id, backup.size);
+            }
+            Job::Retention => {
+                let Some(retention) = &context.retention else {
+                    return Ok(());
+                };
+
+                retention
+                    .run(
+                        &context.metadata_db,
+                        &context.indexes_db,
+                        &context.metadata_cache,
+                        #[cfg(feature = "replication")]
+                        &context.shipper,
+                    )
+                    .await?;
+            }
+            Job::Gc => {
+                let report = check::check(
+                    context.metadata_db.get_ref(),
+                    context.indexes_db.get_ref(),
+                    false,
+                )
+                .await?;
+                for id in &report.orphaned_indexes {
+                    context.indexes_db.delete_index_data(id).await?;
+                    log::info!("Orphaned data of deleted index {id} removed");
+                }
+            }
+            Job::SizeRefresh => {
+                for index in context.metadata_db.get_indexes().await? {
+                    match context.indexes_db.recompute_size(&index).await {
+                        Ok(()) => {}
+                        Err(err @ Error::Unsupported(_)) => return Err(err),
+                        Err(err) => {
+                            log::error!("Cannot recompute the size of index {} ({err:?})", index.id)
+                        }
+                    }
+                }
+            }
+            Job::CacheCleanup => {
+                let flushed = context.metadata_cache.clear();
+                let expired = match &context.idempotency_cache {
+                    Some(idempotency_cache) => idempotency_cache.remove_expired()?,
+                    None => 0,
+                };
+                log::debug!("Metadata cache flushed ({flushed} index(es)), {expired} expired idempotency response(s) removed");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Everything the jobs need, cloned for each job
+#[derive(Clone)]
+pub(crate) struct JobContext {
+    pub(crate) metadata_db: Data<dyn MetadataDatabase>,
+    pub(crate) indexes_db: Data<dyn IndexesDatabase>,
+    pub(crate) metadata_cache: Data<MetadataCache>,
+    pub(crate) idempotency_cache: Option<Data<IdempotencyCache>>,
+    /// `None` if the policy is disabled or on a standby
+    pub(crate) retention: Option<Data<Retention>>,
+    #[cfg(feature = "replication")]
+    pub(crate) shipper: Option<Data<Shipper>>,
+}
+
+#[derive(Serialize, Clone)]
+struct JobStatus {
+    name: &'static str,
+    schedule: String,
+    running: bool,
+    next_run_at: Option<DateTime<Utc>>,
+    last_run_at: Option<DateTime<Utc>>,
+    last_duration_ms: Option<u128>,
+    /// Error of the last run, `None` if it succeeded
+    last_error: Option<String>,
+    runs: u64,
+    failures: u64,
+}
+
+/// Status of the scheduled jobs, for `GET /admin/jobs`
+#[derive(Default)]
+pub(crate) struct Jobs {
+    statuses: RwLock<Vec<JobStatus>>,
+}
+
+impl Jobs {
+    fn update(&self, position: usize, update: impl FnOnce(&mut JobStatus)) {
+        if let Ok(mut statuses) = self.statuses.write() {
+            if let Some(status) = statuses.get_mut(position) {
+                update(status);
+            }
+        }
+    }
+}
+
+/// Parse a cron expression, with or without the seconds.
+fn parse_schedule(variable: &str, expression: &str) -> Schedule {
+    let expression = match expression.split_whitespace().count() {
+        5 => format!("0 {expression}"),
+        _ => expression.to_string(),
+    };
+
+    Schedule::from_str(&expression)
+        .unwrap_or_else(|e| panic!("Invalid cron expression `{expression}` in `{variable}` ({e})"))
+}
+
+/// Start the configured jobs in the background.
+pub(crate) fn start(context: JobContext, metrics: Data<Metrics>) -> Data<Jobs> {
+    let jitter_seconds: u64 = env::var("SCHEDULE_JITTER_SECONDS")
+        .ok()
+        .map(|seconds| {
+            seconds
+                .parse()
+                .expect("`SCHEDULE_JITTER_SECONDS` env variable must be a number of seconds")
+        })
+        .unwrap_or(0);
+
+    let jobs: Data<Jobs> = Data::new(Default::default());
+
+    for job in Job::ALL {
+        let variable = format!("SCHEDULE_{}", job.name().to_uppercase());
+        let expression = match env::var(&variable) {
+            Ok(expression) if expression == "off" => continue,
+            Ok(expression) => expression,
+            Err(_) => match job.default_expression(&context) {
+                Some(expression) => expression.to_string(),
+                None => continue,
+            },
+        };
+        let schedule = parse_schedule(&variable, &expression);
+
+        let position = match jobs.statuses.write() {
+            Ok(mut statuses) => {
+                statuses.push(JobStatus {
+                    name: job.name(),
+                    schedule: expression,
+                    running: false,
+                    next_run_at: None,
+                    last_run_at: None,
+                    last_duration_ms: None,
+                    last_error: None,
+                    runs: 0,
+                    failures: 0,
+                });
+                statuses.len() - 1
+            }
+            Err(_) => continue,
+        };
+
+        log::info!("Job `{}` scheduled", job.name());
+
+        let context = context.clone();
+        let metrics = metrics.clone();
+        let jobs = jobs.clone();
+        actix_web::rt::spawn(async move {
+            while let Some(next_run_at) = schedule.upcoming(Utc).next() {
+                let jitter = Duration::from_secs(rand::thread_rng().gen_range(0..=jitter_seconds));
+                let next_run_at = next_run_at
+                    + chrono::Duration::from_std(jitter)
+                        .unwrap_or_else(|_| chrono::Duration::zero());
+                jobs.update(position, |status| status.next_run_at = Some(next_run_at));
+
+                let delay = (next_run_at - Utc::now()).to_std().unwrap_or_default();
+                actix_web::rt::time::sleep(delay).await;
+
+                jobs.update(position, |status| {
+                    status.running = true;
+                    status.next_run_at = None;
+                    status.last_run_at = Some(Utc::now());
+                });
+
+                let started = Instant::now();
+                let result = job.run(&context).await;
+                let duration = started.elapsed();
+
+                if let Err(err) = &result {
+                    log::error!("Job `{}` failed ({err:?})", job.name());
+                    #[cfg(feature = "smtp")]
+                    if let Job::Backup = job {
+                        crate::smtp::notify(crate::smtp::Alert::BackupFailed {
+                            error: err.to_string(),
+                        });
+                    }
+                }
+                metrics.record_job(job.name(), duration, result.is_ok());
+                jobs.update(position, |status| {
+                    status.running = false;
+                    status.last_duration_ms = Some(duration.as_millis());
+                    status.last_error = result.err().map(|err| err.to_string());
+                    status.runs += 1;
+                    if status.last_error.is_some() {
+                        status.failures += 1;
+                    }
+                });
+            }
+        });
+    }
+
+    jobs
+}
+
+#[get("/admin/jobs")]
+pub(crate) async fn get_jobs(_admin: Admin, jobs: Data<Jobs>) -> Response<Vec<JobStatus>> {
+    let statuses = jobs
+        .statuses
+        .read()
+        .map_err(|_| Error::Internal("Jobs lock is poisoned".to_string()))?;
+
+    Ok(Json(statuses.clone()))
+}
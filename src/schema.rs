@@ -0,0 +1,92 @@
+/// Version of the key layout of the on-disk indexes databases (RocksDB and LMDB).
+///
+/// The version is stored under `SCHEMA_VERSION_KEY` and checked on startup, so a binary
+/// never reads a layout it doesn't know:
+/// - a new database gets the current `SCHEMA_VERSION`,
+/// - a database written before the versioning has the layout of version 1,
+/// - a database written by a newer binary (greater version) is refused,
+/// - an older database is upgraded by the upgrade routines of the backend, one version
+///   after the other, only with `SCHEMA_UPGRADE=true` (back up the database first, the
+///   upgrade cannot be undone).
+///
+/// An upgrade is written in batches and can be interrupted (crash, kill): each batch saves
+/// the last rewritten key under `SCHEMA_UPGRADE_CURSOR_KEY` with its values and its size
+/// deltas, the next start resumes after this key. The last batch writes the new version and
+/// removes the cursor, so a value is never rewritten twice.
+///
+/// Changing the key layout (a new prefix meaning, column families…) must increment
+/// `SCHEMA_VERSION` and add the upgrade from the previous version to each backend.
+///
+/// Versions:
+/// 1. the layout before the versioning,
+/// 2. the values start with a format byte (see `checksum.rs`). A later change of the value
+///    format only needs a new format byte, the values are rewritten when fetched.
+use std::env;
+
+use crate::errors::Error;
+
+/// Version of the key layout written by this binary
+pub(crate) const SCHEMA_VERSION: u32 = 2;
+/// Layout of the databases written before the versioning
+const UNVERSIONED_SCHEMA_VERSION: u32 = 1;
+/// Sorted before the keys of the indexes (their IDs are alphanumeric)
+pub(crate) const SCHEMA_VERSION_KEY: &[u8] = b"\0schema_version";
+
+/// Last key rewritten by an interrupted upgrade, sorted before the version
+pub(crate) const SCHEMA_UPGRADE_CURSOR_KEY: &[u8] = b"\0schema_upgrade_cursor";
+
+/// First key after the version, where the keys of the indexes start
+pub(crate) fn first_index_key() -> Vec<u8> {
+    [SCHEMA_VERSION_KEY, &[0]].concat()
+}
+
+/// Check the stored version of the `backend` database and upgrade it if needed, panic if
+/// the database cannot be used by this binary.
+///
+/// `upgrade(version)` rewrites the database from `version` to `version + 1` and
+/// `write_version` saves the new version.
+pub(crate) fn check(
+    backend: &str,
+    stored_version: Option<&[u8]>,
+    has_data: bool,
+    upgrade: impl Fn(u32) -> Result<(), Error>,
+    write_version: impl Fn(u32) -> Result<(), Error>,
+) {
+    let mut version = match stored_version {
+        Some(bytes) => u32::from_be_bytes(bytes.try_into().unwrap_or_else(|_| {
+            panic!("Corrupted schema version inside the {backend} database ({bytes:?})")
+        })),
+        None if has_data => UNVERSIONED_SCHEMA_VERSION,
+        None => SCHEMA_VERSION,
+    };
+
+    if version > SCHEMA_VERSION {
+        panic!("The {backend} database has the schema version {version}, written by a newer version of findex_cloud (this one only reads the version {SCHEMA_VERSION})");
+    }
+
+    if version < SCHEMA_VERSION && env::var("SCHEMA_UPGRADE").as_deref() != Ok("true") {
+        panic!("The {backend} database has the schema version {version} and must be upgraded to the version {SCHEMA_VERSION}: back it up and restart with `SCHEMA_UPGRADE=true`");
+    }
+
+    while version < SCHEMA_VERSION {
+        log::warn!(
+            "Upgrading the {backend} database from the schema version {version} to {}",
+            version + 1
+        );
+        upgrade(version).unwrap_or_else(|err| {
+            panic!(
+                "Cannot upgrade the {backend} database from the schema version {version} ({err:?})"
+            )
+        });
+        version += 1;
+        write_version(version).unwrap_or_else(|err| {
+            panic!("Cannot save the schema version of the {backend} database ({err:?})")
+        });
+    }
+
+    if stored_version.is_none() {
+        write_version(version).unwrap_or_else(|err| {
+            panic!("Cannot save the schema version of the {backend} database ({err:?})")
+        });
+    }
+}
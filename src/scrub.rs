@@ -0,0 +1,181 @@
+/// Background scrubbing of the indexes database (RocksDB and LMDB).
+///
+/// Every `SCRUB_INTERVAL_HOURS` hours (disabled by default), all the entries and chains of
+/// every index are read again to validate the encoding of the keys and, with
+/// `VALUES_CHECKSUMS` (see `checksum.rs`), the checksums of the values. The items are read
+/// by batches of `SCRUB_BATCH_SIZE` (1 000 by default) with a pause of `SCRUB_PAUSE_MS`
+/// milliseconds (100 by default) between the batches, so the requests keep the priority.
+///
+/// The corrupted items are logged (index ID, table and key) and counted in the metrics
+/// (`findex_cloud_scrub_*`). Nothing is repaired: restore a backup or re-import the index.
+use std::{env, time::Duration};
+
+use actix_web::web::Data;
+use base64::{engine::general_purpose, Engine};
+#[cfg(any(feature = "rocksdb", feature = "lmmd"))]
+use cosmian_findex::{parameters::UID_LENGTH, Uid};
+
+#[cfg(any(feature = "rocksdb", feature = "lmmd"))]
+use crate::checksum::Checksums;
+use crate::{
+    core::{IndexesDatabase, MetadataDatabase, Table},
+    errors::Error,
+    metrics::Metrics,
+};
+
+const DEFAULT_SCRUB_BATCH_SIZE: usize = 1_000;
+const DEFAULT_SCRUB_PAUSE_MS: u64 = 100;
+
+/// Result of the scrubbing of one batch of items (see `IndexesDatabase::scrub`)
+#[derive(Debug, Default)]
+pub struct ScrubBatch {
+    pub checked: u64,
+    pub corrupted: Vec<CorruptedItem>,
+    /// Key (without the index and table prefix) to continue from,
+    /// `None` at the end of the table
+    pub next: Option<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub struct CorruptedItem {
+    /// Without the index and table prefix
+    pub key: Vec<u8>,
+    pub reason: String,
+}
+
+impl ScrubBatch {
+    /// Validate one stored item, `key` is without the index and table prefix.
+    #[cfg(any(feature = "rocksdb", feature = "lmmd"))]
+    pub(crate) fn check(&mut self, checksums: Checksums, key: &[u8], value: &[u8]) {
+        self.checked += 1;
+
+        let reason = match <[u8; UID_LENGTH]>::try_from(key) {
+            Ok(uid) => match checksums.verify(&Uid::from(uid), value) {
+                Ok(_) => return,
+                Err(_) => "wrong checksum or unknown format".to_string(),
+            },
+            Err(_) => format!("key of {} bytes instead of {UID_LENGTH}", key.len()),
+        };
+
+        self.corrupted.push(CorruptedItem {
+            key: key.to_vec(),
+            reason,
+        });
+    }
+}
+
+pub(crate) struct Scrubber {
+    interval: Duration,
+    batch_size: usize,
+    pause: Duration,
+}
+
+impl Scrubber {
+    pub(crate) fn from_env() -> Option<Self> {
+        let interval_hours: u64 = env::var("SCRUB_INTERVAL_HOURS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|hours| *hours > 0)?;
+
+        let batch_size = env::var("SCRUB_BATCH_SIZE")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|size| *size > 0)
+            .unwrap_or(DEFAULT_SCRUB_BATCH_SIZE);
+
+        let pause_ms = env::var("SCRUB_PAUSE_MS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_SCRUB_PAUSE_MS);
+
+        Some(Scrubber {
+            interval: Duration::from_secs(interval_hours * 60 * 60),
+            batch_size,
+            pause: Duration::from_millis(pause_ms),
+        })
+    }
+
+    /// The first pass starts after one interval (the startup check already reads every index).
+    pub(crate) fn start(
+        self,
+        metadata_db: Data<dyn MetadataDatabase>,
+        indexes_db: Data<dyn IndexesDatabase>,
+        metrics: Data<Metrics>,
+    ) {
+        actix_web::rt::spawn(async move {
+            loop {
+                actix_web::rt::time::sleep(self.interval).await;
+
+                match self.scrub_all(&metadata_db, &indexes_db, &metrics).await {
+                    Ok(()) => {}
+                    Err(Error::Unsupported(err)) => {
+                        log::warn!("Scrubbing stopped ({err})");
+                        return;
+                    }
+                    Err(err) => log::error!("Cannot scrub the indexes ({err:?})"),
+                }
+            }
+        });
+    }
+
+    async fn scrub_all(
+        &self,
+        metadata_db: &Data<dyn MetadataDatabase>,
+        indexes_db: &Data<dyn IndexesDatabase>,
+        metrics: &Metrics,
+    ) -> Result<(), Error> {
+        let indexes = metadata_db.get_indexes().await?;
+        log::info!("Scrubbing {} index(es)", indexes.len());
+
+        let mut total_corrupted = 0;
+        for index in indexes {
+            let mut corrupted = 0;
+
+            for table in [Table::Entries, Table::Chains] {
+                let mut from = None;
+                loop {
+                    let batch = match indexes_db
+                        .scrub(&index, table, from.as_deref(), self.batch_size)
+                        .await
+                    {
+                        Ok(batch) => batch,
+                        Err(err @ Error::Unsupported(_)) => return Err(err),
+                        Err(err) => {
+                            log::error!(
+                                "Cannot scrub the {table:?} of index {} ({err:?})",
+                                index.id
+                            );
+                            break;
+                        }
+                    };
+
+                    for item in &batch.corrupted {
+                        log::error!(
+                            "Corrupted item inside the {table:?} of index {}: key {} ({})",
+                            index.id,
+                            general_purpose::STANDARD_NO_PAD.encode(&item.key),
+                            item.reason
+                        );
+                    }
+                    corrupted += batch.corrupted.len() as u64;
+                    metrics.record_scrubbed_items(batch.checked);
+
+                    match batch.next {
+                        Some(next) => from = Some(next),
+                        None => break,
+                    }
+
+                    actix_web::rt::time::sleep(self.pause).await;
+                }
+            }
+
+            metrics.set_scrub_corrupted_items(&index.id, corrupted);
+            total_corrupted += corrupted;
+        }
+
+        metrics.record_scrub_completed(chrono::Utc::now().timestamp());
+        log::info!("Scrubbing done, {total_corrupted} corrupted item(s) found");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,331 @@
+/// Settings of each index, overriding the global configuration for this index.
+///
+/// `GET /indexes/{id}/settings` returns the settings document of the index and
+/// `PUT /indexes/{id}/settings` replaces it (both with the admin API key). A missing field
+/// keeps the global configuration:
+/// - `max_uids_per_request`: instead of `MAX_UIDS_PER_REQUEST` (see `limits.rs`),
+/// - `max_body_bytes`: Findex callbacks with a larger body are refused with a
+///   `413 Payload Too Large`,
+/// - `rate_limit_per_second`: Findex callbacks per second and per instance (with bursts of
+///   one second of requests), refused with a `429 Too Many Requests` beyond,
+/// - `consistency`: `strong` or `eventual` fetches (see
+///   `IndexesDatabase::fetch_with_consistency`), instead of `DYNAMODB_CONSISTENT_READS`,
+/// - `ttl_seconds`: TTL of the values written from now on, instead of the TTL chosen at the
+///   creation of the index (only with an indexes database supporting it),
+/// - `compaction_webhook_url` and `storage_alert_webhook_url`: instead of
+///   `COMPACTION_WEBHOOK_URL` and `STORAGE_ALERT_WEBHOOK_URL` ("webhooks" feature),
+/// - `protocol_version`: Findex protocol version of the clients sending no
+///   `X-Findex-Protocol-Version` header, instead of the current one (see `protocol.rs`).
+///
+/// The settings are versioned to not lose a concurrent update: `GET` returns the version in
+/// the `ETag` header (`"0"` for settings never saved) and `PUT` requires it in `If-Match`,
+/// `428 Precondition Required` without the header and `412 Precondition Failed` if the
+/// settings were modified since (fetch them again and retry). `If-Match: *` overwrites the
+/// settings whatever their version.
+///
+/// The settings are saved in the metadata database and cached with the indexes (see
+/// `MetadataCache`): another instance uses the new settings after its cache is flushed.
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use actix_web::{
+    dev::Payload,
+    get,
+    http::header::{ETAG, IF_MATCH},
+    put,
+    web::{Data, Json, Path},
+    FromRequest, HttpRequest, HttpResponse,
+};
+use cosmian_findex::{parameters::UID_LENGTH, EncryptedTable, Uid};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "replication")]
+use crate::replication::{self, Record, Shipper, Standby};
+use crate::{
+    admin::Admin,
+    core::{Index, IndexesDatabase, MetadataCache, MetadataDatabase, Table},
+    errors::{Error, ResponseBytes},
+    protocol::{CURRENT_PROTOCOL_VERSION, OLDEST_PROTOCOL_VERSION},
+};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Consistency {
+    Strong,
+    Eventual,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct IndexSettings {
+    pub max_uids_per_request: Option<usize>,
+    /// In bytes
+    pub max_body_bytes: Option<usize>,
+    pub rate_limit_per_second: Option<u32>,
+    pub consistency: Option<Consistency>,
+    pub ttl_seconds: Option<i64>,
+    pub compaction_webhook_url: Option<String>,
+    pub storage_alert_webhook_url: Option<String>,
+    pub protocol_version: Option<u8>,
+}
+
+impl IndexSettings {
+    fn validate(&self, indexes_db: &Data<dyn IndexesDatabase>) -> Result<(), Error> {
+        if self.max_uids_per_request == Some(0)
+            || self.max_body_bytes == Some(0)
+            || self.rate_limit_per_second == Some(0)
+        {
+            return Err(Error::BadRequest(
+                "The limits of the settings must be greater than 0 (remove them to use the global configuration)".to_string(),
+            ));
+        }
+
+        if let Some(ttl_seconds) = self.ttl_seconds {
+            if ttl_seconds <= 0 {
+                return Err(Error::BadRequest(
+                    "`ttl_seconds` must be greater than 0".to_string(),
+                ));
+            }
+            if !indexes_db.supports_ttl() {
+                return Err(Error::Unsupported(
+                    "This indexes database doesn't support the TTL of the indexes".to_string(),
+                ));
+            }
+        }
+
+        if let Some(version) = self.protocol_version {
+            if !(OLDEST_PROTOCOL_VERSION..=CURRENT_PROTOCOL_VERSION).contains(&version) {
+                return Err(Error::BadRequest(format!(
+                    "`protocol_version` must be between {OLDEST_PROTOCOL_VERSION} and {CURRENT_PROTOCOL_VERSION}"
+                )));
+            }
+        }
+
+        for url in [
+            &self.compaction_webhook_url,
+            &self.storage_alert_webhook_url,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if cfg!(not(feature = "webhooks")) {
+                return Err(Error::Unsupported(
+                    "Webhooks are not available because `findex_cloud` wasn't compiled with \"webhooks\" feature".to_string(),
+                ));
+            }
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                return Err(Error::BadRequest(format!("Invalid webhook URL `{url}`")));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The index with the TTL of the settings
+    pub(crate) fn apply(&self, mut index: Index) -> Index {
+        if self.ttl_seconds.is_some() {
+            index.ttl_seconds = self.ttl_seconds;
+        }
+
+        index
+    }
+}
+
+/// Settings of the index, or the default (empty) settings if they were never saved.
+pub(crate) async fn settings_with_cache(
+    metadata_db: &dyn MetadataDatabase,
+    cache: &MetadataCache,
+    id: &str,
+) -> Result<Arc<IndexSettings>, Error> {
+    if let Some(settings) = cache.get_settings(id) {
+        return Ok(settings);
+    }
+
+    let settings = Arc::new(metadata_db.get_settings(id).await?.unwrap_or_default());
+    cache.insert_settings(id, settings.clone());
+
+    Ok(settings)
+}
+
+/// Settings of the index of the path (`/indexes/{id}/…`)
+pub(crate) struct Settings(pub(crate) Arc<IndexSettings>);
+
+impl FromRequest for Settings {
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+
+        Box::pin(async move {
+            let metadata_cache = req.app_data::<Data<MetadataCache>>().unwrap();
+            let metadata_database = req.app_data::<Data<dyn MetadataDatabase>>().unwrap();
+
+            let id: Path<String> = Path::<String>::extract(&req)
+                .await
+                .map_err(|_| Error::WrongIndexPublicId)?;
+
+            Ok(Settings(
+                settings_with_cache(metadata_database.get_ref(), metadata_cache, &id).await?,
+            ))
+        })
+    }
+}
+
+/// Fetch with the consistency of the settings, if any.
+pub(crate) async fn fetch(
+    indexes_db: &Data<dyn IndexesDatabase>,
+    index: &Index,
+    settings: &IndexSettings,
+    table: Table,
+    uids: HashSet<Uid<UID_LENGTH>>,
+) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+    match settings.consistency {
+        Some(consistency) => {
+            indexes_db
+                .fetch_with_consistency(index, table, uids, consistency)
+                .await
+        }
+        None => indexes_db.fetch(index, table, uids).await,
+    }
+}
+
+/// Token bucket of each index with a `rate_limit_per_second`
+#[derive(Default)]
+pub(crate) struct RateLimits {
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl RateLimits {
+    pub(crate) fn check(&self, index_id: &str, settings: &IndexSettings) -> Result<(), Error> {
+        let Some(per_second) = settings.rate_limit_per_second else {
+            return Ok(());
+        };
+        let per_second = f64::from(per_second);
+
+        let mut buckets = self
+            .buckets
+            .lock()
+            .map_err(|_| Error::Internal("Rate limits lock is poisoned".to_string()))?;
+
+        let now = Instant::now();
+        let (tokens, refilled_at) = buckets
+            .entry(index_id.to_string())
+            .or_insert((per_second, now));
+
+        *tokens =
+            (*tokens + now.duration_since(*refilled_at).as_secs_f64() * per_second).min(per_second);
+        *refilled_at = now;
+
+        if *tokens < 1.0 {
+            return Err(Error::TooManyRequests { retry_after: 1 });
+        }
+        *tokens -= 1.0;
+
+        Ok(())
+    }
+}
+
+#[get("/indexes/{id}/settings")]
+pub(crate) async fn get_settings(
+    _admin: Admin,
+    index: Index,
+    metadata_db: Data<dyn MetadataDatabase>,
+) -> ResponseBytes {
+    let (settings, version) = metadata_db
+        .get_versioned_settings(&index.id)
+        .await?
+        .unwrap_or_default();
+
+    Ok(HttpResponse::Ok()
+        .insert_header((ETAG, etag(version)))
+        .json(settings))
+}
+
+fn etag(version: u64) -> String {
+    format!("\"{version}\"")
+}
+
+/// Version expected by `If-Match`, `None` for `*`
+fn if_match_version(req: &HttpRequest) -> Result<Option<u64>, Error> {
+    let header = req.headers().get(IF_MATCH).ok_or_else(|| {
+        Error::PreconditionRequired(
+            "Send the `ETag` of the settings (from `GET /indexes/{id}/settings`) in `If-Match`"
+                .to_string(),
+        )
+    })?;
+
+    let value = header.to_str().unwrap_or_default().trim();
+    if value == "*" {
+        return Ok(None);
+    }
+
+    value
+        .strip_prefix("W/")
+        .unwrap_or(value)
+        .trim_matches('"')
+        .parse()
+        .map(Some)
+        .map_err(|_| Error::BadRequest(format!("Invalid `If-Match` header `{value}`")))
+}
+
+#[put("/indexes/{id}/settings")]
+pub(crate) async fn put_settings(
+    _admin: Admin,
+    index: Index,
+    req: HttpRequest,
+    settings: Json<IndexSettings>,
+    metadata_db: Data<dyn MetadataDatabase>,
+    indexes_db: Data<dyn IndexesDatabase>,
+    metadata_cache: Data<MetadataCache>,
+    #[cfg(feature = "replication")] shipper: Option<Data<Shipper>>,
+    #[cfg(feature = "replication")] standby: Option<Data<Standby>>,
+) -> ResponseBytes {
+    #[cfg(feature = "replication")]
+    Standby::check_writable(&standby)?;
+    #[cfg(feature = "replication")]
+    Shipper::check_writable(&shipper)?;
+
+    let expected_version = if_match_version(&req)?;
+
+    let settings = settings.into_inner();
+    settings.validate(&indexes_db)?;
+
+    let version = match expected_version {
+        Some(version) => metadata_db
+            .replace_settings(&index.id, &settings, version)
+            .await?
+            .ok_or_else(|| {
+                Error::PreconditionFailed(format!(
+                    "The settings of index {} were modified since the version {version}, fetch them again",
+                    index.id
+                ))
+            })?,
+        None => {
+            metadata_db.set_settings(&index.id, &settings).await?;
+            metadata_db
+                .get_versioned_settings(&index.id)
+                .await?
+                .map(|(_, version)| version)
+                .unwrap_or_default()
+        }
+    };
+    metadata_cache.remove(&index.id);
+
+    #[cfg(feature = "replication")]
+    replication::ship(&shipper, || Record::PutSettings {
+        index_id: index.id.clone(),
+        settings: settings.clone(),
+    });
+
+    log::info!("Settings of index {} updated (version {version})", index.id);
+
+    Ok(HttpResponse::Ok()
+        .insert_header((ETAG, etag(version)))
+        .json(settings))
+}
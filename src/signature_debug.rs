@@ -0,0 +1,269 @@
+/// Signatures of the Findex callbacks, for the authors of the client SDKs. Its feature
+/// SHOULD never be activated on production: the responses tell which seed signed a body and
+/// give the expected signatures.
+///
+/// `POST /indexes/{id}/debug_signature` takes a body signed like a callback (signature,
+/// expiration timestamp, data) and returns the parsed timestamp, the seed of the index
+/// whose signature matches (if any) and the prefixes of the computed and received
+/// signatures, with an explanation of the failure.
+///
+/// `GET /indexes/{id}/test_vectors` returns a canonical signed body for each callback of
+/// the index, with a fixed expiration timestamp, UID and value, so a client implementation
+/// can compare its serialization and its signatures byte for byte.
+use std::{collections::HashSet, time::SystemTime};
+
+use actix_web::{
+    get, post,
+    web::{Bytes, Json, Query},
+};
+use chrono::NaiveDateTime;
+use cloudproof_findex::{cloud::CALLBACK_SIGNATURE_LENGTH, ser_de::serialize_set};
+use cosmian_crypto_core::bytes_ser_de::Serializable;
+use cosmian_findex::{parameters::UID_LENGTH, CoreError, EncryptedTable, Uid, UpsertData};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin::Admin,
+    core::{body_signature, Index},
+    errors::{Error, Response},
+};
+
+/// Bytes of the signatures shown in the response
+const SIGNATURE_PREFIX_LENGTH: usize = 4;
+const TIMESTAMP_LENGTH: usize = 8;
+/// Around the year 2286, a larger timestamp is probably in milliseconds
+const MAX_TIMESTAMP_SECONDS: u64 = 10_000_000_000;
+/// 2100-01-01, the test vectors stay valid
+const TEST_VECTORS_EXPIRATION_TIMESTAMP: u64 = 4_102_444_800;
+const TEST_VECTORS_UID: [u8; UID_LENGTH] = [0x01; UID_LENGTH];
+const TEST_VECTORS_OLD_VALUE: &[u8] = b"findex_cloud test vector old value";
+const TEST_VECTORS_NEW_VALUE: &[u8] = b"findex_cloud test vector new value";
+
+#[derive(Serialize)]
+struct SeedCheck {
+    callback: &'static str,
+    /// Hexadecimal
+    signature_computed_prefix: String,
+    matches: bool,
+}
+
+#[derive(Serialize)]
+struct SignatureExplanation {
+    body_length: usize,
+    /// Hexadecimal
+    signature_received_prefix: Option<String>,
+    /// Big-endian seconds since the epoch
+    expiration_timestamp: Option<u64>,
+    expiration: Option<NaiveDateTime>,
+    current_timestamp: u64,
+    data_length: Option<usize>,
+    seeds: Vec<SeedCheck>,
+    /// Callback of the matching seed
+    matched_seed: Option<&'static str>,
+    valid: bool,
+    explanation: String,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn hex_prefix(bytes: &[u8]) -> String {
+    hex(&bytes[..bytes.len().min(SIGNATURE_PREFIX_LENGTH)])
+}
+
+#[post("/indexes/{id}/debug_signature")]
+pub(crate) async fn debug_signature(
+    _admin: Admin,
+    index: Index,
+    body: Bytes,
+) -> Response<SignatureExplanation> {
+    let current_timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|_| Error::BadRequest("SystemTime is before UNIX_EPOCH".to_owned()))?
+        .as_secs();
+
+    let mut explanation = SignatureExplanation {
+        body_length: body.len(),
+        signature_received_prefix: None,
+        expiration_timestamp: None,
+        expiration: None,
+        current_timestamp,
+        data_length: None,
+        seeds: vec![],
+        matched_seed: None,
+        valid: false,
+        explanation: String::new(),
+    };
+
+    if body.len() < CALLBACK_SIGNATURE_LENGTH + TIMESTAMP_LENGTH {
+        explanation.explanation = format!(
+            "The body is too small: it must start with the signature \
+            ({CALLBACK_SIGNATURE_LENGTH} bytes) and the expiration timestamp \
+            ({TIMESTAMP_LENGTH} bytes), followed by the data"
+        );
+        return Ok(Json(explanation));
+    }
+
+    let (signature_received, rest) = body.split_at(CALLBACK_SIGNATURE_LENGTH);
+    let (timestamp_bytes, data) = rest.split_at(TIMESTAMP_LENGTH);
+    let mut be_bytes = [0; TIMESTAMP_LENGTH];
+    be_bytes.copy_from_slice(timestamp_bytes);
+    let expiration_timestamp = u64::from_be_bytes(be_bytes);
+
+    explanation.signature_received_prefix = Some(hex_prefix(signature_received));
+    explanation.expiration_timestamp = Some(expiration_timestamp);
+    explanation.expiration = i64::try_from(expiration_timestamp)
+        .ok()
+        .and_then(|timestamp| NaiveDateTime::from_timestamp_opt(timestamp, 0));
+    explanation.data_length = Some(data.len());
+
+    for (callback, seed) in [
+        ("fetch_entries", &index.fetch_entries_key),
+        ("fetch_chains", &index.fetch_chains_key),
+        ("upsert_entries", &index.upsert_entries_key),
+        ("insert_chains", &index.insert_chains_key),
+    ] {
+        let signature_computed = body_signature(&index.id, seed, timestamp_bytes, data)?;
+        let matches = signature_computed[..] == *signature_received;
+        if matches {
+            explanation.matched_seed = Some(callback);
+        }
+        explanation.seeds.push(SeedCheck {
+            callback,
+            signature_computed_prefix: hex_prefix(&signature_computed),
+            matches,
+        });
+    }
+
+    explanation.explanation = match explanation.matched_seed {
+        Some(callback) if current_timestamp > expiration_timestamp => format!(
+            "Signed with the `{callback}` seed but expired {} second(s) ago: \
+            sign with a later expiration or check the clock of the client",
+            current_timestamp - expiration_timestamp
+        ),
+        Some(callback) => {
+            explanation.valid = true;
+            format!("Valid signature for `{callback}` (only accepted by this callback)")
+        }
+        None => {
+            let mut reasons = vec![format!(
+                "No seed of the index matches. The signature must be the KMAC \
+                ({CALLBACK_SIGNATURE_LENGTH} bytes) of the expiration timestamp followed by \
+                the data, with the key derived from the seed of the callback and the index \
+                ID `{}` as info",
+                index.id
+            )];
+            if expiration_timestamp > MAX_TIMESTAMP_SECONDS {
+                let little_endian = u64::from_le_bytes(be_bytes);
+                if little_endian.abs_diff(current_timestamp) < MAX_TIMESTAMP_SECONDS / 10 {
+                    reasons.push(
+                        "the expiration timestamp looks little-endian, it must be big-endian"
+                            .to_string(),
+                    );
+                } else {
+                    reasons.push(
+                        "the expiration timestamp looks like milliseconds, it must be in seconds"
+                            .to_string(),
+                    );
+                }
+            }
+            reasons.join(", ")
+        }
+    };
+
+    Ok(Json(explanation))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct TestVectorsParams {
+    /// Seconds since the epoch, `TEST_VECTORS_EXPIRATION_TIMESTAMP` by default
+    expiration_timestamp: Option<u64>,
+}
+
+/// Every bytes field is hexadecimal
+#[derive(Serialize)]
+struct TestVector {
+    callback: &'static str,
+    path: String,
+    seed: String,
+    expiration_timestamp_bytes: String,
+    /// Serialized request, before the signature
+    data: String,
+    signature: String,
+    /// Signature, expiration timestamp and data, as sent to `path`
+    body: String,
+}
+
+#[derive(Serialize)]
+struct TestVectors {
+    index_id: String,
+    expiration_timestamp: u64,
+    uid: String,
+    old_value: String,
+    new_value: String,
+    vectors: Vec<TestVector>,
+}
+
+/// The requests contain a single UID: the sets and the tables are serialized in the order
+/// of their hash maps, a request with several UIDs has no canonical bytes.
+#[get("/indexes/{id}/test_vectors")]
+pub(crate) async fn get_test_vectors(
+    _admin: Admin,
+    index: Index,
+    params: Query<TestVectorsParams>,
+) -> Response<TestVectors> {
+    let expiration_timestamp = params
+        .expiration_timestamp
+        .unwrap_or(TEST_VECTORS_EXPIRATION_TIMESTAMP);
+    let expiration_timestamp_bytes = expiration_timestamp.to_be_bytes();
+    let uid = Uid::from(TEST_VECTORS_UID);
+
+    let uids = serialize_set::<CoreError, Uid<UID_LENGTH>>(&HashSet::from([uid]))?;
+
+    let mut old_values = EncryptedTable::<UID_LENGTH>::with_capacity(1);
+    old_values.insert(uid, TEST_VECTORS_OLD_VALUE.to_vec());
+    let mut new_values = EncryptedTable::<UID_LENGTH>::with_capacity(1);
+    new_values.insert(uid, TEST_VECTORS_NEW_VALUE.to_vec());
+    let upsert_data = UpsertData::new(&old_values, new_values.clone()).serialize()?;
+    let chains = new_values.serialize()?;
+
+    let mut vectors = Vec::with_capacity(4);
+    for (callback, seed, data) in [
+        ("fetch_entries", &index.fetch_entries_key, &uids[..]),
+        ("fetch_chains", &index.fetch_chains_key, &uids[..]),
+        (
+            "upsert_entries",
+            &index.upsert_entries_key,
+            &upsert_data[..],
+        ),
+        ("insert_chains", &index.insert_chains_key, &chains[..]),
+    ] {
+        let signature = body_signature(&index.id, seed, &expiration_timestamp_bytes, data)?;
+
+        let mut body =
+            Vec::with_capacity(CALLBACK_SIGNATURE_LENGTH + TIMESTAMP_LENGTH + data.len());
+        body.extend_from_slice(&signature);
+        body.extend_from_slice(&expiration_timestamp_bytes);
+        body.extend_from_slice(data);
+
+        vectors.push(TestVector {
+            callback,
+            path: format!("/indexes/{}/{callback}", index.id),
+            seed: hex(seed),
+            expiration_timestamp_bytes: hex(&expiration_timestamp_bytes),
+            data: hex(data),
+            signature: hex(&signature),
+            body: hex(&body),
+        });
+    }
+
+    Ok(Json(TestVectors {
+        index_id: index.id.clone(),
+        expiration_timestamp,
+        uid: hex(&TEST_VECTORS_UID),
+        old_value: hex(TEST_VECTORS_OLD_VALUE),
+        new_value: hex(TEST_VECTORS_NEW_VALUE),
+        vectors,
+    }))
+}
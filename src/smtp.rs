@@ -0,0 +1,409 @@
+/// Alerts sent by email, for the teams without a webhook receiver ("smtp" feature).
+///
+/// `SMTP_HOST` enables the notifier, the alerts are sent from `SMTP_FROM` to `SMTP_TO`
+/// (comma separated addresses). The connection uses TLS from the start (`SMTP_TLS=true`, the
+/// default, port 465 by default) or plain TCP to a local relay (`SMTP_TLS=false`, port 25 by
+/// default), `SMTP_PORT` overrides the port. `SMTP_USERNAME` and `SMTP_PASSWORD` authenticate
+/// with `AUTH PLAIN`, only over TLS.
+///
+/// Alerts:
+/// - `quota_exceeded`: a monthly quota of an index is exhausted (see `quotas.rs`), once per
+///   index, quota and month,
+/// - `backup_failed`: the scheduled backup failed (see `scheduler.rs`),
+/// - `health_check_failed`: the startup integrity check found problems, or a standby cannot
+///   reach its databases or the primary (see `replication.rs`),
+/// - `storage_threshold` and `signature_spike`: see `alerts.rs`.
+///
+/// The messages are plain text rendered from templates with `{field}` placeholders (the
+/// fields of the alert and `{alert}`). `SMTP_TEMPLATES` overrides the default templates with a
+/// JSON object, for example `{"backup_failed": {"subject": "[prod] Backup failed",
+/// "body": "{error}"}}`. The emails are sent in the background, a failure is only logged.
+use std::{collections::HashMap, env, sync::OnceLock, time::Duration};
+
+use base64::{engine::general_purpose, Engine};
+use chrono::Utc;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tokio_native_tls::{native_tls, TlsConnector};
+
+use crate::errors::Error;
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+static NOTIFIER: OnceLock<SmtpNotifier> = OnceLock::new();
+
+#[derive(Debug, Clone)]
+pub(crate) enum Alert {
+    QuotaExceeded {
+        index_id: String,
+        quota: &'static str,
+        limit: u64,
+    },
+    BackupFailed {
+        error: String,
+    },
+    HealthCheckFailed {
+        check: &'static str,
+        error: String,
+    },
+    StorageThreshold {
+        index_id: String,
+        metric: &'static str,
+        value: i64,
+        threshold: i64,
+    },
+    SignatureSpike {
+        index_id: String,
+        rejected_requests: u64,
+        window_seconds: u64,
+    },
+}
+
+impl Alert {
+    fn name(&self) -> &'static str {
+        match self {
+            Alert::QuotaExceeded { .. } => "quota_exceeded",
+            Alert::BackupFailed { .. } => "backup_failed",
+            Alert::HealthCheckFailed { .. } => "health_check_failed",
+            Alert::StorageThreshold { .. } => "storage_threshold",
+            Alert::SignatureSpike { .. } => "signature_spike",
+        }
+    }
+
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![("alert", self.name().to_string())];
+        match self {
+            Alert::QuotaExceeded {
+                index_id,
+                quota,
+                limit,
+            } => fields.extend([
+                ("index_id", index_id.clone()),
+                ("quota", quota.to_string()),
+                ("limit", limit.to_string()),
+            ]),
+            Alert::BackupFailed { error } => fields.push(("error", error.clone())),
+            Alert::HealthCheckFailed { check, error } => {
+                fields.extend([("check", check.to_string()), ("error", error.clone())])
+            }
+            Alert::StorageThreshold {
+                index_id,
+                metric,
+                value,
+                threshold,
+            } => fields.extend([
+                ("index_id", index_id.clone()),
+                ("metric", metric.to_string()),
+                ("value", value.to_string()),
+                ("threshold", threshold.to_string()),
+            ]),
+            Alert::SignatureSpike {
+                index_id,
+                rejected_requests,
+                window_seconds,
+            } => fields.extend([
+                ("index_id", index_id.clone()),
+                ("rejected_requests", rejected_requests.to_string()),
+                ("window_seconds", window_seconds.to_string()),
+            ]),
+        }
+
+        fields
+    }
+
+    fn default_template(name: &str) -> Template {
+        let (subject, body) = match name {
+            "quota_exceeded" => (
+                "findex_cloud: {quota} quota of index {index_id} exhausted",
+                "The monthly {quota} quota of index {index_id} ({limit}) is exhausted, its Findex callbacks are refused until the end of the month.",
+            ),
+            "backup_failed" => (
+                "findex_cloud: backup failed",
+                "The scheduled backup of the indexes database failed:\n\n{error}",
+            ),
+            "health_check_failed" => (
+                "findex_cloud: {check} health check failed",
+                "The {check} health check failed:\n\n{error}",
+            ),
+            "storage_threshold" => (
+                "findex_cloud: index {index_id} crossed its storage threshold",
+                "The {metric} of index {index_id} is {value} (threshold {threshold}).",
+            ),
+            _ => (
+                "findex_cloud: spike of rejected signatures on index {index_id}",
+                "Index {index_id} had {rejected_requests} requests refused by the signature check in less than {window_seconds} seconds: an attack or a client with a broken key?",
+            ),
+        };
+
+        Template {
+            subject: subject.to_string(),
+            body: body.to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+struct Template {
+    subject: String,
+    body: String,
+}
+
+fn render(template: &str, fields: &[(&'static str, String)]) -> String {
+    fields
+        .iter()
+        .fold(template.to_string(), |rendered, (name, value)| {
+            rendered.replace(&format!("{{{name}}}"), value)
+        })
+}
+
+struct SmtpNotifier {
+    host: String,
+    port: u16,
+    tls: bool,
+    credentials: Option<(String, String)>,
+    from: String,
+    to: Vec<String>,
+    /// By alert name, overriding the defaults
+    templates: HashMap<String, Template>,
+}
+
+/// Read the configuration, the alerts are not sent by email without `SMTP_HOST`.
+pub(crate) fn init_from_env() {
+    let Ok(host) = env::var("SMTP_HOST") else {
+        return;
+    };
+
+    let tls = match env::var("SMTP_TLS").as_deref() {
+        Ok("true") | Err(_) => true,
+        Ok("false") => false,
+        Ok(value) => panic!("`SMTP_TLS` must be `true` or `false` (found `{value}`)"),
+    };
+    let port = match env::var("SMTP_PORT") {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("`SMTP_PORT` must be a port number (found `{value}`)")),
+        Err(_) if tls => 465,
+        Err(_) => 25,
+    };
+
+    let credentials = match (env::var("SMTP_USERNAME"), env::var("SMTP_PASSWORD")) {
+        (Ok(username), Ok(password)) => Some((username, password)),
+        (Err(_), Err(_)) => None,
+        _ => panic!("`SMTP_USERNAME` and `SMTP_PASSWORD` must be set together"),
+    };
+    if credentials.is_some() && !tls {
+        panic!("`SMTP_USERNAME` needs `SMTP_TLS=true`, the password would be sent in clear");
+    }
+
+    let from =
+        env::var("SMTP_FROM").expect("`SMTP_FROM` env variable is required with `SMTP_HOST`");
+    let to: Vec<String> = env::var("SMTP_TO")
+        .expect("`SMTP_TO` env variable is required with `SMTP_HOST`")
+        .split(',')
+        .map(|address| address.trim().to_string())
+        .filter(|address| !address.is_empty())
+        .collect();
+    if to.is_empty() {
+        panic!("`SMTP_TO` must contain at least one address");
+    }
+    for address in to.iter().chain([&from]) {
+        if address.contains(['<', '>', '\r', '\n']) || !address.contains('@') {
+            panic!("Invalid email address `{address}` in `SMTP_FROM` or `SMTP_TO`");
+        }
+    }
+
+    let templates: HashMap<String, Template> = match env::var("SMTP_TEMPLATES") {
+        Ok(json) => serde_json::from_str(&json)
+            .unwrap_or_else(|err| panic!("Cannot parse `SMTP_TEMPLATES` ({err})")),
+        Err(_) => HashMap::new(),
+    };
+    for name in templates.keys() {
+        if ![
+            "quota_exceeded",
+            "backup_failed",
+            "health_check_failed",
+            "storage_threshold",
+            "signature_spike",
+        ]
+        .contains(&name.as_str())
+        {
+            panic!("Unknown alert `{name}` inside `SMTP_TEMPLATES`");
+        }
+    }
+
+    log::info!(
+        "Alerts sent by email to {} through {host}:{port}",
+        to.join(", ")
+    );
+
+    let _ = NOTIFIER.set(SmtpNotifier {
+        host,
+        port,
+        tls,
+        credentials,
+        from,
+        to,
+        templates,
+    });
+}
+
+/// Send the alert by email in the background, if the notifier is configured.
+pub(crate) fn notify(alert: Alert) {
+    let Some(notifier) = NOTIFIER.get() else {
+        return;
+    };
+
+    actix_web::rt::spawn(async move {
+        let result = actix_web::rt::time::timeout(SEND_TIMEOUT, notifier.send(&alert))
+            .await
+            .unwrap_or_else(|_| Err(Error::Internal("timeout".to_string())));
+
+        if let Err(err) = result {
+            log::error!("Cannot send the `{}` alert by email ({err})", alert.name());
+        }
+    });
+}
+
+impl SmtpNotifier {
+    async fn send(&self, alert: &Alert) -> Result<(), Error> {
+        let template = self
+            .templates
+            .get(alert.name())
+            .cloned()
+            .unwrap_or_else(|| Alert::default_template(alert.name()));
+        let fields = alert.fields();
+        let message = self.message(
+            &render(&template.subject, &fields),
+            &render(&template.body, &fields),
+        );
+
+        let stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .map_err(smtp_error)?;
+
+        if self.tls {
+            let connector =
+                TlsConnector::from(native_tls::TlsConnector::new().map_err(smtp_error)?);
+            let stream = connector
+                .connect(&self.host, stream)
+                .await
+                .map_err(smtp_error)?;
+            self.transaction(BufReader::new(stream), &message).await
+        } else {
+            self.transaction(BufReader::new(stream), &message).await
+        }
+    }
+
+    /// Headers and body, with the leading dots doubled (RFC 5321 "transparency")
+    fn message(&self, subject: &str, body: &str) -> String {
+        let subject = if subject.is_ascii() {
+            subject.replace(['\r', '\n'], " ")
+        } else {
+            format!(
+                "=?UTF-8?B?{}?=",
+                general_purpose::STANDARD.encode(subject.replace(['\r', '\n'], " "))
+            )
+        };
+
+        let mut message = format!(
+            "From: <{}>\r\nTo: {}\r\nSubject: {subject}\r\nDate: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+            self.from,
+            self.to
+                .iter()
+                .map(|address| format!("<{address}>"))
+                .collect::<Vec<_>>()
+                .join(", "),
+            Utc::now().to_rfc2822(),
+        );
+        for line in body.lines() {
+            if line.starts_with('.') {
+                message.push('.');
+            }
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+
+        message
+    }
+
+    async fn transaction<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut stream: BufReader<S>,
+        message: &str,
+    ) -> Result<(), Error> {
+        expect_reply(&mut stream, 220).await?;
+        command(&mut stream, "EHLO findex_cloud", 250).await?;
+
+        if let Some((username, password)) = &self.credentials {
+            let token = general_purpose::STANDARD.encode(format!("\0{username}\0{password}"));
+            command(&mut stream, &format!("AUTH PLAIN {token}"), 235).await?;
+        }
+
+        command(&mut stream, &format!("MAIL FROM:<{}>", self.from), 250).await?;
+        for address in &self.to {
+            command(&mut stream, &format!("RCPT TO:<{address}>"), 250).await?;
+        }
+        command(&mut stream, "DATA", 354).await?;
+        stream
+            .write_all(message.as_bytes())
+            .await
+            .map_err(smtp_error)?;
+        command(&mut stream, ".", 250).await?;
+
+        // The email is accepted, an error on `QUIT` doesn't matter.
+        let _ = command(&mut stream, "QUIT", 221).await;
+
+        Ok(())
+    }
+}
+
+fn smtp_error(err: impl std::fmt::Display) -> Error {
+    Error::Internal(format!("SMTP: {err}"))
+}
+
+async fn command<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufReader<S>,
+    command: &str,
+    expected_code: u16,
+) -> Result<(), Error> {
+    stream
+        .write_all(format!("{command}\r\n").as_bytes())
+        .await
+        .map_err(smtp_error)?;
+    stream.flush().await.map_err(smtp_error)?;
+
+    expect_reply(stream, expected_code).await
+}
+
+/// Read a (multiline) reply, `250` also accepts `251` (forwarded recipient).
+async fn expect_reply<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufReader<S>,
+    expected_code: u16,
+) -> Result<(), Error> {
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await.map_err(smtp_error)? == 0 {
+            return Err(smtp_error("connection closed by the server"));
+        }
+
+        let code: u16 = line
+            .get(..3)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| smtp_error(format!("invalid reply `{}`", line.trim_end())))?;
+        if code != expected_code && !(expected_code == 250 && code == 251) {
+            return Err(smtp_error(format!(
+                "expected {expected_code}, got `{}`",
+                line.trim_end()
+            )));
+        }
+
+        // `250-…` continues, `250 …` is the last line
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
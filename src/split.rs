@@ -0,0 +1,205 @@
+/// Store the entries and the chains inside different indexes databases
+/// (`INDEXES_CHAINS_DATABASE_TYPE`).
+///
+/// The entries need the conditional writes of `upsert_entries` and benefit from a
+/// transactional store, the chains are only appended and can live in a cheaper store.
+/// `INDEXES_DATABASE_TYPE` keeps the entries and the data of the index itself (changes
+/// log), `INDEXES_CHAINS_DATABASE_TYPE` gets the chains. The sizes of the index add the
+/// sizes of both databases.
+///
+/// A backup of a single database would not be consistent with the other one, backups are
+/// not supported through this database (back up both databases with their own tools).
+use std::{collections::HashSet, sync::Arc};
+
+use async_trait::async_trait;
+use cosmian_findex::{parameters::UID_LENGTH, EncryptedTable, Uid, UpsertData};
+use zeroize::Zeroizing;
+
+use crate::{
+    backup::BackupInfo,
+    changes::Change,
+    core::{Index, IndexesDatabase, Table},
+    errors::Error,
+    events::Mutation,
+    metrics::StorageGauge,
+    scrub::ScrubBatch,
+    settings::Consistency,
+};
+
+pub(crate) struct Database {
+    entries: Arc<dyn IndexesDatabase>,
+    chains: Arc<dyn IndexesDatabase>,
+}
+
+impl Database {
+    pub(crate) fn new(entries: Arc<dyn IndexesDatabase>, chains: Arc<dyn IndexesDatabase>) -> Self {
+        Database { entries, chains }
+    }
+
+    fn database(&self, table: Table) -> &Arc<dyn IndexesDatabase> {
+        match table {
+            Table::Entries => &self.entries,
+            Table::Chains => &self.chains,
+        }
+    }
+}
+
+#[async_trait]
+impl IndexesDatabase for Database {
+    async fn set_size(&self, index: &mut Index) -> Result<(), Error> {
+        let mut chains_index = index.clone();
+        self.entries.set_size(index).await?;
+        self.chains.set_size(&mut chains_index).await?;
+
+        index.size = index.size.zip(chains_index.size).map(|(a, b)| a + b);
+        index.chains_size = chains_index.chains_size;
+        index.chains_count = chains_index.chains_count;
+
+        Ok(())
+    }
+
+    async fn fetch(
+        &self,
+        index: &Index,
+        table: Table,
+        uids: HashSet<Uid<UID_LENGTH>>,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        self.database(table).fetch(index, table, uids).await
+    }
+
+    async fn fetch_serialized(
+        &self,
+        index: &Index,
+        table: Table,
+        uids: HashSet<Uid<UID_LENGTH>>,
+    ) -> Result<Zeroizing<Vec<u8>>, Error> {
+        self.database(table)
+            .fetch_serialized(index, table, uids)
+            .await
+    }
+
+    async fn upsert_entries(
+        &self,
+        index: &Index,
+        data: UpsertData<UID_LENGTH>,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        self.entries.upsert_entries(index, data).await
+    }
+
+    async fn insert_chains(
+        &self,
+        index: &Index,
+        data: EncryptedTable<UID_LENGTH>,
+    ) -> Result<(), Error> {
+        self.chains.insert_chains(index, data).await
+    }
+
+    async fn put_values(
+        &self,
+        index: &Index,
+        table: Table,
+        data: EncryptedTable<UID_LENGTH>,
+    ) -> Result<(), Error> {
+        self.database(table).put_values(index, table, data).await
+    }
+
+    async fn fetch_all(
+        &self,
+        index: &Index,
+        table: Table,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        self.database(table).fetch_all(index, table).await
+    }
+
+    async fn fetch_with_consistency(
+        &self,
+        index: &Index,
+        table: Table,
+        uids: HashSet<Uid<UID_LENGTH>>,
+        consistency: Consistency,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        self.database(table)
+            .fetch_with_consistency(index, table, uids, consistency)
+            .await
+    }
+
+    async fn indexes_ids_with_data(&self) -> Result<HashSet<String>, Error> {
+        let mut ids = self.entries.indexes_ids_with_data().await?;
+        ids.extend(self.chains.indexes_ids_with_data().await?);
+        Ok(ids)
+    }
+
+    async fn delete_index_data(&self, index_id: &str) -> Result<(), Error> {
+        self.entries.delete_index_data(index_id).await?;
+        self.chains.delete_index_data(index_id).await
+    }
+
+    async fn backup(&self) -> Result<BackupInfo, Error> {
+        Err(Error::Unsupported(
+            "The entries and the chains are in different databases, back up each database with its own tools".to_string(),
+        ))
+    }
+
+    async fn backups(&self) -> Result<Vec<BackupInfo>, Error> {
+        Err(Error::Unsupported(
+            "The entries and the chains are in different databases, back up each database with its own tools".to_string(),
+        ))
+    }
+
+    fn supports_ttl(&self) -> bool {
+        self.entries.supports_ttl() && self.chains.supports_ttl()
+    }
+
+    async fn recompute_size(&self, index: &Index) -> Result<(), Error> {
+        self.entries.recompute_size(index).await?;
+        self.chains.recompute_size(index).await
+    }
+
+    async fn scrub(
+        &self,
+        index: &Index,
+        table: Table,
+        from: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<ScrubBatch, Error> {
+        self.database(table).scrub(index, table, from, limit).await
+    }
+
+    async fn append_changes(&self, index: &Index, mutations: &[Mutation]) -> Result<(), Error> {
+        self.entries.append_changes(index, mutations).await
+    }
+
+    async fn fetch_changes(
+        &self,
+        index: &Index,
+        since: u64,
+        limit: usize,
+    ) -> Result<Vec<Change>, Error> {
+        self.entries.fetch_changes(index, since, limit).await
+    }
+
+    /// Gauges of both databases, with a `table` label.
+    async fn storage_gauges(&self) -> Result<Vec<StorageGauge>, Error> {
+        let mut gauges: Vec<_> = self
+            .entries
+            .storage_gauges()
+            .await?
+            .into_iter()
+            .map(|gauge| gauge.with_label("table", "entries"))
+            .collect();
+        gauges.extend(
+            self.chains
+                .storage_gauges()
+                .await?
+                .into_iter()
+                .map(|gauge| gauge.with_label("table", "chains")),
+        );
+
+        Ok(gauges)
+    }
+
+    #[cfg(feature = "log_requests")]
+    async fn fetch_all_as_json(&self, index: &Index, table: Table) -> Result<String, Error> {
+        self.database(table).fetch_all_as_json(index, table).await
+    }
+}
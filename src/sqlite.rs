@@ -1,28 +1,58 @@
+use std::{path::Path, time::Instant};
+
 use async_trait::async_trait;
-use sqlx::{migrate::MigrateDatabase, sqlite::SqlitePoolOptions, Sqlite, SqlitePool};
+use chrono::{NaiveDate, NaiveDateTime};
+use sqlx::{
+    migrate::MigrateDatabase, sqlite::SqlitePoolOptions, Sqlite, SqliteConnection, SqlitePool,
+};
 
 use crate::{
+    compaction::{CompactionLockState, CompactionProgress, CompactionStats},
+    config,
     core::{Index, MetadataDatabase, NewIndex},
+    counters::IndexCounters,
     errors::Error,
+    metrics::StorageGauge,
+    settings::IndexSettings,
+    usage::{DailyUsage, UsageCounters},
 };
 
 pub(crate) struct Database(SqlitePool);
 
 impl Database {
-    pub(crate) async fn create() -> Self {
-        let db_url = "sqlite://data/database.sqlite";
+    /// `db_url` is the `METADATA_DATABASE_URL` (`sqlite://<path>?<options>`), the database is
+    /// at `SQLITE_PATH` without it.
+    pub(crate) async fn create(db_url: Option<String>) -> Self {
+        let db_url = match db_url {
+            Some(db_url) => {
+                let db_path = db_url
+                    .trim_start_matches("sqlite://")
+                    .split('?')
+                    .next()
+                    .unwrap_or_default();
+                if db_path != ":memory:" {
+                    config::prepare_parent_directory(Path::new(db_path));
+                }
+                db_url
+            }
+            None => {
+                let db_path = config::sqlite_path();
+                config::prepare_parent_directory(&db_path);
+                format!("sqlite://{}", db_path.display())
+            }
+        };
 
-        if !Sqlite::database_exists(db_url)
+        if !Sqlite::database_exists(&db_url)
             .await
             .unwrap_or_else(|e| panic!("Cannot check database existance at {db_url} ({e})"))
         {
-            Sqlite::create_database(db_url)
+            Sqlite::create_database(&db_url)
                 .await
                 .unwrap_or_else(|e| panic!("Cannot create database {db_url} ({e})"));
         }
 
         let pool = SqlitePoolOptions::new()
-            .connect(db_url)
+            .connect(&db_url)
             .await
             .unwrap_or_else(|e| panic!("Cannot connect to database at {db_url} ({e})"));
 
@@ -45,7 +75,11 @@ impl MetadataDatabase for Database {
             r#"
             SELECT
                 *,
-                null as "size: _"
+                null as "size: _",
+                null as "entries_size: _",
+                null as "chains_size: _",
+                null as "entries_count: _",
+                null as "chains_count: _"
             FROM indexes
             ORDER BY created_at DESC"#,
         )
@@ -61,7 +95,11 @@ impl MetadataDatabase for Database {
             r#"
                 SELECT
                     *,
-                    null as "size: _"
+                    null as "size: _",
+                    null as "entries_size: _",
+                    null as "chains_size: _",
+                null as "entries_count: _",
+                null as "chains_count: _"
                 FROM indexes
                 WHERE id = $1
             "#,
@@ -79,43 +117,543 @@ impl MetadataDatabase for Database {
         sqlx::query_as!(Index, r#"DELETE FROM indexes WHERE id = $1"#, id,)
             .execute(&mut db)
             .await?;
+        sqlx::query!(r#"DELETE FROM compactions WHERE index_id = $1"#, id)
+            .execute(&mut db)
+            .await?;
+        sqlx::query!(r#"DELETE FROM usage WHERE index_id = $1"#, id)
+            .execute(&mut db)
+            .await?;
+        sqlx::query!(r#"DELETE FROM index_settings WHERE index_id = $1"#, id)
+            .execute(&mut db)
+            .await?;
 
         Ok(())
     }
 
-    async fn create_index(&self, new_index: NewIndex) -> Result<Index, Error> {
+    async fn set_archived_at(
+        &self,
+        id: &str,
+        archived_at: Option<NaiveDateTime>,
+    ) -> Result<(), Error> {
         let mut db = self.0.acquire().await?;
 
-        let Id { id } = sqlx::query_as!(
-            Id,
-            r#"INSERT INTO indexes (
-                id,
-    
-                name,
-    
-                fetch_entries_key,
-                fetch_chains_key,
-                upsert_entries_key,
-                insert_chains_key
-            ) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id"#,
-            new_index.id,
-            new_index.name,
-            new_index.fetch_entries_key,
-            new_index.fetch_chains_key,
-            new_index.upsert_entries_key,
-            new_index.insert_chains_key,
+        sqlx::query!(
+            r#"UPDATE indexes SET archived_at = $1 WHERE id = $2"#,
+            archived_at,
+            id,
+        )
+        .execute(&mut db)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_compaction_stats(&self, id: &str) -> Result<CompactionStats, Error> {
+        let mut db = self.0.acquire().await?;
+
+        let compaction = sqlx::query!(
+            r#"
+                SELECT
+                    last_compaction_at as "last_compaction_at: NaiveDateTime",
+                    writes_since_compaction
+                FROM compactions
+                WHERE index_id = $1
+            "#,
+            id,
+        )
+        .fetch_optional(&mut db)
+        .await?;
+
+        Ok(compaction
+            .map(|compaction| CompactionStats {
+                last_compaction_at: compaction.last_compaction_at,
+                writes_since_compaction: compaction.writes_since_compaction as u64,
+            })
+            .unwrap_or_default())
+    }
+
+    async fn add_writes_since_compaction(&self, id: &str, writes: u64) -> Result<u64, Error> {
+        let mut db = self.0.acquire().await?;
+        let writes = writes as i64;
+
+        let total = sqlx::query_scalar!(
+            r#"
+                INSERT INTO compactions (index_id, writes_since_compaction) VALUES ($1, $2)
+                ON CONFLICT(index_id) DO UPDATE
+                SET writes_since_compaction = writes_since_compaction + excluded.writes_since_compaction
+                RETURNING writes_since_compaction as "writes_since_compaction!: i64"
+            "#,
+            id,
+            writes,
         )
         .fetch_one(&mut db)
         .await?;
 
-        Ok(sqlx::query_as!(
-            Index,
-            r#"SELECT *, null as "size: _" FROM indexes WHERE id = $1"#,
+        Ok(total as u64)
+    }
+
+    async fn set_compacted(&self, id: &str, compacted_at: NaiveDateTime) -> Result<(), Error> {
+        let mut db = self.0.acquire().await?;
+
+        sqlx::query!(
+            r#"
+                INSERT INTO compactions (index_id, last_compaction_at, writes_since_compaction) VALUES ($1, $2, 0)
+                ON CONFLICT(index_id) DO UPDATE
+                SET last_compaction_at = excluded.last_compaction_at, writes_since_compaction = 0
+            "#,
+            id,
+            compacted_at,
+        )
+        .execute(&mut db)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn acquire_compaction_lock(
+        &self,
+        id: &str,
+        token: &str,
+        expires_at: NaiveDateTime,
+        now: NaiveDateTime,
+    ) -> Result<bool, Error> {
+        let mut db = self.0.acquire().await?;
+
+        // The `WHERE` of the upsert leaves the row untouched if someone else holds the lock.
+        let result = sqlx::query!(
+            r#"
+                INSERT INTO compactions (index_id, lock_token, lock_expires_at) VALUES ($1, $2, $3)
+                ON CONFLICT(index_id) DO UPDATE
+                SET lock_token = excluded.lock_token, lock_expires_at = excluded.lock_expires_at
+                WHERE lock_token IS NULL OR lock_token = excluded.lock_token OR lock_expires_at < $4
+            "#,
+            id,
+            token,
+            expires_at,
+            now,
+        )
+        .execute(&mut db)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn release_compaction_lock(&self, id: &str, token: &str) -> Result<bool, Error> {
+        let mut db = self.0.acquire().await?;
+
+        let result = sqlx::query!(
+            r#"
+                UPDATE compactions SET lock_token = NULL, lock_expires_at = NULL, progress = NULL
+                WHERE index_id = $1 AND lock_token = $2
+            "#,
+            id,
+            token,
+        )
+        .execute(&mut db)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn get_compaction_lock(&self, id: &str) -> Result<Option<CompactionLockState>, Error> {
+        let mut db = self.0.acquire().await?;
+
+        let lock = sqlx::query!(
+            r#"
+                SELECT
+                    lock_token as "lock_token!",
+                    lock_expires_at as "lock_expires_at!: NaiveDateTime",
+                    progress
+                FROM compactions
+                WHERE index_id = $1 AND lock_token IS NOT NULL
+            "#,
+            id,
+        )
+        .fetch_optional(&mut db)
+        .await?;
+
+        lock.map(|lock| {
+            Ok(CompactionLockState {
+                token: lock.lock_token,
+                expires_at: lock.lock_expires_at,
+                progress: lock
+                    .progress
+                    .map(|progress| serde_json::from_str(&progress))
+                    .transpose()?,
+            })
+        })
+        .transpose()
+    }
+
+    async fn set_compaction_progress(
+        &self,
+        id: &str,
+        token: &str,
+        progress: Option<&CompactionProgress>,
+    ) -> Result<bool, Error> {
+        let mut db = self.0.acquire().await?;
+        let progress = progress.map(serde_json::to_string).transpose()?;
+
+        let result = sqlx::query!(
+            r#"UPDATE compactions SET progress = $1 WHERE index_id = $2 AND lock_token = $3"#,
+            progress,
+            id,
+            token,
+        )
+        .execute(&mut db)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn set_last_activity_at(
+        &self,
+        id: &str,
+        last_activity_at: NaiveDateTime,
+    ) -> Result<(), Error> {
+        let mut db = self.0.acquire().await?;
+
+        sqlx::query!(
+            r#"UPDATE indexes SET last_activity_at = $1, stale_at = NULL WHERE id = $2"#,
+            last_activity_at,
+            id,
+        )
+        .execute(&mut db)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn set_stale_at(&self, id: &str, stale_at: NaiveDateTime) -> Result<(), Error> {
+        let mut db = self.0.acquire().await?;
+
+        sqlx::query!(
+            r#"UPDATE indexes SET stale_at = $1 WHERE id = $2"#,
+            stale_at,
+            id,
+        )
+        .execute(&mut db)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn add_usage(
+        &self,
+        id: &str,
+        day: NaiveDate,
+        endpoint: &str,
+        counters: &UsageCounters,
+    ) -> Result<(), Error> {
+        let mut db = self.0.acquire().await?;
+        let requests = counters.requests as i64;
+        let errors = counters.errors as i64;
+        let bytes_received = counters.bytes_received as i64;
+        let bytes_sent = counters.bytes_sent as i64;
+
+        sqlx::query!(
+            r#"
+                INSERT INTO usage (index_id, day, endpoint, requests, errors, bytes_received, bytes_sent)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+                ON CONFLICT(index_id, day, endpoint) DO UPDATE
+                SET
+                    requests = requests + excluded.requests,
+                    errors = errors + excluded.errors,
+                    bytes_received = bytes_received + excluded.bytes_received,
+                    bytes_sent = bytes_sent + excluded.bytes_sent
+            "#,
+            id,
+            day,
+            endpoint,
+            requests,
+            errors,
+            bytes_received,
+            bytes_sent,
+        )
+        .execute(&mut db)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_usage(&self, id: &str, since: NaiveDate) -> Result<Vec<DailyUsage>, Error> {
+        let mut db = self.0.acquire().await?;
+
+        let rows = sqlx::query!(
+            r#"
+                SELECT
+                    day as "day: NaiveDate",
+                    endpoint,
+                    requests,
+                    errors,
+                    bytes_received,
+                    bytes_sent
+                FROM usage
+                WHERE index_id = $1 AND day >= $2
+            "#,
+            id,
+            since,
+        )
+        .fetch_all(&mut db)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| DailyUsage {
+                day: row.day,
+                endpoint: row.endpoint,
+                counters: UsageCounters {
+                    requests: row.requests as u64,
+                    errors: row.errors as u64,
+                    bytes_received: row.bytes_received as u64,
+                    bytes_sent: row.bytes_sent as u64,
+                },
+            })
+            .collect())
+    }
+
+    async fn delete_usage_before(&self, day: NaiveDate) -> Result<(), Error> {
+        let mut db = self.0.acquire().await?;
+
+        sqlx::query!(r#"DELETE FROM usage WHERE day < $1"#, day)
+            .execute(&mut db)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn add_request_counters(
+        &self,
+        id: &str,
+        increments: &IndexCounters,
+    ) -> Result<(), Error> {
+        let mut db = self.0.acquire().await?;
+        let fetches = increments.fetches as i64;
+        let upserts = increments.upserts as i64;
+        let chain_inserts = increments.chain_inserts as i64;
+        let rejected_signatures = increments.rejected_signatures as i64;
+
+        sqlx::query!(
+            r#"
+                UPDATE indexes
+                SET
+                    fetches = fetches + $1,
+                    upserts = upserts + $2,
+                    chain_inserts = chain_inserts + $3,
+                    rejected_signatures = rejected_signatures + $4
+                WHERE id = $5
+            "#,
+            fetches,
+            upserts,
+            chain_inserts,
+            rejected_signatures,
+            id,
+        )
+        .execute(&mut db)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_settings(&self, id: &str) -> Result<Option<IndexSettings>, Error> {
+        let mut db = self.0.acquire().await?;
+
+        let row = sqlx::query!(
+            r#"SELECT settings FROM index_settings WHERE index_id = $1"#,
             id
         )
-        .fetch_one(&mut db)
-        .await?)
+        .fetch_optional(&mut db)
+        .await?;
+
+        match row {
+            Some(row) => Ok(Some(serde_json::from_str(&row.settings)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn set_settings(&self, id: &str, settings: &IndexSettings) -> Result<(), Error> {
+        let mut db = self.0.acquire().await?;
+        let settings = serde_json::to_string(settings)?;
+
+        sqlx::query!(
+            r#"
+                INSERT INTO index_settings (index_id, settings, version) VALUES ($1, $2, 1)
+                ON CONFLICT(index_id) DO UPDATE
+                SET settings = excluded.settings, version = index_settings.version + 1
+            "#,
+            id,
+            settings,
+        )
+        .execute(&mut db)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_versioned_settings(
+        &self,
+        id: &str,
+    ) -> Result<Option<(IndexSettings, u64)>, Error> {
+        let mut db = self.0.acquire().await?;
+
+        let row = sqlx::query!(
+            r#"SELECT settings, version FROM index_settings WHERE index_id = $1"#,
+            id
+        )
+        .fetch_optional(&mut db)
+        .await?;
+
+        match row {
+            Some(row) => Ok(Some((
+                serde_json::from_str(&row.settings)?,
+                row.version as u64,
+            ))),
+            None => Ok(None),
+        }
     }
+
+    async fn replace_settings(
+        &self,
+        id: &str,
+        settings: &IndexSettings,
+        version: u64,
+    ) -> Result<Option<u64>, Error> {
+        let mut db = self.0.acquire().await?;
+        let settings = serde_json::to_string(settings)?;
+
+        if version == 0 {
+            let result = sqlx::query!(
+                r#"
+                    INSERT INTO index_settings (index_id, settings, version) VALUES ($1, $2, 1)
+                    ON CONFLICT(index_id) DO NOTHING
+                "#,
+                id,
+                settings,
+            )
+            .execute(&mut db)
+            .await?;
+
+            return Ok((result.rows_affected() == 1).then_some(1));
+        }
+
+        let version = version as i64;
+        let new_version = sqlx::query_scalar!(
+            r#"
+                UPDATE index_settings SET settings = $1, version = version + 1
+                WHERE index_id = $2 AND version = $3
+                RETURNING version as "version!: i64"
+            "#,
+            settings,
+            id,
+            version,
+        )
+        .fetch_optional(&mut db)
+        .await?;
+
+        Ok(new_version.map(|version| version as u64))
+    }
+
+    /// The acquire time is measured with a connection taken for the scrape, so it's the
+    /// current wait for a connection, not an average.
+    async fn storage_gauges(&self) -> Result<Vec<StorageGauge>, Error> {
+        let started_at = Instant::now();
+        drop(self.0.acquire().await?);
+        let acquire_seconds = started_at.elapsed().as_secs_f64();
+
+        let size = self.0.size();
+        let idle = self.0.num_idle() as u32;
+
+        Ok(vec![
+            StorageGauge::gauge(
+                "findex_cloud_sqlite_pool_connections",
+                "Connections of the SQLite pool by state.",
+                f64::from(size.saturating_sub(idle)),
+            )
+            .with_label("state", "in_use"),
+            StorageGauge::gauge(
+                "findex_cloud_sqlite_pool_connections",
+                "Connections of the SQLite pool by state.",
+                f64::from(idle),
+            )
+            .with_label("state", "idle"),
+            StorageGauge::gauge(
+                "findex_cloud_sqlite_pool_max_connections",
+                "Maximum number of connections of the SQLite pool.",
+                f64::from(self.0.options().get_max_connections()),
+            ),
+            StorageGauge::gauge(
+                "findex_cloud_sqlite_pool_acquire_seconds",
+                "Time to acquire a connection from the SQLite pool during the scrape.",
+                acquire_seconds,
+            ),
+        ])
+    }
+
+    async fn create_index(&self, new_index: NewIndex) -> Result<Index, Error> {
+        let mut db = self.0.acquire().await?;
+
+        insert_index(&mut db, new_index).await
+    }
+
+    async fn create_indexes(&self, new_indexes: Vec<NewIndex>) -> Result<Vec<Index>, Error> {
+        let mut transaction = self.0.begin().await?;
+
+        let mut indexes = Vec::with_capacity(new_indexes.len());
+        for new_index in new_indexes {
+            // The transaction is rolled back on drop if an insertion fails
+            indexes.push(insert_index(&mut transaction, new_index).await?);
+        }
+
+        transaction.commit().await?;
+
+        Ok(indexes)
+    }
+}
+
+async fn insert_index(db: &mut SqliteConnection, new_index: NewIndex) -> Result<Index, Error> {
+    let new_index = new_index.validate()?;
+
+    let Id { id } = sqlx::query_as!(
+        Id,
+        r#"INSERT INTO indexes (
+            id,
+
+            name,
+
+            fetch_entries_key,
+            fetch_chains_key,
+            upsert_entries_key,
+            insert_chains_key,
+
+            ttl_seconds
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id"#,
+        new_index.id,
+        new_index.name,
+        new_index.fetch_entries_key,
+        new_index.fetch_chains_key,
+        new_index.upsert_entries_key,
+        new_index.insert_chains_key,
+        new_index.ttl_seconds,
+    )
+    .fetch_one(&mut *db)
+    .await
+    .map_err(|err| match &err {
+        // SQLITE_CONSTRAINT_PRIMARYKEY
+        sqlx::Error::Database(database_error)
+            if database_error.code().as_deref() == Some("1555") =>
+        {
+            Error::IndexAlreadyExists(new_index.id.clone())
+        }
+        _ => Error::from(err),
+    })?;
+
+    Ok(sqlx::query_as!(
+        Index,
+        r#"SELECT *, null as "size: _", null as "entries_size: _", null as "chains_size: _", null as "entries_count: _", null as "chains_count: _" FROM indexes WHERE id = $1"#,
+        id
+    )
+    .fetch_one(&mut *db)
+    .await?)
 }
 
 struct Id {
@@ -0,0 +1,188 @@
+/// Syslog output of the logs (RFC 5424), for the environments where stderr isn't collected.
+///
+/// Enabled with `LOG_OUTPUT=syslog`, the messages are sent to `SYSLOG_ADDRESS`:
+/// - `unix:///dev/log` (default): local syslog daemon (datagram socket)
+/// - `udp://host:514`: one datagram per message
+/// - `tcp://host:601`: octet-counting framing (RFC 6587), reconnected after a failure
+///
+/// The levels are filtered with `RUST_LOG` like the default stderr output.
+use std::{
+    env,
+    io::{self, Write},
+    net::{TcpStream, UdpSocket},
+    sync::Mutex,
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+
+use chrono::{SecondsFormat, Utc};
+use env_logger::filter::Filter;
+use log::{Level, Log, Metadata, Record};
+
+const DEFAULT_SYSLOG_ADDRESS: &str = "unix:///dev/log";
+/// `daemon`
+const DEFAULT_FACILITY: u8 = 3;
+const APP_NAME: &str = "findex_cloud";
+
+enum Transport {
+    Udp(UdpSocket),
+    Tcp {
+        address: String,
+        stream: Option<TcpStream>,
+    },
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+}
+
+impl Transport {
+    fn connect(address: &str) -> io::Result<Self> {
+        if let Some(address) = address.strip_prefix("udp://") {
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            socket.connect(address)?;
+            Ok(Transport::Udp(socket))
+        } else if let Some(address) = address.strip_prefix("tcp://") {
+            Ok(Transport::Tcp {
+                address: address.to_string(),
+                stream: Some(TcpStream::connect(address)?),
+            })
+        } else if let Some(path) = address.strip_prefix("unix://") {
+            #[cfg(unix)]
+            {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                Ok(Transport::Unix(socket))
+            }
+            #[cfg(not(unix))]
+            {
+                Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("Unix sockets are not supported on this platform ({path})"),
+                ))
+            }
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the address must start with `unix://`, `udp://` or `tcp://`",
+            ))
+        }
+    }
+
+    fn send(&mut self, message: &str) -> io::Result<()> {
+        match self {
+            Transport::Udp(socket) => socket.send(message.as_bytes()).map(|_| ()),
+            Transport::Tcp { address, stream } => {
+                let frame = format!("{} {message}", message.len());
+                if let Some(connected) = stream {
+                    if connected.write_all(frame.as_bytes()).is_ok() {
+                        return Ok(());
+                    }
+                }
+
+                // The connection was closed (syslog server restarted…), retry once
+                // with a new connection.
+                *stream = None;
+                let mut connected = TcpStream::connect(address.as_str())?;
+                connected.write_all(frame.as_bytes())?;
+                *stream = Some(connected);
+                Ok(())
+            }
+            #[cfg(unix)]
+            Transport::Unix(socket) => socket.send(message.as_bytes()).map(|_| ()),
+        }
+    }
+}
+
+pub(crate) struct Syslog {
+    filter: Filter,
+    facility: u8,
+    hostname: String,
+    transport: Mutex<Transport>,
+}
+
+impl Syslog {
+    /// Panic if the syslog server is unreachable, logs would be lost silently.
+    fn from_env(filter: Filter) -> Self {
+        let address =
+            env::var("SYSLOG_ADDRESS").unwrap_or_else(|_| DEFAULT_SYSLOG_ADDRESS.to_string());
+        let transport = Transport::connect(&address)
+            .unwrap_or_else(|e| panic!("Cannot connect to syslog at {address} ({e})"));
+
+        let facility = env::var("SYSLOG_FACILITY")
+            .ok()
+            .map(|facility| match facility.parse() {
+                Ok(facility) if facility <= 23 => facility,
+                _ => panic!("`SYSLOG_FACILITY` must be a number between 0 and 23"),
+            })
+            .unwrap_or(DEFAULT_FACILITY);
+
+        Syslog {
+            filter,
+            facility,
+            hostname: env::var("HOSTNAME")
+                .ok()
+                .filter(|hostname| !hostname.is_empty())
+                .unwrap_or_else(|| "-".to_string()),
+            transport: Mutex::new(transport),
+        }
+    }
+
+    fn format(&self, record: &Record) -> String {
+        let severity = match record.level() {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        };
+
+        // <PRI>VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA MSG
+        format!(
+            "<{}>1 {} {} {APP_NAME} {} - - [{}] {}",
+            self.facility * 8 + severity,
+            Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            self.hostname,
+            std::process::id(),
+            record.target(),
+            record.args()
+        )
+    }
+}
+
+impl Log for Syslog {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+
+        let message = self.format(record);
+        if let Ok(mut transport) = self.transport.lock() {
+            if let Err(err) = transport.send(&message) {
+                // Cannot use `log` here
+                eprintln!("Cannot send log to syslog ({err}): {message}");
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Levels of `RUST_LOG`, like the stderr logger
+pub(crate) fn filter_from_env(default_filter: &str) -> Filter {
+    env_logger::filter::Builder::new()
+        .parse(&env::var("RUST_LOG").unwrap_or_else(|_| default_filter.to_string()))
+        .build()
+}
+
+/// Install the syslog logger, with the same default filter as the stderr logger.
+pub(crate) fn init(default_filter: &str) {
+    let filter = filter_from_env(default_filter);
+    let max_level = filter.filter();
+
+    log::set_boxed_logger(Box::new(Syslog::from_env(filter)))
+        .expect("A logger is already installed");
+    log::set_max_level(max_level);
+}
@@ -0,0 +1,125 @@
+/// Integration with systemd when the server runs as a native service (`Type=notify`).
+///
+/// - Readiness: `READY=1` is sent to `NOTIFY_SOCKET` once the listeners are bound, and
+///   `STOPPING=1` when the server shuts down.
+/// - Watchdog: with `WatchdogSec=` (`WATCHDOG_USEC`), `WATCHDOG=1` is sent at half the interval
+///   from the async executor, so a stuck executor gets the service restarted.
+/// - Socket activation: the sockets passed by systemd (`LISTEN_FDS`, from a `.socket` unit) are
+///   used instead of binding the addresses. With `LISTENERS`, the roles are matched with the
+///   local addresses of these sockets.
+///
+/// Without these variables (not started by systemd) everything is a no-op.
+use std::{env, net::TcpListener, time::Duration};
+
+/// First file descriptor passed by systemd (`SD_LISTEN_FDS_START`)
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Send a state (`READY=1`, `WATCHDOG=1`…) to the service manager, errors are only logged.
+pub(crate) fn notify(state: &str) {
+    let Ok(socket_path) = env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    if let Err(err) = send(&socket_path, state) {
+        log::error!("Cannot notify systemd at {socket_path} ({err})");
+    }
+}
+
+#[cfg(unix)]
+fn send(socket_path: &str, state: &str) -> std::io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+
+    // `@` is the abstract namespace (Linux only)
+    #[cfg(target_os = "linux")]
+    if let Some(name) = socket_path.strip_prefix('@') {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+        let address = SocketAddr::from_abstract_name(name)?;
+        return socket.send_to_addr(state.as_bytes(), &address).map(|_| ());
+    }
+
+    socket.send_to(state.as_bytes(), socket_path).map(|_| ())
+}
+
+#[cfg(not(unix))]
+fn send(_socket_path: &str, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Ping the watchdog in the background if `WATCHDOG_USEC` is set for this process.
+pub(crate) fn start_watchdog() {
+    if !for_this_process("WATCHDOG_PID") {
+        return;
+    }
+
+    let Some(interval) = env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .map(|usec: u64| Duration::from_micros(usec / 2))
+        .filter(|interval| !interval.is_zero())
+    else {
+        return;
+    };
+
+    log::info!("Pinging the systemd watchdog every {interval:?}");
+
+    actix_web::rt::spawn(async move {
+        loop {
+            notify("WATCHDOG=1");
+            actix_web::rt::time::sleep(interval).await;
+        }
+    });
+}
+
+/// Sockets passed by systemd (socket activation), empty if the process wasn't activated.
+/// The variables are removed to not pass the sockets to the child processes.
+pub(crate) fn activated_listeners() -> Vec<TcpListener> {
+    if env::var("LISTEN_PID").is_err() || !for_this_process("LISTEN_PID") {
+        return vec![];
+    }
+
+    let count: i32 = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0);
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    listeners_from_fds(count)
+}
+
+#[cfg(unix)]
+fn listeners_from_fds(count: i32) -> Vec<TcpListener> {
+    use std::os::unix::io::FromRawFd;
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd passes `LISTEN_FDS` open sockets starting at fd 3 to this process
+            // (checked with `LISTEN_PID`), they are not owned by anything else.
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            match listener.local_addr() {
+                Ok(address) => log::info!("Listening on {address} (socket activation)"),
+                Err(err) => panic!("Socket {fd} passed by systemd is not a TCP socket ({err})"),
+            }
+            listener
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+fn listeners_from_fds(_count: i32) -> Vec<TcpListener> {
+    vec![]
+}
+
+/// `true` if the variable is missing or contains the PID of this process
+fn for_this_process(pid_variable: &str) -> bool {
+    match env::var(pid_variable) {
+        Ok(pid) => pid.parse() == Ok(std::process::id()),
+        Err(_) => true,
+    }
+}
@@ -0,0 +1,57 @@
+/// Timeouts of the HTTP server, in seconds (the defaults are the actix-web ones).
+///
+/// - `CLIENT_REQUEST_TIMEOUT_SECONDS` (5): time to receive the request headers,
+/// - `CLIENT_DISCONNECT_TIMEOUT_SECONDS` (1): time for the client to close the connection,
+/// - `KEEP_ALIVE_SECONDS` (5): idle time before closing a keep-alive connection,
+/// - `SHUTDOWN_TIMEOUT_SECONDS` (30): time to finish the running requests on shutdown.
+///
+/// `0` disables the first three timeouts. Clients uploading large upserts over slow
+/// links need longer timeouts than the defaults.
+use std::{env, time::Duration};
+
+use actix_web::http::KeepAlive;
+
+const DEFAULT_CLIENT_REQUEST_TIMEOUT_SECONDS: u64 = 5;
+const DEFAULT_CLIENT_DISCONNECT_TIMEOUT_SECONDS: u64 = 1;
+const DEFAULT_KEEP_ALIVE_SECONDS: u64 = 5;
+const DEFAULT_SHUTDOWN_TIMEOUT_SECONDS: u64 = 30;
+
+pub(crate) struct ServerTimeouts {
+    pub(crate) client_request: Duration,
+    pub(crate) client_disconnect: Duration,
+    pub(crate) keep_alive: KeepAlive,
+    /// In seconds
+    pub(crate) shutdown: u64,
+}
+
+impl ServerTimeouts {
+    pub(crate) fn from_env() -> Self {
+        let keep_alive = match seconds_from_env("KEEP_ALIVE_SECONDS", DEFAULT_KEEP_ALIVE_SECONDS) {
+            0 => KeepAlive::Disabled,
+            seconds => KeepAlive::Timeout(Duration::from_secs(seconds)),
+        };
+
+        ServerTimeouts {
+            client_request: Duration::from_secs(seconds_from_env(
+                "CLIENT_REQUEST_TIMEOUT_SECONDS",
+                DEFAULT_CLIENT_REQUEST_TIMEOUT_SECONDS,
+            )),
+            client_disconnect: Duration::from_secs(seconds_from_env(
+                "CLIENT_DISCONNECT_TIMEOUT_SECONDS",
+                DEFAULT_CLIENT_DISCONNECT_TIMEOUT_SECONDS,
+            )),
+            keep_alive,
+            shutdown: seconds_from_env(
+                "SHUTDOWN_TIMEOUT_SECONDS",
+                DEFAULT_SHUTDOWN_TIMEOUT_SECONDS,
+            ),
+        }
+    }
+}
+
+fn seconds_from_env(name: &str, default: u64) -> u64 {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
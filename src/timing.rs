@@ -0,0 +1,78 @@
+/// `Server-Timing` header on the Findex callbacks (fetches, upserts and inserts)
+/// to let client developers see where the latency goes (network or server, and
+/// which part of the server) without access to the server logs.
+///
+/// The durations (in milliseconds) are split by phase:
+/// - `signature`: check of the body signature
+/// - `deserialization`: parsing of the UIDs and values
+/// - `backend`: calls to the indexes database
+/// - `serialization`: encoding of the response
+/// - `total`: sum of all the phases
+///
+/// The header is enabled with `SERVER_TIMING_ENABLED=true`. We don't send it by default
+/// because it exposes the timings of the backend to every client. HTTP trailers are not
+/// supported by actix-web so the durations are only sent in the header.
+use std::{
+    env,
+    time::{Duration, Instant},
+};
+
+use actix_web::{web::Data, HttpResponseBuilder};
+
+/// Present in the app data only if the header is enabled.
+pub(crate) struct ServerTiming;
+
+impl ServerTiming {
+    pub(crate) fn from_env() -> Option<Data<ServerTiming>> {
+        match env::var("SERVER_TIMING_ENABLED").as_deref() {
+            Ok("true") | Ok("1") => Some(Data::new(ServerTiming)),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) struct Timer {
+    start: Instant,
+    last_mark: Instant,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Timer {
+    pub(crate) fn start() -> Self {
+        let now = Instant::now();
+
+        Timer {
+            start: now,
+            last_mark: now,
+            phases: Vec::with_capacity(4),
+        }
+    }
+
+    /// End the current phase.
+    pub(crate) fn mark(&mut self, phase: &'static str) {
+        let now = Instant::now();
+        self.phases.push((phase, now - self.last_mark));
+        self.last_mark = now;
+    }
+
+    /// Add the `Server-Timing` header if enabled.
+    pub(crate) fn insert_header(
+        &self,
+        server_timing: &Option<Data<ServerTiming>>,
+        response: &mut HttpResponseBuilder,
+    ) {
+        if server_timing.is_none() {
+            return;
+        }
+
+        let value = self
+            .phases
+            .iter()
+            .chain([("total", self.last_mark - self.start)].iter())
+            .map(|(phase, duration)| format!("{phase};dur={:.3}", duration.as_secs_f64() * 1_000.))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        response.insert_header(("Server-Timing", value));
+    }
+}
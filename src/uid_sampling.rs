@@ -0,0 +1,171 @@
+/// Sampling of the fetched UIDs, to measure the access pattern leakage of the indexes
+/// without capturing every request (see the `log_requests` feature for the full capture).
+///
+/// Only compiled with the `uid_sampling` feature. Every UID sent to `fetch_entries` and
+/// `fetch_chains` goes through a reservoir sampling (algorithm R) of `UID_SAMPLING_RESERVOIR_SIZE`
+/// UIDs per index (10 000 by default), so the memory stays bounded whatever the number of
+/// fetches and each fetched UID has the same probability to be in the reservoir.
+///
+/// `GET /indexes/{id}/uid_samples` (with the admin API key) exports the reservoir grouped by
+/// UID: the access frequency of a UID is estimated by `count / sampled * fetched`.
+/// `DELETE /indexes/{id}/uid_samples` starts a new sampling. The reservoirs are kept in
+/// memory, per instance, and lost on restart.
+use std::{collections::HashMap, env, sync::Mutex};
+
+use actix_web::{
+    delete, get,
+    web::{Data, Json},
+};
+use base64::{engine::general_purpose, Engine};
+use cosmian_findex::{parameters::UID_LENGTH, Uid};
+use rand::Rng;
+use serde::Serialize;
+
+use crate::{
+    admin::Admin,
+    core::{Index, Table},
+    errors::{Error, Response},
+};
+
+const DEFAULT_RESERVOIR_SIZE: usize = 10_000;
+
+#[derive(Default)]
+struct Reservoir {
+    /// UIDs fetched since the start of the sampling
+    fetched: u64,
+    samples: Vec<(Table, Uid<UID_LENGTH>)>,
+}
+
+pub(crate) struct UidSampler {
+    reservoir_size: usize,
+    reservoirs: Mutex<HashMap<String, Reservoir>>,
+}
+
+#[derive(Serialize)]
+struct UidSample {
+    table: Table,
+    /// Base64 encoded
+    uid: String,
+    /// Occurrences of the UID in the reservoir
+    count: u64,
+}
+
+#[derive(Serialize)]
+struct UidSamples {
+    index_id: String,
+    fetched: u64,
+    sampled: usize,
+    reservoir_size: usize,
+    samples: Vec<UidSample>,
+}
+
+impl UidSampler {
+    pub(crate) fn from_env() -> Self {
+        let reservoir_size = match env::var("UID_SAMPLING_RESERVOIR_SIZE") {
+            Ok(value) => match value.parse() {
+                Ok(size) if size > 0 => size,
+                _ => panic!(
+                    "`UID_SAMPLING_RESERVOIR_SIZE` must be a positive number (found `{value}`)"
+                ),
+            },
+            Err(_) => DEFAULT_RESERVOIR_SIZE,
+        };
+
+        log::info!("Sampling the fetched UIDs in reservoirs of {reservoir_size} UIDs per index");
+
+        UidSampler {
+            reservoir_size,
+            reservoirs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sample the UIDs of a fetch. The sampling is best effort: a poisoned lock skips it
+    /// instead of failing the fetch.
+    pub(crate) fn record<'a>(
+        &self,
+        index_id: &str,
+        table: Table,
+        uids: impl IntoIterator<Item = &'a Uid<UID_LENGTH>>,
+    ) {
+        let Ok(mut reservoirs) = self.reservoirs.lock() else {
+            return;
+        };
+        let reservoir = reservoirs.entry(index_id.to_string()).or_default();
+        let mut rng = rand::thread_rng();
+
+        for uid in uids {
+            reservoir.fetched += 1;
+
+            if reservoir.samples.len() < self.reservoir_size {
+                reservoir.samples.push((table, uid.clone()));
+            } else {
+                let position = rng.gen_range(0..reservoir.fetched);
+                if let Ok(position) = usize::try_from(position) {
+                    if position < self.reservoir_size {
+                        reservoir.samples[position] = (table, uid.clone());
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[get("/indexes/{id}/uid_samples")]
+pub(crate) async fn get_uid_samples(
+    _admin: Admin,
+    index: Index,
+    uid_sampler: Data<UidSampler>,
+) -> Response<UidSamples> {
+    let reservoirs = uid_sampler
+        .reservoirs
+        .lock()
+        .map_err(|_| Error::Internal("UID sampler lock is poisoned".to_string()))?;
+
+    let (fetched, sampled, mut counts) = match reservoirs.get(&index.id) {
+        Some(reservoir) => {
+            let mut counts: HashMap<(Table, &Uid<UID_LENGTH>), u64> = HashMap::new();
+            for (table, uid) in &reservoir.samples {
+                *counts.entry((*table, uid)).or_default() += 1;
+            }
+            (
+                reservoir.fetched,
+                reservoir.samples.len(),
+                counts
+                    .into_iter()
+                    .map(|((table, uid), count)| UidSample {
+                        table,
+                        uid: general_purpose::STANDARD.encode(uid),
+                        count,
+                    })
+                    .collect(),
+            )
+        }
+        None => (0, 0, Vec::new()),
+    };
+    counts.sort_by(|a, b| b.count.cmp(&a.count));
+
+    Ok(Json(UidSamples {
+        index_id: index.id,
+        fetched,
+        sampled,
+        reservoir_size: uid_sampler.reservoir_size,
+        samples: counts,
+    }))
+}
+
+#[delete("/indexes/{id}/uid_samples")]
+pub(crate) async fn delete_uid_samples(
+    _admin: Admin,
+    index: Index,
+    uid_sampler: Data<UidSampler>,
+) -> Response<()> {
+    uid_sampler
+        .reservoirs
+        .lock()
+        .map_err(|_| Error::Internal("UID sampler lock is poisoned".to_string()))?
+        .remove(&index.id);
+
+    log::info!("UID sampling of index {} reset", index.id);
+
+    Ok(Json(()))
+}
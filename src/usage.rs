@@ -0,0 +1,382 @@
+/// Usage report of each index, for the tenants to follow their consumption.
+///
+/// The requests to the Findex callbacks (`fetch_entries`, `fetch_chains`, `upsert_entries`
+/// and `insert_chains`) are counted per index, per endpoint and per day (UTC): number of
+/// requests, number of errors (4xx and 5xx responses), bytes received (request bodies) and
+/// bytes sent (response bodies). The counters are kept in memory and added to the metadata
+/// database every `USAGE_FLUSH_SECONDS` seconds (60 by default, `0` disables the usage
+/// tracking), so a crash loses at most this interval.
+///
+/// `GET /indexes/{id}/usage?days=30` returns the daily counters of the last `days` days
+/// (1 to 366, today included) with the totals and error rates per endpoint. Counters older
+/// than `USAGE_RETENTION_DAYS` days are deleted. The same counts are used by the monthly
+/// quotas (see `quotas.rs`).
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    future::Future,
+    mem,
+    sync::Mutex,
+    time::Duration,
+};
+
+use actix_web::{
+    body::{BodySize, EitherBody, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse},
+    get,
+    web::{Data, Json, Query},
+    ResponseError,
+};
+use chrono::{NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    core::{Index, MetadataCache, MetadataDatabase},
+    errors::{Error, Response},
+    quotas::{Quotas, QuotasStatus},
+};
+
+const DEFAULT_USAGE_FLUSH_SECONDS: u64 = 60;
+const DEFAULT_USAGE_WINDOW_DAYS: i64 = 30;
+const MAX_USAGE_WINDOW_DAYS: i64 = 366;
+pub(crate) const USAGE_RETENTION_DAYS: i64 = 400;
+
+const ENDPOINTS: [&str; 4] = [
+    "fetch_entries",
+    "fetch_chains",
+    "upsert_entries",
+    "insert_chains",
+];
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub struct UsageCounters {
+    pub requests: u64,
+    pub errors: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+impl UsageCounters {
+    fn add(&mut self, other: &UsageCounters) {
+        self.requests += other.requests;
+        self.errors += other.errors;
+        self.bytes_received += other.bytes_received;
+        self.bytes_sent += other.bytes_sent;
+    }
+}
+
+/// Counters of one endpoint of an index for one day
+#[derive(Serialize, Debug)]
+pub struct DailyUsage {
+    pub day: NaiveDate,
+    pub endpoint: String,
+    #[serde(flatten)]
+    pub counters: UsageCounters,
+}
+
+/// Counters not saved yet, by index ID, day and endpoint
+type PendingUsage = HashMap<(String, NaiveDate, &'static str), UsageCounters>;
+
+/// Present in the app data only if the usage tracking is enabled.
+pub(crate) struct Usage {
+    flush_interval: Duration,
+    pending: Mutex<PendingUsage>,
+}
+
+impl Usage {
+    pub(crate) fn from_env() -> Option<Data<Usage>> {
+        let flush_seconds = env::var("USAGE_FLUSH_SECONDS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_USAGE_FLUSH_SECONDS);
+
+        (flush_seconds > 0).then(|| {
+            Data::new(Usage {
+                flush_interval: Duration::from_secs(flush_seconds),
+                pending: Mutex::new(HashMap::new()),
+            })
+        })
+    }
+
+    fn record(&self, index_id: &str, endpoint: &'static str, counters: &UsageCounters) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending
+                .entry((index_id.to_string(), Utc::now().date_naive(), endpoint))
+                .or_default()
+                .add(counters);
+        }
+    }
+
+    pub(crate) fn start(usage: Data<Self>, metadata_db: Data<dyn MetadataDatabase>) {
+        actix_web::rt::spawn(async move {
+            let mut last_cleanup = None;
+
+            loop {
+                actix_web::rt::time::sleep(usage.flush_interval).await;
+
+                usage.flush(&metadata_db).await;
+
+                let today = Utc::now().date_naive();
+                if last_cleanup != Some(today) {
+                    let before = today - chrono::Duration::days(USAGE_RETENTION_DAYS);
+                    match metadata_db.delete_usage_before(before).await {
+                        Ok(()) => last_cleanup = Some(today),
+                        Err(err) => log::error!("Cannot delete the old usage counters ({err:?})"),
+                    }
+                }
+            }
+        });
+    }
+
+    async fn flush(&self, metadata_db: &Data<dyn MetadataDatabase>) {
+        let pending = match self.pending.lock() {
+            Ok(mut pending) => mem::take(&mut *pending),
+            Err(_) => return,
+        };
+
+        for ((index_id, day, endpoint), counters) in pending {
+            if let Err(err) = metadata_db
+                .add_usage(&index_id, day, endpoint, &counters)
+                .await
+            {
+                log::error!("Cannot save the usage of index {index_id} ({err:?})");
+
+                // Retried at the next flush
+                if let Ok(mut pending) = self.pending.lock() {
+                    pending
+                        .entry((index_id, day, endpoint))
+                        .or_default()
+                        .add(&counters);
+                }
+            }
+        }
+    }
+
+    /// Counters of the index not saved yet
+    fn pending(&self, index_id: &str) -> Vec<DailyUsage> {
+        let Ok(pending) = self.pending.lock() else {
+            return vec![];
+        };
+
+        pending
+            .iter()
+            .filter(|((id, _, _), _)| id == index_id)
+            .map(|((_, day, endpoint), counters)| DailyUsage {
+                day: *day,
+                endpoint: endpoint.to_string(),
+                counters: *counters,
+            })
+            .collect()
+    }
+}
+
+/// Index ID and endpoint of a Findex callback path (with or without the `/api` prefix)
+pub(crate) fn callback(path: &str) -> Option<(&str, &'static str)> {
+    let path = path.strip_prefix("/api").unwrap_or(path);
+    let mut parts = path.strip_prefix("/indexes/")?.split('/');
+
+    let (Some(index_id), Some(endpoint), None) = (parts.next(), parts.next(), parts.next()) else {
+        return None;
+    };
+
+    ENDPOINTS
+        .into_iter()
+        .find(|known| *known == endpoint)
+        .map(|endpoint| (index_id, endpoint))
+}
+
+/// Findex callback of a request, from the path decoded like the router does: the raw path
+/// of `/indexes/{id}/fetch%5Fentries` reaches `fetch_entries` but would not be counted.
+pub(crate) fn request_callback(req: &ServiceRequest) -> Option<(&str, &'static str)> {
+    callback(req.match_info().as_str())
+}
+
+/// Middleware counting the requests to the Findex callbacks and refusing them once a
+/// monthly quota is exhausted (see `quotas.rs` and `App::wrap_fn`).
+pub(crate) fn track<S, B>(
+    req: ServiceRequest,
+    service: &S,
+) -> impl Future<Output = Result<ServiceResponse<EitherBody<B>>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let tracked = req.app_data::<Data<Usage>>().cloned().and_then(|usage| {
+        let (index_id, endpoint) = request_callback(&req)?;
+        let bytes_received = req
+            .headers()
+            .get("Content-Length")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0);
+
+        Some((usage, index_id.to_string(), endpoint, bytes_received))
+    });
+
+    let quotas = req.app_data::<Data<Quotas>>().cloned();
+    let quota_check = match (&quotas, &tracked) {
+        (Some(quotas), Some((_, index_id, _, _))) => quotas.check(index_id),
+        _ => Ok(()),
+    };
+
+    let response = match quota_check {
+        Ok(()) => Ok(service.call(req)),
+        Err(err) => Err(req.into_response(err.error_response())),
+    };
+
+    async move {
+        let response = match response {
+            Ok(response) => response.await?,
+            Err(refused) => return Ok(refused.map_into_right_body()),
+        };
+
+        if let Some((usage, index_id, endpoint, bytes_received)) = tracked {
+            // Do not count the requests to unknown indexes (found indexes are cached)
+            let known = response
+                .request()
+                .app_data::<Data<MetadataCache>>()
+                .map_or(false, |metadata_cache| metadata_cache.contains(&index_id));
+
+            if known {
+                let status = response.status();
+                let counters = UsageCounters {
+                    requests: 1,
+                    errors: u64::from(status.is_client_error() || status.is_server_error()),
+                    bytes_received,
+                    bytes_sent: match response.response().body().size() {
+                        BodySize::Sized(size) => size,
+                        BodySize::None | BodySize::Stream => 0,
+                    },
+                };
+
+                usage.record(&index_id, endpoint, &counters);
+                if let Some(quotas) = quotas {
+                    quotas.record_request(&index_id, counters.bytes_received + counters.bytes_sent);
+                }
+            }
+        }
+
+        Ok(response.map_into_left_body())
+    }
+}
+
+#[derive(Deserialize)]
+struct UsageQuery {
+    days: Option<i64>,
+}
+
+#[derive(Serialize, Default)]
+struct UsageSummary {
+    #[serde(flatten)]
+    counters: UsageCounters,
+    /// Errors divided by requests, `0` without requests
+    error_rate: f64,
+}
+
+impl UsageSummary {
+    fn add(&mut self, counters: &UsageCounters) {
+        self.counters.add(counters);
+        self.error_rate = if self.counters.requests == 0 {
+            0.
+        } else {
+            self.counters.errors as f64 / self.counters.requests as f64
+        };
+    }
+}
+
+#[derive(Serialize)]
+struct UsageReport {
+    from: NaiveDate,
+    to: NaiveDate,
+    /// Sorted by day then endpoint
+    days: Vec<DailyUsage>,
+    endpoints: BTreeMap<String, UsageSummary>,
+    total: UsageSummary,
+    /// Only if quotas are configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quotas: Option<QuotasStatus>,
+}
+
+#[get("/indexes/{id}/usage")]
+pub(crate) async fn get_usage(
+    index: Index,
+    query: Query<UsageQuery>,
+    metadata_db: Data<dyn MetadataDatabase>,
+    usage: Option<Data<Usage>>,
+    quotas: Option<Data<Quotas>>,
+) -> Response<UsageReport> {
+    let Some(usage) = usage else {
+        return Err(Error::BadRequest(
+            "The usage tracking is disabled (`USAGE_FLUSH_SECONDS=0`)".to_string(),
+        ));
+    };
+
+    let days = query.days.unwrap_or(DEFAULT_USAGE_WINDOW_DAYS);
+    if !(1..=MAX_USAGE_WINDOW_DAYS).contains(&days) {
+        return Err(Error::BadRequest(format!(
+            "`days` must be between 1 and {MAX_USAGE_WINDOW_DAYS}"
+        )));
+    }
+
+    let to = Utc::now().date_naive();
+    let from = to - chrono::Duration::days(days - 1);
+
+    let mut daily: BTreeMap<(NaiveDate, String), UsageCounters> = BTreeMap::new();
+    let saved = metadata_db.get_usage(&index.id, from).await?;
+    for usage in saved.into_iter().chain(usage.pending(&index.id)) {
+        if usage.day >= from {
+            daily
+                .entry((usage.day, usage.endpoint))
+                .or_default()
+                .add(&usage.counters);
+        }
+    }
+
+    let mut endpoints: BTreeMap<String, UsageSummary> = BTreeMap::new();
+    let mut total = UsageSummary::default();
+    for ((_, endpoint), counters) in &daily {
+        endpoints.entry(endpoint.clone()).or_default().add(counters);
+        total.add(counters);
+    }
+
+    Ok(Json(UsageReport {
+        from,
+        to,
+        days: daily
+            .into_iter()
+            .map(|((day, endpoint), counters)| DailyUsage {
+                day,
+                endpoint,
+                counters,
+            })
+            .collect(),
+        endpoints,
+        total,
+        quotas: quotas.map(|quotas| quotas.status(&index.id)),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn encoded_callback_path() {
+        for uri in [
+            "/indexes/abc/fetch%5Fentries",
+            "/api/indexes/abc/fetch_%65ntries",
+        ] {
+            let req = TestRequest::with_uri(uri).to_srv_request();
+            assert_eq!(
+                request_callback(&req),
+                Some(("abc", "fetch_entries")),
+                "{uri}"
+            );
+        }
+
+        let req = TestRequest::with_uri("/indexes/abc/fetch%2Fentries").to_srv_request();
+        assert_eq!(request_callback(&req), None);
+    }
+}
@@ -0,0 +1,835 @@
+/// Passkey (WebAuthn) login for the administration endpoints and the web UI, for the
+/// deployments without an identity provider.
+///
+/// Enabled by `WEBAUTHN_RP_ID` (the domain of the dashboard, for example
+/// `admin.example.com`) with the "webauthn" feature. The browser must reach the server at
+/// `WEBAUTHN_ORIGIN` (`https://<WEBAUTHN_RP_ID>` by default). `ADMIN_API_KEY` is required to
+/// register the first passkey.
+///
+/// - `GET /admin/webauthn/login`: page to sign in with a passkey, or to register a new one
+///   with the admin API key,
+/// - `POST /admin/webauthn/register/start` and `POST /admin/webauthn/register/finish`
+///   (admin): registration of a passkey,
+/// - `POST /admin/webauthn/login/start` and `POST /admin/webauthn/login/finish`: sign in,
+///   the response sets the session cookie,
+/// - `POST /admin/webauthn/logout`,
+/// - `GET /admin/webauthn/credentials` and `DELETE /admin/webauthn/credentials/{id}` (admin).
+///
+/// The session cookie (`HttpOnly`, `Secure`, `SameSite=Strict`) is accepted instead of the
+/// admin API key by all the administration endpoints. It expires after
+/// `WEBAUTHN_SESSION_HOURS` (12 by default), the sessions are lost on restart. The web UI
+/// redirects to the login page without a session.
+///
+/// The passkeys (ES256, EdDSA and RS256 public keys) are stored inside
+/// `WEBAUTHN_CREDENTIALS_PATH` (`$DATA_DIR/webauthn_credentials.json` by default). The
+/// attestation statements are not verified (the registration asks for `"none"`): any
+/// authenticator is accepted, the registration itself is protected by the admin API key.
+use std::{
+    collections::HashMap,
+    env, fs,
+    future::Future,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    cookie::{self, Cookie, SameSite},
+    delete,
+    dev::{Service, ServiceRequest, ServiceResponse},
+    get,
+    http::header::{ContentType, LOCATION},
+    post,
+    web::{Data, Json, Path},
+    HttpRequest, HttpResponse,
+};
+use base64::{engine::general_purpose, Engine};
+use chrono::{NaiveDateTime, Utc};
+use rand::RngCore;
+use ring::{digest, signature};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    admin::Admin,
+    config,
+    errors::{Error, Response, ResponseBytes},
+};
+
+const SESSION_COOKIE: &str = "findex_cloud_session";
+const DEFAULT_SESSION_HOURS: u64 = 12;
+const CHALLENGE_TTL: Duration = Duration::from_secs(5 * 60);
+const LOGIN_PAGE: &str = "/admin/webauthn/login";
+
+/// COSE algorithms
+const ES256: i64 = -7;
+const EDDSA: i64 = -8;
+const RS256: i64 = -257;
+
+/// Flags of the authenticator data
+const USER_PRESENT: u8 = 0x01;
+const ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+pub(crate) struct WebAuthn {
+    rp_id: String,
+    rp_name: String,
+    origin: String,
+    session_ttl: Duration,
+    credentials_path: PathBuf,
+    credentials: Mutex<Vec<Credential>>,
+    /// Pending challenges with their ceremony and expiration
+    challenges: Mutex<HashMap<Vec<u8>, (Ceremony, Instant)>>,
+    /// Session tokens with their expiration
+    sessions: Mutex<HashMap<String, Instant>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Ceremony {
+    Registration,
+    Login,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Credential {
+    /// Base64 URL
+    id: String,
+    name: String,
+    public_key: PublicKey,
+    sign_count: u32,
+    created_at: NaiveDateTime,
+    last_used_at: Option<NaiveDateTime>,
+}
+
+/// The keys are encoded in base64 URL
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "alg")]
+enum PublicKey {
+    /// Uncompressed P-256 point
+    ES256 {
+        point: String,
+    },
+    EdDSA {
+        key: String,
+    },
+    RS256 {
+        n: String,
+        e: String,
+    },
+}
+
+#[derive(Serialize)]
+struct CredentialInfo {
+    id: String,
+    name: String,
+    created_at: NaiveDateTime,
+    last_used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+/// The binary fields are encoded in base64 URL
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegistrationResponse {
+    name: String,
+    client_data_json: String,
+    attestation_object: String,
+}
+
+/// The binary fields are encoded in base64 URL
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LoginResponse {
+    id: String,
+    client_data_json: String,
+    authenticator_data: String,
+    signature: String,
+}
+
+impl WebAuthn {
+    pub(crate) fn from_env(admin_api_key: bool) -> Option<Data<WebAuthn>> {
+        let rp_id = env::var("WEBAUTHN_RP_ID")
+            .ok()
+            .filter(|rp_id| !rp_id.is_empty())?;
+        if !admin_api_key {
+            panic!("`WEBAUTHN_RP_ID` requires an `ADMIN_API_KEY` to register the first passkey");
+        }
+
+        let session_hours = match env::var("WEBAUTHN_SESSION_HOURS") {
+            Ok(value) => match value.parse() {
+                Ok(hours) if hours > 0 => hours,
+                _ => panic!(
+                    "`WEBAUTHN_SESSION_HOURS` must be a positive number of hours (found `{value}`)"
+                ),
+            },
+            Err(_) => DEFAULT_SESSION_HOURS,
+        };
+
+        let credentials_path = config::webauthn_credentials_path();
+        let credentials: Vec<Credential> = match fs::read(&credentials_path) {
+            Ok(json) => serde_json::from_slice(&json).unwrap_or_else(|err| {
+                panic!(
+                    "Cannot parse the passkeys inside {} ({err})",
+                    credentials_path.display()
+                )
+            }),
+            Err(_) => {
+                config::prepare_parent_directory(&credentials_path);
+                vec![]
+            }
+        };
+        log::info!(
+            "Passkey login enabled for `{rp_id}` ({} passkey(s) registered)",
+            credentials.len()
+        );
+
+        Some(Data::new(WebAuthn {
+            origin: env::var("WEBAUTHN_ORIGIN").unwrap_or_else(|_| format!("https://{rp_id}")),
+            rp_name: env::var("WEBAUTHN_RP_NAME").unwrap_or_else(|_| "Findex Cloud".to_string()),
+            rp_id,
+            session_ttl: Duration::from_secs(session_hours * 60 * 60),
+            credentials_path,
+            credentials: Mutex::new(credentials),
+            challenges: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// The request has the cookie of an open session.
+    pub(crate) fn has_session(&self, req: &HttpRequest) -> bool {
+        let Some(cookie) = req.cookie(SESSION_COOKIE) else {
+            return false;
+        };
+
+        self.sessions
+            .lock()
+            .map(|sessions| {
+                sessions
+                    .get(cookie.value())
+                    .map_or(false, |expires_at| *expires_at > Instant::now())
+            })
+            .unwrap_or(false)
+    }
+
+    fn new_challenge(&self, ceremony: Ceremony) -> Result<String, Error> {
+        let mut challenge = vec![0; 32];
+        rand::thread_rng().fill_bytes(&mut challenge);
+
+        let now = Instant::now();
+        let mut challenges = self.challenges.lock().map_err(poisoned)?;
+        challenges.retain(|_, (_, expires_at)| *expires_at > now);
+        challenges.insert(challenge.clone(), (ceremony, now + CHALLENGE_TTL));
+
+        Ok(general_purpose::URL_SAFE_NO_PAD.encode(challenge))
+    }
+
+    /// Check the client data of a response and consume its challenge.
+    fn check_client_data(&self, client_data_json: &[u8], ceremony: Ceremony) -> Result<(), Error> {
+        let client_data: ClientData = serde_json::from_slice(client_data_json)
+            .map_err(|_| Error::BadRequest("Invalid `client_data_json`".to_string()))?;
+
+        let expected_kind = match ceremony {
+            Ceremony::Registration => "webauthn.create",
+            Ceremony::Login => "webauthn.get",
+        };
+        if client_data.kind != expected_kind {
+            return Err(Error::BadRequest(format!(
+                "Wrong client data type `{}` (expected `{expected_kind}`)",
+                client_data.kind
+            )));
+        }
+        if client_data.origin != self.origin {
+            return Err(Error::BadRequest(format!(
+                "Wrong origin `{}` (expected `{}`, see `WEBAUTHN_ORIGIN`)",
+                client_data.origin, self.origin
+            )));
+        }
+
+        let challenge = decode(&client_data.challenge, "challenge")?;
+        let pending = self.challenges.lock().map_err(poisoned)?.remove(&challenge);
+        match pending {
+            Some((pending_ceremony, expires_at))
+                if pending_ceremony == ceremony && expires_at > Instant::now() =>
+            {
+                Ok(())
+            }
+            _ => Err(Error::BadRequest(
+                "Unknown or expired challenge, start again".to_string(),
+            )),
+        }
+    }
+
+    /// Check the RP ID hash and the user presence, returns the flags and the signature counter.
+    fn check_authenticator_data(&self, authenticator_data: &[u8]) -> Result<(u8, u32), Error> {
+        if authenticator_data.len() < 37 {
+            return Err(Error::BadRequest(
+                "The authenticator data is too short".to_string(),
+            ));
+        }
+        let rp_id_hash = digest::digest(&digest::SHA256, self.rp_id.as_bytes());
+        if authenticator_data[..32] != *rp_id_hash.as_ref() {
+            return Err(Error::BadRequest(format!(
+                "The passkey is not for `{}` (see `WEBAUTHN_RP_ID`)",
+                self.rp_id
+            )));
+        }
+
+        let flags = authenticator_data[32];
+        if flags & USER_PRESENT == 0 {
+            return Err(Error::BadRequest("The user was not present".to_string()));
+        }
+        let sign_count = u32::from_be_bytes([
+            authenticator_data[33],
+            authenticator_data[34],
+            authenticator_data[35],
+            authenticator_data[36],
+        ]);
+
+        Ok((flags, sign_count))
+    }
+
+    fn save(&self, credentials: &[Credential]) -> Result<(), Error> {
+        let json = serde_json::to_vec_pretty(credentials)?;
+        let tmp_path = self.credentials_path.with_extension("json.tmp");
+        fs::write(&tmp_path, json)
+            .and_then(|()| fs::rename(&tmp_path, &self.credentials_path))
+            .map_err(|err| {
+                Error::Internal(format!(
+                    "Cannot save the passkeys inside {} ({err})",
+                    self.credentials_path.display()
+                ))
+            })
+    }
+
+    fn open_session(&self) -> Result<Cookie<'static>, Error> {
+        let mut token = [0; 32];
+        rand::thread_rng().fill_bytes(&mut token);
+        let token = general_purpose::URL_SAFE_NO_PAD.encode(token);
+
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().map_err(poisoned)?;
+        sessions.retain(|_, expires_at| *expires_at > now);
+        sessions.insert(token.clone(), now + self.session_ttl);
+
+        Ok(Cookie::build(SESSION_COOKIE, token)
+            .path("/")
+            .http_only(true)
+            .secure(true)
+            .same_site(SameSite::Strict)
+            .max_age(cookie::time::Duration::seconds(
+                self.session_ttl.as_secs() as i64
+            ))
+            .finish())
+    }
+}
+
+fn poisoned<T>(_: T) -> Error {
+    Error::Internal("The passkeys mutex is poisoned".to_string())
+}
+
+fn decode(value: &str, field: &str) -> Result<Vec<u8>, Error> {
+    general_purpose::URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .map_err(|_| Error::BadRequest(format!("Invalid base64 URL inside `{field}`")))
+}
+
+#[get("/admin/webauthn/login")]
+pub(crate) async fn login_page() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(include_str!("webauthn_login.html"))
+}
+
+#[post("/admin/webauthn/register/start")]
+pub(crate) async fn register_start(
+    _admin: Admin,
+    webauthn: Data<WebAuthn>,
+) -> Response<serde_json::Value> {
+    let exclude_credentials: Vec<_> = webauthn
+        .credentials
+        .lock()
+        .map_err(poisoned)?
+        .iter()
+        .map(|credential| json!({"type": "public-key", "id": credential.id}))
+        .collect();
+
+    Ok(Json(json!({
+        "challenge": webauthn.new_challenge(Ceremony::Registration)?,
+        "rp": {"id": webauthn.rp_id, "name": webauthn.rp_name},
+        "user": {
+            "id": general_purpose::URL_SAFE_NO_PAD.encode("admin"),
+            "name": "admin",
+            "displayName": "Findex Cloud administrator",
+        },
+        "pubKeyCredParams": [ES256, EDDSA, RS256]
+            .map(|alg| json!({"type": "public-key", "alg": alg})),
+        "excludeCredentials": exclude_credentials,
+        "timeout": CHALLENGE_TTL.as_millis() as u64,
+        "attestation": "none",
+        "authenticatorSelection": {"residentKey": "preferred", "userVerification": "preferred"},
+    })))
+}
+
+#[post("/admin/webauthn/register/finish")]
+pub(crate) async fn register_finish(
+    _admin: Admin,
+    webauthn: Data<WebAuthn>,
+    response: Json<RegistrationResponse>,
+) -> Response<CredentialInfo> {
+    if response.name.trim().is_empty() {
+        return Err(Error::BadRequest("The passkey needs a `name`".to_string()));
+    }
+
+    let client_data_json = decode(&response.client_data_json, "client_data_json")?;
+    webauthn.check_client_data(&client_data_json, Ceremony::Registration)?;
+
+    let attestation_object =
+        cbor::decode_all(&decode(&response.attestation_object, "attestation_object")?)?;
+    let authenticator_data = attestation_object
+        .get("authData")
+        .and_then(cbor::Value::as_bytes)
+        .ok_or_else(|| {
+            Error::BadRequest("No `authData` inside the attestation object".to_string())
+        })?;
+
+    let (flags, sign_count) = webauthn.check_authenticator_data(authenticator_data)?;
+    if flags & ATTESTED_CREDENTIAL_DATA == 0 {
+        return Err(Error::BadRequest(
+            "No credential inside the authenticator data".to_string(),
+        ));
+    }
+
+    // AAGUID (16 bytes), length of the credential ID (2 bytes), credential ID, COSE key
+    let attested = &authenticator_data[37..];
+    let (id_length, rest) = attested
+        .get(16..18)
+        .map(|length| {
+            (
+                u16::from_be_bytes([length[0], length[1]]) as usize,
+                &attested[18..],
+            )
+        })
+        .ok_or_else(|| Error::BadRequest("Truncated attested credential data".to_string()))?;
+    let id = rest
+        .get(..id_length)
+        .ok_or_else(|| Error::BadRequest("Truncated credential ID".to_string()))?;
+    let public_key = public_key(&cbor::decode(&mut &rest[id_length..])?)?;
+
+    let credential = Credential {
+        id: general_purpose::URL_SAFE_NO_PAD.encode(id),
+        name: response.name.trim().to_string(),
+        public_key,
+        sign_count,
+        created_at: Utc::now().naive_utc(),
+        last_used_at: None,
+    };
+
+    let mut credentials = webauthn.credentials.lock().map_err(poisoned)?;
+    if credentials
+        .iter()
+        .any(|existing| existing.id == credential.id)
+    {
+        return Err(Error::BadRequest(
+            "This passkey is already registered".to_string(),
+        ));
+    }
+    credentials.push(credential.clone());
+    if let Err(err) = webauthn.save(&credentials) {
+        credentials.pop();
+        return Err(err);
+    }
+
+    log::warn!("Passkey `{}` registered", credential.name);
+
+    Ok(Json(CredentialInfo {
+        id: credential.id,
+        name: credential.name,
+        created_at: credential.created_at,
+        last_used_at: None,
+    }))
+}
+
+/// Public key of a COSE key
+fn public_key(cose_key: &cbor::Value) -> Result<PublicKey, Error> {
+    let field = |label: i64| {
+        cose_key
+            .get_int(label)
+            .ok_or_else(|| Error::BadRequest(format!("No field {label} inside the COSE key")))
+    };
+    let bytes = |label: i64| {
+        field(label)?
+            .as_bytes()
+            .map(|bytes| general_purpose::URL_SAFE_NO_PAD.encode(bytes))
+            .ok_or_else(|| Error::BadRequest(format!("Field {label} of the COSE key is not bytes")))
+    };
+
+    match field(3)?.as_int() {
+        Some(ES256) => {
+            let x = field(-2)?.as_bytes().filter(|x| x.len() == 32);
+            let y = field(-3)?.as_bytes().filter(|y| y.len() == 32);
+            let (Some(x), Some(y)) = (x, y) else {
+                return Err(Error::BadRequest("Invalid P-256 COSE key".to_string()));
+            };
+            Ok(PublicKey::ES256 {
+                point: general_purpose::URL_SAFE_NO_PAD.encode([&[0x04], x, y].concat()),
+            })
+        }
+        Some(EDDSA) => Ok(PublicKey::EdDSA { key: bytes(-2)? }),
+        Some(RS256) => Ok(PublicKey::RS256 {
+            n: bytes(-1)?,
+            e: bytes(-2)?,
+        }),
+        alg => Err(Error::BadRequest(format!(
+            "Unsupported COSE algorithm {alg:?} (ES256, EdDSA and RS256 are supported)"
+        ))),
+    }
+}
+
+impl PublicKey {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), Error> {
+        let invalid = |_| Error::Internal("Invalid stored passkey".to_string());
+        let result = match self {
+            PublicKey::ES256 { point } => signature::UnparsedPublicKey::new(
+                &signature::ECDSA_P256_SHA256_ASN1,
+                decode(point, "point").map_err(invalid)?,
+            )
+            .verify(message, signature),
+            PublicKey::EdDSA { key } => signature::UnparsedPublicKey::new(
+                &signature::ED25519,
+                decode(key, "key").map_err(invalid)?,
+            )
+            .verify(message, signature),
+            PublicKey::RS256 { n, e } => signature::RsaPublicKeyComponents {
+                n: decode(n, "n").map_err(invalid)?,
+                e: decode(e, "e").map_err(invalid)?,
+            }
+            .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, signature),
+        };
+
+        result.map_err(|_| Error::Unauthorized)
+    }
+}
+
+#[post("/admin/webauthn/login/start")]
+pub(crate) async fn login_start(webauthn: Data<WebAuthn>) -> Response<serde_json::Value> {
+    let allow_credentials: Vec<_> = webauthn
+        .credentials
+        .lock()
+        .map_err(poisoned)?
+        .iter()
+        .map(|credential| json!({"type": "public-key", "id": credential.id}))
+        .collect();
+    if allow_credentials.is_empty() {
+        return Err(Error::BadRequest(
+            "No passkey registered, register one with the admin API key".to_string(),
+        ));
+    }
+
+    Ok(Json(json!({
+        "challenge": webauthn.new_challenge(Ceremony::Login)?,
+        "rpId": webauthn.rp_id,
+        "allowCredentials": allow_credentials,
+        "timeout": CHALLENGE_TTL.as_millis() as u64,
+        "userVerification": "preferred",
+    })))
+}
+
+#[post("/admin/webauthn/login/finish")]
+pub(crate) async fn login_finish(
+    webauthn: Data<WebAuthn>,
+    response: Json<LoginResponse>,
+) -> ResponseBytes {
+    let client_data_json = decode(&response.client_data_json, "client_data_json")?;
+    webauthn.check_client_data(&client_data_json, Ceremony::Login)?;
+
+    let authenticator_data = decode(&response.authenticator_data, "authenticator_data")?;
+    let (_, sign_count) = webauthn.check_authenticator_data(&authenticator_data)?;
+
+    let mut credentials = webauthn.credentials.lock().map_err(poisoned)?;
+    let credential = credentials
+        .iter_mut()
+        .find(|credential| credential.id == response.id.trim_end_matches('='))
+        .ok_or(Error::Unauthorized)?;
+
+    let message = [
+        &authenticator_data[..],
+        digest::digest(&digest::SHA256, &client_data_json).as_ref(),
+    ]
+    .concat();
+    credential
+        .public_key
+        .verify(&message, &decode(&response.signature, "signature")?)?;
+
+    // A counter going backwards means the passkey was cloned.
+    if (sign_count != 0 || credential.sign_count != 0) && sign_count <= credential.sign_count {
+        log::error!(
+            "Refused login with passkey `{}`: signature counter {sign_count} not above {} (cloned authenticator?)",
+            credential.name,
+            credential.sign_count
+        );
+        return Err(Error::Unauthorized);
+    }
+    credential.sign_count = sign_count;
+    credential.last_used_at = Some(Utc::now().naive_utc());
+    let name = credential.name.clone();
+    webauthn.save(&credentials)?;
+    drop(credentials);
+
+    log::info!("Login with passkey `{name}`");
+
+    Ok(HttpResponse::Ok().cookie(webauthn.open_session()?).json(()))
+}
+
+#[post("/admin/webauthn/logout")]
+pub(crate) async fn logout(req: HttpRequest, webauthn: Data<WebAuthn>) -> ResponseBytes {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        webauthn
+            .sessions
+            .lock()
+            .map_err(poisoned)?
+            .remove(cookie.value());
+    }
+
+    let mut removal = Cookie::new(SESSION_COOKIE, "");
+    removal.set_path("/");
+    removal.make_removal();
+
+    Ok(HttpResponse::Ok().cookie(removal).json(()))
+}
+
+#[get("/admin/webauthn/credentials")]
+pub(crate) async fn get_credentials(
+    _admin: Admin,
+    webauthn: Data<WebAuthn>,
+) -> Response<Vec<CredentialInfo>> {
+    Ok(Json(
+        webauthn
+            .credentials
+            .lock()
+            .map_err(poisoned)?
+            .iter()
+            .map(|credential| CredentialInfo {
+                id: credential.id.clone(),
+                name: credential.name.clone(),
+                created_at: credential.created_at,
+                last_used_at: credential.last_used_at,
+            })
+            .collect(),
+    ))
+}
+
+#[delete("/admin/webauthn/credentials/{id}")]
+pub(crate) async fn delete_credential(
+    _admin: Admin,
+    webauthn: Data<WebAuthn>,
+    id: Path<String>,
+) -> Response<()> {
+    let mut credentials = webauthn.credentials.lock().map_err(poisoned)?;
+    let Some(position) = credentials
+        .iter()
+        .position(|credential| credential.id == id.as_str())
+    else {
+        return Err(Error::BadRequest(format!("Unknown passkey {id}")));
+    };
+
+    let credential = credentials.remove(position);
+    if let Err(err) = webauthn.save(&credentials) {
+        credentials.insert(position, credential);
+        return Err(err);
+    }
+
+    log::warn!("Passkey `{}` deleted", credential.name);
+
+    Ok(Json(()))
+}
+
+/// Middleware of the web UI redirecting to the login page without a session
+/// (see `App::wrap_fn`).
+pub(crate) fn protect_ui<S, B>(
+    req: ServiceRequest,
+    service: &S,
+) -> impl Future<Output = Result<ServiceResponse<EitherBody<B>>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let allowed = req
+        .app_data::<Data<WebAuthn>>()
+        .map_or(true, |webauthn| webauthn.has_session(req.request()));
+
+    let response = if allowed {
+        Ok(service.call(req))
+    } else {
+        Err(req.into_response(
+            HttpResponse::SeeOther()
+                .insert_header((LOCATION, LOGIN_PAGE))
+                .finish(),
+        ))
+    };
+
+    async move {
+        match response {
+            Ok(response) => Ok(response.await?.map_into_left_body()),
+            Err(refused) => Ok(refused.map_into_right_body()),
+        }
+    }
+}
+
+/// Minimal CBOR decoder for the attestation objects and the COSE keys (no indefinite
+/// lengths).
+mod cbor {
+    use crate::errors::Error;
+
+    pub(super) enum Value {
+        Int(i128),
+        Bytes(Vec<u8>),
+        Text(String),
+        Array(Vec<Value>),
+        Map(Vec<(Value, Value)>),
+        /// Floats and simple values
+        Other,
+    }
+
+    impl Value {
+        pub(super) fn as_int(&self) -> Option<i64> {
+            match self {
+                Value::Int(value) => i64::try_from(*value).ok(),
+                _ => None,
+            }
+        }
+
+        pub(super) fn as_bytes(&self) -> Option<&[u8]> {
+            match self {
+                Value::Bytes(bytes) => Some(bytes),
+                _ => None,
+            }
+        }
+
+        fn entries(&self) -> &[(Value, Value)] {
+            match self {
+                Value::Map(entries) => entries,
+                _ => &[],
+            }
+        }
+
+        /// Value of a text key of a map
+        pub(super) fn get(&self, key: &str) -> Option<&Value> {
+            self.entries().iter().find_map(|(entry_key, value)| {
+                matches!(entry_key, Value::Text(text) if text == key).then_some(value)
+            })
+        }
+
+        /// Value of an integer key of a map
+        pub(super) fn get_int(&self, key: i64) -> Option<&Value> {
+            self.entries()
+                .iter()
+                .find_map(|(entry_key, value)| (entry_key.as_int() == Some(key)).then_some(value))
+        }
+    }
+
+    fn invalid() -> Error {
+        Error::BadRequest("Invalid CBOR".to_string())
+    }
+
+    fn take<'a>(input: &mut &'a [u8], length: usize) -> Result<&'a [u8], Error> {
+        if input.len() < length {
+            return Err(invalid());
+        }
+        let (bytes, rest) = input.split_at(length);
+        *input = rest;
+        Ok(bytes)
+    }
+
+    /// Argument of the initial byte (value or length)
+    fn argument(input: &mut &[u8], additional: u8) -> Result<u64, Error> {
+        let length = match additional {
+            0..=23 => return Ok(u64::from(additional)),
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            _ => return Err(invalid()),
+        };
+
+        Ok(take(input, length)?
+            .iter()
+            .fold(0, |value, byte| (value << 8) | u64::from(*byte)))
+    }
+
+    fn length(input: &[u8], argument: u64) -> Result<usize, Error> {
+        // Each item takes at least one byte.
+        usize::try_from(argument)
+            .ok()
+            .filter(|length| *length <= input.len())
+            .ok_or_else(invalid)
+    }
+
+    /// Decode one item and advance `input` after it.
+    pub(super) fn decode(input: &mut &[u8]) -> Result<Value, Error> {
+        decode_nested(input, 0)
+    }
+
+    /// Decode one item which must be the whole `input`.
+    pub(super) fn decode_all(mut input: &[u8]) -> Result<Value, Error> {
+        let value = decode(&mut input)?;
+        if !input.is_empty() {
+            return Err(invalid());
+        }
+        Ok(value)
+    }
+
+    fn decode_nested(input: &mut &[u8], depth: usize) -> Result<Value, Error> {
+        if depth > 16 {
+            return Err(invalid());
+        }
+
+        let initial = take(input, 1)?[0];
+        let (major, additional) = (initial >> 5, initial & 0x1f);
+        if major == 7 {
+            argument(input, additional)?;
+            return Ok(Value::Other);
+        }
+        let argument = argument(input, additional)?;
+
+        if major == 0 {
+            return Ok(Value::Int(i128::from(argument)));
+        }
+        if major == 1 {
+            return Ok(Value::Int(-1 - i128::from(argument)));
+        }
+        if major == 6 {
+            // Tag: the tagged item
+            return decode_nested(input, depth + 1);
+        }
+
+        let length = length(input, argument)?;
+        Ok(match major {
+            2 => Value::Bytes(take(input, length)?.to_vec()),
+            3 => Value::Text(
+                String::from_utf8(take(input, length)?.to_vec()).map_err(|_| invalid())?,
+            ),
+            4 => Value::Array(
+                (0..length)
+                    .map(|_| decode_nested(input, depth + 1))
+                    .collect::<Result<_, _>>()?,
+            ),
+            _ => Value::Map(
+                (0..length)
+                    .map(|_| {
+                        Ok((
+                            decode_nested(input, depth + 1)?,
+                            decode_nested(input, depth + 1)?,
+                        ))
+                    })
+                    .collect::<Result<_, Error>>()?,
+            ),
+        })
+    }
+}
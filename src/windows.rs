@@ -0,0 +1,259 @@
+/// Windows service (`windows_service` feature, Windows only).
+///
+/// - `findex_cloud windows-service install`: register the service (automatic start) running
+///   `findex_cloud windows-service run` with the current executable.
+/// - `findex_cloud windows-service uninstall`: stop and delete the service.
+/// - `findex_cloud windows-service run`: called by the Service Control Manager, the server
+///   stops gracefully on the stop and shutdown controls.
+///
+/// The service runs inside the directory of the executable (to find `.env` and `data/`) and logs
+/// to the Windows event log (`LOG_OUTPUT=eventlog`, source `findex_cloud`).
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    io, iter,
+    os::windows::ffi::OsStrExt,
+    ptr,
+    sync::Mutex,
+    time::Duration,
+};
+
+use env_logger::filter::Filter;
+use futures::{
+    channel::oneshot,
+    future::{select, Either},
+};
+use log::{Level, Log, Metadata, Record};
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+use windows_sys::Win32::System::EventLog::{
+    RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE,
+    EVENTLOG_WARNING_TYPE,
+};
+
+use crate::syslog::filter_from_env;
+
+const SERVICE_NAME: &str = "findex_cloud";
+const SERVICE_DISPLAY_NAME: &str = "Findex Cloud";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// `true` if the process is started by the Service Control Manager
+pub(crate) fn is_service_run() -> bool {
+    let mut args = env::args().skip(1);
+    args.next().as_deref() == Some("windows-service") && args.next().as_deref() == Some("run")
+}
+
+/// Services are started inside `C:\Windows\System32`, use the directory of the executable instead.
+pub(crate) fn use_executable_directory() {
+    let executable = env::current_exe().expect("Cannot find the path of the executable");
+    if let Some(directory) = executable.parent() {
+        env::set_current_dir(directory)
+            .unwrap_or_else(|e| panic!("Cannot go to {} ({e})", directory.display()));
+    }
+}
+
+pub(crate) fn run(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    match args.next().as_deref() {
+        Some("install") => install().map_err(into_io_error),
+        Some("uninstall") => uninstall().map_err(into_io_error),
+        // Blocks until the service is stopped, the server runs on the thread of `service_main`
+        Some("run") => {
+            service_dispatcher::start(SERVICE_NAME, ffi_service_main).map_err(into_io_error)
+        }
+        _ => panic!("Usage: findex_cloud windows-service install | uninstall | run"),
+    }
+}
+
+fn install() -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+
+    let service_info = ServiceInfo {
+        name: OsString::from(SERVICE_NAME),
+        display_name: OsString::from(SERVICE_DISPLAY_NAME),
+        service_type: SERVICE_TYPE,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: env::current_exe().map_err(windows_service::Error::Winapi)?,
+        launch_arguments: vec![OsString::from("windows-service"), OsString::from("run")],
+        dependencies: vec![],
+        account_name: None, // LocalSystem
+        account_password: None,
+    };
+
+    let service = manager.create_service(&service_info, ServiceAccess::CHANGE_CONFIG)?;
+    service.set_description("Findex Cloud server (encrypted indexes)")?;
+
+    log::info!("Service `{SERVICE_NAME}` installed, start it with `sc start {SERVICE_NAME}`");
+    Ok(())
+}
+
+fn uninstall() -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(
+        SERVICE_NAME,
+        ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+    )?;
+
+    // The service is deleted when the last handle is closed (after it's stopped).
+    service.delete()?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+    }
+
+    log::info!("Service `{SERVICE_NAME}` uninstalled");
+    Ok(())
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(err) = run_service() {
+        log::error!("Windows service failed ({err})");
+    }
+}
+
+fn run_service() -> windows_service::Result<()> {
+    let (stop_sender, stop_receiver) = oneshot::channel();
+    let stop_sender = Mutex::new(Some(stop_sender));
+
+    let status_handle =
+        service_control_handler::register(SERVICE_NAME, move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                if let Some(stop_sender) = stop_sender.lock().ok().and_then(|mut s| s.take()) {
+                    let _ = stop_sender.send(());
+                }
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })?;
+
+    let status = |current_state, controls_accepted, exit_code| ServiceStatus {
+        service_type: SERVICE_TYPE,
+        current_state,
+        controls_accepted,
+        exit_code,
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    };
+
+    status_handle.set_service_status(status(
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        ServiceExitCode::Win32(0),
+    ))?;
+
+    // Leaving `block_on` stops the actix system and its workers.
+    let result = actix_web::rt::System::new().block_on(async {
+        match select(Box::pin(crate::serve()), stop_receiver).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Ok(()),
+        }
+    });
+
+    let exit_code = match result {
+        Ok(()) => ServiceExitCode::Win32(0),
+        Err(err) => {
+            log::error!("Server stopped ({err})");
+            ServiceExitCode::ServiceSpecific(1)
+        }
+    };
+
+    status_handle.set_service_status(status(
+        ServiceState::Stopped,
+        ServiceControlAccept::empty(),
+        exit_code,
+    ))
+}
+
+fn into_io_error(err: windows_service::Error) -> io::Error {
+    match err {
+        windows_service::Error::Winapi(err) => err,
+        err => io::Error::new(io::ErrorKind::Other, err),
+    }
+}
+
+/// Logger writing to the Windows event log (Application journal)
+struct EventLog {
+    filter: Filter,
+    /// Handle from `RegisterEventSourceW`, never closed
+    source: isize,
+}
+
+impl Log for EventLog {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+
+        let event_type = match record.level() {
+            Level::Error => EVENTLOG_ERROR_TYPE,
+            Level::Warn => EVENTLOG_WARNING_TYPE,
+            Level::Info | Level::Debug | Level::Trace => EVENTLOG_INFORMATION_TYPE,
+        };
+        let message = wide(&format!("[{}] {}", record.target(), record.args()));
+        let strings = [message.as_ptr()];
+
+        // SAFETY: `source` is a valid event source handle and `strings` contains one
+        // null-terminated wide string alive during the call.
+        unsafe {
+            ReportEventW(
+                self.source,
+                event_type,
+                0,
+                0,
+                ptr::null_mut(),
+                1,
+                0,
+                strings.as_ptr(),
+                ptr::null(),
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install the event log logger, with the same default filter as the stderr logger.
+pub(crate) fn init_event_log(default_filter: &str) {
+    let filter = filter_from_env(default_filter);
+    let max_level = filter.filter();
+
+    let source_name = wide(SERVICE_NAME);
+    // SAFETY: `source_name` is a null-terminated wide string, a null server is the local computer.
+    let source = unsafe { RegisterEventSourceW(ptr::null(), source_name.as_ptr()) };
+    if source == 0 {
+        panic!(
+            "Cannot register the event source `{SERVICE_NAME}` ({})",
+            io::Error::last_os_error()
+        );
+    }
+
+    log::set_boxed_logger(Box::new(EventLog { filter, source }))
+        .expect("A logger is already installed");
+    log::set_max_level(max_level);
+}
+
+/// Null-terminated UTF-16 string for the Win32 API
+fn wide(value: &str) -> Vec<u16> {
+    OsStr::new(value)
+        .encode_wide()
+        .chain(iter::once(0))
+        .collect()
+}
@@ -0,0 +1,678 @@
+/// Write-behind queue for `insert_chains` (`WRITE_BEHIND=true`, "write_behind" feature).
+///
+/// The inserted chains are appended to a local write-ahead log (`WRITE_BEHIND_WAL_PATH`,
+/// `$DATA_DIR/write_behind.wal` by default), synced to the disk, and the request is
+/// acknowledged without waiting for the indexes database. A background worker writes them
+/// to the indexes database in order, retrying forever (with a backoff) while it fails.
+/// This trades a delay before the chains reach the indexes database (slow or remote, like
+/// DynamoDB) for a much higher ingestion throughput.
+///
+/// The chains not written yet are kept in memory and returned by `fetch_chains`, so clients
+/// read their own inserts. At most `WRITE_BEHIND_MAX_PENDING_CHAINS` chains (1 000 000 by
+/// default) are queued, the inserts are refused with `429 Too Many Requests` beyond.
+///
+/// On startup, the records of the log are queued again (a record cut by a crash is
+/// ignored). Records written before the crash are written twice, which is harmless because
+/// `insert_chains` overwrites the values. When the log holds more than
+/// `WRITE_BEHIND_MAX_PENDING_CHAINS` chains, the oldest records are written to the indexes
+/// database before the server starts. The records are written in the order of the log, so
+/// the log is truncated each time the queue is empty, and its written prefix is removed
+/// once it reaches `WAL_COMPACTION_LENGTH` bytes.
+/// The appends and the compactions sync the disk on the blocking threads of actix-web.
+/// The upserts of the entries stay synchronous (they need the compare-and-swap of the
+/// indexes database).
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use actix_web::web;
+use async_trait::async_trait;
+use cosmian_crypto_core::bytes_ser_de::Serializable;
+use cosmian_findex::{parameters::UID_LENGTH, EncryptedTable, Uid, UpsertData};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::{
+    backup::BackupInfo,
+    changes::Change,
+    config,
+    core::{Index, IndexesDatabase, MetadataDatabase, Table},
+    errors::Error,
+    events::Mutation,
+    metrics::StorageGauge,
+    scrub::ScrubBatch,
+    settings::Consistency,
+};
+
+const DEFAULT_MAX_PENDING_CHAINS: usize = 1_000_000;
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// Length and checksum of a record
+const RECORD_HEADER_LENGTH: usize = 4;
+const RECORD_CHECKSUM_LENGTH: usize = 4;
+/// Written bytes at the start of the log before they are removed
+const WAL_COMPACTION_LENGTH: u64 = 64 * 1024 * 1024;
+
+/// Inserted chains not written to the indexes database yet
+#[derive(Default)]
+struct Pending {
+    chains: HashMap<String, HashMap<Uid<UID_LENGTH>, Vec<u8>>>,
+    count: usize,
+}
+
+/// Offsets count the bytes appended since the server started, the start of the file moves
+/// forward when the log is compacted.
+struct Wal {
+    file: File,
+    /// Offset of the first byte of the file
+    start: u64,
+    /// Offset of the end of the file
+    end: u64,
+}
+
+/// Record waiting for the background worker: index ID, UIDs in insertion order and offset
+/// of the end of the record inside the log
+type Record = (String, Vec<Uid<UID_LENGTH>>, u64);
+
+struct Queue {
+    wal_path: PathBuf,
+    /// Appends are serialized by this lock, also held to compact the log
+    wal: Mutex<Wal>,
+    pending: RwLock<Pending>,
+    max_pending: usize,
+    sender: UnboundedSender<Record>,
+}
+
+pub(crate) struct Database {
+    inner: Arc<dyn IndexesDatabase>,
+    queue: Arc<Queue>,
+}
+
+impl Database {
+    /// Replay the log and start the background worker.
+    pub(crate) async fn open(
+        inner: Arc<dyn IndexesDatabase>,
+        metadata_database: Arc<dyn MetadataDatabase>,
+    ) -> Self {
+        let wal_path = env::var("WRITE_BEHIND_WAL_PATH")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| config::data_dir().join("write_behind.wal"));
+        config::prepare_parent_directory(&wal_path);
+
+        let max_pending = env::var("WRITE_BEHIND_MAX_PENDING_CHAINS")
+            .ok()
+            .map(|max| {
+                max.parse()
+                    .expect("`WRITE_BEHIND_MAX_PENDING_CHAINS` env variable must be a number")
+            })
+            .unwrap_or(DEFAULT_MAX_PENDING_CHAINS);
+
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&wal_path)
+            .unwrap_or_else(|e| {
+                panic!(
+                    "Cannot open the write-behind log {} ({e})",
+                    wal_path.display()
+                )
+            });
+        let length = file
+            .metadata()
+            .unwrap_or_else(|e| {
+                panic!(
+                    "Cannot read the write-behind log {} ({e})",
+                    wal_path.display()
+                )
+            })
+            .len();
+
+        let (sender, receiver) = unbounded_channel();
+        let mut pending = Pending::default();
+        let mut replayed = Vec::new();
+        let mut written_until = 0;
+
+        let mut reader = BufReader::new(&file);
+        let mut position = 0;
+        loop {
+            let record = read_record(&mut reader, length - position).unwrap_or_else(|e| {
+                panic!(
+                    "Cannot read the write-behind log {} ({e})",
+                    wal_path.display()
+                )
+            });
+            let Some(((index_id, data), record_length)) = record else {
+                break;
+            };
+            position += record_length as u64;
+
+            // Write the oldest records now to keep at most `max_pending` chains in memory.
+            if pending.count + data.len() > max_pending && !replayed.is_empty() {
+                log::warn!(
+                    "Writing {} record(s) of the write-behind log to the indexes database before starting",
+                    replayed.len()
+                );
+                for (index_id, data, _) in replayed.drain(..) {
+                    write_chains(&*inner, &*metadata_database, &index_id, || data.clone()).await;
+                }
+                pending = Pending::default();
+                written_until = position - record_length as u64;
+            }
+
+            pending.add(&index_id, &data);
+            replayed.push((index_id, data, position));
+        }
+
+        if position < length {
+            log::warn!(
+                "Incomplete record at the end of the write-behind log ({} bytes ignored)",
+                length - position
+            );
+        }
+        if !replayed.is_empty() {
+            log::warn!(
+                "{} record(s) of the write-behind log not written to the indexes database yet, queued again",
+                replayed.len()
+            );
+        }
+        for (index_id, data, offset) in replayed {
+            let _ = sender.send((index_id, data.keys().copied().collect(), offset));
+        }
+
+        // Remove the written records and the end of a record cut by a crash.
+        let mut wal = Wal {
+            file,
+            start: 0,
+            end: position,
+        };
+        wal.file.set_len(position).unwrap_or_else(|e| {
+            panic!(
+                "Cannot truncate the write-behind log {} ({e})",
+                wal_path.display()
+            )
+        });
+        if written_until > 0 {
+            wal.compact(&wal_path, written_until).unwrap_or_else(|e| {
+                panic!(
+                    "Cannot compact the write-behind log {} ({e})",
+                    wal_path.display()
+                )
+            });
+        }
+
+        let queue = Arc::new(Queue {
+            wal_path,
+            wal: Mutex::new(wal),
+            pending: RwLock::new(pending),
+            max_pending,
+            sender,
+        });
+
+        actix_web::rt::spawn(drain(
+            queue.clone(),
+            inner.clone(),
+            metadata_database,
+            receiver,
+        ));
+
+        Database { inner, queue }
+    }
+}
+
+impl Pending {
+    fn add(&mut self, index_id: &str, data: &EncryptedTable<UID_LENGTH>) {
+        let chains = self.chains.entry(index_id.to_string()).or_default();
+        for (uid, value) in data.iter() {
+            if chains.insert(*uid, value.clone()).is_none() {
+                self.count += 1;
+            }
+        }
+    }
+}
+
+impl Wal {
+    /// Remove the records before `until`: the records after it are copied to a new file
+    /// replacing the log.
+    fn compact(&mut self, path: &Path, until: u64) -> io::Result<()> {
+        if until <= self.start {
+            return Ok(());
+        }
+        if until >= self.end {
+            self.file.set_len(0)?;
+            self.start = self.end;
+            return Ok(());
+        }
+
+        let mut tail = Vec::with_capacity((self.end - until) as usize);
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(until - self.start))?;
+        file.take(self.end - until).read_to_end(&mut tail)?;
+
+        let compacted_path = path.with_extension("wal.compacted");
+        let mut compacted = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&compacted_path)?;
+        compacted.write_all(&tail)?;
+        compacted.sync_data()?;
+        fs::rename(&compacted_path, path)?;
+
+        self.file = OpenOptions::new().read(true).append(true).open(path)?;
+        self.start = until;
+        Ok(())
+    }
+}
+
+impl Queue {
+    /// Append a record and sync the log, blocking: run it with `web::block`.
+    fn append(&self, index_id: &str, data: EncryptedTable<UID_LENGTH>) -> Result<(), Error> {
+        let record = encode_record(index_id, &data)?;
+
+        let mut wal = self
+            .wal
+            .lock()
+            .map_err(|_| Error::Internal("Write-behind log lock is poisoned".to_string()))?;
+        let mut pending = self
+            .pending
+            .write()
+            .map_err(|_| Error::Internal("Write-behind queue lock is poisoned".to_string()))?;
+
+        if pending.count + data.len() > self.max_pending {
+            return Err(Error::TooManyRequests { retry_after: 1 });
+        }
+
+        wal.file
+            .write_all(&record)
+            .and_then(|_| wal.file.sync_data())
+            .map_err(|e| {
+                Error::Internal(format!(
+                    "Cannot append to the write-behind log {} ({e})",
+                    self.wal_path.display()
+                ))
+            })?;
+        wal.end += record.len() as u64;
+
+        pending.add(index_id, &data);
+        self.sender
+            .send((
+                index_id.to_string(),
+                data.keys().copied().collect(),
+                wal.end,
+            ))
+            .map_err(|_| Error::Internal("Write-behind worker is stopped".to_string()))
+    }
+
+    /// Pending values of these UIDs, empty if the index was deleted
+    fn take(&self, index_id: &str, uids: &[Uid<UID_LENGTH>]) -> EncryptedTable<UID_LENGTH> {
+        let mut data = EncryptedTable::with_capacity(uids.len());
+        if let Ok(pending) = self.pending.read() {
+            if let Some(chains) = pending.chains.get(index_id) {
+                for uid in uids {
+                    if let Some(value) = chains.get(uid) {
+                        data.insert(*uid, value.clone());
+                    }
+                }
+            }
+        }
+        data
+    }
+
+    /// Remove the written chains of the record ending at `offset`, truncate the log once
+    /// everything is written and remove its written prefix once it is large enough.
+    /// Blocking: run it with `web::block`.
+    fn written(&self, index_id: &str, uids: &[Uid<UID_LENGTH>], offset: u64) {
+        let Ok(mut wal) = self.wal.lock() else {
+            return;
+        };
+        let Ok(mut pending) = self.pending.write() else {
+            return;
+        };
+
+        if let Some(chains) = pending.chains.get_mut(index_id) {
+            let before = chains.len();
+            for uid in uids {
+                chains.remove(uid);
+            }
+            let removed = before - chains.len();
+            if chains.is_empty() {
+                pending.chains.remove(index_id);
+            }
+            pending.count -= removed;
+        }
+
+        // The records are written in order: everything before `offset` is written.
+        let until = if pending.count == 0 {
+            wal.end
+        } else if offset.saturating_sub(wal.start) >= WAL_COMPACTION_LENGTH {
+            offset
+        } else {
+            return;
+        };
+        if let Err(err) = wal.compact(&self.wal_path, until) {
+            log::error!(
+                "Cannot compact the write-behind log {} ({err})",
+                self.wal_path.display()
+            );
+        }
+    }
+
+    fn forget_index(&self, index_id: &str) {
+        if let Ok(mut pending) = self.pending.write() {
+            if let Some(chains) = pending.chains.remove(index_id) {
+                pending.count -= chains.len();
+            }
+        }
+    }
+
+    /// Pending values of the fetched UIDs, removed from `uids`
+    fn fetch(
+        &self,
+        index_id: &str,
+        uids: &mut HashSet<Uid<UID_LENGTH>>,
+    ) -> EncryptedTable<UID_LENGTH> {
+        let mut data = EncryptedTable::default();
+        if let Ok(pending) = self.pending.read() {
+            if let Some(chains) = pending.chains.get(index_id) {
+                uids.retain(|uid| match chains.get(uid) {
+                    Some(value) => {
+                        data.insert(*uid, value.clone());
+                        false
+                    }
+                    None => true,
+                });
+            }
+        }
+        data
+    }
+}
+
+/// `[length][index ID length][index ID][serialized chains][CRC32]`, lengths and CRC32
+/// are big-endian.
+fn encode_record(index_id: &str, data: &EncryptedTable<UID_LENGTH>) -> Result<Vec<u8>, Error> {
+    let serialized = data.serialize()?;
+    let length = 1 + index_id.len() + serialized.len();
+
+    let mut record = Vec::with_capacity(RECORD_HEADER_LENGTH + length + RECORD_CHECKSUM_LENGTH);
+    record.extend_from_slice(&(length as u32).to_be_bytes());
+    record.push(index_id.len() as u8);
+    record.extend_from_slice(index_id.as_bytes());
+    record.extend_from_slice(&serialized);
+    let checksum = crc32fast::hash(&record[RECORD_HEADER_LENGTH..]);
+    record.extend_from_slice(&checksum.to_be_bytes());
+
+    Ok(record)
+}
+
+/// Next record and its length inside the log, `None` at the end of the log or if the
+/// record is incomplete or corrupted (`remaining` bytes are left in the log)
+#[allow(clippy::type_complexity)]
+fn read_record(
+    reader: &mut impl Read,
+    remaining: u64,
+) -> io::Result<Option<((String, EncryptedTable<UID_LENGTH>), usize)>> {
+    if remaining < (RECORD_HEADER_LENGTH + RECORD_CHECKSUM_LENGTH) as u64 {
+        return Ok(None);
+    }
+    let mut header = [0; RECORD_HEADER_LENGTH];
+    reader.read_exact(&mut header)?;
+    let length = u32::from_be_bytes(header) as usize;
+    if (RECORD_HEADER_LENGTH + length + RECORD_CHECKSUM_LENGTH) as u64 > remaining {
+        return Ok(None);
+    }
+
+    let mut payload = vec![0; length + RECORD_CHECKSUM_LENGTH];
+    reader.read_exact(&mut payload)?;
+    let checksum = payload.split_off(length);
+    if crc32fast::hash(&payload).to_be_bytes()[..] != checksum[..] {
+        return Ok(None);
+    }
+
+    Ok(decode_payload(&payload).map(|record| {
+        (
+            record,
+            RECORD_HEADER_LENGTH + length + RECORD_CHECKSUM_LENGTH,
+        )
+    }))
+}
+
+/// `None` if the payload is corrupted
+fn decode_payload(payload: &[u8]) -> Option<(String, EncryptedTable<UID_LENGTH>)> {
+    let index_id_length = *payload.first()? as usize;
+    let index_id = String::from_utf8(payload.get(1..1 + index_id_length)?.to_vec()).ok()?;
+    let data = EncryptedTable::deserialize(payload.get(1 + index_id_length..)?).ok()?;
+
+    Some((index_id, data))
+}
+
+/// Write chains to the indexes database, retrying until it succeeds. `take` returns the
+/// chains to write, empty if they are already written.
+async fn write_chains(
+    inner: &dyn IndexesDatabase,
+    metadata_database: &dyn MetadataDatabase,
+    index_id: &str,
+    take: impl Fn() -> EncryptedTable<UID_LENGTH>,
+) {
+    let mut delay = Duration::from_secs(1);
+    loop {
+        let result = match metadata_database.get_index(index_id).await {
+            Ok(Some(index)) => {
+                // Empty if the index was deleted in the meantime
+                let data = take();
+                if data.is_empty() {
+                    Ok(())
+                } else {
+                    inner.insert_chains(&index, data).await
+                }
+            }
+            Ok(None) => {
+                log::warn!("Index {index_id} was deleted, its queued chains are dropped");
+                Ok(())
+            }
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(()) => return,
+            Err(err) => {
+                log::warn!(
+                    "Cannot write queued chains of index {index_id}, retrying in {delay:?} ({err:?})"
+                );
+                actix_web::rt::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+        }
+    }
+}
+
+/// Write the records to the indexes database, in order.
+async fn drain(
+    queue: Arc<Queue>,
+    inner: Arc<dyn IndexesDatabase>,
+    metadata_database: Arc<dyn MetadataDatabase>,
+    mut receiver: UnboundedReceiver<Record>,
+) {
+    while let Some((index_id, uids, offset)) = receiver.recv().await {
+        write_chains(&*inner, &*metadata_database, &index_id, || {
+            queue.take(&index_id, &uids)
+        })
+        .await;
+
+        let queue = queue.clone();
+        if let Err(err) = web::block(move || queue.written(&index_id, &uids, offset)).await {
+            log::error!("Cannot remove the written chains from the write-behind queue ({err})");
+        }
+    }
+}
+
+#[async_trait]
+impl IndexesDatabase for Database {
+    async fn set_size(&self, index: &mut Index) -> Result<(), Error> {
+        self.inner.set_size(index).await
+    }
+
+    async fn set_sizes(&self, indexes: &mut Vec<Index>) -> Result<(), Error> {
+        self.inner.set_sizes(indexes).await
+    }
+
+    async fn fetch(
+        &self,
+        index: &Index,
+        table: Table,
+        mut uids: HashSet<Uid<UID_LENGTH>>,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        let pending = match table {
+            Table::Entries => EncryptedTable::default(),
+            Table::Chains => self.queue.fetch(&index.id, &mut uids),
+        };
+
+        let mut uids_and_values = self.inner.fetch(index, table, uids).await?;
+        for (uid, value) in pending {
+            uids_and_values.insert(uid, value);
+        }
+
+        Ok(uids_and_values)
+    }
+
+    async fn fetch_with_consistency(
+        &self,
+        index: &Index,
+        table: Table,
+        mut uids: HashSet<Uid<UID_LENGTH>>,
+        consistency: Consistency,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        let pending = match table {
+            Table::Entries => EncryptedTable::default(),
+            Table::Chains => self.queue.fetch(&index.id, &mut uids),
+        };
+
+        let mut uids_and_values = self
+            .inner
+            .fetch_with_consistency(index, table, uids, consistency)
+            .await?;
+        for (uid, value) in pending {
+            uids_and_values.insert(uid, value);
+        }
+
+        Ok(uids_and_values)
+    }
+
+    async fn upsert_entries(
+        &self,
+        index: &Index,
+        data: UpsertData<UID_LENGTH>,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        self.inner.upsert_entries(index, data).await
+    }
+
+    async fn insert_chains(
+        &self,
+        index: &Index,
+        data: EncryptedTable<UID_LENGTH>,
+    ) -> Result<(), Error> {
+        let queue = self.queue.clone();
+        let index_id = index.id.clone();
+        web::block(move || queue.append(&index_id, data))
+            .await
+            .map_err(|err| Error::Internal(err.to_string()))?
+    }
+
+    async fn put_values(
+        &self,
+        index: &Index,
+        table: Table,
+        data: EncryptedTable<UID_LENGTH>,
+    ) -> Result<(), Error> {
+        self.inner.put_values(index, table, data).await
+    }
+
+    async fn fetch_all(
+        &self,
+        index: &Index,
+        table: Table,
+    ) -> Result<EncryptedTable<UID_LENGTH>, Error> {
+        let mut uids_and_values = self.inner.fetch_all(index, table).await?;
+
+        if let (Table::Chains, Ok(pending)) = (table, self.queue.pending.read()) {
+            if let Some(chains) = pending.chains.get(&index.id) {
+                for (uid, value) in chains {
+                    uids_and_values.insert(*uid, value.clone());
+                }
+            }
+        }
+
+        Ok(uids_and_values)
+    }
+
+    async fn indexes_ids_with_data(&self) -> Result<HashSet<String>, Error> {
+        self.inner.indexes_ids_with_data().await
+    }
+
+    async fn delete_index_data(&self, index_id: &str) -> Result<(), Error> {
+        self.queue.forget_index(index_id);
+        self.inner.delete_index_data(index_id).await
+    }
+
+    async fn backup(&self) -> Result<BackupInfo, Error> {
+        self.inner.backup().await
+    }
+
+    async fn backups(&self) -> Result<Vec<BackupInfo>, Error> {
+        self.inner.backups().await
+    }
+
+    fn supports_ttl(&self) -> bool {
+        self.inner.supports_ttl()
+    }
+
+    async fn recompute_size(&self, index: &Index) -> Result<(), Error> {
+        self.inner.recompute_size(index).await
+    }
+
+    async fn scrub(
+        &self,
+        index: &Index,
+        table: Table,
+        from: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<ScrubBatch, Error> {
+        self.inner.scrub(index, table, from, limit).await
+    }
+
+    async fn append_changes(&self, index: &Index, mutations: &[Mutation]) -> Result<(), Error> {
+        self.inner.append_changes(index, mutations).await
+    }
+
+    async fn fetch_changes(
+        &self,
+        index: &Index,
+        since: u64,
+        limit: usize,
+    ) -> Result<Vec<Change>, Error> {
+        self.inner.fetch_changes(index, since, limit).await
+    }
+
+    async fn storage_gauges(&self) -> Result<Vec<StorageGauge>, Error> {
+        let mut gauges = self.inner.storage_gauges().await?;
+
+        if let Ok(pending) = self.queue.pending.read() {
+            gauges.push(StorageGauge::gauge(
+                "findex_cloud_write_behind_pending_chains",
+                "Chains inserted but not written to the indexes database yet.",
+                pending.count as f64,
+            ));
+        }
+
+        Ok(gauges)
+    }
+
+    #[cfg(feature = "log_requests")]
+    async fn fetch_all_as_json(&self, index: &Index, table: Table) -> Result<String, Error> {
+        self.inner.fetch_all_as_json(index, table).await
+    }
+}